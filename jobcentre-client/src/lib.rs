@@ -0,0 +1,133 @@
+//! Async client for the job centre (p09).
+
+use anyhow::{bail, Context, Result};
+use p09::protocol::{GetOk, NewJob, Request};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// One connection to a job centre. Jobs handed out by `get` are held by
+/// this connection and go back to their queue if it is dropped first.
+pub struct Client {
+    read: BufReader<OwnedReadHalf>,
+    write: OwnedWriteHalf,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        let (read, write) = TcpStream::connect(addr).await?.into_split();
+        Ok(Client {
+            read: BufReader::new(read),
+            write,
+        })
+    }
+
+    /// Puts a job on `queue`, returning its id.
+    pub async fn put(&mut self, queue: &str, job: Value, pri: u64) -> Result<u64> {
+        self.put_job(NewJob {
+            queue: queue.to_owned(),
+            job,
+            pri,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Puts a job with any of the extension fields set, returning its id.
+    pub async fn put_job(&mut self, job: NewJob) -> Result<u64> {
+        let reply = self.request(&Request::put(job)).await?;
+        reply["id"].as_u64().context("put reply without an id")
+    }
+
+    /// Takes the best job from `queues`, or `None` if there is none and
+    /// `wait` is false.
+    pub async fn get(&mut self, queues: &[&str], wait: bool) -> Result<Option<GetOk>> {
+        let queues = queues.iter().map(|q| q.to_string()).collect();
+        let reply = self.request(&Request::get { queues, wait }).await?;
+        if reply["status"] == "no-job" {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(reply)?))
+    }
+
+    /// Deletes a job, returning false if there was no such job.
+    pub async fn delete(&mut self, id: u64) -> Result<bool> {
+        let reply = self.request(&Request::delete { id }).await?;
+        Ok(reply["status"] == "ok")
+    }
+
+    /// Puts a job this connection got back on its queue, returning false if
+    /// the job is gone.
+    pub async fn abort(&mut self, id: u64) -> Result<bool> {
+        let reply = self.request(&Request::abort { id }).await?;
+        Ok(reply["status"] == "ok")
+    }
+
+    /// Sends `request` and returns the reply. Replies with an `error`
+    /// status come back as errors.
+    pub async fn request(&mut self, request: &Request) -> Result<Value> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.write.write_all(line.as_bytes()).await?;
+
+        let mut line = String::new();
+        if 0 == self.read.read_line(&mut line).await? {
+            bail!("server closed the connection");
+        }
+        let reply: Value = serde_json::from_str(&line)?;
+        if reply["status"] == "error" {
+            bail!("server error: {}", reply["error"]);
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p09::{ClientHandler, JobServer, JobServerHandle};
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    async fn start() -> SocketAddr {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let server = JobServerHandle::spawn(JobServer::default());
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = list.accept().await.unwrap();
+                let (read, write) = stream.into_split();
+                let mut handler = ClientHandler::new(
+                    server.clone(),
+                    BufReader::new(read),
+                    write,
+                    shutdown.clone(),
+                );
+                tokio::spawn(async move { handler.run().await });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn put_get_abort_delete() {
+        let addr = start().await;
+        let mut producer = Client::connect(addr).await.unwrap();
+        let mut worker = Client::connect(addr).await.unwrap();
+
+        let id = producer.put("q", json!({"n": 1}), 3).await.unwrap();
+        let job = worker.get(&["q", "r"], false).await.unwrap().unwrap();
+        assert_eq!((id, "q", 3), (job.id, job.queue.as_str(), job.pri));
+        assert_eq!(json!({"n": 1}), job.job);
+        assert!(worker.get(&["q"], false).await.unwrap().is_none());
+
+        assert!(worker.abort(id).await.unwrap());
+        assert!(producer.delete(id).await.unwrap());
+        assert!(!producer.delete(id).await.unwrap());
+        assert!(producer.abort(id).await.is_err());
+    }
+}
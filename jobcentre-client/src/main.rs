@@ -0,0 +1,67 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use jobcentre_client::Client;
+use serde_json::Value;
+
+/// Pokes a job centre by hand. Each invocation is one connection, so a job
+/// taken with `get` goes back to its queue when the command exits.
+#[derive(Parser)]
+struct Args {
+    /// Address of the job centre.
+    #[arg(long, default_value = "127.0.0.1:4567")]
+    addr: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Puts a job and prints its id.
+    Put {
+        queue: String,
+        /// The job, as JSON.
+        job: String,
+        pri: u64,
+    },
+    /// Takes the best job from the given queues and prints it.
+    Get {
+        #[arg(required = true)]
+        queues: Vec<String>,
+        /// Wait for a job instead of giving up when there is none.
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Deletes a job.
+    Delete { id: u64 },
+    /// Sends a raw JSON request and prints the reply.
+    Raw { request: String },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut client = Client::connect(&args.addr).await?;
+    match args.command {
+        Command::Put { queue, job, pri } => {
+            let job: Value = serde_json::from_str(&job)?;
+            println!("{}", client.put(&queue, job, pri).await?);
+        }
+        Command::Get { queues, wait } => {
+            let queues: Vec<&str> = queues.iter().map(String::as_str).collect();
+            match client.get(&queues, wait).await? {
+                Some(job) => println!("{}", serde_json::to_string(&job)?),
+                None => println!("no job"),
+            }
+        }
+        Command::Delete { id } => {
+            if !client.delete(id).await? {
+                println!("no job {id}");
+            }
+        }
+        Command::Raw { request } => {
+            let request = serde_json::from_str(&request)?;
+            println!("{}", client.request(&request).await?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! Smoke test (p00): echoes back whatever a client sends.
+
+use anyhow::Result;
+use clap::Parser;
+use proto_common::{ConnLimit, Listen, Server};
+use std::future::Future;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+async fn handle(mut stream: TcpStream) -> Result<()> {
+    loop {
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buf[..n]).await?;
+    }
+
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    Server::new(list)
+        .with_limit(&args.limit)
+        .run(stop, |stream, _, _| async move {
+            let _ = handle(stream).await;
+        })
+        .await
+}
@@ -1,27 +1,10 @@
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-
-async fn handle(mut stream: TcpStream) -> Result<()> {
-    loop {
-        let mut buf = [0u8; 4096];
-        let n = stream.read(&mut buf).await?;
-        if n == 0 {
-            break;
-        }
-        stream.write_all(&buf[..n]).await?;
-    }
-
-    stream.shutdown().await?;
-
-    Ok(())
-}
+use clap::Parser;
+use p00::Args;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let list = TcpListener::bind("0.0.0.0:4567").await?;
-    loop {
-        let (stream, _) = list.accept().await?;
-        handle(stream).await?;
-    }
+    let args = Args::parse();
+    proto_common::init_tracing();
+    p00::run(args, proto_common::signal()).await
 }
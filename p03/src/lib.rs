@@ -0,0 +1,134 @@
+//! Budget chat (p03): a chat room everyone connected to takes part in.
+
+use anyhow::Result;
+use clap::Parser;
+use proto_common::{ConnLimit, LineCodec, Listen, Server};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{
+    broadcast::{channel, Sender},
+    Mutex,
+};
+
+#[derive(Debug, Default)]
+struct State {
+    users: HashSet<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Event {
+    NewUser(String),
+    UserQuit(String),
+    Message { from: String, content: String },
+}
+
+const LINES: LineCodec = LineCodec::new(proto_common::DEFAULT_MAX_LEN);
+
+async fn handle(stream: TcpStream, s: Sender<Event>, state: Arc<Mutex<State>>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    LINES.write_line(&mut write, "name?").await?;
+
+    let Some(name) = LINES.read_line(&mut read).await? else {
+        return Ok(());
+    };
+    let name = name.trim().to_owned();
+
+    if !name.chars().all(|c| c.is_ascii_alphanumeric()) || name.is_empty() {
+        return Ok(());
+    }
+
+    state.lock().await.users.insert(name.clone());
+
+    let resp = format!(
+        "* {:?}",
+        state
+            .lock()
+            .await
+            .users
+            .iter()
+            .filter(|u| **u != name)
+            .collect::<Vec<_>>()
+    );
+    LINES.write_line(&mut write, &resp).await?;
+
+    let mut r = s.subscribe();
+    s.send(Event::NewUser(name.clone()))?;
+
+    let handle = tokio::spawn({
+        let s = s.clone();
+        let name = name.clone();
+        let state = state.clone();
+
+        async move {
+            loop {
+                match LINES.read_line(&mut read).await {
+                    Ok(None) | Err(_) => {
+                        s.send(Event::UserQuit(name.clone())).unwrap();
+                        state.lock().await.users.remove(&name);
+                        return;
+                    }
+                    Ok(Some(line)) => {
+                        let line = line.trim();
+                        s.send(Event::Message {
+                            from: name.clone(),
+                            content: line.to_owned(),
+                        })
+                        .unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    loop {
+        match r.recv().await? {
+            Event::UserQuit(user) if user != name => {
+                let resp = format!("* {user} has quit the room");
+                LINES.write_line(&mut write, &resp).await?;
+            }
+            Event::UserQuit(_) => break,
+            Event::NewUser(new_user) if new_user != name => {
+                let resp = format!("* {new_user} has entered the room");
+                LINES.write_line(&mut write, &resp).await?;
+            }
+            Event::Message { from, content } if from != name => {
+                let resp = format!("[{from}] {content}");
+                LINES.write_line(&mut write, &resp).await?;
+            }
+            Event::NewUser(_) => {}
+            Event::Message { .. } => {}
+        }
+    }
+
+    write.shutdown().await?;
+    Ok(handle.await?)
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let (s, _r) = channel(100);
+    let state = Arc::new(Mutex::new(State::default()));
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    Server::new(list)
+        .with_limit(&args.limit)
+        .run(stop, |stream, _, _| {
+            let conn = handle(stream, s.clone(), state.clone());
+            async move {
+                let _ = conn.await;
+            }
+        })
+        .await
+}
@@ -0,0 +1,50 @@
+//! Unusual database program (p04): a key-value store over UDP.
+
+use anyhow::Result;
+use clap::Parser;
+use proto_common::Listen;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::net::UdpSocket;
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let socket = UdpSocket::bind(args.listen.addr()).await?;
+    let mut state: HashMap<Vec<u8>, Vec<u8>> = Default::default();
+    state.insert(b"version".to_vec(), b"0.42".to_vec());
+
+    // Requests are answered as they come, so there's nothing to finish on
+    // the way out.
+    tokio::pin!(stop);
+    loop {
+        let mut buf = vec![0u8; 1024];
+        let (len, addr) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            () = &mut stop => return Ok(()),
+        };
+        let buf = &buf[..len];
+        if let Some(i) = buf.iter().position(|v| *v == b'=') {
+            let key = &buf[..i];
+            if key == b"version" {
+                continue;
+            }
+            let val = &buf[i + 1..];
+            state.insert(key.to_vec(), val.to_vec());
+        } else {
+            let mut val = match state.get(buf) {
+                Some(v) => v.clone(),
+                None => vec![],
+            };
+            let mut reply = buf.to_vec();
+            reply.push(b'=');
+            reply.append(&mut val);
+            socket.send_to(&reply, addr).await?;
+        }
+    }
+}
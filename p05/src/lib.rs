@@ -0,0 +1,84 @@
+//! Mob in the middle (p05): relays chat to an upstream server, swapping
+//! Boguscoin addresses for Tony's on the way.
+
+use anyhow::Result;
+use clap::Parser;
+use proto_common::{ConnLimit, LineCodec, Listen, Server};
+use regex::Regex;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+
+const LINES: LineCodec = LineCodec::new(proto_common::DEFAULT_MAX_LEN);
+
+async fn handle(stream: TcpStream, re: Regex, upstream: Arc<str>) -> Result<()> {
+    let (client_read, mut client_write) = stream.into_split();
+    let mut client_read = BufReader::new(client_read);
+    let real_server = TcpStream::connect(&*upstream).await?;
+    let (server_read, mut server_write) = real_server.into_split();
+    let mut server_read = BufReader::new(server_read);
+
+    tokio::spawn({
+        let re = re.clone();
+        async move {
+            while let Ok(Some(query)) = LINES.read_line(&mut server_read).await {
+                let query = rep(&re, &query);
+                let _ = LINES.write_line(&mut client_write, &query).await;
+            }
+        }
+    });
+
+    while let Some(line) = LINES.read_line(&mut client_read).await? {
+        let line = rep(&re, &line);
+        LINES.write_line(&mut server_write, &line).await?;
+    }
+    Ok(())
+}
+
+fn rep(re: &Regex, s: &str) -> String {
+    use regex::Captures;
+
+    fn aux<'a, 'b>(c: &'a Captures<'b>) -> String {
+        let s = match c.name("before") {
+            Some(s) => s.as_str().to_owned(),
+            None => String::new(),
+        };
+        let s = format!("{s}{}", TONY);
+        match c.name("rest") {
+            Some(rest) => format!("{s}{}", rest.as_str()),
+            None => s,
+        }
+    }
+    re.replace_all(s, aux).to_string()
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+    /// Chat server the clients' messages are relayed to.
+    #[arg(long, default_value = "chat.protohackers.com:16963")]
+    upstream: String,
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    let upstream: Arc<str> = args.upstream.into();
+    let re = Regex::new(r#"(?P<before>[ ])?(?P<coin>7[[:alnum:]]{25,34})(?P<rest>[ ]|$)"#)?;
+
+    Server::new(list)
+        .with_limit(&args.limit)
+        .run(stop, |stream, _, _| {
+            let conn = handle(stream, re.clone(), upstream.clone());
+            async move {
+                let _ = conn.await;
+            }
+        })
+        .await
+}
+
+const TONY: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
@@ -0,0 +1,291 @@
+//! Speed daemon (p06): collects plate sightings from cameras and sends
+//! tickets for speeding to dispatchers.
+
+use anyhow::Result;
+use async_channel::{unbounded, Receiver, Sender};
+use clap::Parser;
+use proto_common::{ConnLimit, Listen, Server, Shutdown};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tracing::{debug, info, Instrument};
+
+const ERROR: u8 = 0x10;
+const PLATE: u8 = 0x20;
+const TICKET: u8 = 0x21;
+const WANT_HEARTBEAT: u8 = 0x40;
+const HEARTBEAT: u8 = 0x41;
+const I_AM_CAMERA: u8 = 0x80;
+const I_AM_DISPATCHER: u8 = 0x81;
+
+#[derive(Debug)]
+struct Position {
+    timestamp: u32,
+    mile: u16,
+}
+
+/// Observations of each plate on each road, by (plate, road).
+type Positions = Arc<Mutex<HashMap<(String, u16), Vec<Position>>>>;
+
+async fn handle(
+    stream: TcpStream,
+    positions: Positions,
+    ticket_state: Arc<Mutex<TicketState>>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    #[derive(Debug, PartialEq)]
+    enum Identity {
+        Camera,
+        Dispatcher,
+    }
+
+    let mut identified = None;
+    let mut road = 0;
+    let mut mile = 0;
+    let mut limit = 0;
+
+    let (mut client_read, client_write) = stream.into_split();
+    let client_write = Arc::new(Mutex::new(client_write));
+    let mut dispatching = vec![];
+    loop {
+        let id = tokio::select! {
+            id = client_read.read_u8() => id?,
+            () = shutdown.started() => break,
+        };
+        match id {
+            ERROR => {
+                debug!("client sent ERROR")
+            }
+            PLATE => {
+                if identified == Some(Identity::Dispatcher) {
+                    let mut c = client_write.lock().await;
+                    let _ = c.write_u8(ERROR).await;
+                    let message = b"plate from Dispatcher";
+                    let _ = c.write_u8(message.len() as u8).await;
+                    let _ = c.write_all(message).await;
+                } else {
+                    let len = client_read.read_u8().await? as usize;
+                    let mut buf = vec![0u8; len];
+                    client_read.read_exact(&mut buf).await?;
+                    let plate = std::str::from_utf8(&buf)?;
+                    let timestamp = client_read.read_u32().await?;
+
+                    debug!(plate, timestamp, "plate");
+                    {
+                        let mut positions = positions.lock().await;
+                        let entry = positions.entry((plate.to_owned(), road)).or_default();
+                        entry.push(Position { timestamp, mile });
+                        let l = entry.len();
+                        if l > 1 {
+                            let new = entry.last().unwrap();
+                            for position in &entry[..l - 1] {
+                                let (prev, next) = if new.timestamp <= position.timestamp {
+                                    (new, position)
+                                } else {
+                                    (position, new)
+                                };
+                                let dist = (next.mile as i64 - prev.mile as i64).abs() as f64;
+                                let time = (next.timestamp as i64 - prev.timestamp as i64) as f64
+                                    / (60.0 * 60.0);
+                                let speed = (dist / time).round() as u16;
+                                if speed > limit {
+                                    let this_days: HashSet<_> = (prev.timestamp..=next.timestamp)
+                                        .map(|t| t / (24 * 60 * 60))
+                                        .collect();
+                                    let mut ticket_state = ticket_state.lock().await;
+                                    let new_days = this_days
+                                        .difference(
+                                            ticket_state.days.entry(plate.to_owned()).or_default(),
+                                        )
+                                        .count();
+
+                                    if new_days == this_days.len() {
+                                        let existing_tickets =
+                                            ticket_state.days.entry(plate.to_owned()).or_default();
+                                        existing_tickets.extend(this_days.clone());
+                                        let sender = ticket_state
+                                            .queues
+                                            .entry(road)
+                                            .or_insert_with(unbounded)
+                                            .0
+                                            .clone();
+                                        sender
+                                            .send(Ticket {
+                                                plate: plate.to_owned(),
+                                                road,
+                                                mile1: prev.mile,
+                                                timestamp1: prev.timestamp,
+                                                mile2: next.mile,
+                                                timestamp2: next.timestamp,
+                                                speed: speed * 100,
+                                            })
+                                            .await?;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            TICKET => {
+                debug!("client sent TICKET")
+            }
+            WANT_HEARTBEAT => {
+                let interval = client_read.read_u32().await?;
+                debug!(interval, "heartbeat wanted");
+                if interval > 0 {
+                    tokio::spawn({
+                        let client_write = client_write.clone();
+                        let duration = Duration::from_millis(interval as u64 * 100);
+                        async move {
+                            loop {
+                                if client_write.lock().await.write_u8(HEARTBEAT).await.is_err() {
+                                    break;
+                                }
+                                sleep(duration).await;
+                            }
+                        }
+                    });
+                }
+            }
+            HEARTBEAT => {
+                debug!("client sent HEARTBEAT")
+            }
+            I_AM_CAMERA => {
+                if identified.is_some() {
+                    let mut c = client_write.lock().await;
+                    let _ = c.write_u8(ERROR).await;
+                    let message = b"double I_AM_CAMERA";
+                    let _ = c.write_u8(message.len() as u8).await;
+                    let _ = c.write_all(message).await;
+                } else {
+                    identified = Some(Identity::Camera);
+                    road = client_read.read_u16().await?;
+                    mile = client_read.read_u16().await?;
+                    limit = client_read.read_u16().await?;
+                    info!(road, mile, limit, "camera");
+                }
+            }
+            I_AM_DISPATCHER => {
+                if identified.is_some() {
+                    let mut c = client_write.lock().await;
+                    let _ = c.write_u8(ERROR).await;
+                    let message = b"double I_AM_DISPATCHER";
+                    let _ = c.write_u8(message.len() as u8).await;
+                    let _ = c.write_all(message).await;
+                } else {
+                    identified = Some(Identity::Dispatcher);
+                    let numroads = client_read.read_u8().await?;
+                    let mut roads: Vec<u16> = vec![];
+                    for _ in 0..numroads {
+                        roads.push(client_read.read_u16().await?);
+                    }
+                    info!(?roads, "dispatcher");
+                    for road in roads {
+                        let receiver = ticket_state
+                            .lock()
+                            .await
+                            .queues
+                            .entry(road)
+                            .or_insert_with(unbounded)
+                            .1
+                            .clone();
+                        let client_write = client_write.clone();
+                        let mut shutdown = shutdown.clone();
+                        dispatching.push(tokio::spawn({
+                            async move {
+                                loop {
+                                    // On shutdown, tickets already queued
+                                    // still go out.
+                                    let ticket = tokio::select! {
+                                        ticket = receiver.recv() => ticket.unwrap(),
+                                        () = shutdown.started() => match receiver.try_recv() {
+                                            Ok(ticket) => ticket,
+                                            Err(_) => return,
+                                        },
+                                    };
+                                    info!(?ticket, "sending ticket");
+                                    let mut c = client_write.lock().await;
+                                    let _ = c.write_u8(TICKET).await;
+                                    let _ = c.write_u8(ticket.plate.len() as u8).await;
+                                    let _ = c.write_all(ticket.plate.as_bytes()).await;
+                                    let _ = c.write_u16(ticket.road).await;
+                                    let _ = c.write_u16(ticket.mile1).await;
+                                    let _ = c.write_u32(ticket.timestamp1).await;
+                                    let _ = c.write_u16(ticket.mile2).await;
+                                    let _ = c.write_u32(ticket.timestamp2).await;
+                                    let _ = c.write_u16(ticket.speed).await;
+                                }
+                            }
+                            .in_current_span()
+                        }));
+                    }
+                }
+            }
+            other => {
+                let mut c = client_write.lock().await;
+                let _ = c.write_u8(ERROR).await;
+                let message = format!("unexpected message with id: {other}");
+                let _ = c.write_u8(message.len() as u8).await;
+                let _ = c.write_all(message.as_bytes()).await;
+            }
+        }
+    }
+    for task in dispatching {
+        let _ = task.await;
+    }
+    Ok(())
+}
+
+// Ticket to be sent out when dispatcher for given road is ready
+#[derive(Debug)]
+struct Ticket {
+    plate: String,
+    road: u16,
+    mile1: u16,
+    timestamp1: u32,
+    mile2: u16,
+    timestamp2: u32,
+    speed: u16,
+}
+
+#[derive(Debug, Default)]
+struct TicketState {
+    // Road -> dispatcher channel
+    queues: HashMap<u16, (Sender<Ticket>, Receiver<Ticket>)>,
+
+    // Plate -> Days with tickets
+    days: HashMap<String, HashSet<u32>>,
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let list = TcpListener::bind(args.listen.addr()).await?;
+
+    let positions: Positions = Arc::new(Mutex::new(Default::default()));
+
+    let ticket_state = Arc::new(Mutex::new(TicketState::default()));
+
+    Server::new(list)
+        .with_limit(&args.limit)
+        .run(stop, |stream, _, shutdown| {
+            let conn = handle(stream, positions.clone(), ticket_state.clone(), shutdown);
+            async move {
+                let _ = conn.await;
+            }
+        })
+        .await
+}
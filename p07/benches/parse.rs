@@ -0,0 +1,21 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use p07::lrcp::Message;
+use std::hint::black_box;
+
+fn parse(c: &mut Criterion) {
+    let ack = b"/ack/1234567/1024/";
+    let data = b"/data/1234567/13/the quick brown fox jumps over the lazy dog\n/";
+    let escaped = b"/data/1234567/13/foo\\/bar\\\\baz\\/the quick brown fox\n/";
+    c.bench_function("parse ack", |b| {
+        b.iter(|| Message::parse(black_box(ack)).unwrap())
+    });
+    c.bench_function("parse data", |b| {
+        b.iter(|| Message::parse(black_box(data)).unwrap())
+    });
+    c.bench_function("parse escaped data", |b| {
+        b.iter(|| Message::parse(black_box(escaped)).unwrap())
+    });
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
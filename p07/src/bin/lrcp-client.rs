@@ -0,0 +1,46 @@
+use anyhow::{anyhow, Result};
+use p07::lrcp::LrcpStream;
+use std::io::Write;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::select;
+use tokio::time::{sleep, Duration};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let addr = args
+        .next()
+        .ok_or(anyhow!("usage: lrcp-client HOST:PORT [SESSION]"))?;
+    let addr = tokio::net::lookup_host(&addr)
+        .await?
+        .next()
+        .ok_or(anyhow!("can't resolve {addr}"))?;
+    let session = match args.next() {
+        Some(session) => session.parse()?,
+        None => std::process::id() as u64,
+    };
+
+    let stream = LrcpStream::connect(addr, session).await?;
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+    loop {
+        // LRCP has no half-close, so after stdin ends the session is only
+        // closed once the server has been quiet for a while.
+        select! {
+            line = stdin.next_line(), if stdin_open => match line? {
+                Some(line) => stream.send(format!("{line}\n").into_bytes()).await?,
+                None => stdin_open = false,
+            },
+            _ = sleep(Duration::from_secs(1)), if !stdin_open => stream.close(),
+            data = stream.recv() => match data {
+                Some(data) => {
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&data)?;
+                    stdout.flush()?;
+                }
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
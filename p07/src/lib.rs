@@ -0,0 +1,4 @@
+pub mod lrcp;
+mod reverse;
+
+pub use reverse::{run, Args};
@@ -0,0 +1,1570 @@
+use anyhow::{anyhow, bail, Result};
+use async_channel::{unbounded, Receiver, Sender};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::select;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info, info_span, Instrument};
+
+#[cfg(test)]
+mod lossy;
+
+/// Parses a decimal protocol number, which the spec requires to be smaller
+/// than 2147483648.
+fn number(b: &[u8]) -> Result<u64> {
+    if b.is_empty() || b.len() > 10 {
+        bail!("invalid number {b:?}");
+    }
+    let mut n = 0u64;
+    for d in b {
+        if !d.is_ascii_digit() {
+            bail!("invalid number {b:?}");
+        }
+        n = n * 10 + (d - b'0') as u64;
+    }
+    if n >= 1 << 31 {
+        bail!("number {n} out of range");
+    }
+    Ok(n)
+}
+
+/// Splits off the field before the next `/`.
+fn field(b: &[u8]) -> Result<(&[u8], &[u8])> {
+    let slash = b
+        .iter()
+        .position(|b| *b == b'/')
+        .ok_or_else(|| anyhow!("missing field in {b:?}"))?;
+    Ok((&b[..slash], &b[slash + 1..]))
+}
+
+/// Validates and unescapes a data payload, borrowing it if it contains no
+/// escapes.
+fn unescape(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let Some(first) = data.iter().position(|b| *b == b'\\' || *b == b'/') else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..first]);
+    let mut rest = data[first..].iter();
+    while let Some(b) = rest.next() {
+        match b {
+            b'\\' => match rest.next() {
+                Some(b @ (b'\\' | b'/')) => out.push(*b),
+                _ => bail!("invalid escape"),
+            },
+            b'/' => bail!("unescaped /"),
+            b => out.push(*b),
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Message<'a> {
+    Connect {
+        session: u64,
+    },
+    Data {
+        session: u64,
+        pos: u64,
+        data: Cow<'a, [u8]>,
+    },
+    Ack {
+        session: u64,
+        len: u64,
+    },
+    Close {
+        session: u64,
+    },
+}
+
+impl<'a> Message<'a> {
+    /// Parses a datagram in a single pass. Data payloads borrow from `b`
+    /// unless they need unescaping.
+    pub fn parse(b: &'a [u8]) -> Result<Message<'a>> {
+        let body = b
+            .strip_prefix(b"/")
+            .and_then(|b| b.strip_suffix(b"/"))
+            .ok_or_else(|| anyhow!("message not enclosed in /"))?;
+        let (kind, rest) = field(body)?;
+        match kind {
+            b"connect" => Ok(Message::Connect {
+                session: number(rest)?,
+            }),
+            b"close" => Ok(Message::Close {
+                session: number(rest)?,
+            }),
+            b"ack" => {
+                let (session, len) = field(rest)?;
+                Ok(Message::Ack {
+                    session: number(session)?,
+                    len: number(len)?,
+                })
+            }
+            b"data" => {
+                let (session, rest) = field(rest)?;
+                let (pos, data) = field(rest)?;
+                Ok(Message::Data {
+                    session: number(session)?,
+                    pos: number(pos)?,
+                    data: unescape(data)?,
+                })
+            }
+            _ => bail!("unknown message {b:?}"),
+        }
+    }
+
+    pub fn into_owned(self) -> Message<'static> {
+        match self {
+            Self::Connect { session } => Message::Connect { session },
+            Self::Data { session, pos, data } => Message::Data {
+                session,
+                pos,
+                data: Cow::Owned(data.into_owned()),
+            },
+            Self::Ack { session, len } => Message::Ack { session, len },
+            Self::Close { session } => Message::Close { session },
+        }
+    }
+
+    fn session(&self) -> u64 {
+        match self {
+            Self::Connect { session }
+            | Self::Data { session, .. }
+            | Self::Ack { session, .. }
+            | Self::Close { session } => *session,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Self::Connect { session } => format!("/connect/{session}/").into_bytes(),
+            Self::Data { session, pos, data } => {
+                let mut msg = format!("/data/{session}/{pos}/").into_bytes();
+                msg.reserve(data.len() + 1);
+                for b in data.iter() {
+                    if *b == b'\\' || *b == b'/' {
+                        msg.push(b'\\');
+                    }
+                    msg.push(*b);
+                }
+                msg.push(b'/');
+                msg
+            }
+            Self::Ack { session, len } => format!("/ack/{session}/{len}/").into_bytes(),
+            Self::Close { session } => format!("/close/{session}/").into_bytes(),
+        }
+    }
+}
+
+/// How often session tasks check for due retransmissions and expiry.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+const MAX_RETRANSMIT_BACKOFF: Duration = Duration::from_secs(15);
+
+/// Maximum number of bytes a session buffers ahead of its contiguous receive
+/// position while waiting for a gap to be filled.
+const MAX_REORDER_BYTES: usize = 64 * 1024;
+
+/// Largest chunk payload that still fits a 1000-byte datagram when every
+/// byte needs escaping and the session and position take ten digits each.
+pub const MAX_CHUNK_SIZE: usize = 480;
+
+/// Data chunks that arrived ahead of the contiguous receive position, keyed by
+/// their stream offset.
+#[derive(Default)]
+struct Reassembly {
+    chunks: BTreeMap<u64, Vec<u8>>,
+    buffered: usize,
+}
+
+impl Reassembly {
+    /// Buffers a chunk starting at `pos`. Returns false if it would exceed
+    /// `MAX_REORDER_BYTES`, in which case the peer has to retransmit it.
+    fn insert(&mut self, pos: u64, data: Vec<u8>) -> bool {
+        let replaced = self.chunks.get(&pos).map(|c| c.len()).unwrap_or(0);
+        if data.len() <= replaced {
+            return true;
+        }
+        if self.buffered - replaced + data.len() > MAX_REORDER_BYTES {
+            return false;
+        }
+        self.buffered = self.buffered - replaced + data.len();
+        self.chunks.insert(pos, data);
+        true
+    }
+
+    /// Removes buffered chunks that are no longer ahead of `received` and
+    /// returns the bytes that extend the stream past it, if any.
+    fn take_ready(&mut self, received: u64) -> Option<Vec<u8>> {
+        while let Some(entry) = self.chunks.first_entry() {
+            let pos = *entry.key();
+            if pos > received {
+                return None;
+            }
+            let data = entry.remove();
+            self.buffered -= data.len();
+            let end = pos + data.len() as u64;
+            if end > received {
+                return Some(data[(received - pos) as usize..].to_vec());
+            }
+        }
+        None
+    }
+}
+
+/// A data message the peer has not acknowledged yet, with its retransmission
+/// schedule.
+struct Pending {
+    msg: Message<'static>,
+    attempts: u32,
+    deadline: Instant,
+}
+
+impl Pending {
+    fn new(msg: Message<'static>, config: &Config) -> Self {
+        Self {
+            msg,
+            attempts: 0,
+            deadline: Instant::now() + config.retransmit_interval,
+        }
+    }
+
+    /// Records a retransmission at `now` and schedules the next one. Returns
+    /// false if the retransmit budget is already spent.
+    fn backoff(&mut self, now: Instant, config: &Config) -> bool {
+        if self.attempts >= config.max_retransmits {
+            return false;
+        }
+        self.attempts += 1;
+        let delay = config
+            .retransmit_interval
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_RETRANSMIT_BACKOFF);
+        self.deadline = now + delay;
+        true
+    }
+
+    fn len(&self) -> usize {
+        match &self.msg {
+            Message::Data { data, .. } => data.len(),
+            _ => 0,
+        }
+    }
+
+    /// Whether `offset` falls strictly inside this chunk.
+    fn splits_at(&self, offset: u64) -> bool {
+        match &self.msg {
+            Message::Data { pos, data, .. } => *pos < offset && offset < *pos + data.len() as u64,
+            _ => false,
+        }
+    }
+
+    fn starts_at(&self, offset: u64) -> bool {
+        matches!(&self.msg, Message::Data { pos, .. } if *pos == offset)
+    }
+
+    /// Drops the part of the chunk covered by a peer ack of `acked` bytes.
+    /// Returns false if nothing is left to deliver.
+    fn trim(&mut self, acked: u64) -> bool {
+        let Message::Data { pos, data, .. } = &mut self.msg else {
+            return false;
+        };
+        if *pos + data.len() as u64 <= acked {
+            return false;
+        }
+        if *pos < acked {
+            data.to_mut().drain(..(acked - *pos) as usize);
+            *pos = acked;
+        }
+        true
+    }
+}
+
+/// One side of an LRCP session as seen by the application.
+pub struct LrcpStream {
+    session: u64,
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+}
+
+impl LrcpStream {
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Returns the next in-order chunk sent by the peer, or `None` once the
+    /// session is closed.
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        self.incoming.recv().await.ok()
+    }
+
+    /// Queues `data` to be delivered to the peer.
+    pub async fn send(&self, data: Vec<u8>) -> Result<()> {
+        self.outgoing
+            .send(data)
+            .await
+            .map_err(|_| anyhow!("session {} closed", self.session))
+    }
+
+    /// Closes the session once everything sent so far is acknowledged.
+    pub fn close(&self) {
+        self.outgoing.close();
+    }
+
+    /// Opens session `session` with the LRCP server at `addr`, retrying the
+    /// connect until it is acknowledged.
+    pub async fn connect(addr: SocketAddr, session: u64) -> Result<LrcpStream> {
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        socket.connect(addr).await?;
+        let config = Arc::new(Config::default());
+        let connect = Message::Connect { session }.serialize();
+        let mut buf = vec![0u8; 1024];
+        let mut acked = false;
+        for attempt in 0..=config.max_retransmits {
+            debug!(session, attempt, "connecting to {addr}");
+            socket.send(&connect).await?;
+            let deadline = Instant::now() + config.retransmit_interval;
+            while let Ok(len) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                match Message::parse(&buf[..len?]) {
+                    Ok(Message::Ack { session: s, len: 0 }) if s == session => acked = true,
+                    Ok(Message::Close { session: s }) if s == session => {
+                        bail!("session {session} refused by {addr}")
+                    }
+                    _ => continue,
+                }
+                break;
+            }
+            if acked {
+                break;
+            }
+        }
+        if !acked {
+            bail!("no ack for session {session} from {addr}");
+        }
+
+        let (inbox_tx, inbox) = unbounded();
+        let (to_app, incoming) = unbounded();
+        let (outgoing, from_app) = unbounded();
+        let state = Session::new(
+            session,
+            addr,
+            socket.clone(),
+            to_app,
+            Default::default(),
+            config,
+        );
+        let span = info_span!("session", id = session, peer = %addr);
+        tokio::spawn(
+            async move {
+                let run = state.run(inbox, from_app, Default::default());
+                tokio::pin!(run);
+                let mut buf = vec![0u8; 1024];
+                loop {
+                    select! {
+                        _ = &mut run => break,
+                        Ok(len) = socket.recv(&mut buf) => match Message::parse(&buf[..len]) {
+                            Ok(msg) if msg.session() == session => {
+                                let _ = inbox_tx.send(msg.into_owned()).await;
+                            }
+                            msg => debug!("ignoring {msg:?}"),
+                        },
+                    }
+                }
+            }
+            .instrument(span),
+        );
+        Ok(LrcpStream {
+            session,
+            incoming,
+            outgoing,
+        })
+    }
+}
+
+/// Transport counters shared by a server and all of its session tasks.
+#[derive(Default)]
+pub struct Metrics {
+    open_sessions: AtomicU64,
+    retransmissions: AtomicU64,
+    duplicate_acks: AtomicU64,
+    bytes_delivered: AtomicU64,
+    expired_sessions: AtomicU64,
+    refused_sessions: AtomicU64,
+    buffered_bytes: AtomicU64,
+}
+
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "open sessions: {}, retransmissions: {}, duplicate acks: {}, bytes delivered: {}, expired sessions: {}, refused sessions: {}, buffered bytes: {}",
+            self.open_sessions.load(Relaxed),
+            self.retransmissions.load(Relaxed),
+            self.duplicate_acks.load(Relaxed),
+            self.bytes_delivered.load(Relaxed),
+            self.expired_sessions.load(Relaxed),
+            self.refused_sessions.load(Relaxed),
+            self.buffered_bytes.load(Relaxed),
+        )
+    }
+}
+
+/// Transport settings: retransmission timers, flow control and the limits
+/// protecting the server from session-exhaustion floods.
+pub struct Config {
+    /// Delay before unacknowledged data is sent again for the first time. It
+    /// doubles with every retransmission, up to `MAX_RETRANSMIT_BACKOFF`.
+    pub retransmit_interval: Duration,
+    /// Number of retransmissions of a single chunk after which the session
+    /// is given up on.
+    pub max_retransmits: u32,
+    /// Sessions that see no traffic from the peer for this long are dropped.
+    pub session_expiry: Duration,
+    /// Largest payload carried by a single data message.
+    pub chunk_size: usize,
+    /// Number of concurrent sessions after which new connects are refused,
+    /// unless an expired session can be evicted to make room.
+    pub max_sessions: usize,
+    /// Cap on the unacknowledged and out-of-order data held across all
+    /// sessions.
+    pub max_buffered_bytes: u64,
+    /// Maximum amount of unacknowledged data in flight per session.
+    pub window: u64,
+    /// How often a session with nothing in flight repeats its last ack, so a
+    /// peer that lost it does not resend the whole stream. Zero disables it.
+    pub ack_interval: Duration,
+    /// How long sessions get on shutdown to deliver what they have left
+    /// before the server stops anyway.
+    pub shutdown_grace: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            retransmit_interval: Duration::from_secs(3),
+            max_retransmits: 8,
+            session_expiry: Duration::from_secs(60),
+            chunk_size: MAX_CHUNK_SIZE,
+            max_sessions: 10_000,
+            max_buffered_bytes: 64 * 1024 * 1024,
+            window: 16 * 1024,
+            ack_interval: Duration::from_secs(5),
+            shutdown_grace: proto_common::SHUTDOWN_GRACE,
+        }
+    }
+}
+
+type Handler = Arc<dyn Fn(LrcpStream) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+struct SessionEntry {
+    inbox: Sender<Message<'static>>,
+    last_seen: Instant,
+}
+
+/// Inboxes of the live session tasks, keyed by session id.
+type Registry = Arc<Mutex<HashMap<u64, SessionEntry>>>;
+
+/// State of a single session, owned by the task that drives it.
+struct Session {
+    id: u64,
+    addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    received: u64,
+    to_app: Sender<Vec<u8>>,
+    unsent: Vec<u8>,
+    sent_len: u64,
+    acked_len: u64,
+    pending: Vec<Pending>,
+    should_close: bool,
+    app_done: bool,
+    reorder: Reassembly,
+    last_seen: Instant,
+    last_ack: Instant,
+    metrics: Arc<Metrics>,
+    config: Arc<Config>,
+    reported_buffered: u64,
+}
+
+impl Session {
+    fn new(
+        id: u64,
+        addr: SocketAddr,
+        socket: Arc<UdpSocket>,
+        to_app: Sender<Vec<u8>>,
+        metrics: Arc<Metrics>,
+        config: Arc<Config>,
+    ) -> Self {
+        Self {
+            id,
+            addr,
+            socket,
+            received: 0,
+            to_app,
+            unsent: vec![],
+            sent_len: 0,
+            acked_len: 0,
+            pending: vec![],
+            should_close: false,
+            app_done: false,
+            reorder: Reassembly::default(),
+            last_seen: Instant::now(),
+            last_ack: Instant::now(),
+            metrics,
+            config,
+            reported_buffered: 0,
+        }
+    }
+
+    async fn send(&mut self, msg: &Message<'_>) -> Result<()> {
+        self.socket.send_to(&msg.serialize(), self.addr).await?;
+        if let Message::Ack { .. } = msg {
+            self.last_ack = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Hands in-order data to the application and advances the receive
+    /// position past it.
+    async fn add(&mut self, data: &[u8]) {
+        self.received += data.len() as u64;
+        let _ = self.to_app.send(data.to_vec()).await;
+    }
+
+    /// Publishes changes in the amount of data this session holds to the
+    /// server-wide gauge.
+    fn report_buffered(&mut self) {
+        let buffered = (self.reorder.buffered + self.unsent.len()) as u64
+            + self.pending.iter().map(|p| p.len() as u64).sum::<u64>();
+        if buffered > self.reported_buffered {
+            self.metrics
+                .buffered_bytes
+                .fetch_add(buffered - self.reported_buffered, Relaxed);
+        } else {
+            self.metrics
+                .buffered_bytes
+                .fetch_sub(self.reported_buffered - buffered, Relaxed);
+        }
+        self.reported_buffered = buffered;
+    }
+
+    /// Processes messages from the peer and data from the application until
+    /// the session is closed, expires or is evicted, then unregisters it.
+    async fn run(
+        mut self,
+        inbox: Receiver<Message<'static>>,
+        from_app: Receiver<Vec<u8>>,
+        sessions: Registry,
+    ) {
+        let session = self.id;
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            let open = select! {
+                msg = inbox.recv() => match msg {
+                    Ok(msg) => self.on_message(msg).await,
+                    Err(_) => Ok(false),
+                },
+                _ = interval.tick() => self.on_tick().await,
+                // Stop reading from the application while the window is full.
+                data = from_app.recv(), if !self.app_done && self.unsent.is_empty() => match data {
+                    Ok(data) => self.on_app_data(data).await,
+                    Err(_) => self.on_app_done().await,
+                },
+            };
+            self.report_buffered();
+            match open {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => debug!("error: {e:?}"),
+            }
+        }
+        {
+            // A closed inbox means the server already evicted this session and
+            // the id may belong to a new one by now.
+            let mut sessions = sessions.lock().await;
+            if !inbox.is_closed() {
+                sessions.remove(&session);
+            }
+        }
+        self.pending.clear();
+        self.reorder = Reassembly::default();
+        self.report_buffered();
+        self.metrics.open_sessions.fetch_sub(1, Relaxed);
+        self.to_app.close();
+        info!("finished");
+    }
+
+    /// Handles a message from the peer. Returns false once the session is over.
+    async fn on_message(&mut self, msg: Message<'_>) -> Result<bool> {
+        let session = self.id;
+        self.last_seen = Instant::now();
+        match msg {
+            Message::Connect { .. } => {
+                self.send(&Message::Ack { session, len: 0 }).await?;
+            }
+            Message::Ack { len, .. } => {
+                if len <= self.acked_len {
+                    debug!(len, "duplicate ack");
+                    self.metrics.duplicate_acks.fetch_add(1, Relaxed);
+                    return Ok(true);
+                }
+                if len > self.sent_len {
+                    info!(
+                        len,
+                        sent = self.sent_len,
+                        "ack beyond what was sent, closing"
+                    );
+                    self.send(&Message::Close { session }).await?;
+                    return Ok(false);
+                }
+                self.acked_len = len;
+                // An ack landing inside a chunk means the peer is missing the
+                // rest of it, so resend that right away instead of waiting
+                // for the retransmit timer. Later chunks keep their deadlines.
+                let mid_chunk = self.pending.iter().any(|p| p.splits_at(len));
+                self.pending.retain_mut(|p| p.trim(len));
+                if mid_chunk && !self.retransmit(Some(len)).await? {
+                    return Ok(false);
+                }
+                self.flush().await?;
+                if self.pending.is_empty() && (self.should_close || self.app_done) {
+                    info!("closing once everything is acked");
+                    self.send(&Message::Close { session }).await?;
+                    return Ok(false);
+                }
+            }
+            Message::Close { .. } => {
+                if self.acked_len == self.sent_len {
+                    info!(sent = self.sent_len, "closing, everything acked");
+                    self.send(&Message::Close { session }).await?;
+                    return Ok(false);
+                }
+                if !self.should_close {
+                    info!(
+                        acked = self.acked_len,
+                        sent = self.sent_len,
+                        "closing once the rest is acked"
+                    );
+                    self.should_close = true;
+                    self.to_app.close();
+                }
+            }
+            Message::Data { pos, data, .. } => {
+                debug!(pos, "received data {:?}", std::str::from_utf8(&data));
+                if self.should_close {
+                    debug!("skipping data, closing");
+                    return Ok(true);
+                }
+                if self.received == pos {
+                    let before = self.received;
+                    self.add(&data).await;
+                    while let Some(next) = self.reorder.take_ready(self.received) {
+                        self.add(&next).await;
+                    }
+                    self.metrics
+                        .bytes_delivered
+                        .fetch_add(self.received - before, Relaxed);
+                    debug!(received = self.received, "added data");
+                } else if pos > self.received {
+                    let buffered = self.reorder.insert(pos, data.into_owned());
+                    debug!(pos, buffered, "out of order data");
+                } else {
+                    debug!(pos, "ignored data");
+                }
+                let len = self.received;
+                self.send(&Message::Ack { session, len }).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Retransmits due data, repeats the last ack on idle sessions and expires
+    /// the session. Returns false once the session is over.
+    async fn on_tick(&mut self) -> Result<bool> {
+        let session = self.id;
+        if self.last_seen.elapsed() >= self.config.session_expiry {
+            info!("expired");
+            self.metrics.expired_sessions.fetch_add(1, Relaxed);
+            return Ok(false);
+        }
+        if self.needs_keepalive() {
+            let len = self.received;
+            self.send(&Message::Ack { session, len }).await?;
+        }
+        self.retransmit(None).await
+    }
+
+    /// Whether the session has nothing outbound and is due to repeat its ack.
+    /// Sessions on their way to closing are left alone.
+    fn needs_keepalive(&self) -> bool {
+        let interval = self.config.ack_interval;
+        !interval.is_zero()
+            && self.pending.is_empty()
+            && self.unsent.is_empty()
+            && !self.should_close
+            && !self.app_done
+            && self.last_ack.elapsed() >= interval
+    }
+
+    /// Resends the pending chunks whose retransmit deadline passed or, given
+    /// `from`, only the chunk starting there. Returns false once the budget
+    /// is spent.
+    async fn retransmit(&mut self, from: Option<u64>) -> Result<bool> {
+        let now = Instant::now();
+        for p in self.pending.iter_mut().filter(|p| match from {
+            Some(from) => p.starts_at(from),
+            None => p.deadline <= now,
+        }) {
+            if !p.backoff(now, &self.config) {
+                info!("retransmit budget exhausted");
+                self.metrics.expired_sessions.fetch_add(1, Relaxed);
+                return Ok(false);
+            }
+            debug!(attempt = p.attempts, "resending {:?}", p.msg);
+            self.socket.send_to(&p.msg.serialize(), self.addr).await?;
+            self.metrics.retransmissions.fetch_add(1, Relaxed);
+        }
+        Ok(true)
+    }
+
+    /// Closes the session once everything the application wrote is acked.
+    async fn on_app_done(&mut self) -> Result<bool> {
+        let session = self.id;
+        self.app_done = true;
+        if self.acked_len == self.sent_len && self.unsent.is_empty() {
+            info!("closing, application is done");
+            self.send(&Message::Close { session }).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Queues data written by the application for the peer.
+    async fn on_app_data(&mut self, data: Vec<u8>) -> Result<bool> {
+        let pos = self.sent_len + self.unsent.len() as u64;
+        debug!(pos, "sending {:?}", std::str::from_utf8(&data));
+        self.unsent.extend(data);
+        self.flush().await?;
+        Ok(true)
+    }
+
+    /// Sends as much unsent data as the flow-control window allows.
+    async fn flush(&mut self) -> Result<()> {
+        while !self.unsent.is_empty() {
+            let in_flight = self.sent_len - self.acked_len;
+            let room = self.config.window.saturating_sub(in_flight) as usize;
+            let len = self.unsent.len().min(self.config.chunk_size).min(room);
+            if len == 0 {
+                break;
+            }
+            let msg = Message::Data {
+                session: self.id,
+                pos: self.sent_len,
+                data: Cow::Owned(self.unsent.drain(..len).collect()),
+            };
+            self.send(&msg).await?;
+            self.pending.push(Pending::new(msg, &self.config));
+            self.sent_len += len as u64;
+        }
+        Ok(())
+    }
+}
+
+pub struct Server {
+    socket: Arc<UdpSocket>,
+    sessions: Registry,
+    handler: Handler,
+    metrics: Arc<Metrics>,
+    config: Arc<Config>,
+    /// Set once shutdown starts, after which no sessions are opened.
+    stopping: AtomicBool,
+}
+
+/// How often a stopping server checks whether its sessions are all gone.
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+impl Server {
+    /// Binds the server to `addr`. `handler` is spawned with the stream of
+    /// every newly connected session.
+    pub async fn bind<F, Fut>(addr: impl ToSocketAddrs, config: Config, handler: F) -> Result<Self>
+    where
+        F: Fn(LrcpStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            sessions: Default::default(),
+            handler: Arc::new(move |stream| Box::pin(handler(stream))),
+            metrics: Default::default(),
+            config: Arc::new(config),
+            stopping: AtomicBool::new(false),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Serves sessions until `stop` resolves. Every open session is then
+    /// closed as if by its peer, so the application sees the end of its
+    /// input, and the server keeps going until they are all done or the
+    /// grace period runs out.
+    pub async fn run(&self, stop: impl Future<Output = ()>) -> Result<()> {
+        let mut buf = vec![0u8; 1024];
+        tokio::pin!(stop);
+        let mut deadline = None;
+        loop {
+            if deadline.is_some() && self.sessions.lock().await.is_empty() {
+                return Ok(());
+            }
+            let (len, addr) = select! {
+                received = self.socket.recv_from(&mut buf) => received?,
+                () = &mut stop, if deadline.is_none() => {
+                    deadline = Some(Instant::now() + self.config.shutdown_grace);
+                    self.close_all().await;
+                    continue;
+                }
+                () = tokio::time::sleep(DRAIN_POLL), if deadline.is_some() => {
+                    if deadline.is_some_and(|d| d <= Instant::now()) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            let msg = Message::parse(&buf[..len]);
+            debug!(%addr, "received {msg:?}");
+            match msg {
+                Err(e) => debug!(%addr, "bad message: {e:?}"),
+                Ok(msg) => self.handle(msg.into_owned(), addr).await?,
+            }
+        }
+    }
+
+    /// Stops taking sessions, and has every open one closed as if its peer
+    /// asked for it.
+    async fn close_all(&self) {
+        self.stopping.store(true, Relaxed);
+        for (&session, entry) in self.sessions.lock().await.iter() {
+            let _ = entry.inbox.send(Message::Close { session }).await;
+        }
+    }
+
+    /// Forwards `msg` to the task owning its session, starting one on connect.
+    async fn handle(&self, msg: Message<'static>, addr: SocketAddr) -> Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(entry) = sessions.get_mut(&msg.session()) {
+            entry.last_seen = Instant::now();
+            let _ = entry.inbox.send(msg).await;
+            return Ok(());
+        }
+        match msg {
+            Message::Connect { session } => {
+                if self.stopping.load(Relaxed) {
+                    info!(session, %addr, "refusing session, server is stopping");
+                    self.socket
+                        .send_to(&Message::Close { session }.serialize(), addr)
+                        .await?;
+                    return Ok(());
+                }
+                if self.is_full(&sessions) {
+                    self.evict_expired(&mut sessions);
+                }
+                if self.is_full(&sessions) {
+                    info!(session, %addr, "refusing session, server is full");
+                    self.metrics.refused_sessions.fetch_add(1, Relaxed);
+                    self.socket
+                        .send_to(&Message::Close { session }.serialize(), addr)
+                        .await?;
+                    return Ok(());
+                }
+                let (inbox_tx, inbox) = unbounded();
+                let (to_app, incoming) = unbounded();
+                let (outgoing, from_app) = unbounded();
+                let mut state = Session::new(
+                    session,
+                    addr,
+                    self.socket.clone(),
+                    to_app,
+                    self.metrics.clone(),
+                    self.config.clone(),
+                );
+                state.send(&Message::Ack { session, len: 0 }).await?;
+                let span = info_span!("session", id = session, peer = %addr);
+                info!(parent: &span, "connected");
+                let run = state.run(inbox, from_app, self.sessions.clone());
+                tokio::spawn(run.instrument(span.clone()));
+                let app = (self.handler)(LrcpStream {
+                    session,
+                    incoming,
+                    outgoing,
+                });
+                tokio::spawn(app.instrument(span));
+                sessions.insert(
+                    session,
+                    SessionEntry {
+                        inbox: inbox_tx,
+                        last_seen: Instant::now(),
+                    },
+                );
+                self.metrics.open_sessions.fetch_add(1, Relaxed);
+            }
+            Message::Data { session, .. }
+            | Message::Ack { session, .. }
+            | Message::Close { session } => {
+                debug!(session, %addr, "closing unknown session");
+                self.socket
+                    .send_to(&Message::Close { session }.serialize(), addr)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_full(&self, sessions: &HashMap<u64, SessionEntry>) -> bool {
+        sessions.len() >= self.config.max_sessions
+            || self.metrics.buffered_bytes.load(Relaxed) >= self.config.max_buffered_bytes
+    }
+
+    /// Drops sessions that have been silent for longer than the expiry
+    /// timeout without waiting for their tasks to notice.
+    fn evict_expired(&self, sessions: &mut HashMap<u64, SessionEntry>) {
+        sessions.retain(|session, entry| {
+            if entry.last_seen.elapsed() < self.config.session_expiry {
+                return true;
+            }
+            info!(session, "evicted");
+            entry.inbox.close();
+            self.metrics.expired_sessions.fetch_add(1, Relaxed);
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_connect() {
+        let input = b"/connect/1234567/";
+        let expected = Message::Connect { session: 1234567 };
+        assert_eq!(expected, Message::parse(input).unwrap());
+        assert_eq!(expected.serialize(), input);
+    }
+
+    #[test]
+    fn parse_data_simple() {
+        let input = b"/data/1234567/13/abc/";
+        let expected = Message::Data {
+            session: 1234567,
+            pos: 13,
+            data: b"abc"[..].into(),
+        };
+        assert_eq!(expected, Message::parse(input).unwrap());
+        assert_eq!(expected.serialize(), input);
+    }
+
+    #[test]
+    fn parse_data_escape() {
+        let input = b"/data/1234567/13/foo\\/bar\\\\baz/";
+        let expected = Message::Data {
+            session: 1234567,
+            pos: 13,
+            data: b"foo/bar\\baz"[..].into(),
+        };
+        assert_eq!(expected, Message::parse(input).unwrap());
+        assert_eq!(expected.serialize(), input);
+    }
+
+    #[test]
+    fn parse_data_escape_invalid() {
+        let input = b"/data/1234567/13/illegal data/has too many/parts/";
+        assert!(Message::parse(input).is_err());
+    }
+
+    #[test]
+    fn parse_ack() {
+        let input = b"/ack/1234567/1024/";
+        let expected = Message::Ack {
+            session: 1234567,
+            len: 1024,
+        };
+        assert_eq!(expected, Message::parse(input).unwrap());
+        assert_eq!(expected.serialize(), input);
+    }
+
+    #[test]
+    fn parse_close() {
+        let input = b"/close/1234567/";
+        let expected = Message::Close { session: 1234567 };
+        assert_eq!(expected, Message::parse(input).unwrap());
+        assert_eq!(expected.serialize(), input);
+    }
+
+    #[test]
+    fn reassembly_fills_gap() {
+        let mut r = Reassembly::default();
+        assert!(r.insert(6, b"ghi".to_vec()));
+        assert!(r.insert(3, b"def".to_vec()));
+        assert_eq!(None, r.take_ready(0));
+        assert_eq!(Some(b"def".to_vec()), r.take_ready(3));
+        assert_eq!(Some(b"ghi".to_vec()), r.take_ready(6));
+        assert_eq!(None, r.take_ready(9));
+        assert_eq!(0, r.buffered);
+    }
+
+    #[test]
+    fn reassembly_trims_overlap() {
+        let mut r = Reassembly::default();
+        assert!(r.insert(2, b"cdef".to_vec()));
+        assert!(r.insert(4, b"ef".to_vec()));
+        assert_eq!(Some(b"def".to_vec()), r.take_ready(3));
+        assert_eq!(None, r.take_ready(6));
+        assert_eq!(0, r.buffered);
+    }
+
+    #[test]
+    fn reassembly_is_bounded() {
+        let mut r = Reassembly::default();
+        assert!(r.insert(10, vec![b'a'; MAX_REORDER_BYTES]));
+        assert!(!r.insert(MAX_REORDER_BYTES as u64 + 10, b"b".to_vec()));
+        assert_eq!(MAX_REORDER_BYTES, r.buffered);
+    }
+
+    async fn echo(stream: LrcpStream) {
+        while let Some(data) = stream.recv().await {
+            if stream.send(data).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn settle(baseline: usize) -> usize {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        for _ in 0..100 {
+            if metrics.num_alive_tasks() == baseline {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        metrics.num_alive_tasks()
+    }
+
+    #[tokio::test]
+    async fn close_terminates_session_task() {
+        let server = Server::bind("127.0.0.1:0", Config::default(), echo)
+            .await
+            .unwrap();
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let baseline = metrics.num_alive_tasks();
+
+        server
+            .handle(Message::Connect { session: 7 }, peer)
+            .await
+            .unwrap();
+        assert_eq!(baseline + 2, metrics.num_alive_tasks());
+
+        server
+            .handle(Message::Close { session: 7 }, peer)
+            .await
+            .unwrap();
+        assert_eq!(baseline, settle(baseline).await);
+        assert!(server.sessions.lock().await.is_empty());
+        assert_eq!(0, server.metrics.open_sessions.load(Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_session_expires() {
+        let server = Server::bind("127.0.0.1:0", Config::default(), echo)
+            .await
+            .unwrap();
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let baseline = metrics.num_alive_tasks();
+
+        server
+            .handle(Message::Connect { session: 7 }, peer)
+            .await
+            .unwrap();
+        let config = Config::default();
+        tokio::time::sleep(config.session_expiry + config.retransmit_interval).await;
+
+        assert_eq!(baseline, settle(baseline).await);
+        assert!(server.sessions.lock().await.is_empty());
+        assert_eq!(1, server.metrics.expired_sessions.load(Relaxed));
+        assert_eq!(0, server.metrics.open_sessions.load(Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmit_backoff_is_exponential_and_bounded() {
+        let config = Config::default();
+        let msg = Message::Data {
+            session: 1,
+            pos: 0,
+            data: b"abc"[..].into(),
+        };
+        let mut p = Pending::new(msg, &config);
+        let start = Instant::now();
+        assert_eq!(start + config.retransmit_interval, p.deadline);
+        let delays: Vec<_> = (0..config.max_retransmits)
+            .map(|_| {
+                assert!(p.backoff(start, &config));
+                p.deadline - start
+            })
+            .collect();
+        assert_eq!(config.retransmit_interval * 2, delays[0]);
+        assert_eq!(config.retransmit_interval * 4, delays[1]);
+        assert_eq!(MAX_RETRANSMIT_BACKOFF, *delays.last().unwrap());
+        assert!(!p.backoff(start, &config));
+    }
+
+    #[test]
+    fn pending_trims_acked_prefix() {
+        let msg = Message::Data {
+            session: 1,
+            pos: 10,
+            data: b"abcdef"[..].into(),
+        };
+        let mut p = Pending::new(msg, &Config::default());
+        assert!(p.trim(10));
+        assert!(p.trim(13));
+        assert_eq!(
+            Message::Data {
+                session: 1,
+                pos: 13,
+                data: b"def"[..].into()
+            },
+            p.msg
+        );
+        assert!(!p.trim(16));
+    }
+
+    async fn test_session() -> (Session, Receiver<Vec<u8>>, UdpSocket) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = peer_socket.local_addr().unwrap();
+        let (to_app, incoming) = unbounded();
+        let config = Config {
+            window: 10,
+            ..Config::default()
+        };
+        let session = Session::new(
+            7,
+            peer,
+            socket,
+            to_app,
+            Default::default(),
+            Arc::new(config),
+        );
+        (session, incoming, peer_socket)
+    }
+
+    async fn recv_message(socket: &UdpSocket) -> Message<'static> {
+        let mut buf = vec![0u8; 1024];
+        let len = socket.recv(&mut buf).await.unwrap();
+        Message::parse(&buf[..len]).unwrap().into_owned()
+    }
+
+    #[tokio::test]
+    async fn data_is_delivered_to_application() {
+        let (mut s, incoming, _peer) = test_session().await;
+        let data = Message::Data {
+            session: 7,
+            pos: 0,
+            data: b"hello\n"[..].into(),
+        };
+        assert!(s.on_message(data).await.unwrap());
+        assert_eq!(b"hello\n".to_vec(), incoming.recv().await.unwrap());
+        assert_eq!(6, s.metrics.bytes_delivered.load(Relaxed));
+    }
+
+    #[tokio::test]
+    async fn ack_tracks_sent_and_acked_lengths() {
+        let (mut s, _incoming, _peer) = test_session().await;
+        s.on_app_data(b"hello\n".to_vec()).await.unwrap();
+
+        assert!(s
+            .on_message(Message::Ack { session: 7, len: 2 })
+            .await
+            .unwrap());
+        assert!(s
+            .on_message(Message::Ack { session: 7, len: 1 })
+            .await
+            .unwrap());
+        assert_eq!(1, s.metrics.duplicate_acks.load(Relaxed));
+        assert_eq!((6, 2), (s.sent_len, s.acked_len));
+        assert_eq!(1, s.pending.len());
+
+        assert!(s
+            .on_message(Message::Ack { session: 7, len: 6 })
+            .await
+            .unwrap());
+        assert!(s.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ack_beyond_sent_closes_session() {
+        let (mut s, _incoming, _peer) = test_session().await;
+        assert!(!s
+            .on_message(Message::Ack { session: 7, len: 5 })
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_server_refuses_and_evicts() {
+        let config = Config {
+            max_sessions: 1,
+            ..Config::default()
+        };
+        let server = Server::bind("127.0.0.1:0", config, echo).await.unwrap();
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        server
+            .handle(Message::Connect { session: 1 }, peer)
+            .await
+            .unwrap();
+        server
+            .handle(Message::Connect { session: 2 }, peer)
+            .await
+            .unwrap();
+        assert_eq!(1, server.metrics.refused_sessions.load(Relaxed));
+        assert!(!server.sessions.lock().await.contains_key(&2));
+
+        // Make session 1 look expired without letting its task run.
+        server.sessions.lock().await.get_mut(&1).unwrap().last_seen -= Duration::from_secs(60);
+        server
+            .handle(Message::Connect { session: 2 }, peer)
+            .await
+            .unwrap();
+        let sessions = server.sessions.lock().await;
+        assert!(sessions.contains_key(&2));
+        assert!(!sessions.contains_key(&1));
+        assert_eq!(1, server.metrics.expired_sessions.load(Relaxed));
+    }
+
+    #[tokio::test]
+    async fn client_talks_to_server() {
+        let server = Arc::new(
+            Server::bind("127.0.0.1:0", Config::default(), echo)
+                .await
+                .unwrap(),
+        );
+        let addr = server.local_addr().unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run(std::future::pending()).await }
+        });
+
+        let stream = LrcpStream::connect(addr, 42).await.unwrap();
+        stream.send(b"hello/world\\".to_vec()).await.unwrap();
+        assert_eq!(b"hello/world\\".to_vec(), stream.recv().await.unwrap());
+
+        stream.close();
+        assert_eq!(None, stream.recv().await);
+    }
+
+    #[tokio::test]
+    async fn stopping_closes_sessions_once_they_are_done() {
+        let server = Server::bind("127.0.0.1:0", Config::default(), echo)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(async move {
+            let stop = async {
+                let _ = stopped.await;
+            };
+            server.run(stop).await
+        });
+
+        let stream = LrcpStream::connect(addr, 42).await.unwrap();
+        stream.send(b"hello".to_vec()).await.unwrap();
+        assert_eq!(b"hello".to_vec(), stream.recv().await.unwrap());
+        stop.send(()).unwrap();
+        assert_eq!(None, stream.recv().await);
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn window_limits_data_in_flight() {
+        let (mut s, _incoming, _peer) = test_session().await;
+        s.on_app_data(vec![b'x'; 25]).await.unwrap();
+        assert_eq!((10, 15), (s.sent_len, s.unsent.len()));
+
+        assert!(s
+            .on_message(Message::Ack { session: 7, len: 4 })
+            .await
+            .unwrap());
+        assert_eq!((14, 11), (s.sent_len, s.unsent.len()));
+
+        assert!(s
+            .on_message(Message::Ack {
+                session: 7,
+                len: 14
+            })
+            .await
+            .unwrap());
+        assert!(s
+            .on_message(Message::Ack {
+                session: 7,
+                len: 24
+            })
+            .await
+            .unwrap());
+        assert_eq!((25, 0), (s.sent_len, s.unsent.len()));
+    }
+
+    #[tokio::test]
+    async fn escaped_chunks_fit_in_a_datagram() {
+        let (mut s, _incoming, peer) = test_session().await;
+        s.config = Arc::new(Config::default());
+        let mut line = vec![b'/'; 2000];
+        line.push(b'\n');
+        s.on_app_data(line).await.unwrap();
+
+        let mut buf = vec![0u8; 4096];
+        let mut sent = 0;
+        while sent < 2001 {
+            let len = peer.recv(&mut buf).await.unwrap();
+            assert!(len < 1000, "datagram of {len} bytes");
+            match Message::parse(&buf[..len]).unwrap() {
+                Message::Data { data, .. } => sent += data.len(),
+                msg => panic!("unexpected {msg:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn data_with_binary_payload_round_trips() {
+        let data: Vec<u8> = (0..=255).collect();
+        let msg = Message::Data {
+            session: 1,
+            pos: 0,
+            data: data.into(),
+        };
+        assert_eq!(msg, Message::parse(&msg.serialize()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn ack_mid_chunk_resends_from_ack_position() {
+        let (mut s, _incoming, peer) = test_session().await;
+        s.on_app_data(b"abcdefgh".to_vec()).await.unwrap();
+        s.on_app_data(b"ij".to_vec()).await.unwrap();
+        recv_message(&peer).await;
+        recv_message(&peer).await;
+
+        assert!(s
+            .on_message(Message::Ack { session: 7, len: 4 })
+            .await
+            .unwrap());
+        assert_eq!(
+            Message::Data {
+                session: 7,
+                pos: 4,
+                data: b"efgh"[..].into()
+            },
+            recv_message(&peer).await
+        );
+        // Only the split chunk is resent; the one after it isn't charged.
+        assert_eq!(
+            vec![1, 0],
+            s.pending.iter().map(|p| p.attempts).collect::<Vec<_>>()
+        );
+
+        // An ack on a chunk boundary is regular progress and resends nothing.
+        s.on_app_data(b"kl".to_vec()).await.unwrap();
+        recv_message(&peer).await;
+        assert!(s
+            .on_message(Message::Ack {
+                session: 7,
+                len: 10
+            })
+            .await
+            .unwrap());
+        assert_eq!(1, s.pending.len());
+        assert_eq!(0, s.pending[0].attempts);
+        assert_eq!(1, s.metrics.retransmissions.load(Relaxed));
+    }
+
+    #[test]
+    fn parse_borrows_unescaped_data() {
+        let input = b"/data/1/0/plain/";
+        let Message::Data { data, .. } = Message::parse(input).unwrap() else {
+            panic!("not data");
+        };
+        assert!(matches!(data, Cow::Borrowed(b"plain")));
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        for input in [
+            &b"/connect/"[..],
+            b"/connect//",
+            b"/connect/2147483648/",
+            b"/connect/+1/",
+            b"/ack/1/",
+            b"/ack/1/2/3/",
+            b"/close/1",
+            b"close/1/",
+            b"/data/1/0/bad\\escape/",
+            b"/data/1/0/trailing\\/",
+            b"/bogus/1/",
+        ] {
+            assert!(Message::parse(input).is_err(), "{input:?}");
+        }
+        assert!(Message::parse(b"/connect/2147483647/").is_ok());
+        assert!(Message::parse(b"/data/1/0//").is_ok());
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_closed() {
+        let server = Server::bind("127.0.0.1:0", Config::default(), echo)
+            .await
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        for msg in [
+            Message::Data {
+                session: 3,
+                pos: 0,
+                data: b"x"[..].into(),
+            },
+            Message::Ack { session: 3, len: 0 },
+            Message::Close { session: 3 },
+        ] {
+            server.handle(msg, addr).await.unwrap();
+            assert_eq!(Message::Close { session: 3 }, recv_message(&peer).await);
+        }
+        assert!(server.sessions.lock().await.is_empty());
+
+        server
+            .handle(Message::Connect { session: 3 }, addr)
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Ack { session: 3, len: 0 },
+            recv_message(&peer).await
+        );
+    }
+
+    async fn echo_over(faults: lossy::Faults) -> (Arc<Server>, lossy::Link) {
+        let server = Arc::new(
+            Server::bind("127.0.0.1:0", Config::default(), echo)
+                .await
+                .unwrap(),
+        );
+        let link = lossy::Link::spawn(server.local_addr().unwrap(), faults)
+            .await
+            .unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run(std::future::pending()).await }
+        });
+        (server, link)
+    }
+
+    async fn round_trip(stream: &LrcpStream, sent: &[u8]) -> Vec<u8> {
+        for chunk in sent.chunks(700) {
+            stream.send(chunk.to_vec()).await.unwrap();
+        }
+        let mut received = vec![];
+        while received.len() < sent.len() {
+            received.extend(stream.recv().await.unwrap());
+        }
+        received
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn echo_survives_lossy_link() {
+        let sent: Vec<u8> = (0..5000).map(|i| b"ab/\\\n"[i % 5]).collect();
+        for seed in 1..=5 {
+            let (server, link) = echo_over(lossy::Faults {
+                seed,
+                drop: 0.2,
+                duplicate: 0.1,
+                reorder: 0.2,
+                ..Default::default()
+            })
+            .await;
+            let stream = LrcpStream::connect(link.addr(), seed).await.unwrap();
+            assert_eq!(sent, round_trip(&stream, &sent).await, "seed {seed}");
+            assert!(link.stats().dropped.load(Relaxed) > 0);
+            assert!(server.metrics.retransmissions.load(Relaxed) > 0);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reordered_data_is_reassembled() {
+        let sent: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let (server, link) = echo_over(lossy::Faults {
+            seed: 7,
+            reorder: 0.5,
+            ..Default::default()
+        })
+        .await;
+        let stream = LrcpStream::connect(link.addr(), 1).await.unwrap();
+        assert_eq!(sent, round_trip(&stream, &sent).await);
+        assert!(link.stats().reordered.load(Relaxed) > 0);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(0, server.metrics.buffered_bytes.load(Relaxed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_session_repeats_ack() {
+        let (mut s, _incoming, peer) = test_session().await;
+        let data = Message::Data {
+            session: 7,
+            pos: 0,
+            data: b"hi"[..].into(),
+        };
+        assert!(s.on_message(data).await.unwrap());
+        assert_eq!(
+            Message::Ack { session: 7, len: 2 },
+            recv_message(&peer).await
+        );
+
+        assert!(s.on_tick().await.unwrap());
+        assert!(peer.try_recv(&mut [0; 64]).is_err());
+        tokio::time::advance(s.config.ack_interval).await;
+        assert!(s.on_tick().await.unwrap());
+        assert_eq!(
+            Message::Ack { session: 7, len: 2 },
+            recv_message(&peer).await
+        );
+
+        assert!(s.on_app_data(b"x".to_vec()).await.unwrap());
+        recv_message(&peer).await;
+        tokio::time::advance(s.config.ack_interval).await;
+        s.pending[0].deadline += s.config.ack_interval;
+        assert!(s.on_tick().await.unwrap());
+        assert!(peer.try_recv(&mut [0; 64]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closing_session_skips_keepalive() {
+        let (mut s, _incoming, peer) = test_session().await;
+        s.should_close = true;
+        tokio::time::advance(s.config.ack_interval).await;
+        assert!(s.on_tick().await.unwrap());
+        assert!(peer.try_recv(&mut [0; 64]).is_err());
+    }
+}
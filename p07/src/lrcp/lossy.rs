@@ -0,0 +1,153 @@
+//! A UDP relay for tests that sits in front of an LRCP endpoint and drops,
+//! duplicates, delays and reorders datagrams in both directions. Every
+//! decision comes from a seeded generator, so a failing run can be replayed
+//! with the same seed.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::select;
+
+/// How badly the relay treats the datagrams passing through it.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    pub seed: u64,
+    /// Probability that a datagram is dropped.
+    pub drop: f64,
+    /// Probability that a datagram is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a datagram is held back long enough for the ones
+    /// behind it to overtake it.
+    pub reorder: f64,
+    /// One-way latency added to every datagram.
+    pub delay: Duration,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            delay: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Counts of what the relay did, so tests can check the faults happened.
+#[derive(Default, Debug)]
+pub struct Stats {
+    pub forwarded: AtomicU64,
+    pub dropped: AtomicU64,
+    pub duplicated: AtomicU64,
+    pub reordered: AtomicU64,
+}
+
+/// splitmix64, good enough to pick faults and cheap to seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub struct Link {
+    addr: SocketAddr,
+    stats: Arc<Stats>,
+}
+
+impl Link {
+    /// Starts relaying between the single client that talks to `addr()` and
+    /// `upstream`.
+    pub async fn spawn(upstream: SocketAddr, faults: Faults) -> std::io::Result<Link> {
+        let front = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        back.connect(upstream).await?;
+        let addr = front.local_addr()?;
+        let stats = Arc::new(Stats::default());
+
+        let mut relay = Relay {
+            rng: Rng(faults.seed),
+            faults,
+            stats: stats.clone(),
+        };
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut up = vec![0u8; 1024];
+            let mut down = vec![0u8; 1024];
+            loop {
+                select! {
+                    Ok((len, from)) = front.recv_from(&mut up) => {
+                        client = Some(from);
+                        relay.forward(&back, None, &up[..len]);
+                    }
+                    Ok(len) = back.recv(&mut down), if client.is_some() => {
+                        relay.forward(&front, client, &down[..len]);
+                    }
+                }
+            }
+        });
+        Ok(Link { addr, stats })
+    }
+
+    /// The address clients should send to instead of the upstream.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+struct Relay {
+    faults: Faults,
+    rng: Rng,
+    stats: Arc<Stats>,
+}
+
+impl Relay {
+    /// Applies the faults to one datagram and schedules the copies that
+    /// survive. `to` is `None` for a connected socket.
+    fn forward(&mut self, socket: &Arc<UdpSocket>, to: Option<SocketAddr>, data: &[u8]) {
+        let rng = &mut self.rng;
+        if rng.next() < self.faults.drop {
+            self.stats.dropped.fetch_add(1, Relaxed);
+            return;
+        }
+        let copies = if rng.next() < self.faults.duplicate {
+            self.stats.duplicated.fetch_add(1, Relaxed);
+            2
+        } else {
+            1
+        };
+        let mut delay = self.faults.delay;
+        if rng.next() < self.faults.reorder {
+            self.stats.reordered.fetch_add(1, Relaxed);
+            delay = delay * 3 + Duration::from_millis(10);
+        }
+        self.stats.forwarded.fetch_add(1, Relaxed);
+
+        let at = tokio::time::Instant::now() + delay;
+        for _ in 0..copies {
+            let socket = socket.clone();
+            let data = data.to_vec();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(at).await;
+                let _ = match to {
+                    Some(to) => socket.send_to(&data, to).await,
+                    None => socket.send(&data).await,
+                };
+            });
+        }
+    }
+}
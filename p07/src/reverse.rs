@@ -0,0 +1,244 @@
+//! Line reversal (p07): reverses every line sent over an LRCP session.
+
+use crate::lrcp::{Config, LrcpStream, Server, MAX_CHUNK_SIZE};
+use anyhow::Result;
+use clap::Parser;
+use proto_common::Listen;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Line reversal over LRCP. Options left unset keep the transport defaults.
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    /// Initial retransmission timeout, in milliseconds.
+    #[arg(long)]
+    retransmit_ms: Option<u64>,
+    /// Retransmissions of a chunk before the session is dropped.
+    #[arg(long)]
+    max_retransmits: Option<u32>,
+    /// Idle time after which a session expires, in seconds.
+    #[arg(long)]
+    session_expiry_secs: Option<u64>,
+    /// Largest payload of a single data message.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=MAX_CHUNK_SIZE as u64))]
+    chunk_size: Option<u64>,
+    /// Concurrent sessions before new connects are refused.
+    #[arg(long)]
+    max_sessions: Option<usize>,
+    /// Unacknowledged and out-of-order bytes held across all sessions.
+    #[arg(long)]
+    max_buffered_bytes: Option<u64>,
+    /// Unacknowledged bytes in flight per session.
+    #[arg(long)]
+    window: Option<u64>,
+    /// Interval between repeated acks on idle sessions, in milliseconds; 0
+    /// disables them.
+    #[arg(long)]
+    ack_interval_ms: Option<u64>,
+}
+
+impl Args {
+    fn config(&self) -> Config {
+        let mut config = Config::default();
+        if let Some(ms) = self.retransmit_ms {
+            config.retransmit_interval = Duration::from_millis(ms);
+        }
+        if let Some(n) = self.max_retransmits {
+            config.max_retransmits = n;
+        }
+        if let Some(secs) = self.session_expiry_secs {
+            config.session_expiry = Duration::from_secs(secs);
+        }
+        if let Some(size) = self.chunk_size {
+            config.chunk_size = size as usize;
+        }
+        if let Some(n) = self.max_sessions {
+            config.max_sessions = n;
+        }
+        if let Some(n) = self.max_buffered_bytes {
+            config.max_buffered_bytes = n;
+        }
+        if let Some(window) = self.window {
+            config.window = window;
+        }
+        if let Some(ms) = self.ack_interval_ms {
+            config.ack_interval = Duration::from_millis(ms);
+        }
+        config
+    }
+}
+
+/// Cuts a byte stream into reversed lines, keeping only the current partial
+/// line buffered.
+#[derive(Default)]
+struct LineReverser {
+    partial: Vec<u8>,
+}
+
+impl LineReverser {
+    /// Appends `data` and returns every newly completed line reversed. With
+    /// `flush` set the trailing partial line is returned as well.
+    fn add(&mut self, data: &[u8], flush: bool) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            self.partial.extend(&rest[..end]);
+            let mut line = std::mem::take(&mut self.partial);
+            line.reverse();
+            line.push(b'\n');
+            out.push(line);
+            rest = &rest[end + 1..];
+        }
+        self.partial.extend(rest);
+        if flush && !self.partial.is_empty() {
+            let mut line = std::mem::take(&mut self.partial);
+            line.reverse();
+            out.push(line);
+        }
+        out
+    }
+}
+
+async fn reverse_lines(stream: LrcpStream) {
+    let mut reverser = LineReverser::default();
+    loop {
+        let data = stream.recv().await;
+        let lines = reverser.add(data.as_deref().unwrap_or_default(), data.is_none());
+        for line in lines {
+            debug!("sending {:?}", std::str::from_utf8(&line));
+            if stream.send(line).await.is_err() {
+                return;
+            }
+        }
+        if data.is_none() {
+            return;
+        }
+    }
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let server = Server::bind(args.listen.addr(), args.config(), reverse_lines).await?;
+    let metrics = server.metrics();
+    let _reporting = proto_common::report(move |_| info!("metrics: {metrics}"));
+    server.run(stop).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lrcp::Config;
+    use std::net::SocketAddr;
+    use tokio::net::UdpSocket;
+    use tokio::time::Instant;
+
+    #[test]
+    fn reverses_complete_lines_only() {
+        let mut r = LineReverser::default();
+        assert!(r.add(b"hel", false).is_empty());
+        assert_eq!(vec![b"olleh\n".to_vec()], r.add(b"lo\nwor", false));
+        assert_eq!(
+            vec![b"dlrow\n".to_vec(), b"a\n".to_vec()],
+            r.add(b"ld\na\nb", false)
+        );
+        assert_eq!(vec![b"b".to_vec()], r.add(b"", true));
+    }
+
+    #[test]
+    fn long_line_is_reversed_without_retaining_it() {
+        let mut r = LineReverser::default();
+        let line: Vec<u8> = (0..1_000_000).map(|i| b'a' + (i % 26) as u8).collect();
+        for chunk in line.chunks(1000) {
+            assert!(r.add(chunk, false).is_empty());
+        }
+        let out = r.add(b"\n", false);
+        let mut expected = line.clone();
+        expected.reverse();
+        expected.push(b'\n');
+        assert_eq!(vec![expected], out);
+        assert!(r.partial.is_empty());
+    }
+
+    async fn start() -> (UdpSocket, SocketAddr) {
+        let server = Server::bind("127.0.0.1:0", Config::default(), reverse_lines)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run(std::future::pending()).await });
+        (UdpSocket::bind("127.0.0.1:0").await.unwrap(), addr)
+    }
+
+    async fn send(peer: &UdpSocket, addr: SocketAddr, msg: &str) {
+        peer.send_to(msg.as_bytes(), addr).await.unwrap();
+    }
+
+    async fn recv(peer: &UdpSocket) -> String {
+        let mut buf = vec![0u8; 1024];
+        let len = peer.recv(&mut buf).await.unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reverses_lines_end_to_end() {
+        let (peer, addr) = start().await;
+        send(&peer, addr, "/connect/12345/").await;
+        assert_eq!("/ack/12345/0/", recv(&peer).await);
+
+        send(&peer, addr, "/data/12345/0/hello\n/").await;
+        assert_eq!("/ack/12345/6/", recv(&peer).await);
+        assert_eq!("/data/12345/0/olleh\n/", recv(&peer).await);
+        send(&peer, addr, "/ack/12345/6/").await;
+
+        send(&peer, addr, "/data/12345/6/Hello, world!\n/").await;
+        assert_eq!("/ack/12345/20/", recv(&peer).await);
+        assert_eq!("/data/12345/6/!dlrow ,olleH\n/", recv(&peer).await);
+        send(&peer, addr, "/ack/12345/20/").await;
+
+        send(&peer, addr, "/close/12345/").await;
+        assert_eq!("/close/12345/", recv(&peer).await);
+    }
+
+    #[tokio::test]
+    async fn escaped_line_split_out_of_order() {
+        let (peer, addr) = start().await;
+        send(&peer, addr, "/connect/1/").await;
+        assert_eq!("/ack/1/0/", recv(&peer).await);
+
+        // "foo/bar\" followed by a newline, with the second half first.
+        send(&peer, addr, "/data/1/5/ar\\\\\n/").await;
+        assert_eq!("/ack/1/0/", recv(&peer).await);
+        send(&peer, addr, "/data/1/0/foo\\/b/").await;
+        assert_eq!("/ack/1/9/", recv(&peer).await);
+        assert_eq!("/data/1/0/\\\\rab\\/oof\n/", recv(&peer).await);
+
+        // A retransmitted chunk is acked again but not delivered twice.
+        send(&peer, addr, "/data/1/0/foo\\/b/").await;
+        assert_eq!("/ack/1/9/", recv(&peer).await);
+        send(&peer, addr, "/ack/1/9/").await;
+        send(&peer, addr, "/close/1/").await;
+        assert_eq!("/close/1/", recv(&peer).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacked_line_is_retransmitted() {
+        let (peer, addr) = start().await;
+        send(&peer, addr, "/connect/9/").await;
+        assert_eq!("/ack/9/0/", recv(&peer).await);
+
+        send(&peer, addr, "/data/9/0/abc\n/").await;
+        assert_eq!("/ack/9/4/", recv(&peer).await);
+        let first = Instant::now();
+        assert_eq!("/data/9/0/cba\n/", recv(&peer).await);
+
+        // Nothing is acked, so the same chunk comes back after the timeout.
+        assert_eq!("/data/9/0/cba\n/", recv(&peer).await);
+        assert!(first.elapsed() >= Config::default().retransmit_interval);
+
+        send(&peer, addr, "/ack/9/4/").await;
+        send(&peer, addr, "/close/9/").await;
+        assert_eq!("/close/9/", recv(&peer).await);
+    }
+}
@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use p08::cipher::Cipher;
+use p08::isl::InsecureSocket;
+use tokio::io::{duplex, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::runtime::Runtime;
+
+const SPEC: [u8; 5] = [0x02, 0x7b, 0x05, 0x01, 0x00];
+const LINE: &[u8] = b"10x toy car,15x dog on a string,4x inflatable motorcycle\n";
+const LINES: usize = 1000;
+
+/// A connection with the spec and `LINES` encoded lines already written.
+async fn connection(encoded: &[u8]) -> DuplexStream {
+    let (mut client, server) = duplex(SPEC.len() + encoded.len());
+    client.write_all(&SPEC).await.unwrap();
+    client.write_all(encoded).await.unwrap();
+    server
+}
+
+/// The previous read path, awaiting and decoding one byte at a time.
+async fn read_bytewise(stream: DuplexStream, cipher: &Cipher) {
+    let mut r = BufReader::new(stream);
+    let mut spec = vec![];
+    r.read_until(0, &mut spec).await.unwrap();
+    let mut pos = 0;
+    for _ in 0..LINES {
+        let mut line = String::new();
+        loop {
+            let b = cipher.decode_one(pos, r.read_u8().await.unwrap());
+            pos += 1;
+            if b == b'\n' {
+                break;
+            }
+            line.push(b as char);
+        }
+    }
+}
+
+async fn read_buffered(stream: DuplexStream) {
+    let mut isl = InsecureSocket::new(stream).await.unwrap();
+    for _ in 0..LINES {
+        isl.read_line().await.unwrap();
+    }
+}
+
+fn read_line(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let cipher = Cipher::new(&SPEC).unwrap();
+    let encoded = cipher.encode(0, &LINE.repeat(LINES));
+
+    let mut group = c.benchmark_group("read_line");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("bytewise", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                read_bytewise(connection(&encoded).await, &cipher).await;
+            })
+        })
+    });
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                read_buffered(connection(&encoded).await).await;
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read_line);
+criterion_main!(benches);
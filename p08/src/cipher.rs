@@ -1,11 +1,24 @@
-use anyhow::{bail, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 
-#[derive(Debug, Clone)]
+/// Longest cipher spec a client may send, terminator included.
+pub const MAX_SPEC_LEN: usize = 80;
+
+#[derive(Clone)]
 pub struct Cipher {
     ops: Vec<Op>,
+    /// Every op depends only on the byte and the position modulo 256, so the
+    /// whole cipher is a pair of lookup tables indexed by `[pos % 256][byte]`.
+    encode: Vec<[u8; 256]>,
+    decode: Vec<[u8; 256]>,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher").field("ops", &self.ops).finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Op {
     ReverseBits,
     Xor(u8),
@@ -16,38 +29,83 @@ enum Op {
 
 use Op::*;
 
+/// Canonicalizes an op list by composing neighbouring XORs and adds and
+/// cancelling ops that undo each other, dropping what ends up as identity.
+fn reduce(ops: Vec<Op>) -> Vec<Op> {
+    let mut out: Vec<Op> = vec![];
+    for op in ops {
+        let merged = match (out.last(), op) {
+            (Some(Xor(a)), Xor(b)) => Some(Xor(a ^ b)),
+            (Some(Add(a)), Add(b)) => Some(Add(a.wrapping_add(b))),
+            (Some(ReverseBits), ReverseBits) | (Some(XorPos), XorPos) => Some(Xor(0)),
+            _ => None,
+        };
+        match merged {
+            Some(op) => {
+                out.pop();
+                if op != Xor(0) && op != Add(0) {
+                    out.push(op);
+                }
+            }
+            None if op == Xor(0) || op == Add(0) => {}
+            None => out.push(op),
+        }
+    }
+    out
+}
+
 impl Cipher {
+    /// Builds a cipher from a complete spec, including the terminating 0x00.
     pub fn new(bytes: &[u8]) -> Result<Self> {
-        ensure!(bytes.len() > 1, "empty spec is invalid");
         ensure!(
-            bytes.last() == Some(&0),
-            "need to have 0 as last op in cipher, got {bytes:?}"
+            bytes.len() <= MAX_SPEC_LEN,
+            "cipher spec longer than {MAX_SPEC_LEN} bytes"
         );
-
         let mut ops = vec![];
-        let mut i = 0;
-        while i < bytes.len() - 1 {
-            let op = match bytes[i] {
-                1 => ReverseBits,
-                2 => Xor(bytes[i + 1]),
-                3 => XorPos,
-                4 => Add(bytes[i + 1]),
-                5 => AddPos,
-                n => bail!("unsupported op code {n}"),
+        let mut bytes = bytes.iter().copied();
+        loop {
+            let op = match bytes.next() {
+                None => bail!("cipher spec is not terminated by 0x00"),
+                Some(0) => break,
+                Some(1) => ReverseBits,
+                Some(2) => Xor(bytes.next().ok_or_else(|| anyhow!("xor without operand"))?),
+                Some(3) => XorPos,
+                Some(4) => Add(bytes.next().ok_or_else(|| anyhow!("add without operand"))?),
+                Some(5) => AddPos,
+                Some(n) => bail!("unsupported op code {n}"),
             };
             ops.push(op);
+        }
+        ensure!(bytes.next().is_none(), "trailing bytes after cipher spec");
 
-            if matches!(op, Xor(_)) || matches!(op, Add(_)) {
-                i += 2;
-            } else {
-                i += 1;
+        let ops = reduce(ops);
+        ensure!(!ops.is_empty(), "cipher reduces to a no-op");
+        let mut cipher = Self {
+            ops,
+            encode: vec![[0; 256]; 256],
+            decode: vec![[0; 256]; 256],
+        };
+        for pos in 0..256 {
+            for b in 0..=255 {
+                let e = cipher.apply(pos, b);
+                cipher.encode[pos][b as usize] = e;
+                cipher.decode[pos][e as usize] = b;
             }
         }
-
-        Ok(Self { ops })
+        // Catches the identities the peephole reduction cannot see, such as
+        // ops that only cancel out once combined with the position.
+        ensure!(
+            !cipher
+                .encode
+                .iter()
+                .all(|t| t.iter().enumerate().all(|(b, e)| b == *e as usize)),
+            "cipher is a no-op"
+        );
+        Ok(cipher)
     }
 
-    pub fn encode_one(&self, start_offset: usize, input: u8) -> Result<u8> {
+    /// Runs the op list over one byte; only used to fill the tables.
+    fn apply(&self, start_offset: usize, input: u8) -> u8 {
         let mut b = input;
         for op in &self.ops {
             match op {
@@ -61,60 +119,43 @@ impl Cipher {
                     b = ((b as usize + start_offset) % 256) as u8;
                 }
                 Xor(n) => {
-                    b = b ^ n;
+                    b ^= n;
                 }
                 XorPos => {
-                    b = b ^ ((start_offset) % 256) as u8;
+                    b ^= (start_offset % 256) as u8;
                 }
             }
         }
-        Ok(b)
+        b
+    }
+
+    /// Number of ops left after reduction.
+    pub fn op_count(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn encode_one(&self, start_offset: usize, input: u8) -> u8 {
+        self.encode[start_offset % 256][input as usize]
     }
 
-    pub fn encode(&self, start_offset: usize, input: &[u8]) -> Result<Vec<u8>> {
-        let out: Result<Vec<_>, _> = input
+    pub fn encode(&self, start_offset: usize, input: &[u8]) -> Vec<u8> {
+        input
             .iter()
             .enumerate()
             .map(|(i, b)| self.encode_one(start_offset + i, *b))
-            .collect();
-        let out = out?;
-        ensure!(input != out, "no change to input");
-        Ok(out)
+            .collect()
     }
 
-    pub fn decode_one(&self, start_offset: usize, input: u8) -> Result<u8> {
-        let mut b = input;
-        for op in self.ops.iter().rev() {
-            match op {
-                ReverseBits => {
-                    b = b.reverse_bits();
-                }
-                Add(n) => {
-                    b = ((b as i64 - *n as i64) % 256) as u8;
-                }
-                AddPos => {
-                    b = ((b as i64 - start_offset as i64) % 256) as u8;
-                }
-                Xor(n) => {
-                    b = b ^ n;
-                }
-                XorPos => {
-                    b = b ^ ((start_offset) % 256) as u8;
-                }
-            }
-        }
-        Ok(b)
+    pub fn decode_one(&self, start_offset: usize, input: u8) -> u8 {
+        self.decode[start_offset % 256][input as usize]
     }
 
-    pub fn decode(&self, start_offset: usize, input: &[u8]) -> Result<Vec<u8>> {
-        let out: Result<Vec<_>, _> = input
+    pub fn decode(&self, start_offset: usize, input: &[u8]) -> Vec<u8> {
+        input
             .iter()
             .enumerate()
             .map(|(i, b)| self.decode_one(start_offset + i, *b))
-            .collect();
-        let out = out?;
-        ensure!(input != out, "no change to input");
-        Ok(out)
+            .collect()
     }
 }
 
@@ -125,14 +166,14 @@ mod tests {
     #[test]
     fn example_ciphers() -> Result<()> {
         let cipher = Cipher::new(&[2, 1, 1, 0])?;
-        let encoded = cipher.encode(0, b"hello").unwrap();
+        let encoded = cipher.encode(0, b"hello");
         assert_eq!(encoded, [0x96, 0x26, 0xb6, 0xb6, 0x76]);
-        assert_eq!(b"hello".to_vec(), cipher.decode(0, &encoded)?);
+        assert_eq!(b"hello".to_vec(), cipher.decode(0, &encoded));
 
         let cipher = Cipher::new(&[5, 5, 0])?;
-        let encoded = cipher.encode(0, b"hello").unwrap();
+        let encoded = cipher.encode(0, b"hello");
         assert_eq!(encoded, [0x68, 0x67, 0x70, 0x72, 0x77]);
-        assert_eq!(b"hello".to_vec(), cipher.decode(0, &encoded)?);
+        assert_eq!(b"hello".to_vec(), cipher.decode(0, &encoded));
 
         Ok(())
     }
@@ -141,56 +182,183 @@ mod tests {
     fn roundtrip() -> Result<()> {
         let cipher = Cipher::new(&[1, 2, 230, 3, 4, 240, 5, 0])?;
         for off in 0..1000 {
-            let encoded = cipher.encode(off, b"hello")?;
-            assert_eq!(b"hello".to_vec(), cipher.decode(off, &encoded)?);
+            let encoded = cipher.encode(off, b"hello");
+            assert_eq!(b"hello".to_vec(), cipher.decode(off, &encoded));
         }
 
         Ok(())
     }
 
+    #[test]
+    fn long_op_list_round_trips() -> Result<()> {
+        let spec: Vec<u8> = [1, 2, 0x5a, 3, 4, 0x11, 5]
+            .repeat(11)
+            .into_iter()
+            .chain([0])
+            .collect();
+        let cipher = Cipher::new(&spec)?;
+        let input: Vec<u8> = (0..=255).collect();
+        for off in [0, 1, 255, 256, 70_000] {
+            let encoded = cipher.encode(off, &input);
+            assert_eq!(input, cipher.decode(off, &encoded));
+        }
+        Ok(())
+    }
+
     #[test]
     fn decode() -> Result<()> {
         let cipher = Cipher::new(&[0x02, 0x7b, 0x05, 0x01, 0x00])?;
-        let encoded = cipher.encode(0, b"4x dog,5x car\n")?;
+        let encoded = cipher.encode(0, b"4x dog,5x car\n");
         assert_eq!(
             encoded,
             [0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e]
         );
-        assert_eq!(b"4x dog,5x car\n".to_vec(), cipher.decode(0, &encoded)?);
+        assert_eq!(b"4x dog,5x car\n".to_vec(), cipher.decode(0, &encoded));
 
-        let encoded = cipher.encode(0, b"5x car\n")?;
+        let encoded = cipher.encode(0, b"5x car\n");
         assert_eq!(encoded, [0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee]);
-        assert_eq!(b"5x car\n".to_vec(), cipher.decode(0, &encoded)?);
+        assert_eq!(b"5x car\n".to_vec(), cipher.decode(0, &encoded));
 
-        let encoded = cipher.encode(14, b"3x rat,2x cat\n")?;
+        let encoded = cipher.encode(14, b"3x rat,2x cat\n");
         assert_eq!(
             encoded,
             [0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31]
         );
-        assert_eq!(b"3x rat,2x cat\n".to_vec(), cipher.decode(14, &encoded)?);
+        assert_eq!(b"3x rat,2x cat\n".to_vec(), cipher.decode(14, &encoded));
 
-        let encoded = cipher.encode(7, b"3x rat\n")?;
+        let encoded = cipher.encode(7, b"3x rat\n");
         assert_eq!(encoded, [0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e]);
-        assert_eq!(b"3x rat\n".to_vec(), cipher.decode(7, &encoded)?);
+        assert_eq!(b"3x rat\n".to_vec(), cipher.decode(7, &encoded));
 
         Ok(())
     }
 
     #[test]
-    fn noop_ciphers() -> Result<()> {
+    fn noop_ciphers() {
         assert!(Cipher::new(&[0]).is_err());
+        assert!(Cipher::new(&[2, 0, 0]).is_err());
+        assert!(Cipher::new(&[2, 0xab, 2, 0xab, 0]).is_err());
+        assert!(Cipher::new(&[1, 1, 0]).is_err());
+        assert!(Cipher::new(&[0x02, 0xa0, 0x02, 0x0b, 0x02, 0xab, 0x00]).is_err());
+        assert!(Cipher::new(&[4, 0x80, 1, 1, 4, 0x80, 0]).is_err());
+        assert!(Cipher::new(&[3, 2, 7, 3, 2, 7, 0]).is_err());
+        // Not adjacent, so only the table check notices these cancel out.
+        assert!(Cipher::new(&[1, 2, 0x0f, 1, 2, 0xf0, 0]).is_err());
+    }
 
-        let cipher = Cipher::new(&[2, 0, 0])?;
-        assert!(dbg!(cipher.encode(0, b"hello")).is_err());
+    #[test]
+    fn reduction_composes_neighbours() {
+        use super::Op::*;
+        assert_eq!(vec![Xor(3)], reduce(vec![Xor(1), Xor(2)]));
+        assert_eq!(vec![Add(1)], reduce(vec![Add(200), Add(57)]));
+        assert_eq!(
+            vec![AddPos],
+            reduce(vec![ReverseBits, XorPos, XorPos, ReverseBits, AddPos])
+        );
+    }
 
-        let cipher = Cipher::new(&[2, 0xab, 2, 0xab, 0])?;
-        assert!(dbg!(cipher.encode(0, b"hello")).is_err());
+    #[test]
+    fn cipher_may_leave_some_messages_unchanged() -> Result<()> {
+        // xor-pos leaves the byte at position 0 alone; that alone must not
+        // make encoding fail.
+        let cipher = Cipher::new(&[3, 0])?;
+        assert_eq!(b"\n".to_vec(), cipher.encode(0, b"\n"));
+        Ok(())
+    }
 
-        let cipher = Cipher::new(&[1, 1, 0])?;
-        assert!(dbg!(cipher.encode(0, b"hello")).is_err());
+    /// The straightforward cipher the tables are checked against: runs the
+    /// ops over every byte, and their inverses backwards to decode.
+    mod reference {
+        pub fn encode(ops: &[(u8, u8)], pos: usize, mut b: u8) -> u8 {
+            for &(op, n) in ops {
+                b = match op {
+                    1 => b.reverse_bits(),
+                    2 => b ^ n,
+                    3 => b ^ pos as u8,
+                    4 => b.wrapping_add(n),
+                    5 => b.wrapping_add(pos as u8),
+                    _ => unreachable!(),
+                };
+            }
+            b
+        }
 
-        let cipher = Cipher::new(&[0x02, 0xa0, 0x02, 0x0b, 0x02, 0xab, 0x00])?;
-        assert!(dbg!(cipher.encode(0, b"hello")).is_err());
-        Ok(())
+        pub fn decode(ops: &[(u8, u8)], pos: usize, mut b: u8) -> u8 {
+            for &(op, n) in ops.iter().rev() {
+                b = match op {
+                    1 => b.reverse_bits(),
+                    2 => b ^ n,
+                    3 => b ^ pos as u8,
+                    4 => b.wrapping_sub(n),
+                    5 => b.wrapping_sub(pos as u8),
+                    _ => unreachable!(),
+                };
+            }
+            b
+        }
+
+        pub fn spec(ops: &[(u8, u8)]) -> Vec<u8> {
+            let mut spec = vec![];
+            for &(op, n) in ops {
+                spec.push(op);
+                if op == 2 || op == 4 {
+                    spec.push(n);
+                }
+            }
+            spec.push(0);
+            spec
+        }
+    }
+
+    mod properties {
+        use super::reference;
+        use crate::cipher::Cipher;
+        use proptest::prelude::*;
+
+        fn ops() -> impl Strategy<Value = Vec<(u8, u8)>> {
+            prop::collection::vec((1u8..=5, any::<u8>()), 1..20)
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn tables_match_reference(ops in ops()) {
+                let Ok(cipher) = Cipher::new(&reference::spec(&ops)) else {
+                    return Ok(());
+                };
+                for pos in 0..256 {
+                    for b in 0..=255 {
+                        prop_assert_eq!(reference::encode(&ops, pos, b), cipher.encode_one(pos, b));
+                        prop_assert_eq!(reference::decode(&ops, pos, b), cipher.decode_one(pos, b));
+                    }
+                }
+            }
+
+            #[test]
+            fn only_identities_are_rejected(ops in ops()) {
+                let identity = (0..256).all(|pos| (0..=255).all(|b| reference::encode(&ops, pos, b) == b));
+                prop_assert_eq!(identity, Cipher::new(&reference::spec(&ops)).is_err());
+            }
+
+            #[test]
+            fn payloads_round_trip(
+                ops in ops(),
+                payload in prop::collection::vec(any::<u8>(), 0..256),
+                offset in 0usize..1_000_000,
+            ) {
+                let Ok(cipher) = Cipher::new(&reference::spec(&ops)) else {
+                    return Ok(());
+                };
+                let encoded = cipher.encode(offset, &payload);
+                let expected: Vec<u8> = payload
+                    .iter()
+                    .enumerate()
+                    .map(|(i, b)| reference::encode(&ops, offset + i, *b))
+                    .collect();
+                prop_assert_eq!(&expected, &encoded);
+                prop_assert_eq!(payload, cipher.decode(offset, &encoded));
+            }
+        }
     }
 }
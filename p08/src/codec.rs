@@ -0,0 +1,147 @@
+use crate::cipher::Cipher;
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Deobfuscates incoming bytes and hands the plain text to `inner`, e.g. a
+/// `LinesCodec`, so the two compose under `FramedRead`.
+pub struct IslDecoder<D> {
+    cipher: Cipher,
+    inner: D,
+    position: usize,
+    plain: BytesMut,
+}
+
+impl<D> IslDecoder<D> {
+    pub fn new(cipher: Cipher, inner: D) -> Self {
+        Self {
+            cipher,
+            inner,
+            position: 0,
+            plain: BytesMut::new(),
+        }
+    }
+
+    fn take(&mut self, src: &mut BytesMut) {
+        self.plain.reserve(src.len());
+        for b in src.split() {
+            self.plain.put_u8(self.cipher.decode_one(self.position, b));
+            self.position += 1;
+        }
+    }
+}
+
+impl<D> Decoder for IslDecoder<D>
+where
+    D: Decoder,
+{
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.take(src);
+        self.inner.decode(&mut self.plain)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.take(src);
+        self.inner.decode_eof(&mut self.plain)
+    }
+}
+
+/// Lets `inner` frame outgoing items and obfuscates the result, so the two
+/// compose under `FramedWrite`.
+pub struct IslEncoder<E> {
+    cipher: Cipher,
+    inner: E,
+    position: usize,
+}
+
+impl<E> IslEncoder<E> {
+    pub fn new(cipher: Cipher, inner: E) -> Self {
+        Self {
+            cipher,
+            inner,
+            position: 0,
+        }
+    }
+}
+
+impl<T, E> Encoder<T> for IslEncoder<E>
+where
+    E: Encoder<T>,
+{
+    type Error = E::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+        self.inner.encode(item, dst)?;
+        for b in &mut dst[start..] {
+            *b = self.cipher.encode_one(self.position, *b);
+            self.position += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isl::read_spec;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+    const SPEC: [u8; 5] = [0x02, 0x7b, 0x05, 0x01, 0x00];
+
+    #[test]
+    fn decoder_tracks_position_across_calls() {
+        let cipher = Cipher::new(&SPEC).unwrap();
+        let encoded = cipher.encode(0, b"4x dog,5x car\n3x rat\n");
+        let mut decoder = IslDecoder::new(cipher, LinesCodec::new());
+        let mut src = BytesMut::from(&encoded[..5]);
+        assert_eq!(None, decoder.decode(&mut src).unwrap());
+        src.extend_from_slice(&encoded[5..]);
+        assert_eq!(
+            Some("4x dog,5x car".to_string()),
+            decoder.decode(&mut src).unwrap()
+        );
+        assert_eq!(
+            Some("3x rat".to_string()),
+            decoder.decode(&mut src).unwrap()
+        );
+        assert_eq!(None, decoder.decode(&mut src).unwrap());
+    }
+
+    #[test]
+    fn encoder_tracks_position_across_items() {
+        let cipher = Cipher::new(&SPEC).unwrap();
+        let mut encoder = IslEncoder::new(cipher.clone(), LinesCodec::new());
+        let mut dst = BytesMut::new();
+        encoder.encode("5x car", &mut dst).unwrap();
+        encoder.encode("3x rat", &mut dst).unwrap();
+        assert_eq!(b"5x car\n3x rat\n".to_vec(), cipher.decode(0, &dst));
+    }
+
+    #[tokio::test]
+    async fn framed_lines_over_duplex() {
+        let (mut client, server) = duplex(1024);
+        let cipher = Cipher::new(&SPEC).unwrap();
+        client.write_all(&SPEC).await.unwrap();
+        client
+            .write_all(&cipher.encode(0, b"4x dog,5x car\n"))
+            .await
+            .unwrap();
+
+        let (r, w) = tokio::io::split(server);
+        let mut r = BufReader::new(r);
+        let cipher = read_spec(&mut r).await.unwrap();
+        let mut lines = FramedRead::new(r, IslDecoder::new(cipher.clone(), LinesCodec::new()));
+        let mut replies = FramedWrite::new(w, IslEncoder::new(cipher.clone(), LinesCodec::new()));
+        assert_eq!("4x dog,5x car", lines.next().await.unwrap().unwrap());
+        replies.send("5x car").await.unwrap();
+
+        let mut reply = [0u8; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(b"5x car\n".to_vec(), cipher.decode(0, &reply));
+    }
+}
@@ -1,54 +1,467 @@
-use crate::cipher::Cipher;
-use anyhow::Result;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
-
-pub struct InsecureSocket {
-    r: BufReader<OwnedReadHalf>,
-    w: OwnedWriteHalf,
+use crate::cipher::{Cipher, MAX_SPEC_LEN};
+use anyhow::{bail, ensure, Context, Result};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
+
+/// Reads the cipher spec a client sends before any application data. The
+/// spec is parsed as it arrives, so a 0x00 operand is not mistaken for the
+/// terminator and an oversized spec is refused without buffering it.
+pub async fn read_spec<R: AsyncRead + Unpin>(r: &mut R) -> Result<Cipher> {
+    let mut spec = vec![];
+    loop {
+        match read_spec_byte(r, &mut spec).await? {
+            0 => break,
+            2 | 4 => {
+                read_spec_byte(r, &mut spec).await?;
+            }
+            _ => {}
+        }
+    }
+    Cipher::new(&spec)
+}
+
+/// Appends the next spec byte, refusing to grow past `MAX_SPEC_LEN`.
+async fn read_spec_byte<R: AsyncRead + Unpin>(r: &mut R, spec: &mut Vec<u8>) -> Result<u8> {
+    ensure!(
+        spec.len() < MAX_SPEC_LEN,
+        "cipher spec longer than {MAX_SPEC_LEN} bytes"
+    );
+    let b = r.read_u8().await.context("truncated cipher spec")?;
+    spec.push(b);
+    Ok(b)
+}
+
+/// A line-oriented connection obfuscated with the cipher the client sends
+/// first. The obfuscation is symmetric, so the same type serves both ends.
+/// Works over any byte stream, e.g. a `TcpStream` or one end of a
+/// `tokio::io::duplex`.
+pub struct InsecureSocket<S> {
+    stream: BufReader<S>,
     cipher: Cipher,
     r_bytes: usize,
     w_bytes: usize,
+    limits: Limits,
+    /// Decoded bytes of a line whose newline has not arrived yet.
+    partial: String,
+    lines_read: u64,
+    lines_written: u64,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// Snapshot of what a single connection has done so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub lines_read: u64,
+    pub lines_written: u64,
+    pub bytes_decoded: u64,
+    pub bytes_encoded: u64,
+    /// Ops in the connection's cipher spec once reduced; the bytes are
+    /// run through tables built from them, not through each op.
+    pub spec_ops: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "lines read: {}, lines written: {}, bytes decoded: {}, bytes encoded: {}, spec ops: {}",
+            self.lines_read,
+            self.lines_written,
+            self.bytes_decoded,
+            self.bytes_encoded,
+            self.spec_ops
+        )
+    }
 }
 
-impl InsecureSocket {
-    pub async fn new(tcp: TcpStream) -> Result<Self> {
-        let (r, w) = tcp.into_split();
-        let mut r = BufReader::new(r);
-        let mut buf = vec![];
-        let n = r.read_until(0, &mut buf).await?;
-        let cipher = Cipher::new(&buf[..n])?;
-        Ok(Self {
-            r,
-            w,
+/// Counters shared by all connections of a server, updated as they go.
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    lines_read: AtomicU64,
+    lines_written: AtomicU64,
+    bytes_decoded: AtomicU64,
+    bytes_encoded: AtomicU64,
+    /// Sum of every connection's `Stats::spec_ops`.
+    spec_ops: AtomicU64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connections: {}, lines read: {}, lines written: {}, bytes decoded: {}, bytes encoded: {}, spec ops: {}",
+            self.connections.load(Relaxed),
+            self.lines_read.load(Relaxed),
+            self.lines_written.load(Relaxed),
+            self.bytes_decoded.load(Relaxed),
+            self.bytes_encoded.load(Relaxed),
+            self.spec_ops.load(Relaxed),
+        )
+    }
+}
+
+/// Bounds on what a peer may make a connection hold or wait for.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Longest decoded line accepted, excluding the newline.
+    pub max_line_len: usize,
+    /// How long a read may go without receiving any bytes.
+    pub read_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_line_len: 1024 * 1024,
+            read_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> InsecureSocket<S> {
+    /// Accepts a client by reading its cipher spec.
+    pub async fn new(stream: S) -> Result<Self> {
+        let mut stream = BufReader::new(stream);
+        let cipher = read_spec(&mut stream).await.context("ISL handshake")?;
+        Ok(Self::with_cipher(stream, cipher))
+    }
+
+    /// Starts the client side by sending `spec` to the server.
+    pub async fn client(mut stream: S, spec: &[u8]) -> Result<Self> {
+        let cipher = Cipher::new(spec)?;
+        stream.write_all(spec).await?;
+        Ok(Self::with_cipher(BufReader::new(stream), cipher))
+    }
+
+    fn with_cipher(stream: BufReader<S>, cipher: Cipher) -> Self {
+        Self {
+            stream,
             cipher,
             r_bytes: 0,
             w_bytes: 0,
-        })
+            limits: Limits::default(),
+            partial: String::new(),
+            lines_read: 0,
+            lines_written: 0,
+            metrics: None,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Counts this connection and its traffic in `metrics` from now on.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        metrics.connections.fetch_add(1, Relaxed);
+        metrics
+            .spec_ops
+            .fetch_add(self.cipher.op_count() as u64, Relaxed);
+        self.metrics = Some(metrics);
     }
 
+    pub fn stats(&self) -> Stats {
+        Stats {
+            lines_read: self.lines_read,
+            lines_written: self.lines_written,
+            bytes_decoded: self.r_bytes as u64,
+            bytes_encoded: self.w_bytes as u64,
+            spec_ops: self.cipher.op_count(),
+        }
+    }
+
+    /// Reads and decodes the next line, without the newline. Decodes whole
+    /// buffered chunks at a time rather than awaiting every byte. Fails once
+    /// the line outgrows the limit or the peer stays silent for too long.
     pub async fn read_line(&mut self) -> Result<String> {
-        let mut buf = String::new();
         loop {
-            let b = self.r.read_u8().await?;
-            let b = self.cipher.decode_one(self.r_bytes, b)?;
+            if let Some(line) = self.buffered_line()? {
+                return Ok(line);
+            }
+            let Ok(buf) = timeout(self.limits.read_timeout, self.stream.fill_buf()).await else {
+                bail!("no data for {:?}", self.limits.read_timeout);
+            };
+            if buf?.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    /// Waits for the next line and returns it together with every further
+    /// line that already arrived, so pipelined requests are handled as one
+    /// batch.
+    pub async fn read_lines(&mut self) -> Result<Vec<String>> {
+        let mut lines = vec![self.read_line().await?];
+        while let Some(line) = self.buffered_line()? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    /// Decodes what is already buffered into the partial line, returning it
+    /// once its newline shows up. Never waits for the peer.
+    fn buffered_line(&mut self) -> Result<Option<String>> {
+        let buf = self.stream.buffer();
+        let mut used = 0;
+        let mut done = false;
+        for &b in buf {
+            let b = self.cipher.decode_one(self.r_bytes, b);
             self.r_bytes += 1;
+            used += 1;
             if b == b'\n' {
+                done = true;
                 break;
-            } else {
-                buf.push(b as char);
             }
+            self.partial.push(b as char);
+        }
+        self.stream.consume(used);
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_decoded.fetch_add(used as u64, Relaxed);
+            metrics.lines_read.fetch_add(done as u64, Relaxed);
+        }
+        self.lines_read += done as u64;
+        if self.partial.len() > self.limits.max_line_len {
+            bail!("line longer than {} bytes", self.limits.max_line_len);
+        }
+        Ok(done.then(|| std::mem::take(&mut self.partial)))
+    }
+
+    pub async fn write_line(&mut self, line: String) -> Result<()> {
+        self.write_lines(&[line]).await
+    }
+
+    /// Encodes all of `lines` and sends them with a single flush.
+    pub async fn write_lines(&mut self, lines: &[String]) -> Result<()> {
+        let mut plain = Vec::with_capacity(lines.iter().map(|l| l.len() + 1).sum());
+        for line in lines {
+            plain.extend_from_slice(line.as_bytes());
+            plain.push(b'\n');
+        }
+        self.write_bytes(&plain).await?;
+        self.lines_written += lines.len() as u64;
+        if let Some(metrics) = &self.metrics {
+            metrics.lines_written.fetch_add(lines.len() as u64, Relaxed);
         }
-        Ok(buf)
+        Ok(())
     }
 
-    pub async fn write_line(&mut self, mut line: String) -> Result<()> {
-        line.push('\n');
-        let encoded_bytes = self.cipher.encode(self.w_bytes, line.as_bytes())?;
-        self.w.write_all(&encoded_bytes).await?;
-        self.w.flush().await?;
+    /// Encodes and sends raw application bytes.
+    pub async fn write_bytes(&mut self, plain: &[u8]) -> Result<()> {
+        let encoded_bytes = self.cipher.encode(self.w_bytes, plain);
+        self.stream.write_all(&encoded_bytes).await?;
+        self.stream.flush().await?;
         self.w_bytes += encoded_bytes.len();
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .bytes_encoded
+                .fetch_add(encoded_bytes.len() as u64, Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl InsecureSocket<TcpStream> {
+    /// Connects to an ISL server at `addr` and obfuscates the session with
+    /// the cipher described by `spec`.
+    pub async fn connect(addr: impl ToSocketAddrs, spec: &[u8]) -> Result<Self> {
+        Self::client(TcpStream::connect(addr).await?, spec).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    const SPEC: [u8; 5] = [0x02, 0x7b, 0x05, 0x01, 0x00];
+
+    #[tokio::test]
+    async fn lines_round_trip_over_duplex() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let cipher = Cipher::new(&SPEC)?;
+        client.write_all(&SPEC).await?;
+        client
+            .write_all(&cipher.encode(0, b"4x dog,5x car\n"))
+            .await?;
+
+        let mut isl = InsecureSocket::new(server).await?;
+        assert_eq!("4x dog,5x car", isl.read_line().await?);
+        isl.write_line("5x car".to_string()).await?;
+
+        let mut reply = [0u8; 7];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(b"5x car\n".to_vec(), cipher.decode(0, &reply));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lines_split_across_writes() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let cipher = Cipher::new(&SPEC)?;
+        let encoded = cipher.encode(0, b"4x dog,5x car\n3x rat\n2x cat");
+        client.write_all(&SPEC).await?;
+        let mut isl = InsecureSocket::new(server).await?;
+        for chunk in encoded.chunks(3) {
+            client.write_all(chunk).await?;
+        }
+        drop(client);
+
+        assert_eq!("4x dog,5x car", isl.read_line().await?);
+        assert_eq!("3x rat", isl.read_line().await?);
+        assert!(isl.read_line().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_and_server_agree() -> Result<()> {
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut isl = InsecureSocket::new(server).await?;
+            let line = isl.read_line().await?;
+            isl.write_line(line.chars().rev().collect()).await
+        });
+
+        let mut isl = InsecureSocket::client(client, &SPEC).await?;
+        isl.write_line("4x dog,5x car".to_string()).await?;
+        assert_eq!("rac x5,god x4", isl.read_line().await?);
+        server.await?
+    }
+
+    #[tokio::test]
+    async fn client_rejects_invalid_spec() {
+        let (client, _server) = duplex(1024);
+        assert!(InsecureSocket::client(client, &[9, 0]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn overlong_line_is_rejected() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let cipher = Cipher::new(&SPEC)?;
+        client.write_all(&SPEC).await?;
+        let mut isl = InsecureSocket::new(server).await?;
+        isl.set_limits(Limits {
+            max_line_len: 10,
+            ..Limits::default()
+        });
+
+        client
+            .write_all(&cipher.encode(0, b"1x ten char\n"))
+            .await?;
+        assert!(isl.read_line().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_times_out() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        client.write_all(&SPEC).await?;
+        let mut isl = InsecureSocket::new(server).await?;
+        client
+            .write_all(&Cipher::new(&SPEC)?.encode(0, b"1x"))
+            .await?;
+        assert!(isl.read_line().await.is_err());
+        Ok(())
+    }
+
+    async fn handshake(spec: &[u8], close: bool) -> Result<Cipher> {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(spec).await?;
+        if close {
+            drop(client);
+        }
+        read_spec(&mut server).await
+    }
+
+    #[tokio::test]
+    async fn spec_with_zero_operand() -> Result<()> {
+        let cipher = handshake(&[2, 0, 4, 0, 5, 0], false).await?;
+        assert_eq!(vec![1, 2], cipher.encode(1, &[0, 0]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn truncated_spec_is_rejected() {
+        assert!(handshake(&[2, 0x7b, 5], true).await.is_err());
+        assert!(handshake(&[2], true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_spec_is_rejected() {
+        let mut spec = [1, 1].repeat(40);
+        spec.extend([5, 0]);
+        let err = handshake(&spec, false).await.unwrap_err();
+        assert!(err.to_string().contains("longer than 80"), "{err}");
+
+        let mut spec = [2, 0x7b].repeat(39);
+        spec.extend([5, 0]);
+        assert!(handshake(&spec, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pipelined_lines_are_read_as_one_batch() -> Result<()> {
+        let (mut client, server) = duplex(64 * 1024);
+        let cipher = Cipher::new(&SPEC)?;
+        client.write_all(&SPEC).await?;
+        let requests: Vec<String> = (0..100).map(|i| format!("{i}x toy {i}")).collect();
+        let plain: String = requests.iter().map(|r| format!("{r}\n")).collect();
+        client
+            .write_all(&cipher.encode(0, plain.as_bytes()))
+            .await?;
+
+        let mut isl = InsecureSocket::new(server).await?;
+        let mut lines = vec![];
+        while lines.len() < requests.len() {
+            lines.extend(isl.read_lines().await?);
+        }
+        assert_eq!(requests, lines);
+
+        isl.write_lines(&lines).await?;
+        let mut reply = vec![0; plain.len()];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(plain.as_bytes(), cipher.decode(0, &reply));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn traffic_is_counted() -> Result<()> {
+        let (client, server) = duplex(1024);
+        let metrics = Arc::new(Metrics::default());
+        let server = tokio::spawn({
+            let metrics = metrics.clone();
+            async move {
+                let mut isl = InsecureSocket::new(server).await?;
+                isl.set_metrics(metrics);
+                let lines = isl.read_lines().await?;
+                isl.write_lines(&lines).await?;
+                anyhow::Ok(isl.stats())
+            }
+        });
+
+        let mut isl = InsecureSocket::client(client, &SPEC).await?;
+        isl.write_lines(&["4x dog".to_string(), "5x car".to_string()])
+            .await?;
+        let stats = server.await??;
+        assert_eq!(
+            Stats {
+                lines_read: 2,
+                lines_written: 2,
+                bytes_decoded: 14,
+                bytes_encoded: 14,
+                spec_ops: 3,
+            },
+            stats
+        );
+        assert_eq!(
+            "connections: 1, lines read: 2, lines written: 2, bytes decoded: 14, bytes encoded: 14, spec ops: 3",
+            metrics.to_string()
+        );
         Ok(())
     }
 }
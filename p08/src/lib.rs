@@ -0,0 +1,7 @@
+pub mod cipher;
+pub mod codec;
+pub mod isl;
+pub mod middleware;
+mod toys;
+
+pub use toys::{run, Args};
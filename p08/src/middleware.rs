@@ -0,0 +1,170 @@
+use crate::isl::{read_spec, Limits};
+use anyhow::{bail, Context, Result};
+use std::future::Future;
+use std::pin::pin;
+use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::select;
+use tokio::time::timeout;
+
+/// Buffer size of the in-memory pipe between the cipher and the handler.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Size of the chunks bytes are passed on in.
+const CHUNK: usize = 4096;
+
+/// Performs the ISL handshake on `stream` and runs `handler`, a plain
+/// line-based handler that knows nothing of ISL, on a pipe that carries
+/// the decoded bytes in and encodes whatever the handler writes.
+///
+/// The two directions are pumped apart, so a client that sends more than
+/// the pipe holds before reading any reply doesn't leave the handler
+/// waiting to write a reply while this waits to hand it more input.
+pub async fn serve_isl<S, H, Fut>(stream: S, handler: H) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: FnOnce(tokio::io::DuplexStream) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let (r, mut w) = split(stream);
+    let mut r = BufReader::new(r);
+    let cipher = read_spec(&mut r).await.context("ISL handshake")?;
+    let read_timeout = Limits::default().read_timeout;
+    let cipher = &cipher;
+    let (app, inner) = duplex(PIPE_CAPACITY);
+    let handler = tokio::spawn(handler(inner));
+    let (mut from_app, mut to_app) = split(app);
+
+    let inbound = async move {
+        let (mut buf, mut pos) = (vec![0u8; CHUNK], 0);
+        loop {
+            let Ok(n) = timeout(read_timeout, r.read(&mut buf)).await else {
+                bail!("no data for {read_timeout:?}");
+            };
+            let n = n?;
+            if n == 0 {
+                // Let the handler see the end of input and finish up.
+                let _ = to_app.shutdown().await;
+                return Ok(());
+            }
+            let plain = cipher.decode(pos, &buf[..n]);
+            pos += n;
+            if to_app.write_all(&plain).await.is_err() {
+                // The handler is done reading; its replies still go out.
+                return Ok(());
+            }
+        }
+    };
+    let outbound = async move {
+        let (mut buf, mut pos) = (vec![0u8; CHUNK], 0);
+        loop {
+            let n = from_app.read(&mut buf).await?;
+            if n == 0 {
+                return anyhow::Ok(());
+            }
+            w.write_all(&cipher.encode(pos, &buf[..n])).await?;
+            w.flush().await?;
+            pos += n;
+        }
+    };
+    // The connection is over once the handler closes its end, whether or
+    // not the client has closed its own.
+    {
+        let (mut inbound, mut outbound) = (pin!(inbound), pin!(outbound));
+        select! {
+            done = &mut outbound => done?,
+            done = &mut inbound => {
+                done?;
+                outbound.await?;
+            }
+        }
+    }
+    handler.await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::Cipher;
+    use crate::isl::InsecureSocket;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, DuplexStream};
+
+    const SPEC: [u8; 5] = [0x02, 0x7b, 0x05, 0x01, 0x00];
+
+    /// A plain line-based handler that knows nothing about ISL.
+    async fn shout(stream: DuplexStream) -> Result<()> {
+        let (r, mut w) = tokio::io::split(stream);
+        let mut lines = BufReader::new(r).lines();
+        while let Some(line) = lines.next_line().await? {
+            w.write_all(format!("{}\n", line.to_uppercase()).as_bytes())
+                .await?;
+        }
+        w.write_all(b"bye\n").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_speaks_isl() -> Result<()> {
+        let (client, server) = duplex(1024);
+        tokio::spawn(serve_isl(server, shout));
+
+        let mut isl = InsecureSocket::client(client, &SPEC).await?;
+        isl.write_lines(&["hello".to_string(), "world".to_string()])
+            .await?;
+        assert_eq!("HELLO", isl.read_line().await?);
+        assert_eq!("WORLD", isl.read_line().await?);
+        Ok(())
+    }
+
+    /// Answers every line with eight copies of it, so replies outgrow the
+    /// pipe well before the input does.
+    async fn repeat(stream: DuplexStream) -> Result<()> {
+        let (r, mut w) = tokio::io::split(stream);
+        let mut lines = BufReader::new(r).lines();
+        while let Some(line) = lines.next_line().await? {
+            w.write_all(format!("{line}\n").repeat(8).as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipelined_lines_beyond_the_pipe_are_answered() -> Result<()> {
+        let (mut client, server) = duplex(64 * PIPE_CAPACITY);
+        let server = tokio::spawn(serve_isl(server, repeat));
+
+        let cipher = Cipher::new(&SPEC)?;
+        let lines: String = (0..64).map(|i| format!("{i:08000}\n")).collect();
+        assert!(lines.len() > 4 * PIPE_CAPACITY);
+        client.write_all(&SPEC).await?;
+        client
+            .write_all(&cipher.encode(0, lines.as_bytes()))
+            .await?;
+        client.shutdown().await?;
+
+        let mut reply = vec![];
+        let read = client.read_to_end(&mut reply);
+        tokio::time::timeout(Duration::from_secs(10), read).await??;
+        let expected: String = lines.lines().map(|l| format!("{l}\n").repeat(8)).collect();
+        assert_eq!(expected.as_bytes(), cipher.decode(0, &reply));
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_sees_client_eof() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+        let server = tokio::spawn(serve_isl(server, shout));
+
+        let cipher = Cipher::new(&SPEC)?;
+        client.write_all(&SPEC).await?;
+        client.write_all(&cipher.encode(0, b"hi\n")).await?;
+        client.shutdown().await?;
+
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await?;
+        assert_eq!(b"HI\nbye\n".to_vec(), cipher.decode(0, &reply));
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Insecure sockets layer (p08): picks the toy to make first from each request, over ISL.
+
+use crate::isl::{InsecureSocket, Metrics};
+use anyhow::Result;
+use clap::Parser;
+use proto_common::{ConnLimit, Listen, Server};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::info;
+
+/// Why a toy list could not be understood.
+#[derive(Debug, PartialEq, Eq)]
+enum ToyError {
+    Empty,
+    MissingSeparator(String),
+    BadCount(String),
+}
+
+impl fmt::Display for ToyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToyError::Empty => write!(f, "empty toy list"),
+            ToyError::MissingSeparator(toy) => write!(f, "missing \"x \" in {toy:?}"),
+            ToyError::BadCount(toy) => write!(f, "bad count in {toy:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ToyError {}
+
+/// Parses a list like `10x toy car,15x dog on a string` into counts and
+/// names.
+fn parse_toys(s: &str) -> Result<Vec<(u64, &str)>, ToyError> {
+    if s.is_empty() {
+        return Err(ToyError::Empty);
+    }
+    s.split(',')
+        .map(|toy| {
+            let (n, name) = toy
+                .split_once("x ")
+                .ok_or_else(|| ToyError::MissingSeparator(toy.to_string()))?;
+            let n = n.parse().map_err(|_| ToyError::BadCount(toy.to_string()))?;
+            Ok((n, name))
+        })
+        .collect()
+}
+
+/// Picks the toy with the highest count; among equal counts the one listed
+/// first wins.
+fn find_best(s: &str) -> Result<String, ToyError> {
+    let toys = parse_toys(s)?;
+    // max_by_key keeps the last of equal maxima, so search back to front.
+    let (n, name) = toys
+        .iter()
+        .rev()
+        .max_by_key(|(n, _)| n)
+        .ok_or(ToyError::Empty)?;
+    Ok(format!("{n}x {name}"))
+}
+
+async fn handle(stream: TcpStream, metrics: Arc<Metrics>) -> Result<()> {
+    let mut isl = InsecureSocket::new(stream).await?;
+    isl.set_metrics(metrics);
+    let result = answer(&mut isl).await;
+    info!("{}", isl.stats());
+    result
+}
+
+async fn answer(isl: &mut InsecureSocket<TcpStream>) -> Result<()> {
+    loop {
+        let lines = isl.read_lines().await?;
+        // The protocol has no way to report errors, so a malformed request
+        // ends the connection.
+        let replies = lines
+            .iter()
+            .map(|line| find_best(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        isl.write_lines(&replies).await?;
+    }
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    let metrics = Arc::new(Metrics::default());
+    let server = Server::new(list).with_limit(&args.limit);
+    let _reporting = proto_common::report({
+        let metrics = metrics.clone();
+        move |_| info!("metrics: {metrics}")
+    });
+    server
+        .run(stop, |stream, _, _| {
+            let metrics = metrics.clone();
+            async move {
+                if let Err(e) = handle(stream, metrics).await {
+                    info!("closing: {e}");
+                }
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_best() {
+        let input = "4x dog,5x car";
+        let expected = "5x car";
+        assert_eq!(expected, find_best(input).unwrap());
+    }
+
+    #[test]
+    fn ties_go_to_the_first_toy() {
+        assert_eq!("5x car", find_best("5x car,5x dog,1x cat").unwrap());
+    }
+
+    #[test]
+    fn toy_names_may_contain_separator() {
+        assert_eq!("3x box of 2x cars", find_best("3x box of 2x cars").unwrap());
+    }
+
+    #[test]
+    fn malformed_lists_are_errors() {
+        assert_eq!(Err(ToyError::Empty), find_best(""));
+        assert_eq!(
+            Err(ToyError::MissingSeparator("dog".to_string())),
+            find_best("4x cat,dog")
+        );
+        assert_eq!(
+            Err(ToyError::BadCount("ax dog".to_string())),
+            find_best("ax dog")
+        );
+        assert_eq!(
+            Err(ToyError::BadCount("-1x dog".to_string())),
+            find_best("-1x dog")
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_toy_requests_end_to_end() -> Result<()> {
+        let list = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = list.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = list.accept().await?;
+            handle(stream, Default::default()).await
+        });
+
+        let mut isl = InsecureSocket::connect(addr, &[0x02, 0x7b, 0x05, 0x01, 0x00]).await?;
+        isl.write_line("4x dog,5x car".to_string()).await?;
+        assert_eq!("5x car", isl.read_line().await?);
+        isl.write_line("3x rat,2x cat".to_string()).await?;
+        assert_eq!("3x rat", isl.read_line().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_request_closes_connection() -> Result<()> {
+        let list = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = list.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = list.accept().await?;
+            handle(stream, Default::default()).await
+        });
+
+        let mut isl = InsecureSocket::connect(addr, &[0x02, 0x7b, 0x05, 0x01, 0x00]).await?;
+        isl.write_line("lots of dogs".to_string()).await?;
+        assert!(isl.read_line().await.is_err());
+        assert!(server.await?.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_all_answered() -> Result<()> {
+        let list = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = list.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = list.accept().await?;
+            handle(stream, Default::default()).await
+        });
+
+        let mut isl = InsecureSocket::connect(addr, &[0x02, 0x7b, 0x05, 0x01, 0x00]).await?;
+        let requests: Vec<String> = (1..=100)
+            .map(|i| format!("{i}x car,{}x dog", 101 - i))
+            .collect();
+        isl.write_lines(&requests).await?;
+        for i in 1..=100 {
+            let expected = if i > 50 {
+                format!("{i}x car")
+            } else {
+                format!("{}x dog", 101 - i)
+            };
+            assert_eq!(expected, isl.read_line().await?);
+        }
+        Ok(())
+    }
+}
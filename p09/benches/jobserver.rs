@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use p09::{Job, JobServer};
+use serde_json::Value;
+
+const JOBS: u64 = 100_000;
+
+fn queue(i: u64) -> String {
+    format!("queue{}", i % 10)
+}
+
+fn server() -> JobServer {
+    let mut server = JobServer::default();
+    for id in 0..JOBS {
+        server
+            .put(Job {
+                id,
+                queue: queue(id),
+                job: Value::Null,
+                pri: id * 7919 % 1000,
+                lease: None,
+                run_at: None,
+                expires_at: None,
+            })
+            .unwrap();
+    }
+    server
+}
+
+fn get(c: &mut Criterion) {
+    let queues: Vec<String> = (0..3).map(queue).collect();
+    let mut server = server();
+    // Aborting puts the job straight back, keeping the queues at 100k jobs.
+    c.bench_function("get and abort with 100k queued jobs", |b| {
+        b.iter(|| {
+            let job = server.get(0, &queues, false).unwrap().unwrap();
+            server.abort(0, job.id)
+        })
+    });
+}
+
+criterion_group!(benches, get);
+criterion_main!(benches);
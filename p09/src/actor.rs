@@ -0,0 +1,100 @@
+use crate::JobServer;
+use std::fmt;
+use tokio::sync::{mpsc, oneshot};
+
+/// Requests queued for the server task before senders have to wait.
+const QUEUE_DEPTH: usize = 1024;
+
+type Command = Box<dyn FnOnce(&mut JobServer) + Send>;
+
+/// The server task is gone, having panicked or been dropped.
+#[derive(Debug)]
+pub struct ServerGone;
+
+impl fmt::Display for ServerGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job server stopped")
+    }
+}
+
+impl std::error::Error for ServerGone {}
+
+/// Access to a `JobServer` owned by its own task. Clients queue up closures
+/// that the task runs one at a time, so nobody holds a lock while waiting
+/// on a socket and there's no lock to contend on at all.
+#[derive(Clone)]
+pub struct JobServerHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl JobServerHandle {
+    /// Moves `server` onto a task of its own. The task ends once every
+    /// handle is dropped.
+    pub fn spawn(mut server: JobServer) -> JobServerHandle {
+        let (commands, mut queue) = mpsc::channel::<Command>(QUEUE_DEPTH);
+        tokio::spawn(async move {
+            while let Some(command) = queue.recv().await {
+                command(&mut server);
+            }
+        });
+        JobServerHandle { commands }
+    }
+
+    /// Runs `f` on the server and returns what it returns.
+    pub async fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut JobServer) -> R + Send + 'static,
+    ) -> Result<R, ServerGone> {
+        let (reply, result) = oneshot::channel();
+        let command: Command = Box::new(move |server| {
+            let _ = reply.send(f(server));
+        });
+        self.commands.send(command).await.map_err(|_| ServerGone)?;
+        result.await.map_err(|_| ServerGone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Job;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn calls_run_in_order_on_the_server() {
+        let server = JobServerHandle::spawn(JobServer::default());
+        let mut puts = vec![];
+        for pri in 0..10 {
+            let server = server.clone();
+            puts.push(tokio::spawn(async move {
+                server
+                    .call(move |s| {
+                        let id = s.next_id();
+                        let job = Job {
+                            id,
+                            queue: "q".to_owned(),
+                            job: Value::Null,
+                            pri,
+                            lease: None,
+                            run_at: None,
+                            expires_at: None,
+                        };
+                        s.put(job).unwrap();
+                    })
+                    .await
+            }));
+        }
+        for put in puts {
+            put.await.unwrap().unwrap();
+        }
+        let stats = server.call(|s| s.stats()).await.unwrap();
+        assert_eq!(10, stats["q"].ready);
+    }
+
+    #[tokio::test]
+    async fn panicking_call_stops_the_server() {
+        let server = JobServerHandle::spawn(JobServer::default());
+        assert!(server.call(|_| panic!("boom")).await.is_err());
+        assert!(server.call(|s| s.stats()).await.is_err());
+    }
+}
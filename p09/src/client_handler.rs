@@ -1,92 +1,150 @@
-use crate::{Job, JobServer};
-use anyhow::{bail, Result};
+use crate::protocol::{GetOk, NewJob, Request};
+use crate::{Job, JobResult, JobServer, JobServerHandle};
+use anyhow::Result;
 use fxhash::FxHashSet as HashSet;
-use serde::{Deserialize, Serialize};
+use proto_common::LineCodec;
 use serde_json::{json, Value};
 use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::Mutex;
-
-#[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-#[serde(tag = "request")]
-enum Request {
-    put {
-        queue: String,
-        job: Value,
-        pri: u64,
-    },
-    get {
-        queues: Vec<String>,
-        #[serde(default)]
-        wait: bool,
-    },
-    delete {
-        id: u64,
-    },
-    abort {
-        id: u64,
-    },
-}
-
-fn next_id() -> u64 {
-    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-    NEXT_ID.fetch_add(1, Relaxed)
-}
+use tokio::select;
+use tokio::sync::watch;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct GetOk {
-    status: &'static str,
-    id: u64,
-    job: Value,
-    pri: u64,
-    queue: String,
-}
-
-impl GetOk {
-    fn from(job: &Job) -> Self {
-        Self {
-            status: "ok",
-            id: job.id,
-            job: job.job.clone(),
-            pri: job.pri,
-            queue: job.queue.clone(),
+/// Puts a job, returning the reply for it.
+fn put_job(server: &mut JobServer, new: NewJob) -> Value {
+    let run_at = match (new.delay, new.run_at) {
+        (None, None) => None,
+        (Some(delay), None) => Some(SystemTime::now() + Duration::from_secs(delay)),
+        (None, Some(at)) => Some(UNIX_EPOCH + Duration::from_secs(at)),
+        (Some(_), Some(_)) => {
+            return json!({
+                "status": "error",
+                "error": "only one of delay and run_at may be given",
+            })
         }
+    };
+    let id = server.next_id();
+    let job = Job {
+        id,
+        queue: new.queue,
+        job: new.job,
+        pri: new.pri,
+        lease: new.lease.map(Duration::from_secs),
+        run_at,
+        expires_at: new
+            .ttl
+            .map(|ttl| SystemTime::now() + Duration::from_secs(ttl)),
+    };
+    match server.put(job) {
+        Ok(()) => json!({
+            "status": "ok",
+            "id": id,
+        }),
+        Err(e) => json!({
+            "status": "error",
+            "error": e.to_string(),
+            "code": e.code(),
+            "limit": e.limit(),
+        }),
     }
 }
 
-async fn read_next_line(r: &mut (impl AsyncBufReadExt + Unpin)) -> Result<String> {
-    let mut line = String::new();
-    if 0 == r.read_line(&mut line).await? {
-        bail!("no message");
+/// Deletes a job, returning the reply for it.
+fn delete_job(server: &mut JobServer, id: u64) -> Value {
+    match server.delete(id) {
+        Ok(true) => json!({"status": "ok"}),
+        Ok(false) => json!({"status": "no-job"}),
+        Err(e) => json!({
+            "status": "error",
+            "error": format!("delete not stored: {e}"),
+        }),
     }
-    Ok(line)
 }
 
-async fn write_next_line(w: &mut (impl AsyncWriteExt + Unpin), msg: &str) -> Result<()> {
-    let msg = format!("{msg}\n");
-    w.write_all(msg.as_bytes()).await?;
-    Ok(w.flush().await?)
+/// Longest request line accepted; the connection is closed on anything
+/// longer rather than buffering it.
+const MAX_REQUEST_LEN: usize = 1024 * 1024;
+
+/// Request and reply lines.
+const LINES: LineCodec = LineCodec::new(MAX_REQUEST_LEN);
+
+async fn write_error(w: &mut (impl AsyncWriteExt + Unpin), error: &str) -> Result<()> {
+    let reply = json!({
+        "status": "error",
+        "error": error,
+    });
+    LINES.write_line(w, &serde_json::to_string(&reply)?).await
 }
 
-pub struct ClientHandler {
-    pub server: Arc<Mutex<JobServer>>,
-    pub read: BufReader<OwnedReadHalf>,
-    pub write: OwnedWriteHalf,
+/// Resolves once the client closes the connection. Anything it sends in the
+/// meantime stays buffered for the next request.
+async fn disconnected(r: &mut (impl AsyncBufReadExt + Unpin)) {
+    if let Ok(buf) = r.fill_buf().await {
+        if !buf.is_empty() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Serves one client, a TCP connection unless tests say otherwise.
+pub struct ClientHandler<R = BufReader<OwnedReadHalf>, W = OwnedWriteHalf> {
+    /// Identifies this client to the server as the holder of its jobs.
+    pub id: u64,
+    pub server: JobServerHandle,
+    pub read: R,
+    pub write: W,
     pub in_progress: HashSet<u64>,
+    /// Flips to true when the server shuts down; no more requests are read
+    /// after that.
+    pub shutdown: watch::Receiver<bool>,
 }
 
-impl ClientHandler {
+impl<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin> ClientHandler<R, W> {
+    pub fn new(
+        server: JobServerHandle,
+        read: R,
+        write: W,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        static NEXT_CLIENT: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_CLIENT.fetch_add(1, Relaxed),
+            server,
+            read,
+            write,
+            in_progress: Default::default(),
+            shutdown,
+        }
+    }
+
+    /// Serves requests until the client leaves or the server shuts down.
+    /// The jobs the client was working on go back to their queues however
+    /// that happens, including on errors.
     pub async fn run(&mut self) -> Result<()> {
+        let result = self.serve().await;
+        let (client, held) = (self.id, std::mem::take(&mut self.in_progress));
+        self.server
+            .call(move |server| {
+                for id in held {
+                    server.abort(client, id);
+                }
+            })
+            .await?;
+        result
+    }
+
+    async fn serve(&mut self) -> Result<()> {
         loop {
-            let line = match read_next_line(&mut self.read).await {
-                Ok(line) => line,
-                Err(_) => {
-                    let mut server = self.server.lock().await;
-                    for id in &self.in_progress {
-                        server.abort(*id);
+            let next = select! {
+                line = LINES.read_line(&mut self.read) => line,
+                Ok(()) = self.shutdown.changed() => Ok(None),
+            };
+            let line = match next {
+                Ok(Some(line)) => line,
+                result => {
+                    if let Err(e) = result {
+                        let _ = write_error(&mut self.write, &e.to_string()).await;
                     }
                     break;
                 }
@@ -94,136 +152,452 @@ impl ClientHandler {
             let req: Result<Request, _> = serde_json::from_str(&line);
             match req {
                 Ok(Request::get { queues, wait }) => self.get(queues, wait).await?,
-                Ok(Request::put { queue, job, pri }) => self.put(queue, job, pri).await?,
+                Ok(Request::put(new)) => {
+                    let reply = self.server.call(|server| put_job(server, new)).await?;
+                    LINES
+                        .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+                        .await?;
+                }
+                Ok(Request::put_batch { jobs }) => {
+                    let results = self
+                        .server
+                        .call(|server| jobs.into_iter().map(|new| put_job(server, new)).collect())
+                        .await?;
+                    self.write_batch(results).await?;
+                }
+                Ok(Request::delete_batch { ids }) => {
+                    let results = self
+                        .server
+                        .call(|server| ids.into_iter().map(|id| delete_job(server, id)).collect())
+                        .await?;
+                    self.write_batch(results).await?;
+                }
                 Ok(Request::abort { id }) => self.abort(id).await?,
-                Ok(Request::delete { id }) => self.delete(id).await?,
-                Err(e) => {
-                    let reply = json!({
-                        "status": "error",
-                        "error": e.to_string(),
-                    });
-                    let msg = serde_json::to_string(&reply)?;
-                    write_next_line(&mut self.write, &msg).await?;
+                Ok(Request::delete { id }) => {
+                    let reply = self
+                        .server
+                        .call(move |server| delete_job(server, id))
+                        .await?;
+                    LINES
+                        .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+                        .await?;
+                }
+                Ok(Request::stats) => self.stats().await?,
+                Ok(Request::list { queue }) => self.list(queue).await?,
+                Ok(Request::requeue { id }) => {
+                    if self.server.call(move |server| server.requeue(id)).await? {
+                        LINES
+                            .write_line(&mut self.write, r#"{"status":"ok"}"#)
+                            .await?;
+                    } else {
+                        LINES
+                            .write_line(&mut self.write, r#"{"status":"no-job"}"#)
+                            .await?;
+                    }
                 }
+                Ok(Request::complete { id, result }) => self.complete(id, result).await?,
+                Ok(Request::result { id }) => self.result(id).await?,
+                Err(e) => write_error(&mut self.write, &e.to_string()).await?,
             }
         }
         Ok(())
     }
 
     async fn get(&mut self, queues: Vec<String>, wait: bool) -> Result<()> {
-        let job = self.server.lock().await.get(&queues, wait);
+        let client = self.id;
+        let job = self
+            .server
+            .call(move |server| server.get(client, &queues, wait))
+            .await?;
         match job {
             Ok(None) => {
-                write_next_line(&mut self.write, r#"{"status":"no-job"}"#).await?;
+                LINES
+                    .write_line(&mut self.write, r#"{"status":"no-job"}"#)
+                    .await?;
             }
             Ok(Some(job)) => {
+                self.in_progress.insert(job.id);
                 let msg = GetOk::from(&job);
                 let msg = serde_json::to_string(&msg)?;
-                write_next_line(&mut self.write, &msg).await?;
-                self.in_progress.insert(job.id);
+                LINES.write_line(&mut self.write, &msg).await?;
             }
-            Err(receiver) => {
-                let job = receiver.await?;
+            Err(mut receiver) => {
+                let job = select! {
+                    job = &mut receiver => match job {
+                        Ok(job) => job,
+                        // The server is shutting down.
+                        Err(_) => {
+                            LINES.write_line(&mut self.write, r#"{"status":"no-job"}"#).await?;
+                            return Ok(());
+                        }
+                    },
+                    _ = disconnected(&mut self.read) => {
+                        // A job may have been handed over just before the
+                        // client left; give it to someone else.
+                        receiver.close();
+                        if let Ok(job) = receiver.try_recv() {
+                            let client = self.id;
+                            self.server.call(move |server| server.abort(client, job.id)).await?;
+                        }
+                        return Ok(());
+                    }
+                };
+                self.in_progress.insert(job.id);
                 let msg = GetOk::from(&job);
                 let msg = serde_json::to_string(&msg)?;
-                write_next_line(&mut self.write, &msg).await?;
-                self.in_progress.insert(job.id);
+                LINES.write_line(&mut self.write, &msg).await?;
             }
         }
         Ok(())
     }
 
-    async fn put(&mut self, queue: String, job: Value, pri: u64) -> Result<()> {
-        let id = next_id();
-        let job = Job {
-            id,
-            queue,
-            job,
-            pri,
-        };
-        self.server.lock().await.put(job);
-        let reply = json!({
-            "status": "ok",
-            "id": id,
-        });
-        let msg = serde_json::to_string(&reply)?;
-        write_next_line(&mut self.write, &msg).await?;
-        Ok(())
-    }
-
     async fn abort(&mut self, id: u64) -> Result<()> {
         if !self.in_progress.contains(&id) {
-            let reply = json!({
-                "status": "error",
-                "error": format!("this client is not working on job {id}"),
-            });
-            let msg = serde_json::to_string(&reply)?;
-            write_next_line(&mut self.write, &msg).await?;
+            let error = format!("this client is not working on job {id}");
+            write_error(&mut self.write, &error).await?;
         } else {
             self.in_progress.remove(&id);
-            if self.server.lock().await.abort(id) {
-                write_next_line(&mut self.write, r#"{"status":"ok"}"#).await?;
+            // The job is no longer ours if its lease ran out in the meantime.
+            let client = self.id;
+            if self
+                .server
+                .call(move |server| server.abort(client, id))
+                .await?
+            {
+                LINES
+                    .write_line(&mut self.write, r#"{"status":"ok"}"#)
+                    .await?;
             } else {
-                write_next_line(&mut self.write, r#"{"status":"no-job"}"#).await?;
+                LINES
+                    .write_line(&mut self.write, r#"{"status":"no-job"}"#)
+                    .await?;
             }
         }
         Ok(())
     }
 
-    async fn delete(&mut self, id: u64) -> Result<()> {
-        if self.server.lock().await.delete(id) {
-            write_next_line(&mut self.write, r#"{"status":"ok"}"#).await?;
-        } else {
-            write_next_line(&mut self.write, r#"{"status":"no-job"}"#).await?;
+    async fn complete(&mut self, id: u64, result: Value) -> Result<()> {
+        if !self.in_progress.contains(&id) {
+            let error = format!("this client is not working on job {id}");
+            return write_error(&mut self.write, &error).await;
         }
-        Ok(())
+        let client = self.id;
+        let completed = self
+            .server
+            .call(move |server| server.complete(client, id, result))
+            .await?;
+        let reply = match completed {
+            Ok(true) => {
+                self.in_progress.remove(&id);
+                json!({"status": "ok"})
+            }
+            Ok(false) => {
+                self.in_progress.remove(&id);
+                json!({"status": "no-job"})
+            }
+            Err(e) => json!({
+                "status": "error",
+                "error": e.to_string(),
+                "code": e.code(),
+                "limit": e.limit(),
+            }),
+        };
+        LINES
+            .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+            .await
+    }
+
+    async fn result(&mut self, id: u64) -> Result<()> {
+        let result = self.server.call(move |server| server.result(id)).await?;
+        let reply = match result {
+            Some(JobResult::Done(result)) => json!({
+                "status": "ok",
+                "id": id,
+                "result": result,
+            }),
+            Some(JobResult::Pending) => json!({"status": "pending"}),
+            None => json!({"status": "no-job"}),
+        };
+        LINES
+            .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+            .await
+    }
+
+    async fn stats(&mut self) -> Result<()> {
+        let queues = self.server.call(|server| server.stats()).await?;
+        let reply = json!({
+            "status": "ok",
+            "queues": queues,
+        });
+        LINES
+            .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+            .await
+    }
+
+    async fn list(&mut self, queue: String) -> Result<()> {
+        let jobs = self.server.call(move |server| server.list(&queue)).await?;
+        let reply = json!({
+            "status": "ok",
+            "jobs": jobs,
+        });
+        LINES
+            .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+            .await
+    }
+
+    async fn write_batch(&mut self, results: Vec<Value>) -> Result<()> {
+        let reply = json!({
+            "status": "ok",
+            "results": results,
+        });
+        LINES
+            .write_line(&mut self.write, &serde_json::to_string(&reply)?)
+            .await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::JobServer;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinSet;
+    use tokio::time::timeout;
 
-    #[test]
-    fn test_deserialization() {
-        let input = r#"{"request":"put","queue":"queue1","job":7,"pri":123}"#;
-        assert_eq!(
-            Request::put {
-                job: 7.into(),
-                queue: "queue1".to_owned(),
-                pri: 123
-            },
-            serde_json::from_str(input).unwrap()
-        );
+    const QUEUES: [&str; 3] = ["a", "b", "c"];
 
-        let input = r#"{"request":"get","queues":["queue1","queue2"],"wait":true}"#;
-        assert_eq!(
-            Request::get {
-                queues: vec!["queue1".to_owned(), "queue2".to_owned()],
-                wait: true,
-            },
-            serde_json::from_str(input).unwrap()
-        );
+    /// What every client has seen, checked as they go.
+    #[derive(Default)]
+    struct Ledger {
+        put: HashSet<u64>,
+        deleted: HashSet<u64>,
+        completed: HashSet<u64>,
+        /// Jobs handed out and not yet given back, with who has them.
+        held: HashMap<u64, usize>,
+    }
 
-        let input = r#"{"request":"get","queues":["queue1","queue2"]}"#;
-        assert_eq!(
-            Request::get {
-                queues: vec!["queue1".to_owned(), "queue2".to_owned()],
-                wait: false,
-            },
-            serde_json::from_str(input).unwrap()
-        );
+    impl Ledger {
+        fn got(&mut self, id: u64, client: usize) {
+            assert!(
+                !self.completed.contains(&id),
+                "job {id} came back after completing"
+            );
+            if let Some(other) = self.held.insert(id, client) {
+                panic!("job {id} handed to {client} while {other} has it");
+            }
+        }
 
-        let input = r#"{"request":"delete","id":12345}"#;
-        assert_eq!(
-            Request::delete { id: 12345 },
-            serde_json::from_str(input).unwrap()
-        );
+        /// Must happen before the server hears about it, or the job could be
+        /// handed on before it's been given back here.
+        fn give_back(&mut self, id: u64) {
+            self.held.remove(&id);
+        }
+    }
+
+    /// xorshift, so runs are repeatable without a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
 
-        let input = r#"{"request":"abort","id":12345}"#;
-        assert_eq!(
-            Request::abort { id: 12345 },
-            serde_json::from_str(input).unwrap()
+    fn connect(
+        server: &JobServerHandle,
+        shutdown: &watch::Receiver<bool>,
+    ) -> BufReader<DuplexStream> {
+        let (client, conn) = duplex(64 * 1024);
+        let (read, write) = tokio::io::split(conn);
+        let mut handler = ClientHandler::new(
+            server.clone(),
+            BufReader::new(read),
+            write,
+            shutdown.clone(),
         );
+        tokio::spawn(async move { handler.run().await });
+        BufReader::new(client)
+    }
+
+    async fn request(conn: &mut BufReader<DuplexStream>, request: Value) -> Value {
+        LINES.write_line(conn, &request.to_string()).await.unwrap();
+        read_reply(conn).await
+    }
+
+    async fn read_reply(conn: &mut BufReader<DuplexStream>) -> Value {
+        let line = LINES
+            .read_line(conn)
+            .await
+            .unwrap()
+            .expect("handler hung up");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// One client doing `ops` random things, reconnecting whenever it
+    /// drops its connection.
+    async fn client(
+        me: usize,
+        ops: usize,
+        server: JobServerHandle,
+        shutdown: watch::Receiver<bool>,
+        ledger: Arc<Mutex<Ledger>>,
+    ) {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15 ^ (me as u64 + 1));
+        let mut conn = connect(&server, &shutdown);
+        let mut held: Vec<u64> = vec![];
+        let queues = |rng: &mut Rng| -> Vec<&str> {
+            let mask = 1 + rng.below(7);
+            QUEUES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, q)| *q)
+                .collect()
+        };
+        for _ in 0..ops {
+            match rng.below(10) {
+                0..=2 => {
+                    let queue = QUEUES[rng.below(3) as usize];
+                    let put =
+                        json!({"request": "put", "queue": queue, "job": me, "pri": rng.below(5)});
+                    let reply = request(&mut conn, put).await;
+                    ledger
+                        .lock()
+                        .unwrap()
+                        .put
+                        .insert(reply["id"].as_u64().unwrap());
+                }
+                3..=4 => {
+                    let get = json!({"request": "get", "queues": queues(&mut rng)});
+                    let reply = request(&mut conn, get).await;
+                    if let Some(id) = reply["id"].as_u64() {
+                        ledger.lock().unwrap().got(id, me);
+                        held.push(id);
+                    }
+                }
+                5 => {
+                    // Wait briefly, then hang up mid-wait if nothing came.
+                    let get = json!({"request": "get", "queues": queues(&mut rng), "wait": true});
+                    LINES.write_line(&mut conn, &get.to_string()).await.unwrap();
+                    match timeout(Duration::from_millis(5), read_reply(&mut conn)).await {
+                        Ok(reply) => {
+                            let id = reply["id"].as_u64().unwrap();
+                            ledger.lock().unwrap().got(id, me);
+                            held.push(id);
+                        }
+                        Err(_) => {
+                            let mut ledger = ledger.lock().unwrap();
+                            held.drain(..).for_each(|id| ledger.give_back(id));
+                            drop(ledger);
+                            conn = connect(&server, &shutdown);
+                        }
+                    }
+                }
+                6 if !held.is_empty() => {
+                    let id = held.swap_remove(rng.below(held.len() as u64) as usize);
+                    ledger.lock().unwrap().give_back(id);
+                    let reply = request(&mut conn, json!({"request": "abort", "id": id})).await;
+                    assert!(
+                        reply["status"] == "ok" || reply["status"] == "no-job",
+                        "{reply}"
+                    );
+                }
+                7 if !held.is_empty() => {
+                    let id = held.swap_remove(rng.below(held.len() as u64) as usize);
+                    ledger.lock().unwrap().give_back(id);
+                    let complete = json!({"request": "complete", "id": id, "result": me});
+                    let reply = request(&mut conn, complete).await;
+                    if reply["status"] == "ok" {
+                        ledger.lock().unwrap().completed.insert(id);
+                    }
+                }
+                8 => {
+                    let id = {
+                        let ledger = ledger.lock().unwrap();
+                        ledger
+                            .put
+                            .iter()
+                            .nth(rng.below(ledger.put.len() as u64 + 1) as usize)
+                            .copied()
+                    };
+                    if let Some(id) = id {
+                        let reply =
+                            request(&mut conn, json!({"request": "delete", "id": id})).await;
+                        if reply["status"] == "ok" {
+                            ledger.lock().unwrap().deleted.insert(id);
+                        }
+                    }
+                }
+                _ => {
+                    let mut ledger = ledger.lock().unwrap();
+                    held.drain(..).for_each(|id| ledger.give_back(id));
+                    drop(ledger);
+                    conn = connect(&server, &shutdown);
+                }
+            }
+        }
+        let mut ledger = ledger.lock().unwrap();
+        held.drain(..).for_each(|id| ledger.give_back(id));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn many_clients_never_share_or_lose_a_job() {
+        let server = JobServerHandle::spawn(JobServer::default());
+        let (stop, shutdown) = watch::channel(false);
+        let ledger = Arc::new(Mutex::new(Ledger::default()));
+        let mut clients = JoinSet::new();
+        for me in 0..200 {
+            clients.spawn(client(
+                me,
+                50,
+                server.clone(),
+                shutdown.clone(),
+                ledger.clone(),
+            ));
+        }
+        while let Some(done) = clients.join_next().await {
+            done.unwrap();
+        }
+
+        // Every connection is closed, so once the handlers have given back
+        // what they held, whatever wasn't deleted or completed is ready.
+        let mut expected: Vec<u64> = {
+            let ledger = ledger.lock().unwrap();
+            assert!(ledger.completed.is_disjoint(&ledger.deleted));
+            assert!(ledger.held.is_empty());
+            ledger
+                .put
+                .iter()
+                .filter(|id| !ledger.deleted.contains(id) && !ledger.completed.contains(id))
+                .copied()
+                .collect()
+        };
+        expected.sort();
+        let queues: Vec<String> = QUEUES.iter().map(|q| q.to_string()).collect();
+        let mut left = vec![];
+        for _ in 0..100 {
+            let stats = server.call(|s| s.stats()).await.unwrap();
+            if stats.values().all(|q| q.running == 0) {
+                let queues = queues.clone();
+                left = server
+                    .call(move |s| {
+                        std::iter::from_fn(|| s.get(0, &queues, false).unwrap())
+                            .map(|j| j.id)
+                            .collect()
+                    })
+                    .await
+                    .unwrap();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        left.sort();
+        assert_eq!(expected, left);
+        drop(stop);
     }
 }
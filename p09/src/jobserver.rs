@@ -1,95 +1,1402 @@
+use crate::metrics::Metrics;
+use crate::timer_wheel::TimerWheel;
+use crate::wal::{Record, Wal};
+use fxhash::FxHashMap as HashMap;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::sync::oneshot::{channel, Receiver, Sender};
+use tokio::time::Instant;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Job {
     pub id: u64,
     pub queue: String,
     pub job: Value,
     pub pri: u64,
+    /// How long a client may hold the job before it is aborted on its
+    /// behalf. `None` lets it hold the job until it disconnects.
+    pub lease: Option<Duration>,
+    /// Time before which the job isn't handed out. `None` or a time already
+    /// passed makes it ready right away.
+    pub run_at: Option<SystemTime>,
+    /// Time after which the job is dropped unless a client is working on
+    /// it. A job given back after this time is dropped then.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A ready job as stored in its queue's heap. Higher priorities come first,
+/// and jobs of equal priority in the order they were put.
+#[derive(Debug)]
+struct JobRef {
+    job: Job,
+    /// Position of the job in the order of puts.
+    seq: u64,
+    /// When the job became ready.
+    since: Instant,
+}
+
+impl JobRef {
+    fn key(&self) -> (u64, Reverse<u64>) {
+        (self.job.pri, Reverse(self.seq))
+    }
+}
+
+impl PartialEq for JobRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for JobRef {}
+
+impl PartialOrd for JobRef {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for JobRef {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// What is going on in one queue, as reported by [`JobServer::stats`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct QueueStats {
+    /// Jobs waiting to be handed out.
+    pub ready: usize,
+    /// Jobs some client is working on.
+    pub running: usize,
+    /// Clients blocked waiting for a job from this queue.
+    pub waiters: usize,
+    /// Jobs put with a `run_at` that hasn't come yet.
+    pub delayed: usize,
+    /// Jobs given up on after too many aborts.
+    pub dead: usize,
+}
+
+/// Bounds on what clients may put, so they can't exhaust memory.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Largest job payload, in bytes of JSON.
+    pub max_payload: usize,
+    /// Most live jobs, ready or running, in one queue.
+    pub max_queue_jobs: usize,
+    /// Most live jobs across all queues.
+    pub max_jobs: usize,
+    /// How long the result of a completed job is kept around. Results are
+    /// held in memory only; they don't survive a restart.
+    pub result_retention: Duration,
+    /// How many times a job may be aborted, by its client or by its lease
+    /// running out, before it's given up on and only comes back through
+    /// `requeue`. `None` retries forever. Abort counts are held in memory
+    /// only; after a restart every job starts over.
+    pub max_aborts: Option<u32>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_payload: 64 * 1024,
+            max_queue_jobs: 1_000_000,
+            max_jobs: 10_000_000,
+            result_retention: Duration::from_secs(600),
+            max_aborts: None,
+        }
+    }
+}
+
+/// Why a put was refused.
+#[derive(Debug)]
+pub enum PutError {
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
+    QueueFull {
+        queue: String,
+        max: usize,
+    },
+    TooManyJobs {
+        max: usize,
+    },
+    /// The server is shutting down.
+    ShuttingDown,
+    /// The job could not be logged.
+    Io(io::Error),
+}
+
+impl PutError {
+    /// Short machine-readable name of the error, for replies.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PutError::PayloadTooLarge { .. } => "payload-too-large",
+            PutError::QueueFull { .. } => "queue-full",
+            PutError::TooManyJobs { .. } => "too-many-jobs",
+            PutError::ShuttingDown => "shutting-down",
+            PutError::Io(_) => "io",
+        }
+    }
+
+    /// The limit that was hit, if any.
+    pub fn limit(&self) -> Option<usize> {
+        match self {
+            PutError::PayloadTooLarge { max, .. }
+            | PutError::QueueFull { max, .. }
+            | PutError::TooManyJobs { max } => Some(*max),
+            PutError::ShuttingDown | PutError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PutError::PayloadTooLarge { size, max } => {
+                write!(f, "job payload of {size} bytes is over the limit of {max}")
+            }
+            PutError::QueueFull { queue, max } => {
+                write!(f, "queue {queue} already holds {max} jobs")
+            }
+            PutError::TooManyJobs { max } => write!(f, "server already holds {max} jobs"),
+            PutError::ShuttingDown => write!(f, "server is shutting down"),
+            PutError::Io(e) => write!(f, "job not stored: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PutError {}
+
+impl From<io::Error> for PutError {
+    fn from(e: io::Error) -> Self {
+        PutError::Io(e)
+    }
+}
+
+/// One job as listed by [`JobServer::list`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub pri: u64,
+    /// `ready`, `running`, `delayed` or `dead`.
+    pub state: &'static str,
+    /// Id of the client working on a running job.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub holder: Option<u64>,
+}
+
+/// What became of a job, as reported by [`JobServer::result`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobResult {
+    /// The job is still queued, delayed or being worked on.
+    Pending,
+    /// The job was completed with this result.
+    Done(Value),
+}
+
+/// Where a live job is. Ready jobs are kept in their queue's heap.
+enum JobLocation {
+    /// Held back until its `run_at`, with an entry in the timer wheel.
+    Delayed { job: Job, seq: u64 },
+    Ready {
+        /// Needed to keep `queue_lens` right when the job is deleted.
+        queue: String,
+    },
+    Running {
+        job: Job,
+        /// Kept so an aborted job gets its old place back.
+        seq: u64,
+        /// Id of the client working on the job.
+        holder: u64,
+        expires: Option<Instant>,
+    },
+    /// Aborted too many times; not handed out until it is requeued.
+    Dead { job: Job, seq: u64 },
+}
+
+/// A client blocked in a `get` with `wait`.
+struct Waiter {
+    client: u64,
+    since: Instant,
+    queues: Vec<String>,
+    sender: Sender<Job>,
 }
 
 #[derive(Default)]
 pub struct JobServer {
-    ready: Vec<Job>,
-    running: Vec<Job>,
-    waiters: Vec<(Vec<String>, Sender<Job>)>,
+    /// Ready jobs per queue. Deleting a ready job only drops it from `jobs`;
+    /// the stale heap entry is discarded once it reaches the top.
+    queues: HashMap<String, BinaryHeap<JobRef>>,
+    jobs: HashMap<u64, JobLocation>,
+    /// Live jobs, whatever their state, per queue.
+    queue_lens: HashMap<String, usize>,
+    limits: Limits,
+    /// Clients blocked in a `get`, oldest first. None of them has a ready
+    /// job in its queues: a job goes straight to a waiter if there is one,
+    /// and a client only starts waiting when its queues are empty.
+    waiters: Vec<Waiter>,
+    /// Lease deadlines of running jobs, earliest first. Entries of jobs that
+    /// finished or changed hands since are skipped when they come up.
+    leases: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// Ids of delayed jobs by their `run_at`. Deleted jobs are skipped when
+    /// they come out.
+    delayed: TimerWheel<u64>,
+    /// `expires_at` of jobs that have one, earliest first. Entries of jobs
+    /// that are gone by then are skipped.
+    expiries: BinaryHeap<Reverse<(SystemTime, u64)>>,
+    next_id: u64,
+    next_seq: u64,
+    /// Where accepted puts and deletes are recorded; `None` keeps everything
+    /// in memory only.
+    wal: Option<Wal>,
+    /// Set by `shutdown`; no more jobs are taken or handed out.
+    closed: bool,
+    /// Results of completed jobs, and when each is dropped in the order
+    /// they were recorded.
+    results: HashMap<u64, Value>,
+    result_expiry: VecDeque<(Instant, u64)>,
+    /// Times each job has been aborted, for jobs aborted at least once.
+    aborts: HashMap<u64, u32>,
+    metrics: Arc<Metrics>,
 }
 
 impl JobServer {
+    /// Opens a server whose jobs are persisted in the log at `path`, with
+    /// every job recorded there ready to be handed out, or delayed if its
+    /// time hasn't come yet.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<JobServer> {
+        let (wal, jobs, next_id) = Wal::open(path)?;
+        let mut server = JobServer {
+            next_id,
+            ..Default::default()
+        };
+        for job in jobs {
+            let seq = server.next_seq();
+            *server.queue_lens.entry(job.queue.clone()).or_default() += 1;
+            server.schedule(job, seq);
+        }
+        server.wal = Some(wal);
+        Ok(server)
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Allocates an id for a new job.
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq - 1
+    }
+
     pub fn get(
         &mut self,
+        client: u64,
         queues: &[String],
         wait: bool,
     ) -> std::result::Result<Option<Job>, Receiver<Job>> {
-        let candidate_idx = self
-            .ready
-            .iter()
-            .enumerate()
-            .filter(|(_, job)| queues.contains(&job.queue))
-            .max_by_key(|(_, job)| job.pri)
-            .map(|(idx, _)| idx);
-        match candidate_idx {
-            Some(idx) => {
-                let job = self.ready.remove(idx);
-                self.running.push(job.clone());
+        let mut best = None;
+        for name in queues {
+            if let Some(key) = self.peek(name) {
+                if best.is_none_or(|(_, k)| key > k) {
+                    best = Some((name, key));
+                }
+            }
+        }
+        match best {
+            _ if self.closed => Ok(None),
+            Some((name, _)) => {
+                let JobRef { job, seq, since } =
+                    self.queues.get_mut(name).and_then(BinaryHeap::pop).unwrap();
+                self.metrics.time_in_queue.record(since.elapsed());
+                self.metrics.gets.fetch_add(1, Relaxed);
+                self.start(job.clone(), seq, client);
                 Ok(Some(job))
             }
             None => {
                 if !wait {
                     return Ok(None);
                 }
-                let (s, r) = channel();
-                self.waiters.push((queues.to_vec(), s));
+                let (sender, r) = channel();
+                self.waiters.retain(|w| !w.sender.is_closed());
+                self.waiters.push(Waiter {
+                    client,
+                    since: Instant::now(),
+                    queues: queues.to_vec(),
+                    sender,
+                });
                 Err(r)
             }
         }
     }
 
-    pub fn put(&mut self, job: Job) {
-        if let Some(idx) = self
-            .waiters
-            .iter()
-            .position(|(queues, _)| queues.contains(&job.queue))
-        {
-            self.running.push(job.clone());
-            self.waiters.remove(idx).1.send(job).unwrap();
-        } else {
-            self.ready.push(job);
+    /// Ordering key of the best ready job in `queue`, discarding deleted
+    /// jobs from the top of its heap on the way.
+    fn peek(&mut self, queue: &str) -> Option<(u64, Reverse<u64>)> {
+        let heap = self.queues.get_mut(queue)?;
+        while let Some(top) = heap.peek() {
+            if let Some(JobLocation::Ready { .. }) = self.jobs.get(&top.job.id) {
+                return Some(top.key());
+            }
+            heap.pop();
+        }
+        None
+    }
+
+    fn push_ready(&mut self, job: Job, seq: u64) {
+        let queue = job.queue.clone();
+        self.jobs.insert(job.id, JobLocation::Ready { queue });
+        self.queues
+            .entry(job.queue.clone())
+            .or_default()
+            .push(JobRef {
+                job,
+                seq,
+                since: Instant::now(),
+            });
+    }
+
+    /// Marks `job` as held by `holder`, starting its lease.
+    fn start(&mut self, job: Job, seq: u64, holder: u64) {
+        let expires = job.lease.map(|lease| Instant::now() + lease);
+        if let Some(at) = expires {
+            self.leases.push(Reverse((at, job.id)));
+        }
+        self.jobs.insert(
+            job.id,
+            JobLocation::Running {
+                job,
+                seq,
+                holder,
+                expires,
+            },
+        );
+    }
+
+    /// Hands `job` to the longest waiting client interested in its queue,
+    /// or makes it ready. Waiters whose client went away are dropped, and a
+    /// job refused by one falls through to the next.
+    fn dispatch(&mut self, mut job: Job, seq: u64) {
+        if job.expires_at.is_some_and(|at| at <= SystemTime::now()) {
+            self.drop_expired(job.id, &job.queue);
+            return;
+        }
+        let mut idx = 0;
+        while idx < self.waiters.len() {
+            if !self.waiters[idx].queues.contains(&job.queue) {
+                idx += 1;
+                continue;
+            }
+            let waiter = self.waiters.remove(idx);
+            self.start(job.clone(), seq, waiter.client);
+            match waiter.sender.send(job) {
+                Ok(()) => {
+                    self.metrics.waits.record(waiter.since.elapsed());
+                    self.metrics.time_in_queue.record(Duration::ZERO);
+                    self.metrics.gets.fetch_add(1, Relaxed);
+                    return;
+                }
+                Err(refused) => job = refused,
+            }
+        }
+        self.push_ready(job, seq);
+    }
+
+    /// Holds `job` back until its `run_at`, or hands it out right away.
+    fn schedule(&mut self, job: Job, seq: u64) {
+        if let Some(at) = job.expires_at {
+            self.expiries.push(Reverse((at, job.id)));
+        }
+        match job.run_at {
+            Some(at) if at > SystemTime::now() => {
+                self.delayed.insert(at, job.id);
+                self.jobs.insert(job.id, JobLocation::Delayed { job, seq });
+            }
+            _ => self.dispatch(job, seq),
         }
     }
 
-    pub fn delete(&mut self, id: u64) -> bool {
-        if let Some(idx) = self.ready.iter().position(|job| job.id == id) {
-            self.ready.remove(idx);
-            true
-        } else if let Some(idx) = self.running.iter().position(|job| job.id == id) {
-            self.running.remove(idx);
-            true
+    /// Records `job` and hands it out. Nothing changes if it is over the
+    /// limits or can't be recorded.
+    pub fn put(&mut self, job: Job) -> Result<(), PutError> {
+        if self.closed {
+            return Err(PutError::ShuttingDown);
+        }
+        let limits = &self.limits;
+        let size = job.job.to_string().len();
+        if size > limits.max_payload {
+            let max = limits.max_payload;
+            return Err(PutError::PayloadTooLarge { size, max });
+        }
+        if self.jobs.len() >= limits.max_jobs {
+            let max = limits.max_jobs;
+            return Err(PutError::TooManyJobs { max });
+        }
+        let queue_len = self.queue_lens.get(&job.queue).copied().unwrap_or(0);
+        if queue_len >= limits.max_queue_jobs {
+            let max = limits.max_queue_jobs;
+            let queue = job.queue.clone();
+            return Err(PutError::QueueFull { queue, max });
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.append(&Record::Put(job.clone()))?;
+        }
+        *self.queue_lens.entry(job.queue.clone()).or_default() += 1;
+        self.metrics.puts.fetch_add(1, Relaxed);
+        let seq = self.next_seq();
+        self.schedule(job, seq);
+        Ok(self.compact_if_needed()?)
+    }
+
+    pub fn delete(&mut self, id: u64) -> io::Result<bool> {
+        if !self.jobs.contains_key(&id) {
+            return Ok(false);
+        }
+        self.remove(id)?;
+        Ok(true)
+    }
+
+    /// Finishes a job `client` is working on, keeping `result` for whoever
+    /// asks for it. Returns false if the job is gone or no longer held by
+    /// `client`.
+    pub fn complete(&mut self, client: u64, id: u64, result: Value) -> Result<bool, PutError> {
+        match self.jobs.get(&id) {
+            Some(JobLocation::Running { holder, .. }) if *holder == client => {}
+            _ => return Ok(false),
+        }
+        let size = result.to_string().len();
+        if size > self.limits.max_payload {
+            let max = self.limits.max_payload;
+            return Err(PutError::PayloadTooLarge { size, max });
+        }
+        self.remove(id)?;
+        let now = Instant::now();
+        self.expire_results(now);
+        self.results.insert(id, result);
+        let until = now + self.limits.result_retention;
+        self.result_expiry.push_back((until, id));
+        Ok(true)
+    }
+
+    /// What became of job `id`, or `None` if it was deleted, its result
+    /// expired, or it never existed.
+    pub fn result(&mut self, id: u64) -> Option<JobResult> {
+        self.expire_results(Instant::now());
+        if self.jobs.contains_key(&id) {
+            return Some(JobResult::Pending);
+        }
+        self.results.get(&id).cloned().map(JobResult::Done)
+    }
+
+    fn expire_results(&mut self, now: Instant) {
+        while let Some(&(until, id)) = self.result_expiry.front() {
+            if until > now {
+                break;
+            }
+            self.result_expiry.pop_front();
+            self.results.remove(&id);
+        }
+    }
+
+    /// Drops a live job for good.
+    fn remove(&mut self, id: u64) -> io::Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.append(&Record::Delete { id })?;
+        }
+        let queue = match self.jobs.remove(&id) {
+            Some(JobLocation::Ready { queue }) => queue,
+            Some(
+                JobLocation::Running { job, .. }
+                | JobLocation::Delayed { job, .. }
+                | JobLocation::Dead { job, .. },
+            ) => job.queue,
+            None => unreachable!(),
+        };
+        self.forget(id, &queue);
+        self.compact_if_needed()
+    }
+
+    /// Drops the bookkeeping of job `id` once it's no longer in `jobs`.
+    fn forget(&mut self, id: u64, queue: &str) {
+        self.aborts.remove(&id);
+        if let Some(len) = self.queue_lens.get_mut(queue) {
+            *len -= 1;
+            if *len == 0 {
+                self.queue_lens.remove(queue);
+            }
+        }
+    }
+
+    /// Drops job `id`, already taken out of `jobs`, because its time ran
+    /// out. Failing to log that is harmless, as the job would just expire
+    /// again once read back.
+    fn drop_expired(&mut self, id: u64, queue: &str) {
+        if let Some(wal) = &mut self.wal {
+            let _ = wal.append(&Record::Delete { id });
+        }
+        self.forget(id, queue);
+        self.metrics.expired.fetch_add(1, Relaxed);
+    }
+
+    fn compact_if_needed(&mut self) -> io::Result<()> {
+        match &self.wal {
+            Some(wal) if wal.needs_compaction(self.jobs.len()) => self.compact(),
+            _ => Ok(()),
+        }
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        let jobs = &self.jobs;
+        let ready = self
+            .queues
+            .values()
+            .flatten()
+            .map(|JobRef { job, .. }| job)
+            .filter(|job| matches!(jobs.get(&job.id), Some(JobLocation::Ready { .. })));
+        let others = jobs.values().filter_map(|location| match location {
+            JobLocation::Running { job, .. }
+            | JobLocation::Delayed { job, .. }
+            | JobLocation::Dead { job, .. } => Some(job),
+            JobLocation::Ready { .. } => None,
+        });
+        wal.compact(self.next_id, ready.chain(others))
+    }
+
+    /// Puts a job `client` is working on back up for grabs. Returns false if
+    /// the job is gone or no longer held by `client`.
+    pub fn abort(&mut self, client: u64, id: u64) -> bool {
+        match self.jobs.get(&id) {
+            Some(JobLocation::Running { holder, .. }) if *holder == client => {}
+            _ => return false,
+        }
+        if let Some(JobLocation::Running { job, seq, .. }) = self.jobs.remove(&id) {
+            self.retry(job, seq);
+        }
+        true
+    }
+
+    /// Hands out an aborted job again, or gives up on it if it has been
+    /// aborted too often.
+    fn retry(&mut self, job: Job, seq: u64) {
+        let aborts = self.aborts.entry(job.id).or_default();
+        *aborts += 1;
+        if self.limits.max_aborts.is_some_and(|max| *aborts > max) {
+            self.jobs.insert(job.id, JobLocation::Dead { job, seq });
         } else {
-            false
+            self.dispatch(job, seq);
+        }
+    }
+
+    /// Gives a dead job a fresh set of tries. Returns false if `id` isn't a
+    /// dead job.
+    pub fn requeue(&mut self, id: u64) -> bool {
+        if !matches!(self.jobs.get(&id), Some(JobLocation::Dead { .. })) {
+            return false;
+        }
+        if let Some(JobLocation::Dead { job, seq }) = self.jobs.remove(&id) {
+            self.aborts.remove(&id);
+            self.dispatch(job, seq);
+        }
+        true
+    }
+
+    /// Stops handing out jobs and puts every running job back up for
+    /// grabs, so nothing is lost to clients that won't get to finish.
+    /// Waiting clients see their `get` fail. The log, if any, is compacted
+    /// to the jobs left, none of them running.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.closed = true;
+        self.waiters.clear();
+        let running: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, location)| matches!(location, JobLocation::Running { .. }))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in running {
+            if let Some(JobLocation::Running { job, seq, .. }) = self.jobs.remove(&id) {
+                self.push_ready(job, seq);
+            }
         }
+        self.leases.clear();
+        self.compact()
     }
 
-    pub fn abort(&mut self, id: u64) -> bool {
-        if let Some(idx) = self.running.iter().position(|job| job.id == id) {
-            if let Some(widx) = self
-                .waiters
+    /// Counts per queue, leaving out queues with nothing in them.
+    pub fn stats(&self) -> BTreeMap<String, QueueStats> {
+        let mut stats: BTreeMap<String, QueueStats> = BTreeMap::new();
+        for (name, heap) in &self.queues {
+            let ready = heap
                 .iter()
-                .position(|(queues, _)| queues.contains(&self.running[idx].queue))
-            {
-                self.waiters
-                    .remove(widx)
-                    .1
-                    .send(self.running[idx].clone())
-                    .unwrap();
-            } else {
-                let job = self.running.remove(idx);
-                self.ready.push(job);
-            }
-            true
-        } else {
-            false
+                .filter(|JobRef { job, .. }| {
+                    matches!(self.jobs.get(&job.id), Some(JobLocation::Ready { .. }))
+                })
+                .count();
+            if ready > 0 {
+                stats.entry(name.clone()).or_default().ready = ready;
+            }
+        }
+        for location in self.jobs.values() {
+            match location {
+                JobLocation::Running { job, .. } => {
+                    stats.entry(job.queue.clone()).or_default().running += 1;
+                }
+                JobLocation::Delayed { job, .. } => {
+                    stats.entry(job.queue.clone()).or_default().delayed += 1;
+                }
+                JobLocation::Dead { job, .. } => {
+                    stats.entry(job.queue.clone()).or_default().dead += 1;
+                }
+                JobLocation::Ready { .. } => {}
+            }
+        }
+        for waiter in self.waiters.iter().filter(|w| !w.sender.is_closed()) {
+            for name in &waiter.queues {
+                stats.entry(name.clone()).or_default().waiters += 1;
+            }
+        }
+        stats
+    }
+
+    /// Every live job in `queue`, by id.
+    pub fn list(&self, queue: &str) -> Vec<JobInfo> {
+        let info = |job: &Job, state, holder| JobInfo {
+            id: job.id,
+            pri: job.pri,
+            state,
+            holder,
+        };
+        let mut jobs: Vec<JobInfo> = self
+            .jobs
+            .values()
+            .filter_map(|location| match location {
+                JobLocation::Running { job, holder, .. } if job.queue == queue => {
+                    Some(info(job, "running", Some(*holder)))
+                }
+                JobLocation::Delayed { job, .. } if job.queue == queue => {
+                    Some(info(job, "delayed", None))
+                }
+                JobLocation::Dead { job, .. } if job.queue == queue => {
+                    Some(info(job, "dead", None))
+                }
+                _ => None,
+            })
+            .collect();
+        if let Some(heap) = self.queues.get(queue) {
+            let ready = heap
+                .iter()
+                .map(|JobRef { job, .. }| job)
+                .filter(|job| matches!(self.jobs.get(&job.id), Some(JobLocation::Ready { .. })));
+            jobs.extend(ready.map(|job| info(job, "ready", None)));
+        }
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    /// Hands out every delayed job whose time has come by `now`, returning
+    /// how many there were.
+    pub fn release_delayed(&mut self, now: SystemTime) -> usize {
+        let mut released = 0;
+        for id in self.delayed.advance(now) {
+            if let Some(JobLocation::Delayed { .. }) = self.jobs.get(&id) {
+                if let Some(JobLocation::Delayed { job, seq }) = self.jobs.remove(&id) {
+                    self.dispatch(job, seq);
+                    released += 1;
+                }
+            }
+        }
+        released
+    }
+
+    /// Drops every job that isn't running and whose `expires_at` came by
+    /// `now`, returning how many there were.
+    pub fn expire_jobs(&mut self, now: SystemTime) -> io::Result<usize> {
+        let mut expired = 0;
+        while let Some(Reverse((at, id))) = self.expiries.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.expiries.pop();
+            let queue = match self.jobs.get(&id) {
+                Some(JobLocation::Running { .. }) | None => continue,
+                Some(JobLocation::Ready { queue }) => queue.clone(),
+                Some(JobLocation::Delayed { job, .. } | JobLocation::Dead { job, .. }) => {
+                    job.queue.clone()
+                }
+            };
+            self.jobs.remove(&id);
+            self.drop_expired(id, &queue);
+            expired += 1;
+        }
+        self.compact_if_needed()?;
+        Ok(expired)
+    }
+
+    /// Aborts every running job whose lease ran out by `now`, returning how
+    /// many there were.
+    pub fn expire_leases(&mut self, now: Instant) -> usize {
+        let mut expired = 0;
+        while let Some(Reverse((at, id))) = self.leases.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.leases.pop();
+            if let Some(JobLocation::Running { expires, .. }) = self.jobs.get(&id) {
+                if *expires == Some(at) {
+                    if let Some(JobLocation::Running { job, seq, .. }) = self.jobs.remove(&id) {
+                        self.retry(job, seq);
+                        expired += 1;
+                    }
+                }
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64, queue: &str, pri: u64) -> Job {
+        Job {
+            id,
+            queue: queue.to_owned(),
+            job: Value::Null,
+            pri,
+            lease: None,
+            run_at: None,
+            expires_at: None,
+        }
+    }
+
+    const CLIENT: u64 = 1;
+
+    fn get_id(server: &mut JobServer, queues: &[&str]) -> Option<u64> {
+        let queues: Vec<String> = queues.iter().map(|q| q.to_string()).collect();
+        server
+            .get(CLIENT, &queues, false)
+            .unwrap()
+            .map(|job| job.id)
+    }
+
+    #[test]
+    fn get_picks_highest_priority_across_queues() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        server.put(job(2, "b", 9)).unwrap();
+        server.put(job(3, "a", 7)).unwrap();
+        server.put(job(4, "c", 100)).unwrap();
+        assert_eq!(Some(2), get_id(&mut server, &["a", "b"]));
+        assert_eq!(Some(3), get_id(&mut server, &["a", "b"]));
+        assert_eq!(Some(1), get_id(&mut server, &["a", "b"]));
+        assert_eq!(None, get_id(&mut server, &["a", "b"]));
+    }
+
+    #[test]
+    fn deleted_jobs_are_skipped() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        server.put(job(2, "a", 9)).unwrap();
+        assert!(server.delete(2).unwrap());
+        assert!(!server.delete(2).unwrap());
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert_eq!(None, get_id(&mut server, &["a"]));
+    }
+
+    #[test]
+    fn aborted_job_is_ready_again() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(server.abort(CLIENT, 1));
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+    }
+
+    #[test]
+    fn running_job_can_be_deleted() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(server.delete(1).unwrap());
+        assert!(!server.abort(CLIENT, 1));
+        assert!(!server.delete(1).unwrap());
+        assert_eq!(None, get_id(&mut server, &["a"]));
+    }
+
+    #[test]
+    fn put_skips_waiters_that_went_away() {
+        let mut server = JobServer::default();
+        let queues = vec!["a".to_string()];
+        let gone = server.get(CLIENT, &queues, true).unwrap_err();
+        let live = server.get(CLIENT, &queues, true).unwrap_err();
+        drop(gone);
+
+        server.put(job(1, "a", 5)).unwrap();
+        assert_eq!(1, live.blocking_recv().unwrap().id);
+        assert!(server.waiters.is_empty());
+
+        let gone = server.get(CLIENT, &queues, true).unwrap_err();
+        drop(gone);
+        server.put(job(2, "a", 5)).unwrap();
+        assert_eq!(Some(2), get_id(&mut server, &["a"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_lease_requeues_job() {
+        let mut server = JobServer::default();
+        server
+            .put(Job {
+                lease: Some(Duration::from_secs(10)),
+                ..job(1, "a", 5)
+            })
+            .unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert_eq!(0, server.expire_leases(Instant::now()));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(1, server.expire_leases(Instant::now()));
+        assert!(!server.abort(CLIENT, 1));
+        let other = vec!["a".to_string()];
+        assert_eq!(1, server.get(2, &other, false).unwrap().unwrap().id);
+        assert!(!server.abort(CLIENT, 1));
+
+        // The new holder got a fresh lease; the old deadline is stale.
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(0, server.expire_leases(Instant::now()));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(1, server.expire_leases(Instant::now()));
+    }
+
+    #[test]
+    fn only_holder_can_abort() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(!server.abort(CLIENT + 1, 1));
+        assert!(server.abort(CLIENT, 1));
+    }
+
+    #[test]
+    fn reopened_server_has_jobs_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.log");
+        let mut server = JobServer::open(&path).unwrap();
+        for pri in [5, 9, 7] {
+            let id = server.next_id();
+            server.put(job(id, "a", pri)).unwrap();
+        }
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(server.delete(2).unwrap());
+        drop(server);
+
+        let mut server = JobServer::open(&path).unwrap();
+        assert_eq!(3, server.next_id());
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert_eq!(Some(0), get_id(&mut server, &["a"]));
+        assert_eq!(None, get_id(&mut server, &["a"]));
+    }
+
+    #[test]
+    fn stats_count_ready_running_and_waiting() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        server.put(job(2, "a", 9)).unwrap();
+        server.put(job(3, "b", 1)).unwrap();
+        server.delete(1).unwrap();
+        assert_eq!(Some(2), get_id(&mut server, &["a"]));
+        let queues = vec!["c".to_string(), "a".to_string()];
+        let _waiting = server.get(CLIENT, &queues, true).unwrap_err();
+        let gone = server.get(CLIENT, &queues, true).unwrap_err();
+        drop(gone);
+
+        let stats = server.stats();
+        let counts = |ready, running, waiters| QueueStats {
+            ready,
+            running,
+            waiters,
+            delayed: 0,
+            dead: 0,
+        };
+        assert_eq!(
+            vec![
+                ("a".to_string(), counts(0, 1, 1)),
+                ("b".to_string(), counts(1, 0, 0)),
+                ("c".to_string(), counts(0, 0, 1)),
+            ],
+            stats.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn equal_priorities_come_out_in_put_order() {
+        let mut server = JobServer::default();
+        server.put(job(3, "a", 5)).unwrap();
+        server.put(job(1, "b", 5)).unwrap();
+        server.put(job(2, "a", 5)).unwrap();
+        assert_eq!(Some(3), get_id(&mut server, &["a", "b"]));
+        assert_eq!(Some(1), get_id(&mut server, &["a", "b"]));
+        assert!(server.abort(CLIENT, 3));
+        assert_eq!(Some(3), get_id(&mut server, &["a", "b"]));
+        assert_eq!(Some(2), get_id(&mut server, &["a", "b"]));
+    }
+
+    #[test]
+    fn puts_over_the_limits_are_refused() {
+        let mut server = JobServer::default();
+        server.set_limits(Limits {
+            max_payload: 8,
+            max_queue_jobs: 2,
+            max_jobs: 3,
+            ..Limits::default()
+        });
+        let big = Job {
+            job: Value::from("123456789"),
+            ..job(1, "a", 1)
+        };
+        let err = server.put(big).unwrap_err();
+        assert!(matches!(
+            err,
+            PutError::PayloadTooLarge { size: 11, max: 8 }
+        ));
+        assert_eq!("payload-too-large", err.code());
+
+        server.put(job(1, "a", 1)).unwrap();
+        server.put(job(2, "a", 1)).unwrap();
+        let err = server.put(job(3, "a", 1)).unwrap_err();
+        assert!(matches!(err, PutError::QueueFull { ref queue, max: 2 } if queue == "a"));
+        server.put(job(3, "b", 1)).unwrap();
+        let err = server.put(job(4, "c", 1)).unwrap_err();
+        assert!(matches!(err, PutError::TooManyJobs { max: 3 }));
+
+        // Running jobs count until they are deleted.
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(server.put(job(4, "a", 1)).is_err());
+        assert!(server.delete(1).unwrap());
+        server.put(job(4, "a", 1)).unwrap();
+    }
+
+    #[test]
+    fn shutdown_returns_running_jobs_and_refuses_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.log");
+        let mut server = JobServer::open(&path).unwrap();
+        server.put(job(1, "a", 5)).unwrap();
+        server.put(job(2, "a", 9)).unwrap();
+        assert_eq!(Some(2), get_id(&mut server, &["a"]));
+        let queues = vec!["b".to_string()];
+        let waiting = server.get(CLIENT, &queues, true).unwrap_err();
+
+        server.shutdown().unwrap();
+        assert!(waiting.blocking_recv().is_err());
+        assert_eq!(2, server.stats()["a"].ready);
+        assert_eq!(None, get_id(&mut server, &["a"]));
+        assert!(server.get(CLIENT, &queues, true).unwrap().is_none());
+        assert!(matches!(
+            server.put(job(3, "a", 1)),
+            Err(PutError::ShuttingDown)
+        ));
+        drop(server);
+
+        let mut server = JobServer::open(&path).unwrap();
+        assert_eq!(Some(2), get_id(&mut server, &["a"]));
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+    }
+
+    #[test]
+    fn delayed_job_is_held_back_until_due() {
+        let mut server = JobServer::default();
+        let now = SystemTime::now();
+        let delayed = |id, secs| Job {
+            run_at: Some(now + Duration::from_secs(secs)),
+            ..job(id, "a", 5)
+        };
+        server.put(delayed(1, 10)).unwrap();
+        server.put(delayed(2, 20)).unwrap();
+        server
+            .put(Job {
+                run_at: Some(now - Duration::from_secs(1)),
+                ..job(3, "a", 1)
+            })
+            .unwrap();
+        assert_eq!(2, server.stats()["a"].delayed);
+        assert!(server.delete(2).unwrap());
+
+        let queues = vec!["a".to_string()];
+        assert_eq!(Some(3), get_id(&mut server, &["a"]));
+        let waiting = server.get(CLIENT, &queues, true).unwrap_err();
+        assert_eq!(0, server.release_delayed(now + Duration::from_secs(9)));
+        assert_eq!(1, server.release_delayed(now + Duration::from_secs(30)));
+        assert_eq!(1, waiting.blocking_recv().unwrap().id);
+        assert_eq!(0, server.stats()["a"].delayed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn completed_job_result_is_kept_for_a_while() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        assert_eq!(Some(JobResult::Pending), server.result(1));
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(!server.complete(CLIENT + 1, 1, Value::from(7)).unwrap());
+        assert!(server.complete(CLIENT, 1, Value::from(7)).unwrap());
+        assert!(!server.complete(CLIENT, 1, Value::from(8)).unwrap());
+        assert!(!server.delete(1).unwrap());
+        assert_eq!(Some(JobResult::Done(Value::from(7))), server.result(1));
+
+        tokio::time::advance(Limits::default().result_retention).await;
+        assert_eq!(None, server.result(1));
+        assert_eq!(None, server.result(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn metrics_time_queued_and_waiting_jobs() {
+        let mut server = JobServer::default();
+        let metrics = server.metrics();
+        server.put(job(1, "a", 5)).unwrap();
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+
+        let queues = vec!["b".to_string()];
+        let _waiting = server.get(CLIENT, &queues, true).unwrap_err();
+        tokio::time::advance(Duration::from_millis(20)).await;
+        server.put(job(2, "b", 5)).unwrap();
+
+        assert_eq!(2, metrics.puts.load(Relaxed));
+        assert_eq!(2, metrics.gets.load(Relaxed));
+        assert_eq!(Duration::from_millis(30), metrics.time_in_queue.max());
+        assert_eq!(1, metrics.waits.count());
+        assert_eq!(Duration::from_millis(20), metrics.waits.max());
+    }
+
+    #[test]
+    fn list_shows_states_and_holders() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 5)).unwrap();
+        server.put(job(2, "a", 9)).unwrap();
+        server.put(job(3, "b", 1)).unwrap();
+        server
+            .put(Job {
+                run_at: Some(SystemTime::now() + Duration::from_secs(60)),
+                ..job(4, "a", 1)
+            })
+            .unwrap();
+        server.put(job(5, "a", 1)).unwrap();
+        server.delete(5).unwrap();
+        assert_eq!(Some(2), get_id(&mut server, &["a"]));
+
+        let info = |id, pri, state, holder| JobInfo {
+            id,
+            pri,
+            state,
+            holder,
+        };
+        assert_eq!(
+            vec![
+                info(1, 5, "ready", None),
+                info(2, 9, "running", Some(CLIENT)),
+                info(4, 1, "delayed", None),
+            ],
+            server.list("a")
+        );
+        assert!(server.list("nope").is_empty());
+    }
+
+    #[test]
+    fn oldest_eligible_waiter_gets_the_job() {
+        let mut server = JobServer::default();
+        let wait = |server: &mut JobServer, client, queues: &[&str]| {
+            let queues: Vec<String> = queues.iter().map(|q| q.to_string()).collect();
+            server.get(client, &queues, true).unwrap_err()
+        };
+        let mut ab = wait(&mut server, 1, &["a", "b"]);
+        let mut b = wait(&mut server, 2, &["b"]);
+        let mut a = wait(&mut server, 3, &["a"]);
+
+        server.put(job(1, "b", 1)).unwrap();
+        assert_eq!(1, ab.try_recv().unwrap().id);
+        server.put(job(2, "a", 1)).unwrap();
+        assert_eq!(2, a.try_recv().unwrap().id);
+        server.put(job(3, "b", 1)).unwrap();
+        assert_eq!(3, b.try_recv().unwrap().id);
+        assert!(server.waiters.is_empty());
+    }
+
+    #[test]
+    fn requeued_job_goes_to_the_oldest_waiter() {
+        let mut server = JobServer::default();
+        server.put(job(1, "a", 1)).unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        let queues = vec!["a".to_string()];
+        let mut first = server.get(2, &queues, true).unwrap_err();
+        let mut second = server.get(3, &queues, true).unwrap_err();
+
+        assert!(server.abort(CLIENT, 1));
+        assert_eq!(1, first.try_recv().unwrap().id);
+        assert!(second.try_recv().is_err());
+        assert!(server.abort(2, 1));
+        assert_eq!(1, second.try_recv().unwrap().id);
+    }
+
+    #[test]
+    fn jobs_aborted_too_often_are_dead_until_requeued() {
+        let mut server = JobServer::default();
+        server.set_limits(Limits {
+            max_aborts: Some(1),
+            ..Limits::default()
+        });
+        server.put(job(1, "a", 5)).unwrap();
+        server.put(job(2, "a", 1)).unwrap();
+        for _ in 0..2 {
+            assert_eq!(Some(1), get_id(&mut server, &["a"]));
+            assert!(server.abort(CLIENT, 1));
+        }
+        assert_eq!(Some(2), get_id(&mut server, &["a"]));
+        assert_eq!(None, get_id(&mut server, &["a"]));
+        assert_eq!(1, server.stats()["a"].dead);
+        assert_eq!("dead", server.list("a")[0].state);
+        assert!(!server.abort(CLIENT, 1));
+        assert_eq!(Some(JobResult::Pending), server.result(1));
+
+        assert!(server.requeue(1));
+        assert!(!server.requeue(1));
+        assert!(!server.requeue(2));
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        assert!(server.abort(CLIENT, 1));
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_leases_count_as_aborts() {
+        let mut server = JobServer::default();
+        server.set_limits(Limits {
+            max_aborts: Some(0),
+            ..Limits::default()
+        });
+        server
+            .put(Job {
+                lease: Some(Duration::from_secs(1)),
+                ..job(1, "a", 5)
+            })
+            .unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(1, server.expire_leases(Instant::now()));
+        assert_eq!(None, get_id(&mut server, &["a"]));
+        assert!(server.delete(1).unwrap());
+        assert!(server.stats().is_empty());
+    }
+
+    #[test]
+    fn jobs_past_their_ttl_are_dropped_unless_running() {
+        let mut server = JobServer::default();
+        let now = SystemTime::now();
+        let expiring = |id, run_at| Job {
+            run_at,
+            expires_at: Some(now + Duration::from_secs(10)),
+            ..job(id, "a", 1)
+        };
+        server.put(expiring(1, None)).unwrap();
+        server.put(expiring(2, None)).unwrap();
+        server
+            .put(expiring(3, Some(now + Duration::from_secs(60))))
+            .unwrap();
+        server.put(job(4, "a", 0)).unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+
+        assert_eq!(0, server.expire_jobs(now).unwrap());
+        let later = now + Duration::from_secs(20);
+        assert_eq!(2, server.expire_jobs(later).unwrap());
+        assert_eq!(0, server.expire_jobs(later).unwrap());
+        assert_eq!(2, server.metrics().expired.load(Relaxed));
+        let ids: Vec<u64> = server.list("a").iter().map(|job| job.id).collect();
+        assert_eq!(vec![1, 4], ids);
+    }
+
+    #[test]
+    fn job_given_back_after_its_ttl_is_dropped() {
+        let mut server = JobServer::default();
+        server
+            .put(Job {
+                expires_at: Some(SystemTime::now() + Duration::from_millis(100)),
+                ..job(1, "a", 1)
+            })
+            .unwrap();
+        assert_eq!(Some(1), get_id(&mut server, &["a"]));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(server.abort(CLIENT, 1));
+        assert_eq!(None, get_id(&mut server, &["a"]));
+        assert!(server.stats().is_empty());
+        assert_eq!(1, server.metrics().expired.load(Relaxed));
+    }
+
+    #[test]
+    fn reopened_server_still_expires_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.log");
+        let mut server = JobServer::open(&path).unwrap();
+        let at = SystemTime::now() + Duration::from_secs(3600);
+        let id = server.next_id();
+        server
+            .put(Job {
+                expires_at: Some(at),
+                ..job(id, "a", 1)
+            })
+            .unwrap();
+        drop(server);
+
+        let mut server = JobServer::open(&path).unwrap();
+        assert_eq!(1, server.expire_jobs(at).unwrap());
+        drop(server);
+        assert!(JobServer::open(&path).unwrap().stats().is_empty());
+    }
+
+    mod properties {
+        use super::{get_id, job, CLIENT};
+        use crate::JobServer;
+        use proptest::prelude::*;
+        use std::collections::VecDeque;
+        use tokio::sync::oneshot::Receiver;
+
+        const QUEUES: [&str; 3] = ["a", "b", "c"];
+
+        #[derive(Debug, Clone)]
+        enum Op {
+            /// Puts a job on a queue with a priority.
+            Put((usize, u64)),
+            /// Waits on the queues in a bit mask over `QUEUES`.
+            Wait(usize),
+        }
+
+        /// Queue and priority of each job, in put order.
+        fn puts() -> impl Strategy<Value = Vec<(usize, u64)>> {
+            prop::collection::vec((0..QUEUES.len(), 0u64..4), 0..50)
+        }
+
+        /// The order the jobs are promised to come out in: highest priority
+        /// first, ties in put order.
+        fn expected(puts: &[(usize, u64)], queues: &[&str]) -> Vec<u64> {
+            let mut ids: Vec<u64> = (0..puts.len() as u64)
+                .filter(|&id| queues.contains(&QUEUES[puts[id as usize].0]))
+                .collect();
+            ids.sort_by_key(|&id| std::cmp::Reverse(puts[id as usize].1));
+            ids
+        }
+
+        fn server(puts: &[(usize, u64)]) -> JobServer {
+            let mut server = JobServer::default();
+            for (id, &(queue, pri)) in puts.iter().enumerate() {
+                server.put(job(id as u64, QUEUES[queue], pri)).unwrap();
+            }
+            server
+        }
+
+        fn drain(server: &mut JobServer, queues: &[&str]) -> Vec<u64> {
+            std::iter::from_fn(|| get_id(server, queues)).collect()
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn gets_follow_priority_then_put_order(puts in puts(), mask in 1usize..8) {
+                let queues: Vec<&str> = QUEUES
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, q)| *q)
+                    .collect();
+                let mut server = server(&puts);
+                prop_assert_eq!(expected(&puts, &queues), drain(&mut server, &queues));
+            }
+
+            #[test]
+            fn waiters_are_served_in_arrival_order(
+                ops in prop::collection::vec(prop_oneof![
+                    (0..QUEUES.len(), 0u64..4).prop_map(Op::Put),
+                    (1usize..8).prop_map(Op::Wait),
+                ], 0..60),
+            ) {
+                let mut server = JobServer::default();
+                // The model: ready jobs as (id, queue, pri), and waiters with
+                // the queues they want, oldest first.
+                let mut ready: Vec<(u64, usize, u64)> = vec![];
+                let mut waiting: VecDeque<(Vec<usize>, Receiver<crate::Job>)> = VecDeque::new();
+                for (id, op) in ops.into_iter().enumerate() {
+                    let id = id as u64;
+                    match op {
+                        Op::Put((queue, pri)) => {
+                            server.put(job(id, QUEUES[queue], pri)).unwrap();
+                            match waiting.iter().position(|(qs, _)| qs.contains(&queue)) {
+                                Some(i) => {
+                                    let (_, mut r) = waiting.remove(i).unwrap();
+                                    prop_assert_eq!(id, r.try_recv().unwrap().id);
+                                }
+                                None => ready.push((id, queue, pri)),
+                            }
+                        }
+                        Op::Wait(mask) => {
+                            let qs: Vec<usize> =
+                                (0..QUEUES.len()).filter(|i| mask & (1 << i) != 0).collect();
+                            let names: Vec<String> =
+                                qs.iter().map(|&q| QUEUES[q].to_owned()).collect();
+                            // Highest priority, then oldest.
+                            let best = ready
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, (_, q, _))| qs.contains(q))
+                                .max_by_key(|(_, &(id, _, pri))| (pri, std::cmp::Reverse(id)))
+                                .map(|(i, _)| i);
+                            match server.get(CLIENT, &names, true) {
+                                Ok(got) => {
+                                    let (id, _, _) = ready.remove(best.unwrap());
+                                    prop_assert_eq!(Some(id), got.map(|job| job.id));
+                                }
+                                Err(r) => {
+                                    prop_assert_eq!(None, best);
+                                    waiting.push_back((qs, r));
+                                }
+                            }
+                        }
+                    }
+                    let wanted: Vec<String> =
+                        server.waiters.iter().flat_map(|w| w.queues.clone()).collect();
+                    for queue in &wanted {
+                        prop_assert_eq!(None, server.peek(queue));
+                    }
+                }
+            }
+
+            #[test]
+            fn aborted_jobs_keep_their_place(puts in puts(), aborts in 0usize..10) {
+                let mut server = server(&puts);
+                for _ in 0..aborts {
+                    if let Some(id) = get_id(&mut server, &QUEUES) {
+                        prop_assert!(server.abort(CLIENT, id));
+                    }
+                }
+                prop_assert_eq!(expected(&puts, &QUEUES), drain(&mut server, &QUEUES));
+            }
         }
     }
 }
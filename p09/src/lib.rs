@@ -0,0 +1,17 @@
+mod jobserver;
+pub use jobserver::*;
+
+mod actor;
+pub use actor::*;
+
+mod client_handler;
+pub use client_handler::*;
+
+mod serve;
+pub use serve::{run, Args};
+
+pub mod metrics;
+pub mod protocol;
+
+mod timer_wheel;
+mod wal;
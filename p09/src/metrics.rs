@@ -0,0 +1,31 @@
+use proto_common::Latency;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Counters of a job server, updated as it goes.
+#[derive(Default)]
+pub struct Metrics {
+    pub puts: AtomicU64,
+    /// Jobs handed out, whether to a plain `get` or to a waiting client.
+    pub gets: AtomicU64,
+    /// How long jobs sat ready before being handed out.
+    pub time_in_queue: Latency,
+    /// How long waiting clients waited before a job came in for them.
+    pub waits: Latency,
+    /// Jobs dropped because their time ran out before they were done.
+    pub expired: AtomicU64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "puts: {}, gets: {}, expired: {}, time in queue: {}, waits: {}",
+            self.puts.load(Relaxed),
+            self.gets.load(Relaxed),
+            self.expired.load(Relaxed),
+            self.time_in_queue,
+            self.waits,
+        )
+    }
+}
@@ -0,0 +1,214 @@
+//! Requests and replies of the job centre protocol, shared by the server
+//! and clients.
+
+use crate::Job;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A request, one JSON object per line, tagged by its `request` field.
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "request")]
+pub enum Request {
+    put(NewJob),
+    get {
+        queues: Vec<String>,
+        #[serde(default)]
+        wait: bool,
+    },
+    delete {
+        id: u64,
+    },
+    abort {
+        id: u64,
+    },
+    /// Extension: per-queue counts of ready, running and waiting.
+    stats,
+    /// Extension: every job in `queue` with its state and, if running, the
+    /// id of the client holding it.
+    list {
+        queue: String,
+    },
+    /// Extension: hands out a job given up on after too many aborts again.
+    requeue {
+        id: u64,
+    },
+    /// Extension: finishes a job this client is working on, like `delete`,
+    /// keeping `result` for a while.
+    complete {
+        id: u64,
+        result: Value,
+    },
+    /// Extension: the result of a completed job.
+    result {
+        id: u64,
+    },
+    /// Extension: several puts in one request, answered with the reply to
+    /// each put in order.
+    #[serde(rename = "put-batch")]
+    put_batch {
+        jobs: Vec<NewJob>,
+    },
+    /// Extension: several deletes in one request, answered like `put-batch`.
+    #[serde(rename = "delete-batch")]
+    delete_batch {
+        ids: Vec<u64>,
+    },
+}
+
+/// The fields of a `put`, also used for each job of a `put-batch`.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NewJob {
+    pub queue: String,
+    pub job: Value,
+    pub pri: u64,
+    /// Seconds a worker may hold the job before it is aborted.
+    #[serde(default)]
+    pub lease: Option<u64>,
+    /// Seconds from now before the job is handed out.
+    #[serde(default)]
+    pub delay: Option<u64>,
+    /// Unix time, in seconds, before which the job isn't handed out.
+    #[serde(default)]
+    pub run_at: Option<u64>,
+    /// Seconds from now after which the job is dropped if nobody is
+    /// working on it.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct GetOk {
+    pub status: String,
+    pub id: u64,
+    pub job: Value,
+    pub pri: u64,
+    pub queue: String,
+}
+
+impl GetOk {
+    pub fn from(job: &Job) -> Self {
+        Self {
+            status: "ok".to_owned(),
+            id: job.id,
+            job: job.job.clone(),
+            pri: job.pri,
+            queue: job.queue.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_deserialization() {
+        let input = r#"{"request":"put","queue":"queue1","job":7,"pri":123}"#;
+        assert_eq!(
+            Request::put(NewJob {
+                job: 7.into(),
+                queue: "queue1".to_owned(),
+                pri: 123,
+                ..Default::default()
+            }),
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"put","queue":"queue1","job":7,"pri":1,"lease":30,"delay":5}"#;
+        assert_eq!(
+            Request::put(NewJob {
+                job: 7.into(),
+                queue: "queue1".to_owned(),
+                pri: 1,
+                lease: Some(30),
+                delay: Some(5),
+                run_at: None,
+                ttl: None,
+            }),
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"get","queues":["queue1","queue2"],"wait":true}"#;
+        assert_eq!(
+            Request::get {
+                queues: vec!["queue1".to_owned(), "queue2".to_owned()],
+                wait: true,
+            },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"get","queues":["queue1","queue2"]}"#;
+        assert_eq!(
+            Request::get {
+                queues: vec!["queue1".to_owned(), "queue2".to_owned()],
+                wait: false,
+            },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"delete","id":12345}"#;
+        assert_eq!(
+            Request::delete { id: 12345 },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"abort","id":12345}"#;
+        assert_eq!(
+            Request::abort { id: 12345 },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"put-batch","jobs":[{"queue":"q","job":1,"pri":2}]}"#;
+        assert_eq!(
+            Request::put_batch {
+                jobs: vec![NewJob {
+                    queue: "q".to_owned(),
+                    job: 1.into(),
+                    pri: 2,
+                    ..Default::default()
+                }],
+            },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"delete-batch","ids":[1,2]}"#;
+        assert_eq!(
+            Request::delete_batch { ids: vec![1, 2] },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"complete","id":1,"result":{"ok":true}}"#;
+        assert_eq!(
+            Request::complete {
+                id: 1,
+                result: json!({"ok": true}),
+            },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"result","id":1}"#;
+        assert_eq!(
+            Request::result { id: 1 },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"requeue","id":7}"#;
+        assert_eq!(
+            Request::requeue { id: 7 },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"list","queue":"q"}"#;
+        assert_eq!(
+            Request::list {
+                queue: "q".to_owned()
+            },
+            serde_json::from_str(input).unwrap()
+        );
+
+        let input = r#"{"request":"stats"}"#;
+        assert_eq!(Request::stats, serde_json::from_str(input).unwrap());
+    }
+}
@@ -0,0 +1,473 @@
+//! Job centre (p09): serves the job server to clients over TCP.
+
+use crate::metrics::Metrics;
+use crate::{ClientHandler, JobServer, JobServerHandle, Limits};
+use anyhow::{Context, Result};
+use clap::Parser;
+use proto_common::{ConnLimit, Limiter, Listen, Reporting, SHUTDOWN_GRACE};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration, Instant};
+use tracing::{error, info, info_span, Instrument};
+
+/// Job centre. Jobs are kept in memory unless a log is given, in which case
+/// they survive restarts.
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+    /// File the jobs are logged to and recovered from on startup. Without
+    /// it jobs are lost when the server stops.
+    #[arg(long)]
+    log: Option<PathBuf>,
+    /// Largest job payload accepted, in bytes of JSON.
+    #[arg(long)]
+    max_payload_bytes: Option<usize>,
+    /// Most live jobs in a single queue.
+    #[arg(long)]
+    max_queue_jobs: Option<usize>,
+    /// Most live jobs across all queues.
+    #[arg(long)]
+    max_jobs: Option<usize>,
+    /// How long results of completed jobs are kept, in seconds.
+    #[arg(long)]
+    result_retention_secs: Option<u64>,
+    /// Times a job may be aborted before it's set aside until requeued.
+    #[arg(long)]
+    max_aborts: Option<u32>,
+}
+
+impl Args {
+    fn limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        if let Some(n) = self.max_payload_bytes {
+            limits.max_payload = n;
+        }
+        if let Some(n) = self.max_queue_jobs {
+            limits.max_queue_jobs = n;
+        }
+        if let Some(n) = self.max_jobs {
+            limits.max_jobs = n;
+        }
+        if let Some(secs) = self.result_retention_secs {
+            limits.result_retention = Duration::from_secs(secs);
+        }
+        limits.max_aborts = self.max_aborts;
+        limits
+    }
+}
+
+/// How often expired leases and delayed jobs that came due are looked for.
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+async fn handle(
+    stream: TcpStream,
+    server: JobServerHandle,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let (read, write) = stream.into_split();
+    let read = BufReader::new(read);
+
+    let mut client_handler = ClientHandler::new(server, read, write, shutdown);
+
+    client_handler.run().await?;
+
+    Ok(())
+}
+
+/// Puts jobs whose workers held them past their lease back up for grabs,
+/// releases delayed jobs once their time comes, and drops jobs whose TTL
+/// ran out.
+async fn run_timers(server: JobServerHandle) {
+    let mut interval = tokio::time::interval(TIMER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let fired = server.call(|server| {
+            server.expire_leases(Instant::now());
+            server.release_delayed(SystemTime::now());
+            if let Err(e) = server.expire_jobs(SystemTime::now()) {
+                error!("failed to compact the log after expiring jobs: {e}");
+            }
+        });
+        if fired.await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reports the server's metrics, with put and get rates since the last
+/// report.
+fn report_metrics(metrics: Arc<Metrics>) -> Reporting {
+    let (mut puts, mut gets) = (0, 0);
+    proto_common::report(move |elapsed: Duration| {
+        let secs = elapsed.as_secs_f64();
+        let (now_puts, now_gets) = (metrics.puts.load(Relaxed), metrics.gets.load(Relaxed));
+        info!(
+            "metrics: {metrics}, puts/s: {:.1}, gets/s: {:.1}",
+            (now_puts - puts) as f64 / secs,
+            (now_gets - gets) as f64 / secs,
+        );
+        (puts, gets) = (now_puts, now_gets);
+    })
+}
+
+/// Serves clients taken through `limiter` until `stop` resolves. The server
+/// is then shut down before clients are told to stop, so waiting clients
+/// get their `no-job` and jobs held by any client are back in the queues
+/// and the log.
+async fn serve(
+    list: TcpListener,
+    limiter: Limiter,
+    server: JobServerHandle,
+    stop: impl Future<Output = ()>,
+) -> Result<()> {
+    tokio::spawn(run_timers(server.clone()).in_current_span());
+    let _reporting = limiter.report();
+    let (shutdown, stopped) = watch::channel(false);
+    let mut clients = JoinSet::new();
+    let mut ids = 0u64..;
+    tokio::pin!(stop);
+    loop {
+        select! {
+            accepted = limiter.accept(&list) => {
+                let (stream, addr, permit) = accepted?;
+                let span = info_span!("conn", id = ids.next(), peer = %addr);
+                let client = handle(stream, server.clone(), stopped.clone());
+                clients.spawn(async move {
+                    if let Err(e) = client.await {
+                        info!("closing: {e}");
+                    }
+                    drop(permit);
+                }.instrument(span));
+            }
+            Some(_) = clients.join_next() => {}
+            () = &mut stop => break,
+        }
+    }
+    drop(list);
+    server.call(|server| server.shutdown()).await??;
+    let _ = shutdown.send(true);
+    let _ = timeout(SHUTDOWN_GRACE, async {
+        while clients.join_next().await.is_some() {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let mut server = match &args.log {
+        Some(log) => JobServer::open(log)
+            .with_context(|| format!("recovering jobs from {}", log.display()))?,
+        None => JobServer::default(),
+    };
+    server.set_limits(args.limits());
+    let limiter = Limiter::new(&args.limit);
+    let _reporting = report_metrics(server.metrics());
+    let server = JobServerHandle::spawn(server);
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    serve(list, limiter, server, stop).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::tcp::OwnedReadHalf;
+    use tokio::time::sleep;
+
+    async fn start() -> SocketAddr {
+        start_with(JobServer::default()).await
+    }
+
+    async fn start_with(server: JobServer) -> SocketAddr {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let server = JobServerHandle::spawn(server);
+        tokio::spawn(serve(
+            list,
+            Limiter::default(),
+            server,
+            std::future::pending(),
+        ));
+        addr
+    }
+
+    struct Client {
+        read: BufReader<OwnedReadHalf>,
+        write: tokio::net::tcp::OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Self {
+            let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+            Self {
+                read: BufReader::new(read),
+                write,
+            }
+        }
+
+        async fn send(&mut self, request: Value) {
+            let line = format!("{request}\n");
+            self.write.write_all(line.as_bytes()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Value {
+            let mut line = String::new();
+            self.read.read_line(&mut line).await.unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        async fn request(&mut self, request: Value) -> Value {
+            self.send(request).await;
+            self.recv().await
+        }
+    }
+
+    #[tokio::test]
+    async fn job_survives_waiter_disconnecting() {
+        let addr = start().await;
+        let mut gone = Client::connect(addr).await;
+        gone.send(json!({"request": "get", "queues": ["q"], "wait": true}))
+            .await;
+        sleep(Duration::from_millis(50)).await;
+        drop(gone);
+
+        let mut producer = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": {"x": 1}, "pri": 3});
+        let id = producer.request(put).await["id"].clone();
+
+        let mut worker = Client::connect(addr).await;
+        let got = worker
+            .request(json!({"request": "get", "queues": ["q"], "wait": true}))
+            .await;
+        assert_eq!(id, got["id"]);
+        assert_eq!(json!({"x": 1}), got["job"]);
+    }
+
+    #[tokio::test]
+    async fn expired_lease_hands_job_to_another_worker() {
+        let addr = start().await;
+        let mut producer = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": 1, "pri": 3, "lease": 1});
+        let id = producer.request(put).await["id"].clone();
+
+        let mut stuck = Client::connect(addr).await;
+        let get = json!({"request": "get", "queues": ["q"]});
+        assert_eq!(id, stuck.request(get.clone()).await["id"]);
+
+        let mut worker = Client::connect(addr).await;
+        assert_eq!("no-job", worker.request(get).await["status"]);
+        let got = worker
+            .request(json!({"request": "get", "queues": ["q"], "wait": true}))
+            .await;
+        assert_eq!(id, got["id"]);
+
+        let abort = json!({"request": "abort", "id": id});
+        assert_eq!("no-job", stuck.request(abort.clone()).await["status"]);
+        assert_eq!("ok", worker.request(abort).await["status"]);
+    }
+
+    #[tokio::test]
+    async fn stats_reports_queue_counts() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        for pri in [1, 2] {
+            let put = json!({"request": "put", "queue": "q", "job": null, "pri": pri});
+            client.request(put).await;
+        }
+        client
+            .request(json!({"request": "get", "queues": ["q"]}))
+            .await;
+        let stats = client.request(json!({"request": "stats"})).await;
+        assert_eq!(
+            json!({"status": "ok", "queues": {"q": {"ready": 1, "running": 1, "waiters": 0, "delayed": 0, "dead": 0}}}),
+            stats
+        );
+    }
+
+    #[tokio::test]
+    async fn full_queue_refuses_puts() {
+        let mut server = JobServer::default();
+        server.set_limits(Limits {
+            max_queue_jobs: 1,
+            ..Limits::default()
+        });
+        let addr = start_with(server).await;
+        let mut client = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": null, "pri": 1});
+        assert_eq!("ok", client.request(put.clone()).await["status"]);
+        let refused = client.request(put).await;
+        assert_eq!("error", refused["status"]);
+        assert_eq!("queue-full", refused["code"]);
+        assert_eq!(1, refused["limit"]);
+    }
+
+    #[tokio::test]
+    async fn batches_get_one_reply_per_item() {
+        let mut server = JobServer::default();
+        server.set_limits(Limits {
+            max_queue_jobs: 2,
+            ..Limits::default()
+        });
+        let addr = start_with(server).await;
+        let mut client = Client::connect(addr).await;
+        let job = json!({"queue": "q", "job": null, "pri": 1});
+        let put = json!({"request": "put-batch", "jobs": [job, job, job]});
+        let reply = client.request(put).await;
+        assert_eq!("ok", reply["status"]);
+        let results = reply["results"].as_array().unwrap();
+        assert_eq!(3, results.len());
+        assert_eq!("ok", results[0]["status"]);
+        assert_eq!("ok", results[1]["status"]);
+        assert_eq!("queue-full", results[2]["code"]);
+
+        let ids = [
+            results[0]["id"].clone(),
+            json!(12345),
+            results[1]["id"].clone(),
+        ];
+        let delete = json!({"request": "delete-batch", "ids": ids});
+        assert_eq!(
+            json!({"status": "ok", "results": [{"status": "ok"}, {"status": "no-job"}, {"status": "ok"}]}),
+            client.request(delete).await
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_releases_jobs_and_waiters() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let server = JobServerHandle::spawn(JobServer::default());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(serve(list, Limiter::default(), server.clone(), async {
+            let _ = stopped.await;
+        }));
+
+        let mut worker = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": null, "pri": 1});
+        worker.request(put).await;
+        let get = json!({"request": "get", "queues": ["q"]});
+        assert_eq!("ok", worker.request(get).await["status"]);
+        let mut waiter = Client::connect(addr).await;
+        waiter
+            .send(json!({"request": "get", "queues": ["other"], "wait": true}))
+            .await;
+        sleep(Duration::from_millis(50)).await;
+
+        stop.send(()).unwrap();
+        assert_eq!(json!({"status": "no-job"}), waiter.recv().await);
+        serving.await.unwrap().unwrap();
+        let stats = server.call(|server| server.stats()).await.unwrap();
+        assert_eq!(1, stats["q"].ready);
+    }
+
+    #[tokio::test]
+    async fn delayed_job_waits_for_its_time() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": null, "pri": 1, "delay": 1});
+        let id = client.request(put).await["id"].clone();
+        let get = json!({"request": "get", "queues": ["q"]});
+        assert_eq!("no-job", client.request(get).await["status"]);
+        let got = client
+            .request(json!({"request": "get", "queues": ["q"], "wait": true}))
+            .await;
+        assert_eq!(id, got["id"]);
+    }
+
+    #[tokio::test]
+    async fn completed_job_result_can_be_fetched() {
+        let addr = start().await;
+        let mut producer = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": 20, "pri": 1});
+        let id = producer.request(put).await["id"].clone();
+        let result = json!({"request": "result", "id": id});
+        assert_eq!("pending", producer.request(result.clone()).await["status"]);
+
+        let mut worker = Client::connect(addr).await;
+        let got = worker
+            .request(json!({"request": "get", "queues": ["q"]}))
+            .await;
+        let complete = json!({"request": "complete", "id": got["id"], "result": 400});
+        assert_eq!("ok", worker.request(complete).await["status"]);
+
+        assert_eq!(
+            json!({"status": "ok", "id": id, "result": 400}),
+            producer.request(result).await
+        );
+    }
+
+    #[tokio::test]
+    async fn list_shows_who_holds_a_job() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        for pri in [1, 2] {
+            let put = json!({"request": "put", "queue": "q", "job": null, "pri": pri});
+            client.request(put).await;
+        }
+        let got = client
+            .request(json!({"request": "get", "queues": ["q"]}))
+            .await;
+        let list = client
+            .request(json!({"request": "list", "queue": "q"}))
+            .await;
+        let jobs = list["jobs"].as_array().unwrap();
+        assert_eq!(2, jobs.len());
+        let running: Vec<_> = jobs.iter().filter(|j| j["state"] == "running").collect();
+        assert_eq!(1, running.len());
+        assert_eq!(got["id"], running[0]["id"]);
+        assert!(running[0]["holder"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn poison_job_is_set_aside_until_requeued() {
+        let mut server = JobServer::default();
+        server.set_limits(Limits {
+            max_aborts: Some(1),
+            ..Limits::default()
+        });
+        let addr = start_with(server).await;
+        let mut client = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": null, "pri": 1});
+        let id = client.request(put).await["id"].clone();
+        let get = json!({"request": "get", "queues": ["q"]});
+        for _ in 0..2 {
+            assert_eq!(id, client.request(get.clone()).await["id"]);
+            let abort = json!({"request": "abort", "id": id});
+            assert_eq!("ok", client.request(abort).await["status"]);
+        }
+        assert_eq!("no-job", client.request(get.clone()).await["status"]);
+        let stats = client.request(json!({"request": "stats"})).await;
+        assert_eq!(1, stats["queues"]["q"]["dead"]);
+
+        let requeue = json!({"request": "requeue", "id": id});
+        assert_eq!("ok", client.request(requeue.clone()).await["status"]);
+        assert_eq!("no-job", client.request(requeue).await["status"]);
+        assert_eq!(id, client.request(get).await["id"]);
+    }
+
+    #[tokio::test]
+    async fn job_with_no_time_to_live_is_dropped() {
+        let addr = start().await;
+        let mut client = Client::connect(addr).await;
+        let put = json!({"request": "put", "queue": "q", "job": null, "pri": 1, "ttl": 0});
+        let id = client.request(put).await["id"].clone();
+        let get = json!({"request": "get", "queues": ["q"]});
+        assert_eq!("no-job", client.request(get).await["status"]);
+        let result = json!({"request": "result", "id": id});
+        assert_eq!("no-job", client.request(result).await["status"]);
+    }
+}
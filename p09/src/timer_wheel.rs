@@ -0,0 +1,95 @@
+//! Hashed timer wheel holding items until a point in wall-clock time.
+//!
+//! Time is cut into ticks of `TICK`; an item due in tick `t` sits in slot
+//! `t % SLOTS` alongside items due whole revolutions later. Inserting is
+//! constant time, and advancing only looks at the slots of the ticks passed.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TICK: Duration = Duration::from_millis(100);
+const SLOTS: usize = 1024;
+
+pub(crate) struct TimerWheel<T> {
+    slots: Vec<Vec<(u64, T)>>,
+    /// Last tick whose items have been handed out.
+    current: u64,
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        Self {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            current: 0,
+        }
+    }
+}
+
+fn tick(at: SystemTime) -> u64 {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_millis() / TICK.as_millis()) as u64
+}
+
+impl<T> TimerWheel<T> {
+    /// Holds `item` until `at`. Items due in a tick that was already
+    /// handed out come out on the next advance.
+    pub(crate) fn insert(&mut self, at: SystemTime, item: T) {
+        let tick = tick(at).max(self.current + 1);
+        self.slots[tick as usize % SLOTS].push((tick, item));
+    }
+
+    /// Takes out every item due by `now`.
+    pub(crate) fn advance(&mut self, now: SystemTime) -> Vec<T> {
+        let now = tick(now);
+        let mut due = vec![];
+        if now <= self.current {
+            return due;
+        }
+        // A long gap visits every slot once rather than going round again.
+        let passed = (now - self.current).min(SLOTS as u64);
+        for t in now + 1 - passed..=now {
+            let slot = &mut self.slots[t as usize % SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= now {
+                    due.push(slot.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = now;
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_come_out_once_due() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut wheel = TimerWheel::default();
+        wheel.advance(start);
+        wheel.insert(start + Duration::from_secs(1), 1);
+        wheel.insert(start + TICK * SLOTS as u32 + Duration::from_secs(1), 2);
+        wheel.insert(start + Duration::from_millis(250), 3);
+
+        assert!(wheel.advance(start + Duration::from_millis(199)).is_empty());
+        assert_eq!(vec![3], wheel.advance(start + Duration::from_millis(300)));
+        assert_eq!(vec![1], wheel.advance(start + Duration::from_secs(2)));
+        // Same slot as 1, a revolution later.
+        assert!(wheel.advance(start + Duration::from_secs(3)).is_empty());
+        assert_eq!(vec![2], wheel.advance(start + Duration::from_secs(3600)));
+        assert!(wheel.slots.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn past_items_come_out_on_next_advance() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut wheel = TimerWheel::default();
+        wheel.advance(start);
+        wheel.insert(start - Duration::from_secs(5), 1);
+        assert_eq!(vec![1], wheel.advance(start + TICK));
+    }
+}
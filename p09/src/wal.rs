@@ -0,0 +1,199 @@
+//! Append-only log of the puts and deletes the job centre has accepted, so
+//! queued work survives a restart.
+//!
+//! Only the set of live jobs is recorded. Which client holds a job is not:
+//! holders don't outlive the server, so every recovered job starts out ready.
+//! The log is compacted by rewriting it with a put per live job once most of
+//! its records are dead, headed by the next free id so ids of deleted jobs
+//! are never handed out again.
+
+use crate::Job;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Records below which the log is never compacted.
+const MIN_COMPACT_RECORDS: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub(crate) enum Record {
+    Put(Job),
+    Delete { id: u64 },
+    NextId { id: u64 },
+}
+
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Records in the file, live or not.
+    records: usize,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if needed, and returns the jobs
+    /// it holds in id order along with the first id never used. A torn
+    /// record at the end, left by a crash mid-write, is dropped.
+    pub(crate) fn open(path: impl AsRef<Path>) -> io::Result<(Wal, Vec<Job>, u64)> {
+        let path = path.as_ref().to_owned();
+        let mut jobs = BTreeMap::new();
+        let mut next_id = 0;
+        let mut records = 0;
+        let mut valid_len = 0;
+        if let Ok(file) = File::open(&path) {
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            while reader.read_line(&mut line)? != 0 {
+                let record = match serde_json::from_str(&line) {
+                    Ok(record) if line.ends_with('\n') => record,
+                    _ => break,
+                };
+                match record {
+                    Record::Put(job) => {
+                        next_id = next_id.max(job.id + 1);
+                        jobs.insert(job.id, job);
+                    }
+                    Record::Delete { id } => {
+                        jobs.remove(&id);
+                    }
+                    Record::NextId { id } => next_id = next_id.max(id),
+                }
+                records += 1;
+                valid_len += line.len() as u64;
+                line.clear();
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(valid_len)?;
+        let wal = Wal {
+            path,
+            file: BufWriter::new(file),
+            records,
+        };
+        Ok((wal, jobs.into_values().collect(), next_id))
+    }
+
+    /// Appends `record`, handing it to the OS before returning.
+    pub(crate) fn append(&mut self, record: &Record) -> io::Result<()> {
+        serde_json::to_writer(&mut self.file, record)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Whether the log has grown enough past `live` jobs to be worth
+    /// rewriting.
+    pub(crate) fn needs_compaction(&self, live: usize) -> bool {
+        self.records >= MIN_COMPACT_RECORDS && self.records > 2 * live
+    }
+
+    /// Replaces the log with `next_id` and one put per job in `live`. The
+    /// new log is written next to the old one and renamed over it, so a
+    /// crash leaves one or the other intact.
+    pub(crate) fn compact<'a>(
+        &mut self,
+        next_id: u64,
+        live: impl Iterator<Item = &'a Job>,
+    ) -> io::Result<()> {
+        let tmp = self.path.with_extension("compact");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        let records = std::iter::once(Record::NextId { id: next_id })
+            .chain(live.map(|job| Record::Put(job.clone())));
+        let mut written = 0;
+        for record in records {
+            serde_json::to_writer(&mut out, &record)?;
+            out.write_all(b"\n")?;
+            written += 1;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.records = written;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn job(id: u64) -> Job {
+        Job {
+            id,
+            queue: "q".to_owned(),
+            job: Value::from(id),
+            pri: id,
+            lease: None,
+            run_at: None,
+            expires_at: None,
+        }
+    }
+
+    fn ids(jobs: &[Job]) -> Vec<u64> {
+        jobs.iter().map(|job| job.id).collect()
+    }
+
+    #[test]
+    fn replays_puts_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.log");
+        let (mut wal, jobs, _) = Wal::open(&path).unwrap();
+        assert!(jobs.is_empty());
+        for id in 0..3 {
+            wal.append(&Record::Put(job(id))).unwrap();
+        }
+        wal.append(&Record::Delete { id: 1 }).unwrap();
+        drop(wal);
+
+        let (_, jobs, next_id) = Wal::open(&path).unwrap();
+        assert_eq!(vec![0, 2], ids(&jobs));
+        assert_eq!(Value::from(2), jobs[1].job);
+        assert_eq!(3, next_id);
+    }
+
+    #[test]
+    fn torn_tail_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.log");
+        let (mut wal, ..) = Wal::open(&path).unwrap();
+        wal.append(&Record::Put(job(0))).unwrap();
+        drop(wal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"op":"put","id":1,"que"#).unwrap();
+
+        let (mut wal, jobs, _) = Wal::open(&path).unwrap();
+        assert_eq!(vec![0], ids(&jobs));
+        wal.append(&Record::Put(job(2))).unwrap();
+        drop(wal);
+        let (_, jobs, _) = Wal::open(&path).unwrap();
+        assert_eq!(vec![0, 2], ids(&jobs));
+    }
+
+    #[test]
+    fn compaction_keeps_live_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.log");
+        let (mut wal, ..) = Wal::open(&path).unwrap();
+        for id in 0..MIN_COMPACT_RECORDS as u64 {
+            wal.append(&Record::Put(job(id))).unwrap();
+            wal.append(&Record::Delete { id }).unwrap();
+        }
+        let live = [job(7), job(9)];
+        assert!(wal.needs_compaction(live.len()));
+        wal.compact(MIN_COMPACT_RECORDS as u64, live.iter())
+            .unwrap();
+        assert!(!wal.needs_compaction(live.len()));
+        wal.append(&Record::Delete { id: 7 }).unwrap();
+        drop(wal);
+
+        let (wal, jobs, next_id) = Wal::open(&path).unwrap();
+        assert_eq!(vec![9], ids(&jobs));
+        assert_eq!(MIN_COMPACT_RECORDS as u64, next_id);
+        assert_eq!(4, wal.records);
+    }
+}
@@ -0,0 +1,119 @@
+//! Tokens that allow writing, each only under some directories, so a
+//! server can be left where anyone can reach it.
+
+use crate::path::VcsPath;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Every token, with the directories it may write under.
+#[derive(Debug, Default)]
+pub struct Tokens(HashMap<String, Vec<VcsPath>>);
+
+/// Why a write was refused.
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    /// There was no AUTH, or none with a known token.
+    NoToken,
+    /// The token doesn't allow writing here.
+    NotAllowed(VcsPath),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::NoToken => f.write_str("auth required"),
+            Denied::NotAllowed(path) => write!(f, "no write access to {path}"),
+        }
+    }
+}
+
+impl Tokens {
+    /// Reads tokens from `text`, a line each: the token, then the
+    /// directories it may write under. Blank lines and ones starting with
+    /// `#` are skipped.
+    pub fn parse(text: &str) -> Result<Tokens> {
+        let mut tokens = Tokens::default();
+        for (i, line) in text.lines().enumerate() {
+            let mut words = line.split_ascii_whitespace();
+            let Some(token) = words.next().filter(|token| !token.starts_with('#')) else {
+                continue;
+            };
+            let mut dirs = vec![];
+            for dir in words {
+                match VcsPath::dir(dir) {
+                    Ok(dir) => dirs.push(dir),
+                    Err(e) => bail!("line {}: bad dir {dir:?}: {e:?}", i + 1),
+                }
+            }
+            if dirs.is_empty() {
+                bail!("line {}: token without dirs", i + 1);
+            }
+            if tokens.0.insert(token.to_owned(), dirs).is_some() {
+                bail!("line {}: token given twice", i + 1);
+            }
+        }
+        Ok(tokens)
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.0.contains_key(token)
+    }
+
+    /// Whether `token` may change every one of `paths`, files or dirs.
+    /// Nothing needs a token to change no paths.
+    pub fn check(&self, token: Option<&str>, paths: &[&VcsPath]) -> Result<(), Denied> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let dirs = token
+            .and_then(|token| self.0.get(token))
+            .ok_or(Denied::NoToken)?;
+        for &path in paths {
+            if !dirs
+                .iter()
+                .any(|dir| path == dir || path.strip_dir(dir).is_some())
+            {
+                return Err(Denied::NotAllowed(path.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_write_under_their_dirs_only() {
+        let tokens = Tokens::parse("# who may write\n\nabc /a /b/c\nroot /\n").unwrap();
+        let file = |path| VcsPath::file(path).unwrap();
+        let (a, bc, bd) = (file("/a/x"), file("/b/c/x"), file("/b/d"));
+        assert_eq!(Ok(()), tokens.check(Some("abc"), &[&a, &bc]));
+        assert_eq!(
+            Ok(()),
+            tokens.check(Some("abc"), &[&VcsPath::dir("/a").unwrap()])
+        );
+        assert_eq!(
+            Err(Denied::NotAllowed(bd.clone())),
+            tokens.check(Some("abc"), &[&a, &bd])
+        );
+        assert_eq!(
+            Err(Denied::NotAllowed(file("/ab"))),
+            tokens.check(Some("abc"), &[&file("/ab")])
+        );
+        assert_eq!(Ok(()), tokens.check(Some("root"), &[&a, &bd]));
+        assert_eq!(Err(Denied::NoToken), tokens.check(Some("xyz"), &[&a]));
+        assert_eq!(Err(Denied::NoToken), tokens.check(None, &[&a]));
+        assert_eq!(Ok(()), tokens.check(None, &[]));
+        assert!(tokens.contains("root") && !tokens.contains("#"));
+    }
+
+    #[test]
+    fn bad_token_files_are_refused() {
+        for text in ["abc\n", "abc a\n", "abc /a\nabc /b\n", "abc /..\n"] {
+            assert!(Tokens::parse(text).is_err(), "{text:?}");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{bail, Result};
+use clap::Parser;
+use p10::{Client, Entry};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::fs;
+use std::io::Write;
+
+/// Talks to a VCS server from a prompt. Type `help` for what it takes.
+#[derive(Parser)]
+struct Args {
+    /// Address of the server.
+    #[arg(long, default_value = "127.0.0.1:4567")]
+    addr: String,
+}
+
+const HELP: &str = "\
+put <local file> <path>      store a local file as a new revision
+get <path> [rev] [local]     print a revision, or save it to a local file
+ls [dir]                     list a directory, / by default
+raw <request>                send any one-line request and print the reply
+quit";
+
+/// Runs one line typed at the prompt.
+async fn run(client: &mut Client, line: &str) -> Result<()> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words[..] {
+        [] => {}
+        ["help"] => println!("{HELP}"),
+        ["put", local, path] => {
+            let rev = client.put(path, &fs::read(local)?).await?;
+            println!("r{rev}");
+        }
+        ["get", path] => print(&client.get(path, None).await?)?,
+        ["get", path, rev] => match rev.trim_start_matches('r').parse() {
+            Ok(rev) => print(&client.get(path, Some(rev)).await?)?,
+            Err(_) => fs::write(rev, client.get(path, None).await?)?,
+        },
+        ["get", path, rev, local] => {
+            let Ok(rev) = rev.trim_start_matches('r').parse() else {
+                bail!("bad revision {rev:?}");
+            };
+            fs::write(local, client.get(path, Some(rev)).await?)?;
+        }
+        ["ls"] | ["ls", _] => {
+            for entry in client.list(words.get(1).unwrap_or(&"/")).await? {
+                match entry {
+                    Entry::File { name, revision } => println!("{name} r{revision}"),
+                    Entry::Dir(name) => println!("{name}"),
+                }
+            }
+        }
+        ["raw", ..] => {
+            let request = line.trim_start()["raw".len()..].trim();
+            println!("{}", client.request(request).await?);
+        }
+        _ => bail!("unknown command, try help"),
+    }
+    Ok(())
+}
+
+fn print(content: &[u8]) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(content)?;
+    if !content.ends_with(b"\n") {
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut client = Client::connect(&args.addr).await?;
+    let mut editor = DefaultEditor::new()?;
+    loop {
+        let line = match editor.readline("vcs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if line.trim() == "quit" {
+            return Ok(());
+        }
+        editor.add_history_entry(&line)?;
+        if let Err(e) = run(&mut client, &line).await {
+            eprintln!("error: {e}");
+        }
+    }
+}
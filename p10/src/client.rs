@@ -0,0 +1,137 @@
+//! Async client for the VCS.
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    File {
+        name: String,
+        revision: u64,
+    },
+    /// With the slash at the end.
+    Dir(String),
+}
+
+/// One connection to a VCS server. Replies of `ERR` come back as errors
+/// with the server's text, and the connection stays usable after them.
+pub struct Client {
+    read: BufReader<OwnedReadHalf>,
+    write: OwnedWriteHalf,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        let (read, write) = TcpStream::connect(addr).await?.into_split();
+        let mut client = Client {
+            read: BufReader::new(read),
+            write,
+        };
+        client.ready().await?;
+        Ok(client)
+    }
+
+    /// Stores `content` as the newest revision of `path`, returning its
+    /// number.
+    pub async fn put(&mut self, path: &str, content: &[u8]) -> Result<u64> {
+        self.write
+            .write_all(format!("PUT {path} {}\n", content.len()).as_bytes())
+            .await?;
+        self.write.write_all(content).await?;
+        let reply = self.reply().await?;
+        self.ready().await?;
+        revision(&reply).with_context(|| format!("bad reply to PUT: {reply:?}"))
+    }
+
+    /// Content of revision `revision` of `path`, or of the newest.
+    pub async fn get(&mut self, path: &str, revision: Option<u64>) -> Result<Vec<u8>> {
+        let request = match revision {
+            Some(rev) => format!("GET {path} r{rev}\n"),
+            None => format!("GET {path}\n"),
+        };
+        self.write.write_all(request.as_bytes()).await?;
+        let reply = self.reply().await?;
+        let len: usize = reply
+            .parse()
+            .with_context(|| format!("bad reply to GET: {reply:?}"))?;
+        let mut content = vec![0; len];
+        self.read.read_exact(&mut content).await?;
+        self.ready().await?;
+        Ok(content)
+    }
+
+    /// Files and directories right under `dir`, sorted by name.
+    pub async fn list(&mut self, dir: &str) -> Result<Vec<Entry>> {
+        self.write
+            .write_all(format!("LIST {dir}\n").as_bytes())
+            .await?;
+        let reply = self.reply().await?;
+        let count: usize = reply
+            .parse()
+            .with_context(|| format!("bad reply to LIST: {reply:?}"))?;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let line = self.line().await?;
+            let entry = match line.split_once(' ') {
+                Some((name, "DIR")) => Entry::Dir(name.to_owned()),
+                Some((name, rev)) => Entry::File {
+                    name: name.to_owned(),
+                    revision: revision(rev).with_context(|| format!("bad LIST entry {line:?}"))?,
+                },
+                None => bail!("bad LIST entry {line:?}"),
+            };
+            entries.push(entry);
+        }
+        self.ready().await?;
+        Ok(entries)
+    }
+
+    /// Sends a request line with no content after it and returns the
+    /// reply, after `OK `, for requests answered in one line.
+    pub async fn request(&mut self, line: &str) -> Result<String> {
+        self.write.write_all(format!("{line}\n").as_bytes()).await?;
+        let reply = self.reply().await?;
+        self.ready().await?;
+        Ok(reply)
+    }
+
+    /// The first line of a reply, without `OK`. `ERR` replies are read to
+    /// the `READY` after them and returned as errors.
+    async fn reply(&mut self) -> Result<String> {
+        let line = self.line().await?;
+        if let Some(e) = line.strip_prefix("ERR ") {
+            self.ready().await?;
+            bail!("{e}");
+        }
+        match line.strip_prefix("OK") {
+            Some(reply) => Ok(reply.trim_start().to_owned()),
+            None => bail!("unexpected reply {line:?}"),
+        }
+    }
+
+    /// Reads the `READY` the server sends before each request.
+    async fn ready(&mut self) -> Result<()> {
+        let line = self.line().await?;
+        if line != "READY" {
+            bail!("expected READY, got {line:?}");
+        }
+        Ok(())
+    }
+
+    async fn line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if 0 == self.read.read_line(&mut line).await? {
+            bail!("server closed the connection");
+        }
+        line.pop();
+        Ok(line)
+    }
+}
+
+/// Number of a revision written as `r<n>`.
+fn revision(word: &str) -> Option<u64> {
+    word.strip_prefix('r')?.parse().ok()
+}
@@ -0,0 +1,455 @@
+//! Request lines, split into words and checked into commands before
+//! anything is done about them.
+
+use crate::path::VcsPath;
+use crate::Selector;
+use regex::bytes::{Regex, RegexBuilder};
+use std::fmt;
+
+/// What GREP looks for in each line: a word, or a regex with `-E`.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    /// Largest compiled regex accepted, so one can't take all the memory.
+    const SIZE_LIMIT: usize = 1 << 20;
+
+    fn new(pattern: &str, regex: bool) -> Option<Pattern> {
+        let pattern = if regex {
+            pattern.to_owned()
+        } else {
+            regex::escape(pattern)
+        };
+        RegexBuilder::new(&pattern)
+            .size_limit(Pattern::SIZE_LIMIT)
+            .build()
+            .ok()
+            .map(Pattern)
+    }
+
+    pub fn is_match(&self, line: &[u8]) -> bool {
+        self.0.is_match(line)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Followed by `len` bytes of content.
+    Put {
+        path: VcsPath,
+        len: u64,
+    },
+    Get {
+        path: VcsPath,
+        selector: Selector,
+    },
+    List(VcsPath),
+    Stat(VcsPath),
+    Copy {
+        from: VcsPath,
+        to: VcsPath,
+    },
+    Move {
+        from: VcsPath,
+        to: VcsPath,
+    },
+    /// Through the newest revisions of the files under `dir`.
+    Grep {
+        pattern: Pattern,
+        dir: VcsPath,
+    },
+    Author(String),
+    /// With a token that allows writing under some directories.
+    Auth(String),
+    /// Of new revisions of the files under the directory, for as long as
+    /// the connection lasts.
+    Watch(VcsPath),
+    /// Of revision `rev`, or of the whole file.
+    Delete {
+        path: VcsPath,
+        rev: Option<u64>,
+    },
+    /// Of every file under the directory.
+    DeleteDir(VcsPath),
+    Help,
+}
+
+impl Command {
+    /// The files and directories this command changes.
+    pub fn writes(&self) -> Vec<&VcsPath> {
+        match self {
+            Command::Put { path, .. } | Command::Delete { path, .. } => vec![path],
+            Command::DeleteDir(dir) => vec![dir],
+            Command::Copy { to, .. } => vec![to],
+            Command::Move { from, to } => vec![from, to],
+            _ => vec![],
+        }
+    }
+}
+
+/// Why a line isn't a command. Shown after `ERR ` in the reply.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// A known method with the wrong arguments; says how it's used.
+    Usage(&'static str),
+    IllegalFileName,
+    IllegalDirName,
+    IllegalAuthor,
+    IllegalPattern,
+    IllegalMethod(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Usage(usage) => write!(f, "usage: {usage}"),
+            ParseError::IllegalFileName => f.write_str("illegal file name"),
+            ParseError::IllegalDirName => f.write_str("illegal dir name"),
+            ParseError::IllegalAuthor => f.write_str("illegal author"),
+            ParseError::IllegalPattern => f.write_str("illegal pattern"),
+            ParseError::IllegalMethod(method) => write!(f, "illegal method: {method}"),
+        }
+    }
+}
+
+const PUT: &str = "PUT file length newline data";
+const GET: &str = "GET file [revision]";
+const LIST: &str = "LIST dir";
+const STAT: &str = "STAT file";
+const COPY: &str = "COPY file file";
+const MOVE: &str = "MOVE file file";
+const GREP: &str = "GREP [-E] pattern dir";
+const AUTHOR: &str = "AUTHOR name";
+const AUTH: &str = "AUTH token";
+const WATCH: &str = "WATCH dir";
+const DELETE: &str = "DELETE file [revision] | DELETE -r dir";
+
+/// Parses a request line. Words are separated by any amount of
+/// whitespace and methods are matched ignoring case.
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let words: Vec<&str> = line.split_ascii_whitespace().collect();
+    let Some((method, args)) = words.split_first() else {
+        return Err(ParseError::IllegalMethod(String::new()));
+    };
+    match method.to_ascii_uppercase().as_str() {
+        "PUT" => match *args {
+            [path, len] => Ok(Command::Put {
+                path: file(path)?,
+                len: len.parse().map_err(|_| ParseError::Usage(PUT))?,
+            }),
+            _ => Err(ParseError::Usage(PUT)),
+        },
+        "GET" => match *args {
+            [path] => Ok(Command::Get {
+                path: file(path)?,
+                selector: Selector::Head,
+            }),
+            [path, rev] => Ok(Command::Get {
+                path: file(path)?,
+                selector: revision(rev).ok_or(ParseError::Usage(GET))?,
+            }),
+            _ => Err(ParseError::Usage(GET)),
+        },
+        "LIST" => match *args {
+            [path] => Ok(Command::List(dir(path)?)),
+            _ => Err(ParseError::Usage(LIST)),
+        },
+        "STAT" => match *args {
+            [path] => Ok(Command::Stat(file(path)?)),
+            _ => Err(ParseError::Usage(STAT)),
+        },
+        "COPY" => match *args {
+            [from, to] => Ok(Command::Copy {
+                from: file(from)?,
+                to: file(to)?,
+            }),
+            _ => Err(ParseError::Usage(COPY)),
+        },
+        "MOVE" => match *args {
+            [from, to] => Ok(Command::Move {
+                from: file(from)?,
+                to: file(to)?,
+            }),
+            _ => Err(ParseError::Usage(MOVE)),
+        },
+        "GREP" => {
+            let (pattern, dir, regex) = match *args {
+                ["-E", pattern, dir] => (pattern, dir, true),
+                [pattern, dir] => (pattern, dir, false),
+                _ => return Err(ParseError::Usage(GREP)),
+            };
+            Ok(Command::Grep {
+                pattern: Pattern::new(pattern, regex).ok_or(ParseError::IllegalPattern)?,
+                dir: self::dir(dir)?,
+            })
+        }
+        "AUTHOR" => match *args {
+            [name] if valid_author(name) => Ok(Command::Author(name.to_owned())),
+            [_] => Err(ParseError::IllegalAuthor),
+            _ => Err(ParseError::Usage(AUTHOR)),
+        },
+        "AUTH" => match *args {
+            [token] => Ok(Command::Auth(token.to_owned())),
+            _ => Err(ParseError::Usage(AUTH)),
+        },
+        "WATCH" => match *args {
+            [path] => Ok(Command::Watch(dir(path)?)),
+            _ => Err(ParseError::Usage(WATCH)),
+        },
+        "DELETE" => match *args {
+            ["-r", path] => Ok(Command::DeleteDir(dir(path)?)),
+            [path] => Ok(Command::Delete {
+                path: file(path)?,
+                rev: None,
+            }),
+            [path, rev] => match revision(rev) {
+                Some(Selector::Number(rev)) => Ok(Command::Delete {
+                    path: file(path)?,
+                    rev: Some(rev),
+                }),
+                _ => Err(ParseError::Usage(DELETE)),
+            },
+            _ => Err(ParseError::Usage(DELETE)),
+        },
+        "HELP" => Ok(Command::Help),
+        _ => Err(ParseError::IllegalMethod(method.to_string())),
+    }
+}
+
+fn file(path: &str) -> Result<VcsPath, ParseError> {
+    VcsPath::file(path).map_err(|_| ParseError::IllegalFileName)
+}
+
+fn dir(path: &str) -> Result<VcsPath, ParseError> {
+    VcsPath::dir(path).map_err(|_| ParseError::IllegalDirName)
+}
+
+/// A revision, with or without an `r` in front.
+fn revision(word: &str) -> Option<Selector> {
+    let word = word
+        .strip_prefix('r')
+        .or_else(|| word.strip_prefix('R'))
+        .unwrap_or(word);
+    word.parse().ok()
+}
+
+/// Authors are a single printable word, so they fit on an index line.
+fn valid_author(name: &str) -> bool {
+    name.len() <= 64 && name.bytes().all(|c| c.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> VcsPath {
+        VcsPath::file(path).unwrap()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let cases = [
+            (
+                "PUT /a 5",
+                Command::Put {
+                    path: file("/a"),
+                    len: 5,
+                },
+            ),
+            (
+                "put  /a\t0 ",
+                Command::Put {
+                    path: file("/a"),
+                    len: 0,
+                },
+            ),
+            (
+                "GET /a",
+                Command::Get {
+                    path: file("/a"),
+                    selector: Selector::Head,
+                },
+            ),
+            (
+                "Get /a r2",
+                Command::Get {
+                    path: file("/a"),
+                    selector: Selector::Number(2),
+                },
+            ),
+            (
+                "GET /a 2",
+                Command::Get {
+                    path: file("/a"),
+                    selector: Selector::Number(2),
+                },
+            ),
+            (
+                "GET /a rHEAD",
+                Command::Get {
+                    path: file("/a"),
+                    selector: Selector::Head,
+                },
+            ),
+            (
+                "GET /a r-1",
+                Command::Get {
+                    path: file("/a"),
+                    selector: Selector::Back(1),
+                },
+            ),
+            ("LIST /", Command::List(VcsPath::dir("/").unwrap())),
+            ("list /a/", Command::List(VcsPath::dir("/a").unwrap())),
+            ("STAT /a", Command::Stat(file("/a"))),
+            ("WATCH /a/", Command::Watch(VcsPath::dir("/a").unwrap())),
+            (
+                "COPY /a /b/c",
+                Command::Copy {
+                    from: file("/a"),
+                    to: file("/b/c"),
+                },
+            ),
+            (
+                "move /a /b",
+                Command::Move {
+                    from: file("/a"),
+                    to: file("/b"),
+                },
+            ),
+            (
+                "GREP a.c /",
+                Command::Grep {
+                    pattern: Pattern::new("a\\.c", true).unwrap(),
+                    dir: VcsPath::dir("/").unwrap(),
+                },
+            ),
+            (
+                "GREP -E a.c /d/",
+                Command::Grep {
+                    pattern: Pattern::new("a.c", true).unwrap(),
+                    dir: VcsPath::dir("/d").unwrap(),
+                },
+            ),
+            (
+                "GREP -E /",
+                Command::Grep {
+                    pattern: Pattern::new("-E", false).unwrap(),
+                    dir: VcsPath::dir("/").unwrap(),
+                },
+            ),
+            ("AUTHOR bob", Command::Author("bob".to_owned())),
+            ("auth s3cret", Command::Auth("s3cret".to_owned())),
+            (
+                "DELETE /a",
+                Command::Delete {
+                    path: file("/a"),
+                    rev: None,
+                },
+            ),
+            (
+                "DELETE /a r3",
+                Command::Delete {
+                    path: file("/a"),
+                    rev: Some(3),
+                },
+            ),
+            (
+                "DELETE -r /a",
+                Command::DeleteDir(VcsPath::dir("/a").unwrap()),
+            ),
+            ("HELP", Command::Help),
+            ("help me", Command::Help),
+        ];
+        for (line, command) in cases {
+            assert_eq!(Ok(command), parse(line), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_refused() {
+        let cases = [
+            ("", ParseError::IllegalMethod(String::new())),
+            ("   ", ParseError::IllegalMethod(String::new())),
+            ("FOO /a", ParseError::IllegalMethod("FOO".to_owned())),
+            ("PUTT /a 1", ParseError::IllegalMethod("PUTT".to_owned())),
+            ("PUT", ParseError::Usage(PUT)),
+            ("PUT /a", ParseError::Usage(PUT)),
+            ("PUT /a 1 2", ParseError::Usage(PUT)),
+            ("PUT /a x", ParseError::Usage(PUT)),
+            ("PUT /a -1", ParseError::Usage(PUT)),
+            ("PUT a 1", ParseError::IllegalFileName),
+            ("PUT /a/ 1", ParseError::IllegalFileName),
+            ("PUT /a;b 1", ParseError::IllegalFileName),
+            ("GET", ParseError::Usage(GET)),
+            ("GET /a r1 r2", ParseError::Usage(GET)),
+            ("GET /a rx", ParseError::Usage(GET)),
+            ("GET /a r", ParseError::Usage(GET)),
+            ("GET /a/ r1", ParseError::IllegalFileName),
+            ("LIST", ParseError::Usage(LIST)),
+            ("LIST / /", ParseError::Usage(LIST)),
+            ("LIST a", ParseError::IllegalDirName),
+            ("LIST /..", ParseError::IllegalDirName),
+            ("STAT", ParseError::Usage(STAT)),
+            ("STAT /", ParseError::IllegalFileName),
+            ("COPY /a", ParseError::Usage(COPY)),
+            ("COPY /a /b /c", ParseError::Usage(COPY)),
+            ("COPY /a /b/", ParseError::IllegalFileName),
+            ("MOVE", ParseError::Usage(MOVE)),
+            ("MOVE a /b", ParseError::IllegalFileName),
+            ("GREP", ParseError::Usage(GREP)),
+            ("GREP a", ParseError::Usage(GREP)),
+            ("GREP -E a b /", ParseError::Usage(GREP)),
+            ("GREP a b", ParseError::IllegalDirName),
+            ("GREP -E a( /", ParseError::IllegalPattern),
+            ("GREP -E a{1000}{1000} /", ParseError::IllegalPattern),
+            ("AUTHOR", ParseError::Usage(AUTHOR)),
+            ("AUTHOR two words", ParseError::Usage(AUTHOR)),
+            ("AUTHOR a\u{7f}", ParseError::IllegalAuthor),
+            ("AUTH", ParseError::Usage(AUTH)),
+            ("AUTH a b", ParseError::Usage(AUTH)),
+            ("WATCH", ParseError::Usage(WATCH)),
+            ("WATCH / /a", ParseError::Usage(WATCH)),
+            ("WATCH a", ParseError::IllegalDirName),
+            ("DELETE", ParseError::Usage(DELETE)),
+            ("DELETE /a rHEAD", ParseError::Usage(DELETE)),
+            ("DELETE /a r-1", ParseError::Usage(DELETE)),
+            ("DELETE -r", ParseError::IllegalFileName),
+            ("DELETE -r a", ParseError::IllegalDirName),
+            ("DELETE /a r1 r2", ParseError::Usage(DELETE)),
+        ];
+        for (line, e) in cases {
+            assert_eq!(Err(e), parse(line), "{line:?}");
+        }
+        let long = format!("AUTHOR {}", "a".repeat(65));
+        assert_eq!(Err(ParseError::IllegalAuthor), parse(&long));
+        assert!(parse(&long[..long.len() - 1]).is_ok());
+    }
+
+    #[test]
+    fn patterns_match_words_or_regexes() {
+        let word = Pattern::new("a.c", false).unwrap();
+        assert!(word.is_match(b"xa.cx"));
+        assert!(!word.is_match(b"abc"));
+        let regex = Pattern::new("^a.c$", true).unwrap();
+        assert!(regex.is_match(b"abc"));
+        assert!(!regex.is_match(b"xabc"));
+    }
+
+    #[test]
+    fn errors_read_as_replies() {
+        assert_eq!(
+            "usage: PUT file length newline data",
+            ParseError::Usage(PUT).to_string()
+        );
+        assert_eq!(
+            "illegal method: FOO",
+            ParseError::IllegalMethod("FOO".to_owned()).to_string()
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Deltas between two versions of a file, as a list of ops that build the
+//! new version out of ranges copied from the old one and inserted bytes.
+
+use std::collections::HashMap;
+use std::io;
+
+/// Length of the runs of the old version that matches are looked up by.
+const BLOCK: usize = 16;
+
+/// Places in the old version remembered per distinct block. Repetitive
+/// files have many; the longest match among these is taken.
+const CANDIDATES: usize = 8;
+
+/// Shortest match worth a copy. Shorter ones are likely to be text that
+/// just happens to repeat, and the scan goes on looking for a better one.
+const MIN_MATCH: usize = 2 * BLOCK;
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+/// Encodes `new` as a delta against `old`.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for start in (0..old.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        let places = blocks.entry(&old[start..start + BLOCK]).or_default();
+        if places.len() < CANDIDATES {
+            places.push(start);
+        }
+    }
+
+    let mut delta = vec![];
+    let (mut i, mut pending) = (0, 0);
+    while i + BLOCK <= new.len() {
+        let places = blocks
+            .get(&new[i..i + BLOCK])
+            .map_or(&[][..], Vec::as_slice);
+        // Grow each match both ways, backwards only over unmatched bytes.
+        let best = places
+            .iter()
+            .map(|&found| {
+                let back = (1..=(i - pending).min(found))
+                    .take_while(|&n| new[i - n] == old[found - n])
+                    .count();
+                let forward = new[i..]
+                    .iter()
+                    .zip(&old[found..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (i - back, found - back, back + forward)
+            })
+            .max_by_key(|&(_, _, len)| len)
+            .filter(|&(_, _, len)| len >= MIN_MATCH.min(new.len() - pending));
+        let Some((start, from, len)) = best else {
+            i += 1;
+            continue;
+        };
+        insert(&mut delta, &new[pending..start]);
+        delta.push(COPY);
+        put_varint(&mut delta, from as u64);
+        put_varint(&mut delta, len as u64);
+        i = start + len;
+        pending = i;
+    }
+    insert(&mut delta, &new[pending..]);
+    delta
+}
+
+fn insert(delta: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        delta.push(INSERT);
+        put_varint(delta, bytes.len() as u64);
+        delta.extend_from_slice(bytes);
+    }
+}
+
+/// Rebuilds the new version from `old` and a delta made by `diff`.
+pub fn apply(old: &[u8], mut delta: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt delta");
+    let mut new = vec![];
+    while let Some((&op, rest)) = delta.split_first() {
+        delta = rest;
+        match op {
+            COPY => {
+                let from = get_varint(&mut delta).ok_or_else(invalid)? as usize;
+                let len = get_varint(&mut delta).ok_or_else(invalid)? as usize;
+                let end = from.checked_add(len).ok_or_else(invalid)?;
+                new.extend_from_slice(old.get(from..end).ok_or_else(invalid)?);
+            }
+            INSERT => {
+                let len = get_varint(&mut delta).ok_or_else(invalid)? as usize;
+                let bytes = delta.get(..len).ok_or_else(invalid)?;
+                new.extend_from_slice(bytes);
+                delta = &delta[len..];
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(new)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn get_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn text(lines: usize) -> Vec<u8> {
+        (0..lines)
+            .flat_map(|i| format!("line {i} of some text file\n").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn small_edit_makes_a_small_delta() {
+        let old = text(1000);
+        let mut new = old.clone();
+        new.splice(5000..5010, b"changed!".iter().copied());
+        new.extend_from_slice(b"one more line\n");
+        let delta = diff(&old, &new);
+        assert!(delta.len() < 64, "{} bytes", delta.len());
+        assert_eq!(new, apply(&old, &delta).unwrap());
+    }
+
+    #[test]
+    fn unrelated_versions_are_inserted() {
+        let delta = diff(b"abc", b"xyz");
+        assert_eq!(vec![INSERT, 3, b'x', b'y', b'z'], delta);
+        assert!(diff(b"", b"").is_empty());
+    }
+
+    #[test]
+    fn corrupt_deltas_are_errors() {
+        assert!(apply(b"abc", &[COPY, 1, 5]).is_err());
+        assert!(apply(b"abc", &[INSERT, 4, b'x']).is_err());
+        assert!(apply(b"abc", &[COPY, 0x80]).is_err());
+        assert!(apply(b"abc", &[7]).is_err());
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            put_varint(&mut buf, n);
+            assert_eq!(Some(n), get_varint(&mut &buf[..]));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn apply_undoes_diff(
+            old in prop::collection::vec(0u8..4, 0..400),
+            edits in prop::collection::vec((0usize..400, 0usize..20, prop::collection::vec(0u8..4, 0..20)), 0..5),
+        ) {
+            let mut new = old.clone();
+            for (at, removed, inserted) in edits {
+                let at = at.min(new.len());
+                let end = (at + removed).min(new.len());
+                new.splice(at..end, inserted);
+            }
+            prop_assert_eq!(&new, &apply(&old, &diff(&old, &new)).unwrap());
+        }
+    }
+}
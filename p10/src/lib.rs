@@ -0,0 +1,2032 @@
+//! Version control server (p10), and an async client for it shared by the
+//! `p10-client` REPL and the integration tests.
+
+use anyhow::{bail, Context, Result};
+use auth::Tokens;
+use clap::{Parser, Subcommand};
+use command::{Command, Pattern};
+use metrics::{Metrics, Usage};
+use path::VcsPath;
+use proto_common::{ConnLimit, LineCodec, Listen, Reporting, Server, Shutdown};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::hash::{Hash as _, Hasher};
+use std::io::BufRead as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+use storage::{Change, DiskStorage, Hash, MemoryStorage, Revision, Store, Upload};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+use tokio::task;
+use tracing::{error, info, Instrument};
+
+mod auth;
+mod client;
+mod command;
+mod delta;
+mod metrics;
+mod path;
+mod storage;
+
+pub use client::{Client, Entry};
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+    /// Keep file revisions on disk in this directory, and pick up the ones
+    /// already there. Without it everything is kept in memory.
+    #[arg(long)]
+    store: Option<PathBuf>,
+    /// Largest file accepted by PUT, in bytes.
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    max_file_size: u64,
+    /// Most revisions of any one file, deleted ones included.
+    #[arg(long, default_value_t = 10_000)]
+    max_revisions: u64,
+    /// Most files, not counting deleted ones.
+    #[arg(long, default_value_t = 100_000)]
+    max_files: u64,
+    /// Most bytes of file contents stored, once deduplicated and stored
+    /// as deltas.
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    max_stored_bytes: u64,
+    /// Accept DELETE, which isn't part of the protocol.
+    #[arg(long)]
+    allow_delete: bool,
+    /// Accept any bytes as file content, not just text. Contents are sent
+    /// after their length either way, so GET needs no change for it.
+    #[arg(long)]
+    allow_binary: bool,
+    /// Every so often, delete all but this many of the newest revisions of
+    /// each file. With --keep-secs too, only revisions dropped by both go.
+    #[arg(long)]
+    keep_revisions: Option<u64>,
+    /// Every so often, delete revisions older than this many seconds.
+    #[arg(long)]
+    keep_secs: Option<u64>,
+    /// Require AUTH with one of the tokens in this file before any write.
+    /// Each line has a token and the dirs it may write under.
+    #[arg(long)]
+    tokens: Option<PathBuf>,
+    #[command(subcommand)]
+    admin: Option<Admin>,
+}
+
+/// Instead of serving, do something to the store in --store and exit.
+/// Not while a server has it open.
+#[derive(Subcommand)]
+enum Admin {
+    /// Write every file, with every revision, to a dump file.
+    Export { dump: PathBuf },
+    /// Read the files in a dump file into the store, which must have none.
+    Import { dump: PathBuf },
+}
+
+/// First line of a dump, naming its format.
+const DUMP_HEADER: &str = "p10 dump 1";
+
+/// Most lines a GREP replies with.
+const MAX_GREP_MATCHES: usize = 100;
+
+/// Most new revisions a WATCH may fall behind on before it misses some.
+const WATCH_BACKLOG: usize = 1024;
+
+/// How often old revisions are deleted, if any are to be.
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Size of the pieces file contents are read and written in.
+const CHUNK: usize = 64 * 1024;
+
+/// What came of reading the content of a PUT.
+#[derive(Debug, PartialEq)]
+enum Body {
+    /// All of it was passed on.
+    Read,
+    TooLarge,
+    NotText,
+}
+
+/// Reads `len` bytes of file content a chunk at a time, passing each to
+/// `keep`. Content that is too large, or not text when `text_only`, is
+/// read to the end all the same, so the next request starts in the right
+/// place, but not passed on past that.
+async fn read_body(
+    r: &mut (impl AsyncReadExt + Unpin),
+    len: u64,
+    max: u64,
+    text_only: bool,
+    mut keep: impl FnMut(&[u8]) -> io::Result<()>,
+) -> Result<Body> {
+    let mut body = if len > max {
+        Body::TooLarge
+    } else {
+        Body::Read
+    };
+    let mut chunk = vec![0; CHUNK.min(len as usize)];
+    let mut left = len;
+    while left > 0 {
+        let chunk = &mut chunk[..CHUNK.min(left as usize)];
+        r.read_exact(chunk).await?;
+        left -= chunk.len() as u64;
+        if body == Body::Read {
+            if !text_only || is_text(chunk) {
+                keep(chunk)?;
+            } else {
+                body = Body::NotText;
+            }
+        }
+    }
+    Ok(body)
+}
+
+/// Writes `len` bytes of file content from `content` a chunk at a time.
+async fn write_body(
+    w: &mut (impl AsyncWriteExt + Unpin),
+    len: u64,
+    content: &mut impl io::Read,
+) -> Result<()> {
+    let mut chunk = vec![0; CHUNK.min(len as usize)];
+    let mut left = len;
+    while left > 0 {
+        let chunk = &mut chunk[..CHUNK.min(left as usize)];
+        content.read_exact(chunk)?;
+        w.write_all(chunk).await?;
+        left -= chunk.len() as u64;
+    }
+    Ok(())
+}
+
+const LINES: LineCodec = LineCodec::new(proto_common::DEFAULT_MAX_LEN);
+
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+enum Stat {
+    File { path: String, revision: u64 },
+    Dir(String),
+}
+
+impl Stat {
+    fn path(&self) -> &str {
+        match self {
+            Self::File { path, .. } => path,
+            Self::Dir(path) => path,
+        }
+    }
+}
+
+/// Which revision of a file a GET is for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Selector {
+    /// `HEAD`, the newest.
+    Head,
+    /// Counting from 1.
+    Number(u64),
+    /// `-n`, counting back from the newest: `-1` is the one before it.
+    Back(u64),
+}
+
+impl Selector {
+    /// Number of the revision this selects among `revisions`, if it can
+    /// be one.
+    fn resolve(self, revisions: &[Option<Revision>]) -> Option<u64> {
+        let (head, _) = newest(revisions)?;
+        match self {
+            Selector::Head => Some(head),
+            Selector::Number(rev) => Some(rev),
+            Selector::Back(n) => head.checked_sub(n),
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("HEAD") {
+            Ok(Selector::Head)
+        } else if let Some(n) = s.strip_prefix('-') {
+            n.parse().map(Selector::Back)
+        } else {
+            s.parse().map(Selector::Number)
+        }
+    }
+}
+
+/// What STAT tells about a file.
+#[derive(Debug, PartialEq)]
+struct FileInfo {
+    revisions: u64,
+    /// Of the newest revision, as are the rest.
+    size: u64,
+    modified: SystemTime,
+    author: Option<String>,
+}
+
+/// How much the server may be made to keep.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    revisions: u64,
+    files: u64,
+    stored_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            revisions: u64::MAX,
+            files: u64::MAX,
+            stored_bytes: u64::MAX,
+        }
+    }
+}
+
+struct State {
+    /// Every revision of every file, oldest first, with `None` in place of
+    /// deleted ones so the rest keep their numbers. Files with none left
+    /// are gone.
+    files: HashMap<VcsPath, Vec<Option<Revision>>>,
+    store: Arc<Store>,
+    limits: Limits,
+    /// Where new revisions of the files under each watched directory are
+    /// sent, as paths and revision numbers.
+    watchers: HashMap<VcsPath, broadcast::Sender<(VcsPath, u64)>>,
+}
+
+/// Which old revisions the server deletes by itself. The newest revision
+/// of a file is always kept.
+#[derive(Debug, Clone, Copy, Default)]
+struct Retention {
+    /// Revisions to keep, counting back from the newest.
+    keep: Option<u64>,
+    /// Age of the oldest revision to keep.
+    max_age: Option<Duration>,
+}
+
+impl Retention {
+    fn is_empty(&self) -> bool {
+        self.keep.is_none() && self.max_age.is_none()
+    }
+
+    /// Whether to delete a revision that has `newer` revisions after it
+    /// and was made `age` ago: only if every policy set would.
+    fn drops(&self, newer: u64, age: Duration) -> bool {
+        !self.is_empty()
+            && newer > 0
+            && self.keep.is_none_or(|keep| newer >= keep)
+            && self.max_age.is_none_or(|max_age| age > max_age)
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            files: HashMap::new(),
+            store: Arc::new(Store::new(MemoryStorage::default())),
+            limits: Limits::default(),
+            watchers: HashMap::new(),
+        }
+    }
+}
+
+impl State {
+    /// Opens the store in `dir` with every revision recorded there.
+    fn open(dir: impl AsRef<Path>) -> io::Result<State> {
+        let (storage, changes) = DiskStorage::open(dir)?;
+        let mut state = State {
+            files: HashMap::new(),
+            store: Arc::new(Store::new(storage)),
+            limits: Limits::default(),
+            watchers: HashMap::new(),
+        };
+        let parse = |path: &str| {
+            VcsPath::file(path).map_err(|e| {
+                let e = format!("bad path {path:?} in the index: {e:?}");
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            })
+        };
+        for change in changes {
+            match change {
+                Change::Put(path, revision) => {
+                    let path = parse(&path)?;
+                    state.files.entry(path).or_default().push(Some(revision));
+                }
+                Change::Delete(path, rev) => state.forget(&parse(&path)?, rev),
+                Change::Copy(from, to) => {
+                    if let Some(revisions) = state.files.get(&parse(&from)?) {
+                        state.files.insert(parse(&to)?, revisions.clone());
+                    }
+                }
+                Change::Move(from, to) => {
+                    if let Some(revisions) = state.files.remove(&parse(&from)?) {
+                        state.files.insert(parse(&to)?, revisions);
+                    }
+                }
+            }
+        }
+        // Deleted revisions may have had their blobs removed, but no others.
+        for (path, revisions) in &state.files {
+            for revision in revisions.iter().flatten() {
+                if !state.store.contains(&revision.hash) {
+                    let e = format!("revision of {path} has no blob {}", revision.hash);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// Refuses PUTs that would go over `limits`. What's already stored
+    /// is kept even if it's over them.
+    fn with_limits(mut self, limits: Limits) -> State {
+        // Nothing else has the store until the state is shared.
+        Arc::get_mut(&mut self.store)
+            .expect("limits are set before the state is shared")
+            .set_max_bytes(limits.stored_bytes);
+        self.limits = limits;
+        self
+    }
+
+    /// First part of a PUT of the content with `hash` to `path`: finds
+    /// whether it's the same as the newest revision already, or else what
+    /// it may be stored as a delta against. Anything over the limits is
+    /// refused now rather than after storing it; the file limit may be
+    /// overshot by PUTs of new files at the same time.
+    fn prepare_put(&self, path: &VcsPath, hash: &Hash) -> Result<Prepared, PutError> {
+        let newest = self.files.get(path).and_then(|revisions| newest(revisions));
+        if let Some((rev, revision)) = newest {
+            if revision.hash == *hash {
+                return Ok(Prepared::Unchanged(rev));
+            }
+        }
+        self.check_limits(path)?;
+        Ok(Prepared::New(newest.map(|(_, revision)| revision.hash)))
+    }
+
+    /// Last part of a PUT, once its revision is stored and recorded: makes
+    /// it the newest revision of `path` and returns its number.
+    fn commit_put(&mut self, path: VcsPath, revision: Revision) -> u64 {
+        let revisions = self.files.entry(path.clone()).or_default();
+        revisions.push(Some(revision));
+        let rev = revisions.len() as u64;
+        self.notify(&path, rev);
+        rev
+    }
+
+    /// Receives every new revision of the files under `dir` from now on.
+    fn watch(&mut self, dir: VcsPath) -> broadcast::Receiver<(VcsPath, u64)> {
+        self.watchers
+            .entry(dir)
+            .or_insert_with(|| broadcast::channel(WATCH_BACKLOG).0)
+            .subscribe()
+    }
+
+    /// Tells the watchers of the directories above `path` about its new
+    /// revision `rev`, dropping directories nobody watches any more.
+    fn notify(&mut self, path: &VcsPath, rev: u64) {
+        for dir in path.dirs() {
+            if let Some(watchers) = self.watchers.get(&dir) {
+                if watchers.send((path.clone(), rev)).is_err() {
+                    self.watchers.remove(&dir);
+                }
+            }
+        }
+    }
+
+    /// Whether `path` may get another revision.
+    fn check_limits(&self, path: &VcsPath) -> Result<(), PutError> {
+        match self.files.get(path) {
+            Some(revisions) if revisions.len() as u64 >= self.limits.revisions => {
+                Err(PutError::TooManyRevisions(self.limits.revisions))
+            }
+            None if self.files.len() as u64 >= self.limits.files => {
+                Err(PutError::TooManyFiles(self.limits.files))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The revision of `path` that `selector` picks.
+    fn find(&self, path: &VcsPath, selector: Selector) -> Result<&Revision, GetError> {
+        let revisions = self.files.get(path).ok_or(GetError::NoSuchFile)?;
+        selector
+            .resolve(revisions)
+            .and_then(|rev| revision(revisions, rev))
+            .ok_or(GetError::NoSuchRevision)
+    }
+
+    /// Content of the revision of `path` that `selector` picks, to be read
+    /// a piece at a time, and its size. It can still be read once the
+    /// revision is gone.
+    fn read(
+        &self,
+        path: &VcsPath,
+        selector: Selector,
+    ) -> Result<(u64, Box<dyn io::Read + Send>), GetError> {
+        let revision = self.find(path, selector)?;
+        Ok(self.store.open(&revision.hash)?)
+    }
+
+    fn stat(&self, path: &VcsPath) -> Result<FileInfo, GetError> {
+        let revisions = self.files.get(path).ok_or(GetError::NoSuchFile)?;
+        let (rev, newest) = newest(revisions).unwrap();
+        Ok(FileInfo {
+            revisions: rev,
+            size: self.store.size(&newest.hash)?,
+            modified: newest.at,
+            author: newest.author.clone(),
+        })
+    }
+
+    /// Deletes revision `rev` of `path`, counting from 1, or the whole file.
+    fn delete(&mut self, path: &VcsPath, rev: Option<u64>) -> Result<(), GetError> {
+        let revisions = self.files.get(path).ok_or(GetError::NoSuchFile)?;
+        if let Some(rev) = rev {
+            revision(revisions, rev).ok_or(GetError::NoSuchRevision)?;
+        }
+        self.store.record(&Change::Delete(path.to_string(), rev))?;
+        self.forget(path, rev);
+        Ok(())
+    }
+
+    /// Deletes every file under `dir` and returns how many there were.
+    fn delete_dir(&mut self, dir: &VcsPath) -> io::Result<usize> {
+        let mut paths: Vec<VcsPath> = self
+            .files
+            .keys()
+            .filter(|path| path.strip_dir(dir).is_some())
+            .cloned()
+            .collect();
+        paths.sort_unstable();
+        for path in &paths {
+            self.store.record(&Change::Delete(path.to_string(), None))?;
+            self.forget(path, None);
+        }
+        Ok(paths.len())
+    }
+
+    /// Gives `to` every revision of `from`, under the same numbers.
+    fn copy(&mut self, from: &VcsPath, to: &VcsPath) -> Result<(), CopyError> {
+        let revisions = self.files.get(from).ok_or(CopyError::NoSuchFile)?;
+        if self.files.contains_key(to) {
+            return Err(CopyError::Exists);
+        }
+        if self.files.len() as u64 >= self.limits.files {
+            return Err(CopyError::TooManyFiles(self.limits.files));
+        }
+        let revisions = revisions.clone();
+        self.store
+            .record(&Change::Copy(from.to_string(), to.to_string()))?;
+        self.files.insert(to.clone(), revisions);
+        Ok(())
+    }
+
+    /// Moves every revision of `from` to `to`, under the same numbers.
+    fn rename(&mut self, from: &VcsPath, to: &VcsPath) -> Result<(), CopyError> {
+        if !self.files.contains_key(from) {
+            return Err(CopyError::NoSuchFile);
+        }
+        if self.files.contains_key(to) {
+            return Err(CopyError::Exists);
+        }
+        self.store
+            .record(&Change::Move(from.to_string(), to.to_string()))?;
+        let revisions = self.files.remove(from).unwrap();
+        self.files.insert(to.clone(), revisions);
+        Ok(())
+    }
+
+    /// Drops a deleted revision, or file, from `files`.
+    fn forget(&mut self, path: &VcsPath, rev: Option<u64>) {
+        let Some(rev) = rev else {
+            self.files.remove(path);
+            return;
+        };
+        if let Some(revisions) = self.files.get_mut(path) {
+            if let Some(revision) = rev
+                .checked_sub(1)
+                .and_then(|i| revisions.get_mut(i as usize))
+            {
+                *revision = None;
+            }
+            if revisions.iter().all(Option::is_none) {
+                self.files.remove(path);
+            }
+        }
+    }
+
+    /// Deletes the revisions `retention` drops as of `now`, then removes
+    /// the blobs no revision left needs. Returns how many revisions and
+    /// blobs went.
+    fn gc(&mut self, retention: Retention, now: SystemTime) -> io::Result<(usize, usize)> {
+        let mut dropped = vec![];
+        for (path, revisions) in &self.files {
+            let live = revisions
+                .iter()
+                .enumerate()
+                .rev()
+                .filter_map(|(i, revision)| Some((i as u64 + 1, revision.as_ref()?)));
+            for (newer, (rev, revision)) in live.enumerate() {
+                let age = now.duration_since(revision.at).unwrap_or_default();
+                if retention.drops(newer as u64, age) {
+                    dropped.push((path.clone(), rev));
+                }
+            }
+        }
+        dropped.sort_unstable();
+        for (path, rev) in &dropped {
+            self.store
+                .record(&Change::Delete(path.to_string(), Some(*rev)))?;
+            self.forget(path, Some(*rev));
+        }
+        let live = self.files.values().flatten().flatten();
+        let removed = self.store.compact(live.map(|revision| &revision.hash))?;
+        Ok((dropped.len(), removed))
+    }
+
+    /// Writes every file to `w` in a dump `import` reads back: a line
+    /// naming the format, then for each file a `file <path> <revisions>`
+    /// line and a line per revision, `r<n> deleted` or `r<n> <unix millis>
+    /// <length>[ <author>]` followed by the content and a newline, and
+    /// `end` after all of them.
+    fn export(&self, w: &mut impl io::Write) -> io::Result<()> {
+        writeln!(w, "{DUMP_HEADER}")?;
+        let mut paths: Vec<&VcsPath> = self.files.keys().collect();
+        paths.sort_unstable();
+        for path in paths {
+            let revisions = &self.files[path];
+            writeln!(w, "file {path} {}", revisions.len())?;
+            for (i, revision) in revisions.iter().enumerate() {
+                let rev = i + 1;
+                let Some(revision) = revision else {
+                    writeln!(w, "r{rev} deleted")?;
+                    continue;
+                };
+                let content = self.store.get(&revision.hash)?;
+                let millis = revision
+                    .at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                write!(w, "r{rev} {millis} {}", content.len())?;
+                if let Some(author) = &revision.author {
+                    write!(w, " {author}")?;
+                }
+                writeln!(w)?;
+                w.write_all(&content)?;
+                writeln!(w)?;
+            }
+        }
+        writeln!(w, "end")?;
+        w.flush()
+    }
+
+    /// Reads a dump written by `export` into this state, which must have
+    /// no files, and returns how many files there were. Revisions keep
+    /// their numbers, times and authors. Limits don't apply.
+    fn import(&mut self, r: &mut impl io::BufRead) -> io::Result<usize> {
+        if !self.files.is_empty() {
+            let e = "files can only be imported into an empty store";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        if next_dump_line(r)? != DUMP_HEADER {
+            return Err(bad_dump(format!("not in the format of {DUMP_HEADER:?}")));
+        }
+        let mut files = 0;
+        loop {
+            let line = next_dump_line(r)?;
+            if line == "end" {
+                return Ok(files);
+            }
+            let bad = || bad_dump(format!("bad line {line:?}"));
+            let (path, count) = match line.split(' ').collect::<Vec<_>>()[..] {
+                ["file", path, count] => (path, count),
+                _ => return Err(bad()),
+            };
+            let path = VcsPath::file(path).map_err(|_| bad())?;
+            let count: u64 = count.parse().map_err(|_| bad())?;
+            let mut deleted = vec![];
+            for rev in 1..=count {
+                let line = next_dump_line(r)?;
+                let bad = || bad_dump(format!("bad line {line:?}"));
+                let (n, fields) = line.split_once(' ').ok_or_else(bad)?;
+                if n != format!("r{rev}") {
+                    return Err(bad());
+                }
+                let fields: Vec<&str> = fields.split(' ').collect();
+                let (millis, len, author) = match fields[..] {
+                    ["deleted"] => {
+                        // Stands in for the revision until it's deleted
+                        // again below, once the file has the rest.
+                        deleted.push(rev);
+                        self.import_revision(&path, b"", UNIX_EPOCH, None)?;
+                        continue;
+                    }
+                    [millis, len] => (millis, len, None),
+                    [millis, len, author] => (millis, len, Some(author)),
+                    _ => return Err(bad()),
+                };
+                let millis = millis.parse().map_err(|_| bad())?;
+                let len: usize = len.parse().map_err(|_| bad())?;
+                let mut content = vec![0; len + 1];
+                r.read_exact(&mut content)?;
+                if content.pop() != Some(b'\n') {
+                    return Err(bad_dump(format!("{path} r{rev} isn't {len} bytes")));
+                }
+                let at = UNIX_EPOCH + Duration::from_millis(millis);
+                self.import_revision(&path, &content, at, author)?;
+            }
+            for rev in deleted {
+                self.store
+                    .record(&Change::Delete(path.to_string(), Some(rev)))?;
+                self.forget(&path, Some(rev));
+            }
+            files += 1;
+        }
+    }
+
+    /// Adds a revision of `path` as it was exported.
+    fn import_revision(
+        &mut self,
+        path: &VcsPath,
+        content: &[u8],
+        at: SystemTime,
+        author: Option<&str>,
+    ) -> io::Result<()> {
+        let hash = Hash::of(content);
+        let revisions = self.files.entry(path.clone()).or_default();
+        let base = newest(revisions).map(|(_, revision)| &revision.hash);
+        self.store.put(&hash, content, base)?;
+        let revision = Revision {
+            hash,
+            at,
+            author: author.map(str::to_owned),
+        };
+        self.store
+            .record(&Change::Put(path.to_string(), revision.clone()))?;
+        revisions.push(Some(revision));
+        Ok(())
+    }
+
+    /// Lines of the newest revisions of the files under `dir` that match
+    /// `pattern`, as paths and line numbers counting from 1, in order and
+    /// at most `max` of them. Contents are read from the store a line at a
+    /// time, so only a line of a full copy is in memory at once; revisions
+    /// stored as deltas are rebuilt first, but none is over a MiB.
+    fn grep(
+        &self,
+        dir: &VcsPath,
+        pattern: &Pattern,
+        max: usize,
+    ) -> io::Result<Vec<(&VcsPath, usize)>> {
+        let mut paths: Vec<&VcsPath> = self
+            .files
+            .keys()
+            .filter(|path| path.strip_dir(dir).is_some())
+            .collect();
+        paths.sort_unstable();
+        let mut found = vec![];
+        for path in paths {
+            let (_, revision) = newest(&self.files[path]).unwrap();
+            let (_, content) = self.store.open(&revision.hash)?;
+            let mut content = io::BufReader::new(content);
+            let (mut line, mut n) = (vec![], 0);
+            while content.read_until(b'\n', &mut line)? > 0 {
+                if found.len() == max {
+                    return Ok(found);
+                }
+                n += 1;
+                if pattern.is_match(line.strip_suffix(b"\n").unwrap_or(&line)) {
+                    found.push((path, n));
+                }
+                line.clear();
+            }
+        }
+        Ok(found)
+    }
+
+    /// How many files and revisions there are and what they take.
+    fn usage(&self) -> io::Result<Usage> {
+        let mut usage = Usage {
+            files: self.files.len() as u64,
+            revisions: 0,
+            logical_bytes: 0,
+            stored_bytes: self.store.stored_bytes(),
+        };
+        for revision in self.files.values().flatten().flatten() {
+            usage.revisions += 1;
+            usage.logical_bytes += self.store.size(&revision.hash)?;
+        }
+        Ok(usage)
+    }
+
+    fn list(&self, dir: &VcsPath) -> BTreeSet<Stat> {
+        self.files
+            .iter()
+            .filter_map(|(path, revisions)| Some((path.strip_dir(dir)?, revisions)))
+            .map(|(rest, revisions)| match rest.split_once('/') {
+                Some((subdir, _)) => Stat::Dir(format!("{subdir}/")),
+                None => Stat::File {
+                    path: rest.to_owned(),
+                    revision: newest(revisions).unwrap().0,
+                },
+            })
+            .collect()
+    }
+}
+
+/// A line of a dump, without its newline.
+fn next_dump_line(r: &mut impl io::BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Err(bad_dump("it ends too soon".to_owned()));
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn bad_dump(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad dump: {e}"))
+}
+
+/// Revision `rev`, counting from 1, unless it was deleted.
+fn revision(revisions: &[Option<Revision>], rev: u64) -> Option<&Revision> {
+    revisions.get(rev.checked_sub(1)? as usize)?.as_ref()
+}
+
+/// Number and content of the newest revision not deleted.
+fn newest(revisions: &[Option<Revision>]) -> Option<(u64, &Revision)> {
+    revisions
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, revision)| Some((i as u64 + 1, revision.as_ref()?)))
+}
+
+fn is_text(content: &[u8]) -> bool {
+    content
+        .iter()
+        .all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace())
+}
+
+/// What the first part of a PUT found.
+#[derive(Debug)]
+enum Prepared {
+    /// The content is that of this revision, the newest.
+    Unchanged(u64),
+    /// The content is new, and may be stored as a delta against this.
+    New(Option<Hash>),
+}
+
+/// Middle of a PUT, which needs no lock on the state: stores the content
+/// in `upload`, as a delta against `base` if that's worth it, and records
+/// it as a new revision of `path`.
+fn store_put(
+    store: &Store,
+    path: &VcsPath,
+    upload: Upload,
+    base: Option<Hash>,
+    author: Option<&str>,
+) -> Result<Revision, PutError> {
+    let hash = match store.finish(upload, base.as_ref()) {
+        Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+            return Err(PutError::StoreFull(store.max_bytes()));
+        }
+        hash => hash?,
+    };
+    let revision = Revision {
+        hash,
+        at: SystemTime::now(),
+        author: author.map(str::to_owned),
+    };
+    store.record(&Change::Put(path.to_string(), revision.clone()))?;
+    Ok(revision)
+}
+
+/// Number of locks PUTs are sequenced by. Paths share them by hash.
+const PATH_LOCKS: usize = 64;
+
+/// What every connection shares. Most requests hold a lock on the whole
+/// state while they run, but PUTs store and record their content holding
+/// no lock on it, and take the write lock just to number the new revision,
+/// so nothing waits for their disk writes but other changes to their path.
+/// Whatever may wait on the disk is run by `spawn_blocking`, so a slow
+/// disk doesn't hold up the runtime's threads.
+struct Vcs {
+    state: Arc<RwLock<State>>,
+    /// Held by a PUT from before it looks at its path until its revision
+    /// is numbered, so PUTs of one path get numbers in the order they got
+    /// here and each stores a delta against the revision before its own.
+    /// Every other change holds the locks of the paths it changes too, so
+    /// changes are recorded in the order they're made.
+    paths: Vec<Mutex<()>>,
+    metrics: Metrics,
+    /// If set, writes need an AUTH with one of these.
+    tokens: Option<Tokens>,
+}
+
+impl Vcs {
+    fn new(state: State) -> Vcs {
+        Vcs {
+            state: Arc::new(RwLock::new(state)),
+            paths: (0..PATH_LOCKS).map(|_| Mutex::new(())).collect(),
+            metrics: Metrics::default(),
+            tokens: None,
+        }
+    }
+
+    /// Refuses writes without an AUTH with one of `tokens` that allows
+    /// them.
+    fn with_tokens(mut self, tokens: Tokens) -> Vcs {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Takes the locks of `paths`, in order so that two changes never
+    /// each wait for a lock the other holds.
+    async fn lock_paths(&self, paths: &[&VcsPath]) -> Vec<MutexGuard<'_, ()>> {
+        let mut locks: Vec<usize> = paths
+            .iter()
+            .map(|path| {
+                let mut hasher = DefaultHasher::new();
+                path.hash(&mut hasher);
+                hasher.finish() as usize % PATH_LOCKS
+            })
+            .collect();
+        locks.sort_unstable();
+        locks.dedup();
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in locks {
+            guards.push(self.paths[lock].lock().await);
+        }
+        guards
+    }
+
+    /// Takes the locks of every path.
+    async fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        let mut guards = Vec::with_capacity(PATH_LOCKS);
+        for lock in &self.paths {
+            guards.push(lock.lock().await);
+        }
+        guards
+    }
+
+    /// Runs `look` on the state where it may wait on the disk, out of the
+    /// way of the tasks serving other connections.
+    async fn inspect<T: Send + 'static>(
+        &self,
+        look: impl FnOnce(&State) -> T + Send + 'static,
+    ) -> io::Result<T> {
+        let state = self.state.clone().read_owned().await;
+        Ok(task::spawn_blocking(move || look(&state)).await?)
+    }
+
+    /// Runs `change`, which changes only `paths`, on the state where it may
+    /// wait on the disk.
+    async fn change<T: Send + 'static>(
+        &self,
+        paths: &[&VcsPath],
+        change: impl FnOnce(&mut State) -> T + Send + 'static,
+    ) -> io::Result<T> {
+        let _paths = self.lock_paths(paths).await;
+        let mut state = self.state.clone().write_owned().await;
+        Ok(task::spawn_blocking(move || change(&mut state)).await?)
+    }
+
+    /// Runs `change`, which may change any path, on the state where it may
+    /// wait on the disk.
+    async fn change_all<T: Send + 'static>(
+        &self,
+        change: impl FnOnce(&mut State) -> T + Send + 'static,
+    ) -> io::Result<T> {
+        let _paths = self.lock_all().await;
+        let mut state = self.state.clone().write_owned().await;
+        Ok(task::spawn_blocking(move || change(&mut state)).await?)
+    }
+
+    /// Starts the content of a PUT, to be written as it's read.
+    async fn upload(&self) -> io::Result<Upload> {
+        let store = self.state.read().await.store.clone();
+        task::spawn_blocking(move || store.upload()).await?
+    }
+
+    /// Stores the content in `upload` as the newest revision of `path`,
+    /// unless it's the same as the newest one already, and returns the
+    /// revision number.
+    async fn put(
+        &self,
+        path: VcsPath,
+        upload: Upload,
+        author: Option<&str>,
+    ) -> Result<u64, PutError> {
+        let _path = self.lock_paths(&[&path]).await;
+        let (prepared, store) = {
+            let state = self.state.read().await;
+            (
+                state.prepare_put(&path, &upload.hash())?,
+                state.store.clone(),
+            )
+        };
+        let base = match prepared {
+            Prepared::Unchanged(rev) => return Ok(rev),
+            Prepared::New(base) => base,
+        };
+        let author = author.map(str::to_owned);
+        let (path, revision) = task::spawn_blocking(move || {
+            let revision = store_put(&store, &path, upload, base, author.as_deref())?;
+            Ok::<_, PutError>((path, revision))
+        })
+        .await
+        .map_err(io::Error::from)??;
+        Ok(self.state.write().await.commit_put(path, revision))
+    }
+
+    /// Runs `State::gc` once no PUT is between its parts, as the content
+    /// it stored isn't a revision yet.
+    async fn gc(&self, retention: Retention) -> io::Result<(usize, usize)> {
+        self.change_all(move |state| state.gc(retention, SystemTime::now()))
+            .await?
+    }
+}
+
+/// Why a PUT wasn't kept.
+#[derive(Debug)]
+enum PutError {
+    TooManyRevisions(u64),
+    TooManyFiles(u64),
+    StoreFull(u64),
+    Io(io::Error),
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PutError::TooManyRevisions(max) => {
+                write!(f, "too many revisions, the limit is {max} per file")
+            }
+            PutError::TooManyFiles(max) => write!(f, "too many files, the limit is {max}"),
+            PutError::StoreFull(max) => write!(f, "store full, the limit is {max} bytes"),
+            PutError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PutError {}
+
+impl From<io::Error> for PutError {
+    fn from(e: io::Error) -> Self {
+        PutError::Io(e)
+    }
+}
+
+/// Why a COPY or MOVE didn't happen.
+#[derive(Debug)]
+enum CopyError {
+    NoSuchFile,
+    /// There's a file where it would go already.
+    Exists,
+    TooManyFiles(u64),
+    Io(io::Error),
+}
+
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CopyError::NoSuchFile => f.write_str("no such file"),
+            CopyError::Exists => f.write_str("file exists"),
+            CopyError::TooManyFiles(max) => write!(f, "too many files, the limit is {max}"),
+            CopyError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<io::Error> for CopyError {
+    fn from(e: io::Error) -> Self {
+        CopyError::Io(e)
+    }
+}
+
+/// Why a GET found nothing to send.
+#[derive(Debug)]
+enum GetError {
+    NoSuchFile,
+    NoSuchRevision,
+    Io(io::Error),
+}
+
+impl From<io::Error> for GetError {
+    fn from(e: io::Error) -> Self {
+        GetError::Io(e)
+    }
+}
+
+/// Serves commands until the client leaves, or until shutdown starts while
+/// it's between commands.
+async fn handle(
+    stream: TcpStream,
+    vcs: Arc<Vcs>,
+    args: Arc<Args>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let max_file_size = args.max_file_size;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    // Set by AUTHOR and kept with every revision PUT after it.
+    let mut author: Option<String> = None;
+    // Set by AUTH, if tokens are needed.
+    let mut token: Option<String> = None;
+
+    loop {
+        LINES.write_line(&mut write, "READY").await?;
+        let line = tokio::select! {
+            line = LINES.read_line(&mut read) => line?,
+            () = shutdown.started() => return Ok(()),
+        };
+        let Some(line) = line else {
+            return Ok(());
+        };
+        vcs.metrics.commands.fetch_add(1, Relaxed);
+        let command = match command::parse(&line) {
+            Ok(Command::Delete { .. } | Command::DeleteDir(_)) if !args.allow_delete => {
+                LINES
+                    .write_line(&mut write, "ERR illegal method: DELETE")
+                    .await?;
+                continue;
+            }
+            Ok(Command::Auth(_)) if vcs.tokens.is_none() => {
+                LINES
+                    .write_line(&mut write, "ERR illegal method: AUTH")
+                    .await?;
+                continue;
+            }
+            Ok(command) => command,
+            Err(e) => {
+                LINES.write_line(&mut write, &format!("ERR {e}")).await?;
+                continue;
+            }
+        };
+        if let Some(tokens) = &vcs.tokens {
+            if let Err(e) = tokens.check(token.as_deref(), &command.writes()) {
+                if let Command::Put { len, .. } = command {
+                    read_body(&mut read, len, 0, false, |_| Ok(())).await?;
+                }
+                LINES.write_line(&mut write, &format!("ERR {e}")).await?;
+                continue;
+            }
+        }
+        match command {
+            Command::Put { path, len } => {
+                let mut upload = vcs.upload().await?;
+                let text_only = !args.allow_binary;
+                let body = read_body(&mut read, len, max_file_size, text_only, |chunk| {
+                    upload.write(chunk)
+                })
+                .await?;
+                match body {
+                    Body::Read => {}
+                    Body::TooLarge => {
+                        let e = format!("ERR file too large, the limit is {max_file_size} bytes");
+                        LINES.write_line(&mut write, &e).await?;
+                        continue;
+                    }
+                    Body::NotText => {
+                        LINES
+                            .write_line(&mut write, "ERR illegal file content")
+                            .await?;
+                        continue;
+                    }
+                }
+
+                vcs.metrics.puts.fetch_add(1, Relaxed);
+                let revision = vcs.put(path, upload, author.as_deref()).await;
+                match revision {
+                    Ok(revision) => {
+                        LINES
+                            .write_line(&mut write, &format!("OK r{revision}"))
+                            .await?;
+                    }
+                    Err(PutError::Io(e)) => return Err(e.into()),
+                    Err(e) => LINES.write_line(&mut write, &format!("ERR {e}")).await?,
+                }
+            }
+            Command::Get { path, selector } => {
+                vcs.metrics.gets.fetch_add(1, Relaxed);
+                let content = vcs
+                    .inspect(move |state| state.read(&path, selector))
+                    .await?;
+                match content {
+                    Ok((len, mut content)) => {
+                        LINES.write_line(&mut write, &format!("OK {len}")).await?;
+                        write_body(&mut write, len, &mut content).await?;
+                    }
+                    Err(GetError::NoSuchFile) => {
+                        LINES.write_line(&mut write, "ERR no such file").await?;
+                    }
+                    Err(GetError::NoSuchRevision) => {
+                        LINES.write_line(&mut write, "ERR no such revision").await?;
+                    }
+                    Err(GetError::Io(e)) => return Err(e.into()),
+                }
+            }
+            Command::List(dir) => {
+                let mut listing: Vec<Stat> =
+                    vcs.state.read().await.list(&dir).into_iter().collect();
+                listing.sort_unstable_by(|a, b| a.path().cmp(b.path()));
+                LINES
+                    .write_line(&mut write, &format!("OK {}", listing.len()))
+                    .await?;
+                for entry in listing {
+                    match entry {
+                        Stat::File { path, revision } => {
+                            LINES
+                                .write_line(&mut write, &format!("{path} r{revision}"))
+                                .await?;
+                        }
+                        Stat::Dir(path) => {
+                            LINES.write_line(&mut write, &format!("{path} DIR")).await?;
+                        }
+                    }
+                }
+            }
+            Command::Stat(path) => {
+                let info = vcs.inspect(move |state| state.stat(&path)).await?;
+                match info {
+                    Ok(info) => {
+                        let modified = info
+                            .modified
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs();
+                        let mut reply = format!("OK r{} {} {modified}", info.revisions, info.size);
+                        if let Some(author) = info.author {
+                            reply = format!("{reply} {author}");
+                        }
+                        LINES.write_line(&mut write, &reply).await?;
+                    }
+                    Err(GetError::Io(e)) => return Err(e.into()),
+                    Err(_) => LINES.write_line(&mut write, "ERR no such file").await?,
+                }
+            }
+            Command::Copy { ref from, ref to } | Command::Move { ref from, ref to } => {
+                let copy = matches!(command, Command::Copy { .. });
+                let paths = [from, to];
+                let (from, to) = (from.clone(), to.clone());
+                let done = vcs
+                    .change(&paths, move |state| {
+                        if copy {
+                            state.copy(&from, &to)
+                        } else {
+                            state.rename(&from, &to)
+                        }
+                    })
+                    .await?;
+                match done {
+                    Ok(()) => LINES.write_line(&mut write, "OK").await?,
+                    Err(CopyError::Io(e)) => return Err(e.into()),
+                    Err(e) => LINES.write_line(&mut write, &format!("ERR {e}")).await?,
+                }
+            }
+            Command::Grep { pattern, dir } => {
+                let found = vcs
+                    .inspect(move |state| {
+                        let found = state.grep(&dir, &pattern, MAX_GREP_MATCHES)?;
+                        let found = found
+                            .into_iter()
+                            .map(|(path, line)| format!("{path}:{line}"));
+                        io::Result::Ok(found.collect::<Vec<_>>())
+                    })
+                    .await??;
+                LINES
+                    .write_line(&mut write, &format!("OK {}", found.len()))
+                    .await?;
+                for line in found {
+                    LINES.write_line(&mut write, &line).await?;
+                }
+            }
+            Command::Author(name) => {
+                author = Some(name);
+                LINES.write_line(&mut write, "OK").await?;
+            }
+            Command::Auth(given) => {
+                let known = vcs
+                    .tokens
+                    .as_ref()
+                    .is_some_and(|tokens| tokens.contains(&given));
+                if known {
+                    token = Some(given);
+                    LINES.write_line(&mut write, "OK").await?;
+                } else {
+                    token = None;
+                    LINES.write_line(&mut write, "ERR bad token").await?;
+                }
+            }
+            Command::Watch(dir) => {
+                let mut revisions = vcs.state.write().await.watch(dir);
+                LINES.write_line(&mut write, "OK").await?;
+                loop {
+                    tokio::select! {
+                        revision = revisions.recv() => match revision {
+                            Ok((path, rev)) => {
+                                LINES.write_line(&mut write, &format!("{path} r{rev}")).await?;
+                            }
+                            Err(RecvError::Lagged(n)) => {
+                                let e = format!("ERR missed {n} revisions");
+                                LINES.write_line(&mut write, &e).await?;
+                            }
+                            Err(RecvError::Closed) => return Ok(()),
+                        },
+                        // Nothing more is read as a command, but this
+                        // notices the client hanging up.
+                        line = LINES.read_line(&mut read) => {
+                            if line?.is_none() {
+                                return Ok(());
+                            }
+                        }
+                        () = shutdown.started() => return Ok(()),
+                    }
+                }
+            }
+            Command::Delete { path, rev } => {
+                let locked = path.clone();
+                let deleted = vcs
+                    .change(&[&locked], move |state| state.delete(&path, rev))
+                    .await?;
+                match deleted {
+                    Ok(()) => LINES.write_line(&mut write, "OK").await?,
+                    Err(GetError::NoSuchFile) => {
+                        LINES.write_line(&mut write, "ERR no such file").await?;
+                    }
+                    Err(GetError::NoSuchRevision) => {
+                        LINES.write_line(&mut write, "ERR no such revision").await?;
+                    }
+                    Err(GetError::Io(e)) => return Err(e.into()),
+                }
+            }
+            Command::DeleteDir(dir) => {
+                let deleted = vcs
+                    .change_all(move |state| state.delete_dir(&dir))
+                    .await??;
+                if deleted == 0 {
+                    LINES.write_line(&mut write, "ERR no such dir").await?;
+                } else {
+                    LINES
+                        .write_line(&mut write, &format!("OK {deleted}"))
+                        .await?;
+                }
+            }
+            Command::Help => {
+                let mut usage =
+                    "OK usage: HELP|GET|PUT|LIST|STAT|COPY|MOVE|GREP|AUTHOR|WATCH".to_owned();
+                if vcs.tokens.is_some() {
+                    usage.push_str("|AUTH");
+                }
+                if args.allow_delete {
+                    usage.push_str("|DELETE");
+                }
+                LINES.write_line(&mut write, &usage).await?;
+            }
+        }
+    }
+}
+
+/// Reports the server's metrics and usage, with command rates since the
+/// last report. Usage is left out while a write holds the state.
+fn report_metrics(vcs: Arc<Vcs>) -> Reporting {
+    let (mut commands, mut puts, mut gets) = (0, 0, 0);
+    proto_common::report(move |elapsed: Duration| {
+        let usage = match vcs.state.try_read().map(|state| state.usage()) {
+            Ok(Ok(usage)) => usage.to_string(),
+            Ok(Err(e)) => format!("usage unknown: {e}"),
+            Err(_) => "usage unknown: state busy".to_owned(),
+        };
+        let metrics = &vcs.metrics;
+        let secs = elapsed.as_secs_f64();
+        let now = (
+            metrics.commands.load(Relaxed),
+            metrics.puts.load(Relaxed),
+            metrics.gets.load(Relaxed),
+        );
+        info!(
+            "metrics: {usage}, {metrics}, commands/s: {:.1}, puts/s: {:.1}, gets/s: {:.1}",
+            (now.0 - commands) as f64 / secs,
+            (now.1 - puts) as f64 / secs,
+            (now.2 - gets) as f64 / secs,
+        );
+        (commands, puts, gets) = now;
+    })
+}
+
+/// Deletes what `retention` drops every `GC_INTERVAL`.
+async fn collect_garbage(vcs: Arc<Vcs>, retention: Retention) {
+    let mut interval = tokio::time::interval(GC_INTERVAL);
+    loop {
+        interval.tick().await;
+        match vcs.gc(retention).await {
+            Ok((0, 0)) => {}
+            Ok((revisions, blobs)) => {
+                info!("gc: deleted {revisions} revisions, removed {blobs} blobs");
+            }
+            Err(e) => error!("gc failed: {e}"),
+        }
+    }
+}
+
+/// Runs an admin command on the store in `dir`.
+fn admin(admin: &Admin, dir: &Path) -> Result<()> {
+    let mut state = State::open(dir)?;
+    match admin {
+        Admin::Export { dump } => {
+            let mut w = io::BufWriter::new(std::fs::File::create(dump)?);
+            state.export(&mut w)?;
+            w.into_inner()?.sync_all()?;
+            println!("exported {} files", state.files.len());
+        }
+        Admin::Import { dump } => {
+            let mut r = io::BufReader::new(std::fs::File::open(dump)?);
+            println!("imported {} files", state.import(&mut r)?);
+        }
+    }
+    Ok(())
+}
+
+/// Serves until `stop` resolves, or runs the admin command asked for
+/// instead.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let args = Arc::new(args);
+    if let Some(command) = &args.admin {
+        let Some(dir) = &args.store else {
+            bail!("export and import need --store");
+        };
+        return admin(command, dir);
+    }
+    let state = match args.store.clone() {
+        Some(dir) => task::spawn_blocking(move || State::open(dir)).await??,
+        None => State::default(),
+    };
+    let state = state.with_limits(Limits {
+        revisions: args.max_revisions,
+        files: args.max_files,
+        stored_bytes: args.max_stored_bytes,
+    });
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    let mut vcs = Vcs::new(state);
+    if let Some(path) = &args.tokens {
+        let text = std::fs::read_to_string(path).with_context(|| format!("{path:?}"))?;
+        let tokens = Tokens::parse(&text).with_context(|| format!("{path:?}"))?;
+        vcs = vcs.with_tokens(tokens);
+    }
+    let vcs = Arc::new(vcs);
+    let retention = Retention {
+        keep: args.keep_revisions,
+        max_age: args.keep_secs.map(Duration::from_secs),
+    };
+    if !retention.is_empty() {
+        tokio::spawn(collect_garbage(vcs.clone(), retention).in_current_span());
+    }
+    let server = Server::new(list).with_limit(&args.limit);
+    let _reporting = report_metrics(vcs.clone());
+    server
+        .run(stop, |stream, _, shutdown| {
+            let conn = handle(stream, vcs.clone(), args.clone(), shutdown);
+            async move {
+                let _ = conn.await;
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> VcsPath {
+        VcsPath::file(path).unwrap()
+    }
+
+    use bytes::Bytes;
+
+    impl State {
+        /// Content of the revision of `path` that `selector` picks.
+        fn get(&self, path: &VcsPath, selector: Selector) -> Result<Bytes, GetError> {
+            let revision = self.find(path, selector)?;
+            Ok(self.store.get(&revision.hash)?)
+        }
+
+        /// Every part of a PUT at once.
+        fn put(
+            &mut self,
+            path: VcsPath,
+            content: Vec<u8>,
+            author: Option<&str>,
+        ) -> Result<u64, PutError> {
+            let mut upload = self.store.upload()?;
+            upload.write(&content)?;
+            match self.prepare_put(&path, &upload.hash())? {
+                Prepared::Unchanged(rev) => Ok(rev),
+                Prepared::New(base) => {
+                    let revision = store_put(&self.store, &path, upload, base, author)?;
+                    Ok(self.commit_put(path, revision))
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_listing() -> Result<()> {
+        let mut state = State::default();
+
+        state.put(file("/a"), vec![], None)?;
+        state.put(file("/b"), vec![], None)?;
+        state.put(file("/c/d"), vec![], None)?;
+
+        let expected = [
+            Stat::File {
+                path: "a".to_owned(),
+                revision: 1,
+            },
+            Stat::File {
+                path: "b".to_owned(),
+                revision: 1,
+            },
+            Stat::Dir("c/".to_owned()),
+        ];
+
+        assert_eq!(
+            expected.into_iter().collect::<BTreeSet<_>>(),
+            state.list(&VcsPath::dir("/").unwrap())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reopened_store_has_every_revision() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        assert_eq!(1, state.put(file("/a"), b"one\n".to_vec(), None)?);
+        assert_eq!(2, state.put(file("/a"), b"two\n".to_vec(), None)?);
+        assert_eq!(2, state.put(file("/a"), b"two\n".to_vec(), None)?);
+        assert_eq!(1, state.put(file("/b/c"), b"one\n".to_vec(), None)?);
+        drop(state);
+
+        let state = State::open(dir.path())?;
+        assert_eq!(
+            b"one\n".to_vec(),
+            state.get(&file("/a"), Selector::Number(1)).unwrap()
+        );
+        assert_eq!(
+            b"two\n".to_vec(),
+            state.get(&file("/a"), Selector::Head).unwrap()
+        );
+        assert_eq!(
+            b"one\n".to_vec(),
+            state.get(&file("/b/c"), Selector::Head).unwrap()
+        );
+        assert!(matches!(
+            state.get(&file("/a"), Selector::Number(3)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert!(matches!(
+            state.get(&file("/a"), Selector::Number(0)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert!(matches!(
+            state.get(&file("/x"), Selector::Head),
+            Err(GetError::NoSuchFile)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn stat_describes_the_newest_revision() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        let before = SystemTime::now();
+        state.put(file("/a"), b"one\n".to_vec(), None)?;
+        state.put(file("/a"), b"three\n".to_vec(), Some("bob"))?;
+        let info = state.stat(&file("/a")).unwrap();
+        assert_eq!((2, 6), (info.revisions, info.size));
+        assert_eq!(Some("bob"), info.author.as_deref());
+        assert!(info.modified >= before);
+        assert!(matches!(state.stat(&file("/b")), Err(GetError::NoSuchFile)));
+        drop(state);
+
+        let state = State::open(dir.path())?;
+        let reopened = state.stat(&file("/a")).unwrap();
+        let millis = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert_eq!(millis(info.modified), millis(reopened.modified));
+        assert_eq!(info.author, reopened.author);
+        Ok(())
+    }
+
+    #[test]
+    fn watchers_hear_of_new_revisions_under_their_dir() -> Result<()> {
+        let mut state = State::default();
+        let mut watched = state.watch(VcsPath::dir("/a").unwrap());
+        state.put(file("/a/b"), b"1\n".to_vec(), None)?;
+        state.put(file("/c"), b"1\n".to_vec(), None)?;
+        state.put(file("/ab"), b"1\n".to_vec(), None)?;
+        state.put(file("/a/d/e"), b"1\n".to_vec(), None)?;
+        state.put(file("/a/b"), b"1\n".to_vec(), None)?;
+        state.put(file("/a/b"), b"2\n".to_vec(), None)?;
+        assert_eq!((file("/a/b"), 1), watched.try_recv()?);
+        assert_eq!((file("/a/d/e"), 1), watched.try_recv()?);
+        assert_eq!((file("/a/b"), 2), watched.try_recv()?);
+        assert!(watched.try_recv().is_err());
+
+        drop(watched);
+        state.put(file("/a/b"), b"3\n".to_vec(), None)?;
+        assert!(state.watchers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn gc_deletes_what_retention_drops() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        for i in 1..=4 {
+            state.put(file("/a"), format!("{i}\n").into_bytes(), None)?;
+        }
+        state.put(file("/b"), b"1\n".to_vec(), None)?;
+        let now = SystemTime::now();
+        let young = Retention {
+            keep: Some(1),
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        assert_eq!((0, 0), state.gc(young, now)?);
+
+        let keep = Retention {
+            keep: Some(2),
+            max_age: None,
+        };
+        // The content of /a r1 is still that of /b.
+        assert_eq!((2, 1), state.gc(keep, now)?);
+        assert!(matches!(
+            state.get(&file("/a"), Selector::Number(2)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert_eq!(
+            &b"3\n"[..],
+            state.get(&file("/a"), Selector::Number(3)).unwrap()
+        );
+        drop(state);
+
+        let mut state = State::open(dir.path())?;
+        let old = Retention {
+            keep: None,
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        let later = now + Duration::from_secs(7200);
+        assert_eq!((1, 1), state.gc(old, later)?);
+        assert_eq!(4, state.stat(&file("/a")).unwrap().revisions);
+        assert_eq!(2, state.usage()?.revisions);
+        Ok(())
+    }
+
+    #[test]
+    fn exports_are_imported_as_they_were() -> Result<()> {
+        let mut state = State::default();
+        state.put(file("/a"), b"one\n".to_vec(), None)?;
+        state.put(file("/a"), b"two".to_vec(), Some("bob"))?;
+        state.put(file("/a"), vec![0, 255, b'\n'], None)?;
+        state.put(file("/b/c"), b"".to_vec(), None)?;
+        state.delete(&file("/a"), Some(1)).unwrap();
+        let mut dump = vec![];
+        state.export(&mut dump)?;
+
+        let dir = tempfile::tempdir()?;
+        let mut imported = State::open(dir.path())?;
+        assert_eq!(2, imported.import(&mut &dump[..])?);
+        drop(imported);
+        let imported = State::open(dir.path())?;
+        let mut again = vec![];
+        imported.export(&mut again)?;
+        assert_eq!(
+            String::from_utf8_lossy(&dump),
+            String::from_utf8_lossy(&again)
+        );
+        assert!(matches!(
+            imported.get(&file("/a"), Selector::Number(1)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert_eq!(
+            &b"two"[..],
+            imported.get(&file("/a"), Selector::Number(2)).unwrap()
+        );
+
+        let mut imported = imported;
+        assert!(imported.import(&mut &dump[..]).is_err());
+        for cut in [0, 11, dump.len() - 10, dump.len() - 2] {
+            assert!(State::default().import(&mut &dump[..cut]).is_err(), "{cut}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn revision_without_its_blob_is_an_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        state.put(file("/a"), b"gone\n".to_vec(), None)?;
+        drop(state);
+        let hash = Hash::of(b"gone\n").to_string();
+        std::fs::remove_file(dir.path().join("blobs").join(hash))?;
+        assert!(State::open(dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn usage_counts_logical_and_stored_bytes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        let text: Vec<u8> = (0..100)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let edited = [&text[..], b"one more\n"].concat();
+        state.put(file("/a"), text.clone(), None)?;
+        state.put(file("/a"), edited.clone(), None)?;
+        state.put(file("/b"), text.clone(), None)?;
+        state.put(file("/c"), b"gone\n".to_vec(), None)?;
+        state.delete(&file("/c"), None).unwrap();
+        let usage = state.usage()?;
+        assert_eq!((2, 3), (usage.files, usage.revisions));
+        assert_eq!(2 * text.len() + edited.len(), usage.logical_bytes as usize);
+        assert!(usage.stored_bytes < (text.len() + 64) as u64, "{usage}");
+        drop(state);
+
+        assert_eq!(usage, State::open(dir.path())?.usage()?);
+        Ok(())
+    }
+
+    #[test]
+    fn selectors_count_from_either_end() -> Result<()> {
+        let parse = |s: &str| s.parse::<Selector>().ok();
+        assert_eq!(Some(Selector::Head), parse("HEAD"));
+        assert_eq!(Some(Selector::Head), parse("head"));
+        assert_eq!(Some(Selector::Number(3)), parse("3"));
+        assert_eq!(Some(Selector::Back(1)), parse("-1"));
+        assert_eq!(None, parse("--1"));
+        assert_eq!(None, parse("HEAD-1"));
+        assert_eq!(None, parse(""));
+
+        let mut state = State::default();
+        for content in ["1\n", "2\n", "3\n"] {
+            state.put(file("/a"), content.into(), None)?;
+        }
+        let get = |selector| state.get(&file("/a"), selector);
+        assert_eq!(b"3\n".to_vec(), get(Selector::Head).unwrap());
+        assert_eq!(b"3\n".to_vec(), get(Selector::Back(0)).unwrap());
+        assert_eq!(b"2\n".to_vec(), get(Selector::Back(1)).unwrap());
+        assert_eq!(b"1\n".to_vec(), get(Selector::Back(2)).unwrap());
+        assert!(matches!(
+            get(Selector::Back(3)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert!(matches!(
+            get(Selector::Back(u64::MAX)),
+            Err(GetError::NoSuchRevision)
+        ));
+
+        state.delete(&file("/a"), Some(3)).unwrap();
+        state.delete(&file("/a"), Some(1)).unwrap();
+        assert_eq!(
+            b"2\n".to_vec(),
+            state.get(&file("/a"), Selector::Head).unwrap()
+        );
+        assert!(matches!(
+            state.get(&file("/a"), Selector::Back(1)),
+            Err(GetError::NoSuchRevision)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_revisions_keep_the_numbers_of_the_rest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        for content in ["1\n", "2\n", "3\n"] {
+            state.put(file("/a"), content.into(), None)?;
+        }
+        state.put(file("/d/b"), b"b\n".to_vec(), None)?;
+        state.put(file("/d/e/c"), b"c\n".to_vec(), None)?;
+        state.put(file("/dd"), b"dd\n".to_vec(), None)?;
+
+        state.delete(&file("/a"), Some(3)).unwrap();
+        assert!(matches!(
+            state.delete(&file("/a"), Some(3)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert_eq!(
+            b"2\n".to_vec(),
+            state.get(&file("/a"), Selector::Head).unwrap()
+        );
+        assert_eq!(2, state.stat(&file("/a")).unwrap().revisions);
+        assert_eq!(4, state.put(file("/a"), b"4\n".to_vec(), None)?);
+        state.delete(&file("/a"), Some(1)).unwrap();
+        assert!(matches!(
+            state.get(&file("/a"), Selector::Number(1)),
+            Err(GetError::NoSuchRevision)
+        ));
+        assert_eq!(2, state.delete_dir(&VcsPath::dir("/d/").unwrap())?);
+        assert_eq!(0, state.delete_dir(&VcsPath::dir("/d/").unwrap())?);
+        drop(state);
+
+        let mut state = State::open(dir.path())?;
+        assert_eq!(
+            b"2\n".to_vec(),
+            state.get(&file("/a"), Selector::Number(2)).unwrap()
+        );
+        assert_eq!(
+            b"4\n".to_vec(),
+            state.get(&file("/a"), Selector::Head).unwrap()
+        );
+        assert!(matches!(
+            state.get(&file("/d/b"), Selector::Head),
+            Err(GetError::NoSuchFile)
+        ));
+        assert_eq!(
+            b"dd\n".to_vec(),
+            state.get(&file("/dd"), Selector::Head).unwrap()
+        );
+        state.delete(&file("/a"), Some(2)).unwrap();
+        state.delete(&file("/a"), Some(4)).unwrap();
+        assert!(matches!(
+            state.get(&file("/a"), Selector::Head),
+            Err(GetError::NoSuchFile)
+        ));
+        assert_eq!(1, state.put(file("/a"), b"new\n".to_vec(), None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn copies_and_moves_take_the_whole_history() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut state = State::open(dir.path())?;
+        for content in ["1\n", "2\n", "3\n"] {
+            state.put(file("/a"), content.into(), None)?;
+        }
+        state.delete(&file("/a"), Some(2)).unwrap();
+        state.copy(&file("/a"), &file("/b/c")).unwrap();
+        assert!(matches!(
+            state.copy(&file("/a"), &file("/b/c")),
+            Err(CopyError::Exists)
+        ));
+        assert!(matches!(
+            state.rename(&file("/x"), &file("/y")),
+            Err(CopyError::NoSuchFile)
+        ));
+        state.rename(&file("/a"), &file("/d")).unwrap();
+        assert_eq!(4, state.put(file("/d"), b"4\n".to_vec(), None)?);
+        drop(state);
+
+        let state = State::open(dir.path())?;
+        let get = |path, rev| state.get(&file(path), Selector::Number(rev));
+        assert!(matches!(get("/a", 1), Err(GetError::NoSuchFile)));
+        assert_eq!(b"1\n".to_vec(), get("/b/c", 1).unwrap());
+        assert!(matches!(get("/b/c", 2), Err(GetError::NoSuchRevision)));
+        assert_eq!(b"3\n".to_vec(), get("/b/c", 3).unwrap());
+        assert!(matches!(get("/b/c", 4), Err(GetError::NoSuchRevision)));
+        assert_eq!(b"4\n".to_vec(), get("/d", 4).unwrap());
+        let root = state.list(&VcsPath::dir("/").unwrap());
+        let expected = [
+            Stat::Dir("b/".to_owned()),
+            Stat::File {
+                path: "d".to_owned(),
+                revision: 4,
+            },
+        ];
+        assert_eq!(expected.into_iter().collect::<BTreeSet<_>>(), root);
+        Ok(())
+    }
+
+    #[test]
+    fn grep_finds_lines_of_the_newest_revisions() -> Result<()> {
+        let mut state = State::default();
+        state.put(file("/d/a"), b"foo\nbar\nfoo bar\n".to_vec(), None)?;
+        state.put(file("/d/b"), b"foo\n".to_vec(), None)?;
+        state.put(file("/d/b"), b"nothing\nfoo".to_vec(), None)?;
+        state.put(file("/d/e/c"), b"foo\n".to_vec(), None)?;
+        state.put(file("/dd"), b"foo\n".to_vec(), None)?;
+        state.put(file("/d/empty"), vec![], None)?;
+
+        let grep = |dir, pattern, regex, max| {
+            let pattern = match command::parse(&format!("GREP {regex} {pattern} {dir}")) {
+                Ok(Command::Grep { pattern, .. }) => pattern,
+                _ => unreachable!(),
+            };
+            let found = state
+                .grep(&VcsPath::dir(dir).unwrap(), &pattern, max)
+                .unwrap();
+            found
+                .into_iter()
+                .map(|(path, line)| format!("{path}:{line}"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["/d/a:1", "/d/a:3", "/d/b:2", "/d/e/c:1"],
+            grep("/d", "foo", "", 100)
+        );
+        assert_eq!(vec!["/d/a:1", "/d/a:3"], grep("/d", "foo", "", 2));
+        assert_eq!(vec!["/d/a:2"], grep("/d", "^bar$", "-E", 100));
+        assert_eq!(Vec::<String>::new(), grep("/d", "^bar$", "", 100));
+        assert_eq!(
+            vec!["/d/a:1", "/d/a:3", "/d/b:1", "/d/b:2", "/d/e/c:1", "/dd:1"],
+            grep("/", "o", "", 100)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn puts_over_the_limits_are_refused() -> Result<()> {
+        let mut state = State::default().with_limits(Limits {
+            revisions: 2,
+            files: 2,
+            stored_bytes: 100,
+        });
+        state.put(file("/a"), b"1\n".to_vec(), None)?;
+        state.put(file("/a"), b"2\n".to_vec(), None)?;
+        assert_eq!(2, state.put(file("/a"), b"2\n".to_vec(), None)?);
+        assert!(matches!(
+            state.put(file("/a"), b"3\n".to_vec(), None),
+            Err(PutError::TooManyRevisions(2))
+        ));
+        state.put(file("/b"), b"1\n".to_vec(), None)?;
+        assert!(matches!(
+            state.put(file("/c"), b"1\n".to_vec(), None),
+            Err(PutError::TooManyFiles(2))
+        ));
+        state.delete(&file("/b"), None).unwrap();
+        let big = vec![b'x'; 100];
+        assert!(matches!(
+            state.put(file("/c"), big, None),
+            Err(PutError::StoreFull(100))
+        ));
+        assert!(matches!(
+            state.get(&file("/c"), Selector::Head),
+            Err(GetError::NoSuchFile)
+        ));
+        state.put(file("/c"), b"1\n".to_vec(), None)?;
+        Ok(())
+    }
+
+    /// Has `writers` tasks PUT to a few paths at once while another keeps
+    /// looking at the newest revisions, and checks every path got dense
+    /// revision numbers in the order each writer made its PUTs.
+    async fn put_concurrently(vcs: Arc<Vcs>, writers: usize, puts: usize) {
+        let paths: Vec<VcsPath> = (0..3).map(|i| file(&format!("/d/f{i}"))).collect();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = tokio::spawn({
+            let (vcs, paths, done) = (vcs.clone(), paths.clone(), done.clone());
+            async move {
+                let mut seen = vec![0; paths.len()];
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    for (path, seen) in paths.iter().zip(&mut seen) {
+                        if let Ok(info) = vcs.state.read().await.stat(path) {
+                            assert!(info.revisions >= *seen, "{path} went back");
+                            *seen = info.revisions;
+                        }
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for writer in 0..writers {
+            let (vcs, paths) = (vcs.clone(), paths.clone());
+            tasks.spawn(async move {
+                let mut made = vec![];
+                for i in 0..puts {
+                    let path = paths[(writer + i) % paths.len()].clone();
+                    let content = format!("{writer} {i}\n").repeat(1 + i % 5);
+                    let mut upload = vcs.upload().await.unwrap();
+                    upload.write(content.as_bytes()).unwrap();
+                    let rev = vcs.put(path.clone(), upload, None).await.unwrap();
+                    made.push((path, rev, content));
+                }
+                made
+            });
+        }
+        let mut revs: HashMap<VcsPath, Vec<u64>> = HashMap::new();
+        while let Some(made) = tasks.join_next().await {
+            let made = made.unwrap();
+            for path in &paths {
+                let mine: Vec<u64> = made
+                    .iter()
+                    .filter(|(p, _, _)| p == path)
+                    .map(|&(_, rev, _)| rev)
+                    .collect();
+                assert!(mine.windows(2).all(|w| w[0] < w[1]), "{mine:?}");
+            }
+            let state = vcs.state.read().await;
+            for (path, rev, content) in made {
+                let got = state.get(&path, Selector::Number(rev)).unwrap();
+                assert_eq!(content.as_bytes(), &got[..]);
+                revs.entry(path).or_default().push(rev);
+            }
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        reader.await.unwrap();
+
+        assert_eq!(writers * puts, revs.values().map(Vec::len).sum::<usize>());
+        for (path, mut revs) in revs {
+            revs.sort_unstable();
+            let dense: Vec<u64> = (1..=revs.len() as u64).collect();
+            assert_eq!(dense, revs, "{path}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_puts_are_numbered_densely_in_order() {
+        put_concurrently(Arc::new(Vcs::new(State::default())), 8, 60).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_puts_to_disk_are_numbered_densely_in_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let vcs = Arc::new(Vcs::new(State::open(dir.path())?));
+        put_concurrently(vcs.clone(), 4, 20).await;
+        let before = vcs.state.read().await.list(&VcsPath::dir("/d").unwrap());
+        drop(vcs);
+        let state = State::open(dir.path())?;
+        assert_eq!(before, state.list(&VcsPath::dir("/d").unwrap()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bodies_are_read_whole_even_when_refused() -> Result<()> {
+        let input = b"hello\nworld\nGET /a\n";
+        let mut r = &input[..];
+        let mut kept = vec![];
+        let keep = |kept: &mut Vec<u8>, chunk: &[u8]| {
+            kept.extend_from_slice(chunk);
+            Ok(())
+        };
+        let body = read_body(&mut r, 6, 6, true, |c| keep(&mut kept, c)).await?;
+        assert_eq!((Body::Read, &b"hello\n"[..]), (body, &kept[..]));
+        kept.clear();
+        let body = read_body(&mut r, 6, 5, true, |c| keep(&mut kept, c)).await?;
+        assert_eq!((Body::TooLarge, &b""[..]), (body, &kept[..]));
+        assert_eq!(b"GET /a\n", r);
+
+        let mut input = vec![b'a'; CHUNK];
+        input.push(0);
+        input.extend_from_slice(&[b'b'; CHUNK]);
+        input.extend_from_slice(b"rest");
+        let mut r = &input[..];
+        let len = 2 * CHUNK as u64 + 1;
+        let body = read_body(&mut r, len, u64::MAX, true, |c| keep(&mut kept, c)).await?;
+        assert_eq!(Body::NotText, body);
+        assert_eq!(vec![b'a'; CHUNK], kept);
+        assert_eq!(b"rest", r);
+
+        let mut r = &input[..];
+        kept.clear();
+        let body = read_body(&mut r, len, u64::MAX, false, |c| keep(&mut kept, c)).await?;
+        assert_eq!((Body::Read, &input[..len as usize]), (body, &kept[..]));
+        assert_eq!(b"rest", r);
+
+        let mut r = &b"short"[..];
+        assert!(read_body(&mut r, 6, 6, false, |_| Ok(())).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn large_files_stream_through_the_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let vcs = Arc::new(Vcs::new(State::open(dir.path())?));
+        let line = b"0123456789abcdef0123456789abcdef0123456789abcdef012345678\n";
+        let content = line.repeat(3 * 1024 * 1024 / line.len());
+        let list = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(list.local_addr()?).await?;
+        let (server, _) = list.accept().await?;
+        let args = Arc::new(Args::parse_from(["p10"]));
+        tokio::spawn(handle(server, vcs.clone(), args, Shutdown::never()));
+
+        let put = format!("PUT /big {}\n", content.len());
+        client.write_all(put.as_bytes()).await?;
+        client.write_all(&content).await?;
+        client.write_all(b"GET /big\n").await?;
+        let mut r = BufReader::new(client);
+        let mut replies = vec![];
+        for _ in 0..4 {
+            replies.push(LINES.read_line(&mut r).await?.unwrap());
+        }
+        let ok = format!("OK {}", content.len());
+        assert_eq!(vec!["READY", "OK r1", "READY", ok.as_str()], replies);
+        let mut got = vec![0; content.len()];
+        r.read_exact(&mut got).await?;
+        assert!(got == content);
+        let blobs: Vec<_> = std::fs::read_dir(dir.path().join("blobs"))?.collect();
+        assert_eq!(1, blobs.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Counters of what the server does and how much it keeps, for watching
+//! it over a long run.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Commands handled, updated as they come in.
+#[derive(Default)]
+pub struct Metrics {
+    /// Every request line, whatever came of it.
+    pub commands: AtomicU64,
+    /// PUTs that made it to storing their content.
+    pub puts: AtomicU64,
+    pub gets: AtomicU64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "commands: {}, puts: {}, gets: {}",
+            self.commands.load(Relaxed),
+            self.puts.load(Relaxed),
+            self.gets.load(Relaxed),
+        )
+    }
+}
+
+/// How much the server keeps at a moment.
+#[derive(Debug, PartialEq)]
+pub struct Usage {
+    pub files: u64,
+    /// Revisions of every file, not counting deleted ones.
+    pub revisions: u64,
+    /// Total size of those revisions, as they'd be sent by GET.
+    pub logical_bytes: u64,
+    /// What they take in the store, deduplicated and stored as deltas.
+    pub stored_bytes: u64,
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "files: {}, revisions: {}, logical bytes: {}, stored bytes: {}",
+            self.files, self.revisions, self.logical_bytes, self.stored_bytes,
+        )?;
+        if self.stored_bytes > 0 {
+            let ratio = self.logical_bytes as f64 / self.stored_bytes as f64;
+            write!(f, " ({ratio:.1}x)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_shows_how_much_storing_saves() {
+        let mut usage = Usage {
+            files: 2,
+            revisions: 3,
+            logical_bytes: 300,
+            stored_bytes: 120,
+        };
+        assert_eq!(
+            "files: 2, revisions: 3, logical bytes: 300, stored bytes: 120 (2.5x)",
+            usage.to_string()
+        );
+        usage.stored_bytes = 0;
+        assert!(usage.to_string().ends_with("stored bytes: 0"));
+    }
+}
@@ -0,0 +1,237 @@
+//! Paths of files and directories, checked and normalized once where
+//! they come in so the rest of the server can take them as they are.
+
+use std::fmt;
+
+/// Longest path accepted, in bytes, before normalization.
+pub const MAX_LEN: usize = 1024;
+
+/// Most components a path may have after normalization.
+pub const MAX_DEPTH: usize = 32;
+
+/// An absolute path in normal form: `/` alone, or `/` and components
+/// joined by single slashes, none of them `.` or `..` and none ending
+/// with a slash.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VcsPath(String);
+
+/// Why a path was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PathError {
+    NotAbsolute,
+    /// A component has a character other than ASCII letters, digits,
+    /// `.`, `_` and `-`.
+    IllegalChar,
+    /// `..` goes above the root.
+    AboveRoot,
+    TooLong,
+    TooDeep,
+    /// A file name that ends with a slash or names the root.
+    NotAFile,
+    /// A file name with two slashes in a row, which the protocol doesn't
+    /// allow even though they'd normalize away.
+    EmptyComponent,
+}
+
+impl VcsPath {
+    /// Normalizes the path of a file: the root and paths ending with a
+    /// slash aren't files. Unlike a directory's, a file's path may not
+    /// have empty components.
+    pub fn file(path: &str) -> Result<VcsPath, PathError> {
+        let normal = VcsPath::dir(path)?;
+        if path.contains("//") {
+            return Err(PathError::EmptyComponent);
+        }
+        if path.ends_with('/') || normal.is_root() {
+            return Err(PathError::NotAFile);
+        }
+        Ok(normal)
+    }
+
+    /// Normalizes the path of a directory, with or without a slash at the
+    /// end. Empty and `.` components are dropped and `..` drops the one
+    /// before it.
+    pub fn dir(path: &str) -> Result<VcsPath, PathError> {
+        if path.len() > MAX_LEN {
+            return Err(PathError::TooLong);
+        }
+        let rest = path.strip_prefix('/').ok_or(PathError::NotAbsolute)?;
+        let mut components = vec![];
+        for component in rest.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop().ok_or(PathError::AboveRoot)?;
+                }
+                _ if component.chars().all(legal_char) => components.push(component),
+                _ => return Err(PathError::IllegalChar),
+            }
+        }
+        if components.len() > MAX_DEPTH {
+            return Err(PathError::TooDeep);
+        }
+        Ok(VcsPath(format!("/{}", components.join("/"))))
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0 == "/"
+    }
+
+    /// The directories this path is under, from the root down.
+    pub fn dirs(&self) -> impl Iterator<Item = VcsPath> + '_ {
+        let ends = self.0.match_indices('/').map(|(i, _)| i.max(1));
+        ends.map(|end| VcsPath(self.0[..end].to_owned()))
+    }
+
+    /// What's left of this path under `dir`, without a leading slash, or
+    /// `None` if it isn't under it.
+    pub fn strip_dir(&self, dir: &VcsPath) -> Option<&str> {
+        let rest = if dir.is_root() {
+            &self.0[1..]
+        } else {
+            self.0.strip_prefix(&dir.0)?.strip_prefix('/')?
+        };
+        Some(rest).filter(|rest| !rest.is_empty())
+    }
+}
+
+impl fmt::Display for VcsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn legal_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn files_are_normalized() {
+        let cases = [
+            ("/a", "/a"),
+            ("/a/b.txt", "/a/b.txt"),
+            ("/./a/./b", "/a/b"),
+            ("/a/../b", "/b"),
+            ("/a/b/../../c", "/c"),
+            ("/a/b/..", "/a"),
+            ("/a/.", "/a"),
+            ("/...", "/..."),
+            ("/.a/b..", "/.a/b.."),
+            ("/A-Z_0-9", "/A-Z_0-9"),
+        ];
+        for (path, normal) in cases {
+            assert_eq!(
+                Ok(normal.to_owned()),
+                VcsPath::file(path).map(|path| path.to_string()),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn puts_of_files_with_empty_components_are_illegal() {
+        let e = crate::command::parse("PUT /a//b 1").unwrap_err();
+        assert_eq!("ERR illegal file name", format!("ERR {e}"));
+    }
+
+    #[test]
+    fn bad_files_are_refused() {
+        let cases = [
+            ("", PathError::NotAbsolute),
+            ("a", PathError::NotAbsolute),
+            ("a/b", PathError::NotAbsolute),
+            ("/", PathError::NotAFile),
+            ("//", PathError::EmptyComponent),
+            ("/a//b", PathError::EmptyComponent),
+            ("//a", PathError::EmptyComponent),
+            ("/a/.//b", PathError::EmptyComponent),
+            ("/a/", PathError::NotAFile),
+            ("/a/..", PathError::NotAFile),
+            ("/..", PathError::AboveRoot),
+            ("/a/../..", PathError::AboveRoot),
+            ("/../a", PathError::AboveRoot),
+            ("/a b", PathError::IllegalChar),
+            ("/a;", PathError::IllegalChar),
+            ("/a\\b", PathError::IllegalChar),
+            ("/é", PathError::IllegalChar),
+            ("/a*", PathError::IllegalChar),
+        ];
+        for (path, e) in cases {
+            assert_eq!(Err(e), VcsPath::file(path), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn dirs_are_normalized() {
+        let cases = [
+            ("/", "/"),
+            ("//", "/"),
+            ("/.", "/"),
+            ("/a/..", "/"),
+            ("/a", "/a"),
+            ("/a/", "/a"),
+            ("/a//b//", "/a/b"),
+        ];
+        for (path, normal) in cases {
+            assert_eq!(
+                Ok(normal.to_owned()),
+                VcsPath::dir(path).map(|path| path.to_string()),
+                "{path}"
+            );
+        }
+        assert_eq!(Err(PathError::NotAbsolute), VcsPath::dir("a/"));
+        assert_eq!(Err(PathError::AboveRoot), VcsPath::dir("/.."));
+    }
+
+    #[test]
+    fn length_and_depth_are_limited() {
+        let long = format!("/{}", "a".repeat(MAX_LEN - 1));
+        assert!(VcsPath::file(&long).is_ok());
+        assert_eq!(Err(PathError::TooLong), VcsPath::file(&format!("{long}a")));
+
+        let deep = "/a".repeat(MAX_DEPTH);
+        assert!(VcsPath::file(&deep).is_ok());
+        assert_eq!(Err(PathError::TooDeep), VcsPath::file(&format!("{deep}/a")));
+        assert!(VcsPath::file(&format!("{deep}/a/..")).is_ok());
+    }
+
+    #[test]
+    fn paths_are_stripped_of_dirs() {
+        let file = VcsPath::file("/a/b/c").unwrap();
+        let dir = |path| VcsPath::dir(path).unwrap();
+        assert_eq!(Some("a/b/c"), file.strip_dir(&dir("/")));
+        assert_eq!(Some("b/c"), file.strip_dir(&dir("/a")));
+        assert_eq!(Some("c"), file.strip_dir(&dir("/a/b/")));
+        assert_eq!(None, file.strip_dir(&dir("/a/b/c")));
+        assert_eq!(None, file.strip_dir(&dir("/a/bb")));
+        assert_eq!(None, file.strip_dir(&dir("/a/b/c/d")));
+        assert_eq!(None, VcsPath::file("/ab").unwrap().strip_dir(&dir("/a")));
+    }
+
+    #[test]
+    fn files_are_under_every_dir_above_them() {
+        let dirs = |path| -> Vec<String> {
+            let path = VcsPath::file(path).unwrap();
+            path.dirs().map(|dir| dir.to_string()).collect()
+        };
+        assert_eq!(vec!["/"], dirs("/a"));
+        assert_eq!(vec!["/", "/a", "/a/b"], dirs("/a/b/c"));
+    }
+
+    proptest! {
+        #[test]
+        fn normal_form_is_a_fixed_point(path in "(/(a|b|\\.|\\.\\.|)){0,8}") {
+            if let Ok(normal) = VcsPath::dir(&path) {
+                let s = normal.to_string();
+                prop_assert_eq!(Ok(normal.clone()), VcsPath::dir(&s));
+                prop_assert!(!s.contains("//"));
+                prop_assert!(normal.is_root() || !s.ends_with('/'));
+            }
+        }
+    }
+}
@@ -0,0 +1,964 @@
+use crate::delta;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// SHA-256 of a blob, naming it in the store.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Hash([u8; 32]);
+
+impl Hash {
+    pub fn of(content: &[u8]) -> Hash {
+        Hash(Sha256::digest(content).into())
+    }
+
+    fn parse(hex: &str) -> Option<Hash> {
+        if hex.len() != 64 {
+            return None;
+        }
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+        }
+        Some(Hash(hash))
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// One revision of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revision {
+    pub hash: Hash,
+    /// When it was made.
+    pub at: SystemTime,
+    /// Who made it, if they said; a single word.
+    pub author: Option<String>,
+}
+
+/// A change to the files, as kept in the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// `path` got a new revision.
+    Put(String, Revision),
+    /// Revision `n` of `path` was deleted, counting from 1, or the whole
+    /// file with `None`.
+    Delete(String, Option<u64>),
+    /// Every revision of the first path was copied to the second.
+    Copy(String, String),
+    /// Every revision of the first path was moved to the second.
+    Move(String, String),
+}
+
+impl Change {
+    /// `<hash> <path> <unix millis>[ <author>]` for a revision,
+    /// `delete <path>[ r<n>]` for a deletion and `copy <from> <to>` or
+    /// `move <from> <to>` for the others.
+    fn to_line(&self) -> String {
+        match self {
+            Change::Put(path, revision) => {
+                let millis = revision
+                    .at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                match &revision.author {
+                    Some(author) => format!("{} {path} {millis} {author}\n", revision.hash),
+                    None => format!("{} {path} {millis}\n", revision.hash),
+                }
+            }
+            Change::Delete(path, Some(n)) => format!("delete {path} r{n}\n"),
+            Change::Delete(path, None) => format!("delete {path}\n"),
+            Change::Copy(from, to) => format!("copy {from} {to}\n"),
+            Change::Move(from, to) => format!("move {from} {to}\n"),
+        }
+    }
+
+    /// Reads back what `to_line` wrote. Lines of stores made before times
+    /// were recorded have just the hash and path, and get the epoch.
+    fn parse_line(line: &str) -> Option<Change> {
+        let mut fields = line.strip_suffix('\n')?.split(' ');
+        let first = fields.next()?;
+        let path = fields.next()?.to_owned();
+        let change = if first == "delete" {
+            let n = match fields.next() {
+                Some(n) => Some(n.strip_prefix('r')?.parse().ok()?),
+                None => None,
+            };
+            Change::Delete(path, n)
+        } else if first == "copy" {
+            Change::Copy(path, fields.next()?.to_owned())
+        } else if first == "move" {
+            Change::Move(path, fields.next()?.to_owned())
+        } else {
+            let hash = Hash::parse(first)?;
+            let at = match fields.next() {
+                Some(millis) => UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?),
+                None => UNIX_EPOCH,
+            };
+            let author = fields.next().map(str::to_owned);
+            Change::Put(path, Revision { hash, at, author })
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(change)
+    }
+}
+
+/// A blob being written a piece at a time. It's not in the storage until
+/// it's finished, and what was written is thrown away if it's dropped
+/// before that.
+pub trait PartialBlob: Send {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+    /// Everything written so far.
+    fn read(&self) -> io::Result<Vec<u8>>;
+    /// Stores what was written as the blob with hash `hash`, unless it's
+    /// already there.
+    fn finish(self: Box<Self>, hash: &Hash) -> io::Result<()>;
+}
+
+/// Where file contents live. Contents are stored once per distinct blob,
+/// however many revisions of however many files share them, and every
+/// change is recorded so the index can be rebuilt on startup.
+///
+/// Blobs may be put from many threads at once; changes are recorded by
+/// one at a time, in order.
+pub trait Storage: Send + Sync {
+    /// Stores `content`, whose hash is `hash`, unless it's already there.
+    fn put(&self, hash: &Hash, content: &[u8]) -> io::Result<()> {
+        if self.contains(hash) {
+            return Ok(());
+        }
+        let mut blob = self.create()?;
+        blob.write(content)?;
+        blob.finish(hash)
+    }
+    /// Starts a blob to be written a piece at a time.
+    fn create(&self) -> io::Result<Box<dyn PartialBlob>>;
+    fn get(&self, hash: &Hash) -> io::Result<Bytes>;
+    /// The blob with hash `hash`, to be read a piece at a time, and its
+    /// size.
+    fn open(&self, hash: &Hash) -> io::Result<(u64, Box<dyn Read + Send>)>;
+    fn contains(&self, hash: &Hash) -> bool;
+    /// Total size of the blobs stored.
+    fn stored_bytes(&self) -> u64;
+    /// Every blob stored.
+    fn hashes(&self) -> io::Result<Vec<Hash>>;
+    /// Removes a blob, if it's there. Only `Store::compact` does, once no
+    /// revision needs it.
+    fn remove(&self, hash: &Hash) -> io::Result<()>;
+    /// Records `change`. The content of a new revision must already be
+    /// stored.
+    fn record(&self, change: &Change) -> io::Result<()>;
+}
+
+/// Keeps everything in memory; it's gone once the server stops.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    blobs: Arc<RwLock<HashMap<Hash, Bytes>>>,
+    bytes: Arc<AtomicU64>,
+}
+
+/// A blob being written to a `MemoryStorage`.
+struct MemoryBlob {
+    content: Vec<u8>,
+    blobs: Arc<RwLock<HashMap<Hash, Bytes>>>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl PartialBlob for MemoryBlob {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.content.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read(&self) -> io::Result<Vec<u8>> {
+        Ok(self.content.clone())
+    }
+
+    fn finish(self: Box<Self>, hash: &Hash) -> io::Result<()> {
+        let MemoryBlob {
+            content,
+            blobs,
+            bytes,
+        } = *self;
+        blobs.write().unwrap().entry(*hash).or_insert_with(|| {
+            bytes.fetch_add(content.len() as u64, Ordering::Relaxed);
+            Bytes::from(content)
+        });
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self) -> io::Result<Box<dyn PartialBlob>> {
+        Ok(Box::new(MemoryBlob {
+            content: vec![],
+            blobs: self.blobs.clone(),
+            bytes: self.bytes.clone(),
+        }))
+    }
+
+    fn get(&self, hash: &Hash) -> io::Result<Bytes> {
+        self.blobs
+            .read()
+            .unwrap()
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no blob {hash}")))
+    }
+
+    fn open(&self, hash: &Hash) -> io::Result<(u64, Box<dyn Read + Send>)> {
+        let blob = self.get(hash)?;
+        Ok((blob.len() as u64, Box::new(io::Cursor::new(blob))))
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.blobs.read().unwrap().contains_key(hash)
+    }
+
+    fn stored_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn hashes(&self) -> io::Result<Vec<Hash>> {
+        Ok(self.blobs.read().unwrap().keys().copied().collect())
+    }
+
+    fn remove(&self, hash: &Hash) -> io::Result<()> {
+        if let Some(blob) = self.blobs.write().unwrap().remove(hash) {
+            self.bytes.fetch_sub(blob.len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn record(&self, _: &Change) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps each blob in a file of its own under `blobs/`, named by its hash,
+/// and every change to the files in `index`, one line each in the order
+/// they were made.
+///
+/// A blob is written to a temporary file, synced and renamed into place
+/// before the revision using it goes into the index, and the index is
+/// synced after every line, so a crash loses at most the revision being
+/// made. Whatever it left behind is cleaned up by `open`. Blobs are only
+/// removed once the deletions of the revisions using them are recorded.
+pub struct DiskStorage {
+    blobs: PathBuf,
+    index: Mutex<File>,
+    /// Two puts of the same new blob at once may both count it, until the
+    /// store is opened again.
+    bytes: Arc<AtomicU64>,
+}
+
+/// Numbers the temporary files of blobs being written, so no two write to
+/// the same file.
+static TMP_NUMBER: AtomicU64 = AtomicU64::new(0);
+
+/// A blob being written to a temporary file under `blobs/`.
+struct DiskBlob {
+    tmp: PathBuf,
+    file: File,
+    len: u64,
+    blobs: PathBuf,
+    bytes: Arc<AtomicU64>,
+    /// Set once the file is renamed into place.
+    stored: bool,
+}
+
+impl PartialBlob for DiskBlob {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn read(&self) -> io::Result<Vec<u8>> {
+        fs::read(&self.tmp)
+    }
+
+    fn finish(mut self: Box<Self>, hash: &Hash) -> io::Result<()> {
+        let path = self.blobs.join(hash.to_string());
+        if !path.exists() {
+            self.file.sync_all()?;
+            fs::rename(&self.tmp, &path)?;
+            self.stored = true;
+            File::open(&self.blobs)?.sync_all()?;
+            self.bytes.fetch_add(self.len, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskBlob {
+    fn drop(&mut self) {
+        if !self.stored {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+impl DiskStorage {
+    /// Opens the store in `dir`, creating it if needed, and returns it with
+    /// every recorded change in the order they were made.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<(DiskStorage, Vec<Change>)> {
+        let dir = dir.as_ref();
+        let blobs = dir.join("blobs");
+        fs::create_dir_all(&blobs)?;
+        let mut bytes = 0;
+        for entry in fs::read_dir(&blobs)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                fs::remove_file(path)?;
+            } else {
+                bytes += entry.metadata()?.len();
+            }
+        }
+
+        let mut index = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join("index"))?;
+        let mut changes = vec![];
+        let mut good = 0;
+        let mut reader = BufReader::new(&index);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            // Anything after the last whole line is a write the crash cut off.
+            let Some(change) = Change::parse_line(&line) else {
+                break;
+            };
+            changes.push(change);
+            good += line.len() as u64;
+            line.clear();
+        }
+        if good < index.seek(SeekFrom::End(0))? {
+            index.set_len(good)?;
+            index.sync_all()?;
+        }
+        Ok((
+            DiskStorage {
+                blobs,
+                index: Mutex::new(index),
+                bytes: Arc::new(AtomicU64::new(bytes)),
+            },
+            changes,
+        ))
+    }
+}
+
+impl Storage for DiskStorage {
+    fn create(&self) -> io::Result<Box<dyn PartialBlob>> {
+        let n = TMP_NUMBER.fetch_add(1, Ordering::Relaxed);
+        let tmp = self.blobs.join(format!("{n}.tmp"));
+        Ok(Box::new(DiskBlob {
+            file: File::create(&tmp)?,
+            tmp,
+            len: 0,
+            blobs: self.blobs.clone(),
+            bytes: self.bytes.clone(),
+            stored: false,
+        }))
+    }
+
+    fn get(&self, hash: &Hash) -> io::Result<Bytes> {
+        fs::read(self.blobs.join(hash.to_string())).map(Bytes::from)
+    }
+
+    fn open(&self, hash: &Hash) -> io::Result<(u64, Box<dyn Read + Send>)> {
+        let file = File::open(self.blobs.join(hash.to_string()))?;
+        Ok((file.metadata()?.len(), Box::new(file)))
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.blobs.join(hash.to_string()).exists()
+    }
+
+    fn stored_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn hashes(&self) -> io::Result<Vec<Hash>> {
+        let mut hashes = vec![];
+        for entry in fs::read_dir(&self.blobs)? {
+            if let Some(hash) = entry?.file_name().to_str().and_then(Hash::parse) {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    fn remove(&self, hash: &Hash) -> io::Result<()> {
+        let path = self.blobs.join(hash.to_string());
+        let len = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        fs::remove_file(path)?;
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn record(&self, change: &Change) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        index.write_all(change.to_line().as_bytes())?;
+        index.sync_data()
+    }
+}
+
+/// Most deltas between a stored revision and the full copy it's rebuilt
+/// from, bounding the work of a GET.
+const MAX_CHAIN: u32 = 16;
+
+/// Largest content stored as a delta. Anything bigger is kept whole, an
+/// upload as it was written, so it never has to be in memory all at once
+/// to be diffed or rebuilt.
+const MAX_DIFF_SIZE: u64 = 1024 * 1024;
+
+/// Tags of the blobs a `Store` keeps in its `Storage`.
+const FULL: u8 = 0;
+const DELTA: u8 = 1;
+
+/// File contents on top of a `Storage`. A revision is stored as a delta
+/// against the one before it when that's smaller, with a full copy every
+/// `MAX_CHAIN` revisions at most.
+pub struct Store {
+    storage: Box<dyn Storage>,
+    /// Length of the delta chain behind each blob stored since startup;
+    /// 0 for full copies. Nothing is stored as a delta against a blob not
+    /// in here, so after a restart every file starts with a full copy.
+    chains: Mutex<HashMap<Hash, u32>>,
+    /// Size of the content of each blob stored since startup or looked up
+    /// by `size` since.
+    sizes: Mutex<HashMap<Hash, u64>>,
+    /// Most bytes of blobs the storage may hold.
+    max_bytes: u64,
+}
+
+impl Store {
+    pub fn new(storage: impl Storage + 'static) -> Store {
+        Store {
+            storage: Box::new(storage),
+            chains: Mutex::default(),
+            sizes: Mutex::default(),
+            max_bytes: u64::MAX,
+        }
+    }
+
+    /// Refuses blobs that would take the storage over `max_bytes`.
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Stores `content`, whose hash is `hash`, as a delta against `base`
+    /// if that's worth it. Nothing happens if it's already stored. Fails
+    /// with `StorageFull` if there's no room for it under the limit.
+    /// Puts of different contents can go on at the same time; the byte
+    /// limit may be overshot by the ones that do.
+    pub fn put(&self, hash: &Hash, content: &[u8], base: Option<&Hash>) -> io::Result<()> {
+        if self.storage.contains(hash) {
+            return Ok(());
+        }
+        let (blob, chain) = match self.delta(content, base)? {
+            Some(delta) => delta,
+            None => ([&[FULL][..], content].concat(), 0),
+        };
+        self.check_room(hash, blob.len() as u64)?;
+        self.storage.put(hash, &blob)?;
+        self.stored(hash, chain, content.len() as u64);
+        Ok(())
+    }
+
+    /// Starts content to be put a piece at a time.
+    pub fn upload(&self) -> io::Result<Upload> {
+        let mut blob = self.storage.create()?;
+        blob.write(&[FULL])?;
+        Ok(Upload {
+            blob,
+            hasher: Sha256::new(),
+            len: 0,
+        })
+    }
+
+    /// Stores the content of `upload` as `put` would, and returns its hash.
+    /// Only content up to `MAX_DIFF_SIZE` is read back to be stored as a
+    /// delta.
+    pub fn finish(&self, upload: Upload, base: Option<&Hash>) -> io::Result<Hash> {
+        let hash = upload.hash();
+        if self.storage.contains(&hash) {
+            return Ok(hash);
+        }
+        if upload.len <= MAX_DIFF_SIZE && base.is_some() {
+            let content = upload.blob.read()?;
+            if let Some((delta, chain)) = self.delta(&content[1..], base)? {
+                self.check_room(&hash, delta.len() as u64)?;
+                self.storage.put(&hash, &delta)?;
+                self.stored(&hash, chain, upload.len);
+                return Ok(hash);
+            }
+        }
+        self.check_room(&hash, 1 + upload.len)?;
+        upload.blob.finish(&hash)?;
+        self.stored(&hash, 0, upload.len);
+        Ok(hash)
+    }
+
+    /// Fails with `StorageFull` if a blob of `len` bytes would take the
+    /// storage over the limit.
+    fn check_room(&self, hash: &Hash, len: u64) -> io::Result<()> {
+        let bytes = self.storage.stored_bytes().saturating_add(len);
+        if bytes > self.max_bytes {
+            let e = format!("{hash} would take the store over {} bytes", self.max_bytes);
+            return Err(io::Error::new(io::ErrorKind::StorageFull, e));
+        }
+        Ok(())
+    }
+
+    /// Remembers a blob just stored.
+    fn stored(&self, hash: &Hash, chain: u32, size: u64) {
+        self.chains.lock().unwrap().insert(*hash, chain);
+        self.sizes.lock().unwrap().insert(*hash, size);
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.storage.contains(hash)
+    }
+
+    /// Removes every blob that neither holds one of the `live` contents
+    /// nor is the base of a delta that does, and returns how many there
+    /// were.
+    pub fn compact<'a>(&self, live: impl IntoIterator<Item = &'a Hash>) -> io::Result<usize> {
+        let mut keep = HashSet::new();
+        for hash in live {
+            let mut next = Some(*hash);
+            while let Some(hash) = next.filter(|&hash| keep.insert(hash)) {
+                next = self.base(&hash)?;
+            }
+        }
+        let mut removed = 0;
+        for hash in self.storage.hashes()? {
+            if !keep.contains(&hash) {
+                self.storage.remove(&hash)?;
+                self.chains.lock().unwrap().remove(&hash);
+                self.sizes.lock().unwrap().remove(&hash);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// The blob the one with hash `hash` is a delta against, if any.
+    fn base(&self, hash: &Hash) -> io::Result<Option<Hash>> {
+        let blob = self.storage.get(hash)?;
+        match blob.split_first() {
+            Some((&DELTA, rest)) if rest.len() >= 32 => {
+                Ok(Some(Hash(rest[..32].try_into().unwrap())))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Bytes of blobs in the storage.
+    pub fn stored_bytes(&self) -> u64 {
+        self.storage.stored_bytes()
+    }
+
+    /// Size of the content with hash `hash`, rebuilding it only the first
+    /// time it's asked for after a restart.
+    pub fn size(&self, hash: &Hash) -> io::Result<u64> {
+        if let Some(&size) = self.sizes.lock().unwrap().get(hash) {
+            return Ok(size);
+        }
+        let size = self.get(hash)?.len() as u64;
+        self.sizes.lock().unwrap().insert(*hash, size);
+        Ok(size)
+    }
+
+    /// The blob to store `content` as if it's worth storing as a delta
+    /// against `base`, and the length of its delta chain.
+    fn delta(&self, content: &[u8], base: Option<&Hash>) -> io::Result<Option<(Vec<u8>, u32)>> {
+        if content.len() as u64 > MAX_DIFF_SIZE {
+            return Ok(None);
+        }
+        let chain = base.and_then(|base| self.chains.lock().unwrap().get(base).copied());
+        let base = base.zip(chain);
+        if let Some((base, chain)) = base.filter(|&(_, chain)| chain < MAX_CHAIN) {
+            let delta = delta::diff(&self.get(base)?, content);
+            if 1 + base.0.len() + delta.len() < 1 + content.len() {
+                return Ok(Some(([&[DELTA][..], &base.0, &delta].concat(), chain + 1)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Content with hash `hash`. Full copies kept in memory are shared
+    /// rather than copied.
+    pub fn get(&self, hash: &Hash) -> io::Result<Bytes> {
+        let blob = self.storage.get(hash)?;
+        match blob.split_first() {
+            Some((&FULL, _)) => Ok(blob.slice(1..)),
+            Some((&DELTA, rest)) if rest.len() >= 32 => {
+                let (base, delta) = rest.split_at(32);
+                let base = Hash(base.try_into().unwrap());
+                delta::apply(&self.get(&base)?, delta).map(Bytes::from)
+            }
+            _ => {
+                let e = format!("blob {hash} is corrupt");
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    /// Content with hash `hash`, to be read a piece at a time, and its
+    /// size. Only deltas are rebuilt in memory; full copies are read from
+    /// the storage as they are.
+    pub fn open(&self, hash: &Hash) -> io::Result<(u64, Box<dyn Read + Send>)> {
+        let (len, mut blob) = self.storage.open(hash)?;
+        let mut tag = [0];
+        blob.read_exact(&mut tag)?;
+        if tag[0] == FULL {
+            return Ok((len - 1, blob));
+        }
+        let content = self.get(hash)?;
+        Ok((content.len() as u64, Box::new(io::Cursor::new(content))))
+    }
+
+    pub fn record(&self, change: &Change) -> io::Result<()> {
+        self.storage.record(change)
+    }
+}
+
+/// Content being put a piece at a time. It's hashed and written out as a
+/// full copy as it comes, so it's never all in memory, and stored by
+/// `Store::finish`; dropping it instead throws it away.
+pub struct Upload {
+    blob: Box<dyn PartialBlob>,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Upload {
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.len += bytes.len() as u64;
+        self.blob.write(bytes)
+    }
+
+    /// Hash of the content written so far.
+    pub fn hash(&self) -> Hash {
+        Hash(self.hasher.clone().finalize().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(storage: &impl Storage, path: &str, content: &[u8]) -> Hash {
+        let hash = Hash::of(content);
+        storage.put(&hash, content).unwrap();
+        let revision = Revision {
+            hash,
+            at: SystemTime::now(),
+            author: None,
+        };
+        let change = Change::Put(path.to_owned(), revision);
+        storage.record(&change).unwrap();
+        hash
+    }
+
+    fn hashes(changes: Vec<Change>) -> Vec<(String, Hash)> {
+        changes
+            .into_iter()
+            .map(|change| match change {
+                Change::Put(path, revision) => (path, revision.hash),
+                _ => panic!("unexpected {change:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hashes_print_and_parse_as_hex() {
+        let hash = Hash::of(b"");
+        let hex = hash.to_string();
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hex
+        );
+        assert_eq!(Some(hash), Hash::parse(&hex));
+        assert_eq!(None, Hash::parse("e3b0"));
+        assert_eq!(None, Hash::parse(&"g".repeat(64)));
+    }
+
+    #[test]
+    fn identical_contents_are_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let a = put(&storage, "/a", b"same\n");
+        let b = put(&storage, "/b", b"same\n");
+        put(&storage, "/a", b"other\n");
+        assert_eq!(a, b);
+        assert_eq!(2, fs::read_dir(dir.path().join("blobs")).unwrap().count());
+        assert_eq!(b"same\n".to_vec(), storage.get(&a).unwrap());
+    }
+
+    #[test]
+    fn revisions_are_recovered_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let one = put(&storage, "/a", b"1\n");
+        let two = put(&storage, "/b/c", b"2\n");
+        let three = put(&storage, "/a", b"3\n");
+        drop(storage);
+
+        let (storage, revisions) = DiskStorage::open(dir.path()).unwrap();
+        let expected = vec![
+            ("/a".to_owned(), one),
+            ("/b/c".to_owned(), two),
+            ("/a".to_owned(), three),
+        ];
+        assert_eq!(expected, hashes(revisions));
+        assert_eq!(b"3\n".to_vec(), storage.get(&three).unwrap());
+    }
+
+    #[test]
+    fn leftovers_of_a_crash_are_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let hash = put(&storage, "/a", b"kept\n");
+        drop(storage);
+        let index = dir.path().join("index");
+        let mut file = OpenOptions::new().append(true).open(&index).unwrap();
+        file.write_all(b"e3b0c442").unwrap();
+        let tmp = dir.path().join("blobs").join("half-written.tmp");
+        fs::write(&tmp, b"half").unwrap();
+
+        let (storage, revisions) = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(vec![("/a".to_owned(), hash)], hashes(revisions));
+        assert!(!tmp.exists());
+        let other = put(&storage, "/b", b"new\n");
+        drop(storage);
+        let (_, revisions) = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(
+            vec![("/a".to_owned(), hash), ("/b".to_owned(), other)],
+            hashes(revisions)
+        );
+    }
+
+    #[test]
+    fn every_kind_of_change_is_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let hash = Hash::of(b"x\n");
+        storage.put(&hash, b"x\n").unwrap();
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let changes = [
+            Change::Put(
+                "/a".to_owned(),
+                Revision {
+                    hash,
+                    at,
+                    author: Some("alice".to_owned()),
+                },
+            ),
+            Change::Put(
+                "/a".to_owned(),
+                Revision {
+                    hash,
+                    at,
+                    author: None,
+                },
+            ),
+            Change::Delete("/a".to_owned(), Some(1)),
+            Change::Delete("/c".to_owned(), None),
+            Change::Copy("/a".to_owned(), "/d".to_owned()),
+            Change::Move("/d".to_owned(), "/e/f".to_owned()),
+        ];
+        for change in &changes {
+            storage.record(change).unwrap();
+        }
+        drop(storage);
+        // As written before revisions had times.
+        let index = dir.path().join("index");
+        let mut file = OpenOptions::new().append(true).open(&index).unwrap();
+        file.write_all(format!("{hash} /b\n").as_bytes()).unwrap();
+
+        let (_, recovered) = DiskStorage::open(dir.path()).unwrap();
+        let old = Revision {
+            hash,
+            at: UNIX_EPOCH,
+            author: None,
+        };
+        let mut expected = changes.to_vec();
+        expected.push(Change::Put("/b".to_owned(), old));
+        assert_eq!(expected, recovered);
+    }
+
+    #[test]
+    fn compacting_keeps_the_bases_of_live_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let store = Store::new(storage);
+        let mut content: Vec<u8> = (0..200)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let mut hashes = vec![];
+        for i in 0..3 {
+            content.extend_from_slice(format!("appended {i}\n").as_bytes());
+            let hash = Hash::of(&content);
+            store.put(&hash, &content, hashes.last()).unwrap();
+            hashes.push(hash);
+        }
+        let unrelated = Hash::of(b"unrelated\n");
+        store.put(&unrelated, b"unrelated\n", None).unwrap();
+
+        assert_eq!(2, store.compact([&hashes[1]]).unwrap());
+        assert!(store.contains(&hashes[0]) && store.contains(&hashes[1]));
+        assert!(!store.contains(&hashes[2]) && !store.contains(&unrelated));
+        assert_eq!(hashes[1], Hash::of(&store.get(&hashes[1]).unwrap()));
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(store.stored_bytes(), storage.stored_bytes());
+        assert_eq!(2, storage.hashes().unwrap().len());
+    }
+
+    #[test]
+    fn revisions_are_stored_as_deltas_between_full_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let store = Store::new(storage);
+        let mut content: Vec<u8> = (0..200)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let mut hashes = vec![];
+        for i in 0..40 {
+            content.extend_from_slice(format!("appended {i}\n").as_bytes());
+            let hash = Hash::of(&content);
+            store.put(&hash, &content, hashes.last()).unwrap();
+            hashes.push(hash);
+        }
+
+        let blob = |hash: &Hash| fs::read(dir.path().join("blobs").join(hash.to_string())).unwrap();
+        let full: Vec<usize> = (0..hashes.len())
+            .filter(|&i| blob(&hashes[i])[0] == FULL)
+            .collect();
+        assert_eq!(vec![0, 17, 34], full);
+        assert!(blob(&hashes[1]).len() < 64);
+        assert_eq!(content, store.get(hashes.last().unwrap()).unwrap());
+        for hash in &hashes {
+            assert_eq!(*hash, Hash::of(&store.get(hash).unwrap()));
+        }
+    }
+
+    #[test]
+    fn stored_bytes_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let mut store = Store::new(storage);
+        store.set_max_bytes(20);
+        let ten = Hash::of(&[b'a'; 9]);
+        store.put(&ten, &[b'a'; 9], None).unwrap();
+        let e = store.put(&Hash::of(&[b'b'; 10]), &[b'b'; 10], None);
+        assert_eq!(io::ErrorKind::StorageFull, e.unwrap_err().kind());
+        store.put(&ten, &[b'a'; 9], None).unwrap();
+        store.put(&Hash::of(&[b'c'; 9]), &[b'c'; 9], None).unwrap();
+        drop(store);
+
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(20, storage.stored_bytes());
+        let memory = MemoryStorage::default();
+        put(&memory, "/a", b"abc");
+        put(&memory, "/b", b"abc");
+        assert_eq!(3, memory.stored_bytes());
+    }
+
+    #[test]
+    fn deltas_bigger_than_the_content_are_not_used() {
+        let store = Store::new(MemoryStorage::default());
+        let (a, b) = (b"first\n", b"second\n");
+        store.put(&Hash::of(a), a, None).unwrap();
+        store.put(&Hash::of(b), b, Some(&Hash::of(a))).unwrap();
+        assert_eq!(Some(&0), store.chains.lock().unwrap().get(&Hash::of(b)));
+        assert_eq!(b.to_vec(), store.get(&Hash::of(b)).unwrap());
+    }
+
+    #[test]
+    fn uploads_are_stored_whole_or_as_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = DiskStorage::open(dir.path()).unwrap();
+        let store = Store::new(storage);
+        let upload = |content: &[u8], base: Option<&Hash>| {
+            let mut upload = store.upload().unwrap();
+            for chunk in content.chunks(1000) {
+                upload.write(chunk).unwrap();
+            }
+            store.finish(upload, base).unwrap()
+        };
+        let blob = |hash: &Hash| fs::read(dir.path().join("blobs").join(hash.to_string())).unwrap();
+        let read = |hash: &Hash| {
+            let (len, mut content) = store.open(hash).unwrap();
+            let mut read = vec![];
+            content.read_to_end(&mut read).unwrap();
+            assert_eq!(len, read.len() as u64);
+            read
+        };
+
+        let mut small: Vec<u8> = (0..200)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let first = upload(&small, None);
+        small.extend_from_slice(b"one more\n");
+        let second = upload(&small, Some(&first));
+        assert_eq!(Hash::of(&small), second);
+        assert_eq!(DELTA, blob(&second)[0]);
+        assert_eq!(small, read(&second));
+
+        let big = small.repeat(1 + MAX_DIFF_SIZE as usize / small.len());
+        let third = upload(&big, Some(&second));
+        assert_eq!(FULL, blob(&third)[0]);
+        assert_eq!(big, read(&third));
+        assert_eq!(third, upload(&big, Some(&second)));
+        let bigger = [&big[..], b"one more\n"].concat();
+        store
+            .put(&Hash::of(&bigger), &bigger, Some(&third))
+            .unwrap();
+        assert_eq!(FULL, blob(&Hash::of(&bigger))[0]);
+
+        let mut dropped = store.upload().unwrap();
+        dropped.write(b"never finished\n").unwrap();
+        drop(dropped);
+        assert_eq!(4, fs::read_dir(dir.path().join("blobs")).unwrap().count());
+    }
+
+    #[test]
+    fn full_copies_in_memory_are_shared() {
+        let store = Store::new(MemoryStorage::default());
+        let content = b"shared\n";
+        let hash = Hash::of(content);
+        store.put(&hash, content, None).unwrap();
+        let (a, b) = (store.get(&hash).unwrap(), store.get(&hash).unwrap());
+        assert_eq!(&content[..], a);
+        assert_eq!(a.as_ptr(), b.as_ptr());
+    }
+}
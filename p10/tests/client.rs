@@ -0,0 +1,110 @@
+use p10::{Client, Entry};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// A server run from the p10 binary, killed when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    async fn start(store: &Path, extra: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{port}");
+        let child = Command::new(env!("CARGO_BIN_EXE_p10"))
+            .args(["--listen", &addr, "--store"])
+            .arg(store)
+            .args(extra)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, addr };
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(&server.addr).await.is_ok() {
+                return server;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server didn't start listening on {}", server.addr);
+    }
+
+    async fn connect(&self) -> Client {
+        Client::connect(&self.addr).await.unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[tokio::test]
+async fn revisions_are_kept_across_restarts() {
+    let store = tempfile::tempdir().unwrap();
+    let server = Server::start(store.path(), &[]).await;
+    let mut client = server.connect().await;
+    assert_eq!(1, client.put("/a/b", b"one\n").await.unwrap());
+    assert_eq!(2, client.put("/a/b", b"two\n").await.unwrap());
+    assert_eq!(2, client.put("/a/b", b"two\n").await.unwrap());
+    assert_eq!(1, client.put("/c", b"").await.unwrap());
+    drop(server);
+
+    let server = Server::start(store.path(), &[]).await;
+    let mut client = server.connect().await;
+    assert_eq!(b"two\n".to_vec(), client.get("/a/b", None).await.unwrap());
+    assert_eq!(
+        b"one\n".to_vec(),
+        client.get("/a/b", Some(1)).await.unwrap()
+    );
+    assert_eq!(Vec::<u8>::new(), client.get("/c", None).await.unwrap());
+    let listing = vec![
+        Entry::Dir("a/".to_owned()),
+        Entry::File {
+            name: "c".to_owned(),
+            revision: 1,
+        },
+    ];
+    assert_eq!(listing, client.list("/").await.unwrap());
+}
+
+#[tokio::test]
+async fn errors_leave_the_connection_usable() {
+    let store = tempfile::tempdir().unwrap();
+    let server = Server::start(store.path(), &[]).await;
+    let mut client = server.connect().await;
+    let e = client.get("/a", None).await.unwrap_err();
+    assert_eq!("no such file", e.to_string());
+    let e = client.put("/a", &[0, 1, 2]).await.unwrap_err();
+    assert_eq!("illegal file content", e.to_string());
+    assert!(client.request("NOPE").await.is_err());
+    assert_eq!(1, client.put("/a", b"text\n").await.unwrap());
+    assert!(client.get("/a", Some(2)).await.is_err());
+    assert!(client.request("HELP").await.unwrap().starts_with("usage: "));
+}
+
+#[tokio::test]
+async fn writes_need_a_token_for_their_dir() {
+    let store = tempfile::tempdir().unwrap();
+    let tokens = store.path().join("tokens");
+    std::fs::write(&tokens, "secret /mine\n").unwrap();
+    let tokens = tokens.to_str().unwrap();
+    let server = Server::start(&store.path().join("vcs"), &["--tokens", tokens]).await;
+    let mut client = server.connect().await;
+    let e = client.put("/mine/a", b"1\n").await.unwrap_err();
+    assert_eq!("auth required", e.to_string());
+    client.request("AUTH secret").await.unwrap();
+    assert_eq!(1, client.put("/mine/a", b"1\n").await.unwrap());
+    let e = client.put("/theirs/a", b"1\n").await.unwrap_err();
+    assert_eq!("no write access to /theirs/a", e.to_string());
+    let mut reader = server.connect().await;
+    assert_eq!(b"1\n".to_vec(), reader.get("/mine/a", None).await.unwrap());
+}
@@ -0,0 +1,1153 @@
+//! Pest control (p11): takes site visits from clients and keeps the
+//! policies at each site's authority in line with its populations.
+
+mod metrics;
+mod policy;
+mod pool;
+mod store;
+mod targets;
+use metrics::SiteMetrics;
+use policy::{Op, PolicyManager};
+use pool::{Pool, Slot};
+use std::future::Future;
+use store::Store;
+use targets::{TargetCache, Targets};
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use pestcontrol::{hello, Action, AuthorityClient, Message, ObservedPopulation, ProtocolError};
+use proto_common::{ConnLimit, Listen, Reporting, Server, Shutdown};
+use std::collections::HashMap;
+use std::future;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Mutex};
+use tokio::time;
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
+
+/// Where the authority of every site is, unless set on the command line.
+const AUTHORITY: &str = "pestcontrol.protohackers.com:20547";
+
+/// How long a site handler waits before dialing the authority again after
+/// its first failure. It doubles with every failure after that.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest wait between dialing attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long the authority gets to answer each request, unless set on the
+/// command line.
+const AUTHORITY_TIMEOUT_SECS: u64 = 5;
+
+/// How long clients get to say hello, unless set on the command line.
+const HELLO_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(flatten)]
+    listen: Listen,
+    #[command(flatten)]
+    limit: ConnLimit,
+    /// Protocol clients have to say hello with, and are said hello to with.
+    #[arg(long, default_value = "pestcontrol")]
+    protocol: String,
+    /// Version of the protocol clients have to say hello with.
+    #[arg(long, default_value_t = 1)]
+    protocol_version: u32,
+    /// Seconds clients get to say hello before they're hung up on.
+    #[arg(long, default_value_t = HELLO_TIMEOUT_SECS)]
+    hello_timeout_secs: u64,
+    /// Where the authority of every site is, as addresses to try in order
+    /// separated by commas. Each dial goes to the first that answers.
+    #[arg(long, default_value = AUTHORITY, value_delimiter = ',')]
+    authority: Vec<String>,
+    /// Seconds a site's target populations are used before dialing its
+    /// authority again to refresh them. Without it they're kept for good.
+    #[arg(long)]
+    targets_ttl_secs: Option<u64>,
+    /// Address to serve the cached target populations on, for debugging.
+    /// Each connection gets them as text and is closed.
+    #[arg(long)]
+    admin: Option<String>,
+    /// Seconds the authority gets to answer each request before its
+    /// connection is given up on and dialed again.
+    #[arg(long, default_value_t = AUTHORITY_TIMEOUT_SECS)]
+    authority_timeout_secs: u64,
+    /// Seconds a site may go without visits before its handler is stopped
+    /// and its authority connection closed. Without it they're kept for
+    /// good.
+    #[arg(long)]
+    idle_secs: Option<u64>,
+    /// Most connections to the authority open at once. A site handler
+    /// that needs one while there are that many gets the one of a handler
+    /// that's waiting for visits, which dials again on its next visit.
+    /// Without it there's no limit.
+    #[arg(long)]
+    max_authority_connections: Option<usize>,
+    /// Visits in a row that have to call for another policy for a species
+    /// before it's changed, so counts going back and forth don't keep
+    /// changing it. With 1 it's changed on the first.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    policy_debounce: u32,
+    /// File to keep the policies made at the authority in. Those left
+    /// from the last run are deleted on startup.
+    #[arg(long)]
+    state: Option<String>,
+}
+
+/// The `Hello` clients are greeted with and have to answer with, and how
+/// long they get to answer.
+struct Greeting {
+    hello: Message,
+    timeout: Duration,
+}
+
+impl Default for Greeting {
+    fn default() -> Greeting {
+        Greeting {
+            hello: hello(),
+            timeout: Duration::from_secs(HELLO_TIMEOUT_SECS),
+        }
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    sites: Arc<Sites>,
+    greeting: Arc<Greeting>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    // Both ends say hello first, without waiting for the other.
+    greeting.hello.encode(&mut write).await?;
+    let first = time::timeout(greeting.timeout, Message::decode(&mut read)).await;
+    let refusal = match first {
+        Ok(Ok(hello)) if hello == greeting.hello => None,
+        Ok(Ok(Message::Hello { protocol, version })) => Some(format!(
+            "unsupported protocol '{protocol}' version {version}"
+        )),
+        Ok(Ok(other)) => Some(format!("expected a hello first, got {other:?}")),
+        Ok(Err(e)) => Some(format!("bad hello: {e}")),
+        Err(_) => Some(format!("no hello in {:?}", greeting.timeout)),
+    };
+    if let Some(message) = refusal {
+        let err = Message::Error {
+            message: message.clone(),
+        };
+        err.encode(&mut write).await?;
+        write.flush().await?;
+        bail!("{message}");
+    }
+
+    loop {
+        // Visits already passed on are left to the site handlers.
+        let msg = tokio::select! {
+            msg = Message::decode(&mut read) => msg,
+            () = shutdown.started() => return Ok(()),
+        };
+        match msg {
+            Ok(Message::SiteVisit { site, populations }) => {
+                if let Some(species) = conflicting_species(&populations) {
+                    let err = Message::Error {
+                        message: format!("conflicting counts for '{species}'"),
+                    };
+                    err.encode(&mut write).await?;
+                    continue;
+                }
+                sites.send_visit(site, populations).await;
+            }
+            Err(e) if e.is::<ProtocolError>() => {
+                let err = Message::Error {
+                    message: e.to_string(),
+                };
+                err.encode(&mut write).await?;
+                return Err(e);
+            }
+            // The client is gone.
+            Err(e) => return Err(e),
+            // Anything else is only ever sent to clients, or only once, so
+            // the client is broken and gets hung up on.
+            Ok(other) => {
+                let err = Message::Error {
+                    message: format!("unexpected message: {other:?}"),
+                };
+                err.encode(&mut write).await?;
+                write.flush().await?;
+                bail!("unexpected message: {other:?}");
+            }
+        }
+    }
+}
+
+/// The handler of every site visited so far. Handlers outlive the client
+/// connections that visit them, as do the target populations they've
+/// fetched.
+struct Sites {
+    /// Where the authority of every site is, in the order to try them.
+    authorities: Vec<String>,
+    /// How long the authority gets to answer a request.
+    timeout: Duration,
+    /// How long a handler waits for a visit before stopping.
+    idle: Option<Duration>,
+    /// Visits in a row needed to change a policy.
+    debounce: u32,
+    /// Slots for authority connections, one per open connection.
+    pool: Pool,
+    store: Arc<Store>,
+    targets: Arc<TargetCache>,
+    handlers: Mutex<HashMap<u32, Visits>>,
+    /// What handlers stopped for being idle knew, for the next handler of
+    /// their site.
+    retired: std::sync::Mutex<HashMap<u32, SiteState>>,
+    metrics: std::sync::Mutex<HashMap<u32, Arc<SiteMetrics>>>,
+}
+
+/// What a site handler knows of its site.
+#[derive(Default)]
+struct SiteState {
+    policies: PolicyManager,
+    latest: Option<Vec<ObservedPopulation>>,
+}
+
+impl Sites {
+    fn new(authorities: Vec<String>, targets: TargetCache) -> Sites {
+        Sites {
+            authorities,
+            timeout: Duration::from_secs(AUTHORITY_TIMEOUT_SECS),
+            idle: None,
+            debounce: 1,
+            pool: Pool::new(None),
+            store: Default::default(),
+            targets: Arc::new(targets),
+            handlers: Mutex::new(HashMap::new()),
+            retired: Default::default(),
+            metrics: Default::default(),
+        }
+    }
+
+    fn with_timeout(self, timeout: Duration) -> Sites {
+        Sites { timeout, ..self }
+    }
+
+    fn with_idle(self, idle: Option<Duration>) -> Sites {
+        Sites { idle, ..self }
+    }
+
+    fn with_debounce(self, debounce: u32) -> Sites {
+        Sites { debounce, ..self }
+    }
+
+    fn with_max_connections(self, limit: Option<usize>) -> Sites {
+        let pool = Pool::new(limit);
+        Sites { pool, ..self }
+    }
+
+    fn with_store(self, store: Store) -> Sites {
+        let store = Arc::new(store);
+        Sites { store, ..self }
+    }
+
+    /// Starts a handler for every site with stored policies, which were
+    /// made before a restart, to delete them. They're all deleted, even a
+    /// second one for a species, and the site's visits make them over.
+    async fn restore(self: &Arc<Self>) {
+        let mut handlers = self.handlers.lock().await;
+        for (site, policies) in self.store.sites() {
+            let mut state = SiteState::default();
+            for (species, id, action) in policies {
+                state.policies.restored(&species, id, action);
+            }
+            self.retired.lock().unwrap().insert(site, state);
+            handlers.insert(site, self.start_handler(site));
+        }
+    }
+
+    /// Hands `populations` to the handler of `site`, starting one if
+    /// there's none or the one there is has stopped.
+    async fn send_visit(self: &Arc<Self>, site: u32, populations: Vec<ObservedPopulation>) {
+        let mut handlers = self.handlers.lock().await;
+        let handler = handlers
+            .entry(site)
+            .or_insert_with(|| self.start_handler(site));
+        if let Err(e) = handler.send(populations) {
+            let handler = self.start_handler(site);
+            handler.send_replace(e.0);
+            handlers.insert(site, handler);
+        }
+    }
+
+    /// Starts the task that keeps the policies of `site` in line with its
+    /// visits and returns where to send it the visits.
+    fn start_handler(self: &Arc<Self>, site: u32) -> Visits {
+        let (s, r) = watch::channel(Vec::new());
+        let state = self.retired.lock().unwrap().remove(&site);
+        let handler = run_handler(self.clone(), site, state.unwrap_or_default(), r);
+        tokio::spawn(handler.instrument(info_span!("site", site)));
+        s
+    }
+
+    /// Counters of `site`, made when first asked for.
+    fn metrics(&self, site: u32) -> Arc<SiteMetrics> {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.entry(site).or_default().clone()
+    }
+
+    /// Takes the handler of `site` out, keeping `state` for the next one,
+    /// unless a visit came in for it after all. Returns whether it did.
+    async fn retire(&self, site: u32, visits: &VisitsReceiver, state: &mut SiteState) -> bool {
+        let mut handlers = self.handlers.lock().await;
+        // Visits are sent with the lock held, so none can come in now.
+        if visits.has_changed().unwrap_or(false) {
+            return false;
+        }
+        handlers.remove(&site);
+        self.retired
+            .lock()
+            .unwrap()
+            .insert(site, std::mem::take(state));
+        true
+    }
+}
+
+/// Where the visits of one site go. It only holds the latest, so a visit
+/// the handler hasn't got to yet is replaced by the next one rather than
+/// applied and then undone.
+type Visits = watch::Sender<Vec<ObservedPopulation>>;
+type VisitsReceiver = watch::Receiver<Vec<ObservedPopulation>>;
+
+/// A connection to the authority of one site, past the handshake, that
+/// counts what it does in the site's metrics and records the policies it
+/// makes and deletes in the store. It holds a slot of the pool until it's
+/// closed.
+struct Authority {
+    client: AuthorityClient,
+    site: u32,
+    metrics: Arc<SiteMetrics>,
+    store: Arc<Store>,
+    _slot: Slot,
+}
+
+impl Authority {
+    /// Connects to the authority at `addr` and dials `site`, returning the
+    /// connection and the site's target populations as (min, max) by
+    /// species.
+    async fn dial(
+        sites: &Sites,
+        addr: &str,
+        site: u32,
+        metrics: Arc<SiteMetrics>,
+        slot: Slot,
+    ) -> Result<(Authority, Targets)> {
+        let (client, populations) = AuthorityClient::dial(addr, site, sites.timeout).await?;
+        let targets = populations
+            .into_iter()
+            .map(|p| (p.species, (p.min, p.max)))
+            .collect();
+        let authority = Authority {
+            client,
+            site,
+            metrics,
+            store: sites.store.clone(),
+            _slot: slot,
+        };
+        Ok((authority, targets))
+    }
+
+    /// Creates a policy and returns its id, or `None` if the authority
+    /// replied with anything else, such as an error. Errors are only
+    /// failures of the connection.
+    #[instrument(skip(self))]
+    async fn create_policy(&mut self, species: &str, action: Action) -> Result<Option<u32>> {
+        let start = Instant::now();
+        let created = self.client.create_policy(species, action).await?;
+        self.metrics.round_trips.record(start.elapsed());
+        match created {
+            Ok(policy) => {
+                self.metrics.created.fetch_add(1, Relaxed);
+                self.store.created(self.site, policy, species, action).await;
+                info!(policy, "created");
+                Ok(Some(policy))
+            }
+            Err(reply) => {
+                warn!(?reply, "authority refused to create");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Deletes a policy, returning whether the authority said it did.
+    #[instrument(skip(self))]
+    async fn delete_policy(&mut self, policy: u32) -> Result<bool> {
+        let start = Instant::now();
+        let deleted = self.client.delete_policy(policy).await?;
+        self.metrics.round_trips.record(start.elapsed());
+        // Refused or not, it's taken to be gone.
+        self.store.deleted(self.site, policy).await;
+        match deleted {
+            Ok(()) => {
+                self.metrics.deleted.fetch_add(1, Relaxed);
+                info!("deleted");
+                Ok(true)
+            }
+            Err(reply) => {
+                warn!(?reply, "authority refused to delete");
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Applies the latest visit of `site` whenever there's a new one, starting
+/// from what an earlier handler of the site knew. Whenever the connection
+/// to the authority fails or times out, or the targets it gave go stale,
+/// it's dialed again, with backoff, and the site's policies are made over
+/// from the latest visit. While waiting for visits, it closes the
+/// connection if another handler needs its slot, and drops it if it turns
+/// out dead, dialing again on the next visit. Stops, closing the
+/// connection, after going without visits for as long as `sites` says.
+async fn run_handler(
+    sites: Arc<Sites>,
+    site: u32,
+    mut state: SiteState,
+    mut visits: VisitsReceiver,
+) {
+    let metrics = sites.metrics(site);
+    state.policies.set_debounce(sites.debounce);
+    let mut authority = Some(connect(&sites, site, &metrics, &mut state, &mut visits).await);
+    loop {
+        let idle = async {
+            match sites.idle {
+                Some(idle) => time::sleep(idle).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            changed = visits.changed() => {
+                if changed.is_err() {
+                    // Nothing can send visits here any more.
+                    return;
+                }
+            }
+            () = idle => {
+                if sites.retire(site, &visits, &mut state).await {
+                    info!("stopping after no visits for {:?}", sites.idle.unwrap());
+                    return;
+                }
+                continue;
+            }
+            () = sites.pool.wanted(), if authority.is_some() => {
+                info!("closing the authority connection for another site");
+                authority = None;
+                continue;
+            }
+            e = closed(&mut authority) => {
+                warn!("lost the authority while waiting for visits: {e}");
+                authority = None;
+                continue;
+            }
+        }
+        let populations = visits.borrow_and_update().clone();
+        debug!(?populations, "visit");
+        metrics.visits.fetch_add(1, Relaxed);
+        let latest = state.latest.insert(populations);
+        if sites.targets.get(site, Instant::now()).is_none() {
+            info!("target populations are stale, dialing again");
+            authority = None;
+        }
+        if let Some(connected) = &mut authority {
+            if let Err(e) = apply(connected, &mut state.policies, latest).await {
+                warn!("lost the authority: {e}");
+                authority = None;
+            }
+        }
+        if authority.is_none() {
+            authority = Some(connect(&sites, site, &metrics, &mut state, &mut visits).await);
+        }
+    }
+}
+
+/// Waits for `authority`, if there's one, to turn out dead.
+async fn closed(authority: &mut Option<Authority>) -> anyhow::Error {
+    match authority {
+        Some(authority) => authority.client.closed().await,
+        None => future::pending().await,
+    }
+}
+
+/// Dials the authority for `site` and brings the new connection in line
+/// with the latest visit, dialing again until that works.
+async fn connect(
+    sites: &Sites,
+    site: u32,
+    metrics: &Arc<SiteMetrics>,
+    state: &mut SiteState,
+    visits: &mut VisitsReceiver,
+) -> Authority {
+    loop {
+        let (mut authority, targets) = dial(sites, site, metrics).await;
+        info!(?targets, "dialed");
+        let targets = sites.targets.insert(site, targets, Instant::now());
+        state.policies.set_targets(targets);
+        if visits.has_changed().unwrap_or(false) {
+            // It came in while dialing and supersedes what was there.
+            state.latest = Some(visits.borrow_and_update().clone());
+            metrics.visits.fetch_add(1, Relaxed);
+        }
+        let resynced = resync(&mut authority, &mut state.policies, state.latest.as_deref());
+        match resynced.await {
+            Ok(()) => return authority,
+            Err(e) => warn!("lost the authority while resyncing: {e}"),
+        }
+    }
+}
+
+/// Dials the authority until it answers, trying each address in order and
+/// waiting longer after each round in which none did. Each attempt waits
+/// for a slot of the pool first.
+async fn dial(sites: &Sites, site: u32, metrics: &Arc<SiteMetrics>) -> (Authority, Targets) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        for addr in &sites.authorities {
+            let slot = sites.pool.take().await;
+            let dialed = Authority::dial(sites, addr, site, metrics.clone(), slot);
+            match dialed.await {
+                Ok(dialed) => return dialed,
+                Err(e) => warn!("dialing the authority at {addr} failed: {e}"),
+            }
+        }
+        warn!("no authority answered, retrying in {backoff:?}");
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Brings a new connection to the authority in line with what the site
+/// needs. Policies made on an earlier connection may or may not have gone
+/// with it, so each is deleted, whatever the authority says to that, and
+/// the latest visit applied again.
+async fn resync(
+    authority: &mut Authority,
+    policies: &mut PolicyManager,
+    latest: Option<&[ObservedPopulation]>,
+) -> Result<()> {
+    for id in policies.take_all() {
+        authority.delete_policy(id).await?;
+    }
+    if let Some(populations) = latest {
+        apply(authority, policies, populations).await?;
+    }
+    Ok(())
+}
+
+/// Creates and deletes policies so each targeted species has the one its
+/// count in `populations` calls for, if any. A policy the authority won't
+/// delete is taken to be gone already, and one it won't create is tried
+/// again on the next visit.
+async fn apply(
+    authority: &mut Authority,
+    policies: &mut PolicyManager,
+    populations: &[ObservedPopulation],
+) -> Result<()> {
+    for op in policies.visit(populations) {
+        match op {
+            Op::Delete { species, policy } => {
+                let span = info_span!("species", %species);
+                authority.delete_policy(policy).instrument(span).await?;
+                policies.deleted(&species);
+            }
+            Op::Create { species, action } => {
+                let span = info_span!("species", %species);
+                let created = authority.create_policy(&species, action);
+                if let Some(id) = created.instrument(span).await? {
+                    policies.created(&species, id, action);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first species a visit counts more than once with different counts.
+/// Counting one twice the same is fine.
+fn conflicting_species(populations: &[ObservedPopulation]) -> Option<&str> {
+    let mut counts: HashMap<&str, u32> = HashMap::new();
+    for pop in populations {
+        if *counts.entry(&pop.species).or_insert(pop.count) != pop.count {
+            return Some(&pop.species);
+        }
+    }
+    None
+}
+
+/// Reports the counters of every site visited so far.
+fn report_metrics(sites: Arc<Sites>) -> Reporting {
+    proto_common::report(move |_| {
+        let metrics = sites.metrics.lock().unwrap().clone();
+        let mut ids: Vec<_> = metrics.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            info!(site = id, "metrics: {}", metrics[&id]);
+        }
+    })
+}
+
+/// Writes the cached target populations to each connection on `list`.
+async fn serve_admin(list: TcpListener, targets: Arc<TargetCache>) -> Result<()> {
+    loop {
+        let (mut stream, _) = list.accept().await?;
+        let report = targets.report(Instant::now());
+        tokio::spawn(async move { stream.write_all(report.as_bytes()).await });
+    }
+}
+
+/// Serves until `stop` resolves.
+pub async fn run(args: Args, stop: impl Future<Output = ()>) -> Result<()> {
+    let ttl = args.targets_ttl_secs.map(Duration::from_secs);
+    let timeout = Duration::from_secs(args.authority_timeout_secs);
+    let idle = args.idle_secs.map(Duration::from_secs);
+    let sites = Sites::new(args.authority, TargetCache::new(ttl))
+        .with_timeout(timeout)
+        .with_idle(idle)
+        .with_debounce(args.policy_debounce)
+        .with_max_connections(args.max_authority_connections);
+    let sites = match &args.state {
+        Some(path) => sites.with_store(Store::open(path)?),
+        None => sites,
+    };
+    let sites = Arc::new(sites);
+    sites.restore().await;
+    if let Some(addr) = &args.admin {
+        let admin = TcpListener::bind(addr).await?;
+        tokio::spawn(serve_admin(admin, sites.targets.clone()).in_current_span());
+    }
+    let greeting = Arc::new(Greeting {
+        hello: Message::Hello {
+            protocol: args.protocol,
+            version: args.protocol_version,
+        },
+        timeout: Duration::from_secs(args.hello_timeout_secs),
+    });
+    let list = TcpListener::bind(args.listen.addr()).await?;
+    let server = Server::new(list).with_limit(&args.limit);
+    let _reporting = report_metrics(sites.clone());
+    server
+        .run(stop, |stream, _, shutdown| {
+            let client = handle(stream, sites.clone(), greeting.clone(), shutdown);
+            async move {
+                if let Err(e) = client.await {
+                    info!("hung up: {e}");
+                }
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pestcontrol::{PestControlClient, TargetPopulation};
+    use std::net::SocketAddr;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::time::timeout;
+
+    /// One connection accepted by a test authority.
+    struct Conn {
+        read: BufReader<OwnedReadHalf>,
+        write: OwnedWriteHalf,
+    }
+
+    impl Conn {
+        async fn accept(list: &TcpListener) -> Conn {
+            let (stream, _) = timeout(Duration::from_secs(5), list.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            let (read, write) = stream.into_split();
+            Conn {
+                read: BufReader::new(read),
+                write,
+            }
+        }
+
+        /// Waits for `expected` and sends `reply` to it.
+        async fn expect(&mut self, expected: Message, reply: Message) {
+            let got = timeout(Duration::from_secs(5), Message::decode(&mut self.read))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(expected, got);
+            reply.encode(&mut self.write).await.unwrap();
+        }
+
+        async fn handshake(&mut self, site: u32) {
+            self.expect(hello(), hello()).await;
+            let targets = Message::TargetPopulations {
+                site,
+                populations: vec![TargetPopulation {
+                    species: "dog".to_owned(),
+                    min: 1,
+                    max: 3,
+                }],
+            };
+            self.expect(Message::DialAuthority { site }, targets).await;
+        }
+    }
+
+    /// Serves clients with sites whose authority is never dialed.
+    async fn serve(greeting: Greeting) -> SocketAddr {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let sites = Sites::new(vec![AUTHORITY.to_owned()], TargetCache::new(None));
+        let sites = Arc::new(sites);
+        let greeting = Arc::new(greeting);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = list.accept().await.unwrap();
+                let shutdown = Shutdown::never();
+                tokio::spawn(handle(stream, sites.clone(), greeting.clone(), shutdown));
+            }
+        });
+        addr
+    }
+
+    fn sites(list: &TcpListener, ttl: Option<Duration>) -> Sites {
+        let addr = list.local_addr().unwrap().to_string();
+        Sites::new(vec![addr], TargetCache::new(ttl))
+    }
+
+    fn visit(count: u32) -> Vec<ObservedPopulation> {
+        vec![ObservedPopulation {
+            species: "dog".to_owned(),
+            count,
+        }]
+    }
+
+    fn create(action: Action) -> Message {
+        Message::CreatePolicy {
+            species: "dog".to_owned(),
+            action,
+        }
+    }
+
+    fn counts(counts: &[(&str, u32)]) -> Vec<ObservedPopulation> {
+        counts
+            .iter()
+            .map(|&(species, count)| ObservedPopulation {
+                species: species.to_owned(),
+                count,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn conflicting_counts_are_found() {
+        assert_eq!(None, conflicting_species(&[]));
+        assert_eq!(None, conflicting_species(&counts(&[("a", 1), ("b", 2)])));
+        assert_eq!(
+            None,
+            conflicting_species(&counts(&[("a", 1), ("b", 2), ("a", 1)]))
+        );
+        assert_eq!(
+            Some("a"),
+            conflicting_species(&counts(&[("a", 1), ("b", 2), ("a", 3)]))
+        );
+        // The last count agreeing with the first doesn't hide the one between.
+        assert_eq!(
+            Some("a"),
+            conflicting_species(&counts(&[("a", 1), ("a", 2), ("a", 1)]))
+        );
+    }
+
+    #[tokio::test]
+    async fn conflicting_visits_get_an_error() {
+        let addr = serve(Greeting::default()).await;
+        let mut client = PestControlClient::connect(addr).await.unwrap();
+        let error = Message::Error {
+            message: "conflicting counts for 'dog'".to_owned(),
+        };
+        // The connection stays up for the next visit.
+        for _ in 0..2 {
+            let conflicting = counts(&[("dog", 1), ("dog", 2)]);
+            client.site_visit(1, conflicting).await.unwrap();
+            assert_eq!(error, client.recv().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn broken_frames_get_an_error_and_a_hangup() {
+        let addr = serve(Greeting::default()).await;
+        let (read, mut write) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut read = BufReader::new(read);
+        hello().encode(&mut write).await.unwrap();
+        assert_eq!(hello(), Message::decode(&mut read).await.unwrap());
+        // A SiteVisit too long to be read.
+        write
+            .write_all(&[0x58, 0xff, 0xff, 0xff, 0xff])
+            .await
+            .unwrap();
+        let reply = Message::decode(&mut read).await.unwrap();
+        assert!(matches!(reply, Message::Error { .. }), "{reply:?}");
+        assert!(Message::decode(&mut read).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_must_say_hello_first() {
+        let greeting = Greeting {
+            hello: Message::Hello {
+                protocol: "pestcontrol".to_owned(),
+                version: 2,
+            },
+            timeout: Duration::from_millis(50),
+        };
+        let addr = serve(greeting).await;
+        let refused = [
+            Some(hello()),
+            Some(Message::SiteVisit {
+                site: 1,
+                populations: vec![],
+            }),
+            // Saying nothing.
+            None,
+        ];
+        for first in refused {
+            let (read, mut write) = TcpStream::connect(addr).await.unwrap().into_split();
+            let mut read = BufReader::new(read);
+            // The server doesn't wait for the client's hello to send its own.
+            let hello = Message::decode(&mut read).await.unwrap();
+            assert!(
+                matches!(hello, Message::Hello { version: 2, .. }),
+                "{hello:?}"
+            );
+            if let Some(first) = first {
+                first.encode(&mut write).await.unwrap();
+            }
+            let reply = timeout(Duration::from_secs(5), Message::decode(&mut read)).await;
+            let reply = reply.unwrap().unwrap();
+            assert!(matches!(reply, Message::Error { .. }), "{reply:?}");
+            assert!(Message::decode(&mut read).await.is_err());
+        }
+
+        let (read, mut write) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut read = BufReader::new(read);
+        let hello = Message::decode(&mut read).await.unwrap();
+        hello.encode(&mut write).await.unwrap();
+        let visit = Message::SiteVisit {
+            site: 1,
+            populations: counts(&[("dog", 1), ("dog", 2)]),
+        };
+        visit.encode(&mut write).await.unwrap();
+        let reply = Message::decode(&mut read).await.unwrap();
+        let conflict = Message::Error {
+            message: "conflicting counts for 'dog'".to_owned(),
+        };
+        assert_eq!(conflict, reply);
+    }
+
+    #[tokio::test]
+    async fn unexpected_messages_get_an_error_and_a_hangup() {
+        let addr = serve(Greeting::default()).await;
+        let mut other = PestControlClient::connect(addr).await.unwrap();
+        let unexpected = [
+            || hello(),
+            || Message::Ok,
+            || Message::DialAuthority { site: 1 },
+            || Message::PolicyResult { policy: 1 },
+        ];
+        for message in unexpected {
+            let mut client = PestControlClient::connect(addr).await.unwrap();
+            client.send(&message()).await.unwrap();
+            let reply = client.recv().await.unwrap();
+            assert!(matches!(reply, Message::Error { .. }), "{reply:?}");
+            assert!(client.recv().await.is_err());
+        }
+
+        // Other clients are still served.
+        other
+            .site_visit(1, counts(&[("dog", 1), ("dog", 2)]))
+            .await
+            .unwrap();
+        let reply = other.recv().await.unwrap();
+        assert!(matches!(reply, Message::Error { .. }), "{reply:?}");
+    }
+
+    #[tokio::test]
+    async fn handler_redials_and_remakes_policies() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let visits = Arc::new(sites(&list, None)).start_handler(7);
+
+        visits.send(visit(0)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(7).await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 1 },
+        )
+        .await;
+        drop(conn);
+
+        // The handler finds the connection gone when it next uses it.
+        visits.send(visit(5)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(7).await;
+        conn.expect(
+            Message::DeletePolicy { policy: 1 },
+            Message::Error {
+                message: "no such policy".to_owned(),
+            },
+        )
+        .await;
+        conn.expect(create(Action::Cull), Message::PolicyResult { policy: 2 })
+            .await;
+        visits.send(visit(2)).unwrap();
+        conn.expect(Message::DeletePolicy { policy: 2 }, Message::Ok)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn dead_connections_are_dropped_between_visits() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let visits = Arc::new(sites(&list, None)).start_handler(2);
+        visits.send(visit(0)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(2).await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 1 },
+        )
+        .await;
+
+        // Something unasked for means the connection is out of step, so the
+        // handler hangs up without waiting for a visit to use it.
+        Message::Ok.encode(&mut conn.write).await.unwrap();
+        let closed = timeout(Duration::from_secs(5), Message::decode(&mut conn.read)).await;
+        assert!(closed.unwrap().is_err());
+        visits.send(visit(9)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(2).await;
+        conn.expect(Message::DeletePolicy { policy: 1 }, Message::Ok)
+            .await;
+        conn.expect(create(Action::Cull), Message::PolicyResult { policy: 2 })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn authorities_are_tried_in_order() {
+        // Nothing listens there any more, so dialing it is refused.
+        let gone = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr();
+        let gone = gone.unwrap().to_string();
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = list.local_addr().unwrap().to_string();
+        let sites = Sites::new(vec![gone, live], TargetCache::new(None));
+        let visits = Arc::new(sites).start_handler(1);
+
+        visits.send(visit(0)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(1).await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 1 },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn refused_operations_are_reset() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sites = Arc::new(sites(&list, None));
+        let visits = sites.start_handler(3);
+        let refusal = || Message::Error {
+            message: "no".to_owned(),
+        };
+
+        visits.send(visit(0)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(3).await;
+        conn.expect(create(Action::Conserve), refusal()).await;
+        // Not created, so the next visit that needs it tries again.
+        visits.send(visit(0)).unwrap();
+        conn.expect(create(Action::Conserve), Message::Ok).await;
+        visits.send(visit(0)).unwrap();
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 4 },
+        )
+        .await;
+        // Refusing to delete it doesn't stop its replacement.
+        visits.send(visit(9)).unwrap();
+        conn.expect(Message::DeletePolicy { policy: 4 }, refusal())
+            .await;
+        conn.expect(create(Action::Cull), Message::PolicyResult { policy: 5 })
+            .await;
+        visits.send(visit(2)).unwrap();
+        conn.expect(Message::DeletePolicy { policy: 5 }, Message::Ok)
+            .await;
+        let metrics = sites.metrics(3);
+        assert_eq!(5, metrics.visits.load(Relaxed));
+        assert_eq!(2, metrics.created.load(Relaxed));
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_visit_is_applied() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let visits = Arc::new(sites(&list, None)).start_handler(4);
+        // The handler is stuck dialing while these come in.
+        visits.send(visit(0)).unwrap();
+        visits.send(visit(9)).unwrap();
+        visits.send(visit(5)).unwrap();
+
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(4).await;
+        conn.expect(create(Action::Cull), Message::PolicyResult { policy: 1 })
+            .await;
+        visits.send(visit(2)).unwrap();
+        conn.expect(Message::DeletePolicy { policy: 1 }, Message::Ok)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn stale_targets_are_fetched_again() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sites = Arc::new(sites(&list, Some(Duration::ZERO)));
+        let visits = sites.start_handler(6);
+        visits.send(visit(0)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(6).await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 1 },
+        )
+        .await;
+
+        // Nothing stays fresh for a ttl of 0, so every visit dials again.
+        visits.send(visit(0)).unwrap();
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(6).await;
+        conn.expect(Message::DeletePolicy { policy: 1 }, Message::Ok)
+            .await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 2 },
+        )
+        .await;
+        let report = sites.targets.report(Instant::now());
+        assert!(report.starts_with("site 6: dog=1..3 "), "{report}");
+    }
+
+    #[tokio::test]
+    async fn silent_authorities_are_dialed_again() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sites = sites(&list, None).with_timeout(Duration::from_millis(50));
+        let sites = Arc::new(sites);
+        let visits = sites.start_handler(8);
+
+        let mut conn = Conn::accept(&list).await;
+        conn.expect(hello(), hello()).await;
+        let silent = Message::decode(&mut conn.read).await.unwrap();
+        assert_eq!(Message::DialAuthority { site: 8 }, silent);
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(8).await;
+        visits.send(visit(0)).unwrap();
+        let silent = Message::decode(&mut conn.read).await.unwrap();
+        assert_eq!(create(Action::Conserve), silent);
+
+        // The policy was never made, so there's nothing to delete, but the
+        // visit is applied again.
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(8).await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 1 },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn idle_handlers_stop_and_hand_over_their_policies() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sites = sites(&list, None).with_idle(Some(Duration::from_millis(50)));
+        let sites = Arc::new(sites);
+        sites.send_visit(5, visit(0)).await;
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(5).await;
+        conn.expect(
+            create(Action::Conserve),
+            Message::PolicyResult { policy: 1 },
+        )
+        .await;
+        // The handler closes the connection once it's idle.
+        let closed = timeout(Duration::from_secs(5), Message::decode(&mut conn.read)).await;
+        assert!(closed.unwrap().is_err());
+        assert!(sites.handlers.lock().await.is_empty());
+
+        // The next handler knows about the policy the last one made.
+        sites.send_visit(5, visit(9)).await;
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(5).await;
+        conn.expect(Message::DeletePolicy { policy: 1 }, Message::Ok)
+            .await;
+        conn.expect(create(Action::Cull), Message::PolicyResult { policy: 2 })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn waiting_handlers_give_up_their_connection() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sites = sites(&list, None).with_max_connections(Some(1));
+        let sites = Arc::new(sites);
+        sites.send_visit(1, visit(0)).await;
+        let mut first = Conn::accept(&list).await;
+        first.handshake(1).await;
+        first
+            .expect(
+                create(Action::Conserve),
+                Message::PolicyResult { policy: 1 },
+            )
+            .await;
+
+        // Site 1 is waiting for visits, so site 2 gets its slot.
+        sites.send_visit(2, visit(0)).await;
+        let closed = timeout(Duration::from_secs(5), Message::decode(&mut first.read)).await;
+        assert!(closed.unwrap().is_err());
+        let mut second = Conn::accept(&list).await;
+        second.handshake(2).await;
+        second
+            .expect(
+                create(Action::Conserve),
+                Message::PolicyResult { policy: 2 },
+            )
+            .await;
+
+        // And site 1 gets it back on its next visit.
+        sites.send_visit(1, visit(9)).await;
+        let closed = timeout(Duration::from_secs(5), Message::decode(&mut second.read)).await;
+        assert!(closed.unwrap().is_err());
+        let mut third = Conn::accept(&list).await;
+        third.handshake(1).await;
+        third
+            .expect(Message::DeletePolicy { policy: 1 }, Message::Ok)
+            .await;
+        third
+            .expect(create(Action::Cull), Message::PolicyResult { policy: 3 })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn stopped_handlers_are_replaced() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sites = Arc::new(sites(&list, None));
+        let (stopped, _) = watch::channel(Vec::new());
+        sites.handlers.lock().await.insert(9, stopped);
+        sites.send_visit(9, visit(5)).await;
+        assert!(!sites.handlers.lock().await[&9].is_closed());
+
+        let mut conn = Conn::accept(&list).await;
+        conn.handshake(9).await;
+        conn.expect(create(Action::Cull), Message::PolicyResult { policy: 1 })
+            .await;
+    }
+}
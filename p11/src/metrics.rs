@@ -0,0 +1,49 @@
+//! Counters of what each site handler does, for spotting sites whose
+//! policies keep flipping.
+
+use proto_common::Latency;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Counters of one site, kept across its handlers.
+#[derive(Default)]
+pub struct SiteMetrics {
+    /// Visits applied, not counting ones superseded before the handler
+    /// got to them.
+    pub visits: AtomicU64,
+    pub created: AtomicU64,
+    pub deleted: AtomicU64,
+    /// How long the authority took to answer each request.
+    pub round_trips: Latency,
+}
+
+impl fmt::Display for SiteMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "visits: {}, created: {}, deleted: {}, round trips: {}",
+            self.visits.load(Relaxed),
+            self.created.load(Relaxed),
+            self.deleted.load(Relaxed),
+            self.round_trips,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn site_metrics_show_counts_and_round_trips() {
+        let metrics = SiteMetrics::default();
+        metrics.visits.fetch_add(3, Relaxed);
+        metrics.created.fetch_add(2, Relaxed);
+        metrics.round_trips.record(Duration::from_millis(2));
+        assert_eq!(
+            "visits: 3, created: 2, deleted: 0, round trips: n=1 mean=2ms p50<=2ms p99<=2ms max=2ms",
+            metrics.to_string()
+        );
+    }
+}
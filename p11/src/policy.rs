@@ -0,0 +1,333 @@
+//! Which policies a site needs, worked out apart from talking to its
+//! authority.
+
+use crate::targets::Targets;
+use pestcontrol::{Action, ObservedPopulation};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::debug;
+
+/// A request the authority has to be sent to bring a species in line.
+#[derive(Debug, PartialEq)]
+pub enum Op {
+    Delete { species: String, policy: u32 },
+    Create { species: String, action: Action },
+}
+
+/// The policies in place at one site, at most one per species. It only
+/// says what to do; the caller does it and reports back with
+/// [`PolicyManager::created`] and [`PolicyManager::deleted`].
+///
+/// With a debounce of more than one, a species' policy is only changed
+/// once that many observations in a row call for the same other one, so
+/// counts going back and forth don't change it every time.
+pub struct PolicyManager {
+    targets: Arc<Targets>,
+    /// Policy in place for each species, by id.
+    policies: HashMap<String, (u32, Action)>,
+    /// Policies restored after the first one for their species.
+    extra: Vec<u32>,
+    debounce: u32,
+    /// For each species whose policy is to change, what it's to change to
+    /// and how many observations in a row have called for that.
+    pending: HashMap<String, (Option<Action>, u32)>,
+}
+
+impl Default for PolicyManager {
+    fn default() -> PolicyManager {
+        PolicyManager {
+            targets: Arc::default(),
+            policies: HashMap::new(),
+            extra: vec![],
+            debounce: 1,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl PolicyManager {
+    /// Uses `targets` for what's observed from now on. Policies in place
+    /// stay until the next observation of their species.
+    pub fn set_targets(&mut self, targets: Arc<Targets>) {
+        self.targets = targets;
+    }
+
+    /// Needs `debounce` observations in a row calling for another policy
+    /// before changing one, from now on.
+    ///
+    /// # Panics
+    ///
+    /// If it's 0.
+    pub fn set_debounce(&mut self, debounce: u32) {
+        assert!(debounce > 0, "a debounce of 0 would never change anything");
+        self.debounce = debounce;
+    }
+
+    /// What has to be done for a species seen `count` times: nothing, or
+    /// deleting the policy in place, creating one, or both, in that order.
+    /// Species without a target need nothing, and neither do those whose
+    /// change hasn't been called for often enough yet.
+    pub fn observe(&mut self, species: &str, count: u32) -> Vec<Op> {
+        let Some(&(min, max)) = self.targets.get(species) else {
+            return vec![];
+        };
+        let wanted = select_new_action(count, min, max);
+        let current = self.policies.get(species).copied();
+        if current.map(|(_, action)| action) == wanted {
+            self.pending.remove(species);
+            debug!(species, count, min, max, action = ?wanted, "decided to keep");
+            return vec![];
+        }
+        let streak = match self.pending.get_mut(species) {
+            Some((pending, streak)) if *pending == wanted => {
+                *streak += 1;
+                *streak
+            }
+            _ => {
+                self.pending.insert(species.to_owned(), (wanted, 1));
+                1
+            }
+        };
+        if streak < self.debounce {
+            debug!(species, count, min, max, action = ?wanted, streak, "holding off");
+            return vec![];
+        }
+        debug!(species, count, min, max, action = ?wanted, "decided");
+        let mut ops = vec![];
+        if let Some((policy, _)) = current {
+            ops.push(Op::Delete {
+                species: species.to_owned(),
+                policy,
+            });
+        }
+        if let Some(action) = wanted {
+            ops.push(Op::Create {
+                species: species.to_owned(),
+                action,
+            });
+        }
+        ops
+    }
+
+    /// What has to be done for a whole visit, in which each species is
+    /// taken to be counted as it is the first time. Targeted species it
+    /// doesn't count are taken to have been counted 0 times.
+    pub fn visit(&mut self, populations: &[ObservedPopulation]) -> Vec<Op> {
+        let mut seen = HashSet::new();
+        let mut counts: Vec<(&str, u32)> = populations
+            .iter()
+            .filter(|p| seen.insert(p.species.as_str()))
+            .map(|p| (p.species.as_str(), p.count))
+            .collect();
+        let targets = self.targets.clone();
+        let mut unseen: Vec<&str> = targets
+            .keys()
+            .map(String::as_str)
+            .filter(|species| !seen.contains(species))
+            .collect();
+        unseen.sort_unstable();
+        counts.extend(unseen.into_iter().map(|species| (species, 0)));
+        counts
+            .into_iter()
+            .flat_map(|(species, count)| self.observe(species, count))
+            .collect()
+    }
+
+    /// Records that `species` got `policy`.
+    ///
+    /// # Panics
+    ///
+    /// If it had one already, which [`PolicyManager::observe`] would have
+    /// said to delete first.
+    pub fn created(&mut self, species: &str, policy: u32, action: Action) {
+        let old = self.policies.insert(species.to_owned(), (policy, action));
+        assert!(old.is_none(), "second policy for '{species}': {old:?}");
+    }
+
+    /// Records that `species` had `policy` before a restart. Unlike
+    /// [`PolicyManager::created`], a second one for a species is fine, and
+    /// kept for [`PolicyManager::take_all`] to return.
+    pub fn restored(&mut self, species: &str, policy: u32, action: Action) {
+        if self.policies.contains_key(species) {
+            self.extra.push(policy);
+        } else {
+            self.created(species, policy, action);
+        }
+    }
+
+    /// Records that `species` has no policy any more.
+    pub fn deleted(&mut self, species: &str) {
+        self.policies.remove(species);
+    }
+
+    /// Forgets every policy, returning their ids in order. Each species
+    /// gets its policy back on the next observation that calls for it,
+    /// however many it takes to change one.
+    pub fn take_all(&mut self) -> Vec<u32> {
+        let mut ids = vec![];
+        for (species, (id, action)) in self.policies.drain() {
+            let settled = (Some(action), self.debounce - 1);
+            self.pending.insert(species, settled);
+            ids.push(id);
+        }
+        ids.append(&mut self.extra);
+        ids.sort_unstable();
+        ids
+    }
+}
+
+fn select_new_action(count: u32, min: u32, max: u32) -> Option<Action> {
+    if count < min {
+        Some(Action::Conserve)
+    } else if count > max {
+        Some(Action::Cull)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> PolicyManager {
+        let mut manager = PolicyManager::default();
+        let targets = [("dog", (1, 3)), ("cat", (0, 2))];
+        let targets = targets.map(|(s, range)| (s.to_owned(), range));
+        manager.set_targets(Arc::new(targets.into_iter().collect()));
+        manager
+    }
+
+    fn create(species: &str, action: Action) -> Op {
+        Op::Create {
+            species: species.to_owned(),
+            action,
+        }
+    }
+
+    fn delete(species: &str, policy: u32) -> Op {
+        Op::Delete {
+            species: species.to_owned(),
+            policy,
+        }
+    }
+
+    #[test]
+    fn counts_in_range_need_no_policy() {
+        let mut manager = manager();
+        for count in 1..=3 {
+            assert_eq!(Vec::<Op>::new(), manager.observe("dog", count));
+        }
+        assert_eq!(
+            vec![create("dog", Action::Conserve)],
+            manager.observe("dog", 0)
+        );
+        assert_eq!(vec![create("dog", Action::Cull)], manager.observe("dog", 4));
+        assert_eq!(Vec::<Op>::new(), manager.observe("fox", 100));
+    }
+
+    #[test]
+    fn a_changed_policy_is_deleted_before_its_replacement() {
+        let mut manager = manager();
+        manager.created("dog", 1, Action::Conserve);
+        assert_eq!(Vec::<Op>::new(), manager.observe("dog", 0));
+        assert_eq!(
+            vec![delete("dog", 1), create("dog", Action::Cull)],
+            manager.observe("dog", 9)
+        );
+        assert_eq!(vec![delete("dog", 1)], manager.observe("dog", 2));
+    }
+
+    #[test]
+    fn visits_count_unseen_species_as_none() {
+        let mut manager = manager();
+        manager.created("cat", 7, Action::Cull);
+        let visit =
+            [("fox", 9), ("dog", 0), ("dog", 0)].map(|(species, count)| ObservedPopulation {
+                species: species.to_owned(),
+                count,
+            });
+        assert_eq!(
+            vec![create("dog", Action::Conserve), delete("cat", 7)],
+            manager.visit(&visit)
+        );
+    }
+
+    #[test]
+    fn every_species_has_at_most_one_policy() {
+        let mut manager = manager();
+        let mut next = 1;
+        for count in [0, 0, 5, 2, 9, 0, 1, 4] {
+            for op in manager.observe("dog", count) {
+                match op {
+                    Op::Delete { species, .. } => manager.deleted(&species),
+                    Op::Create { species, action } => {
+                        manager.created(&species, next, action);
+                        next += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(vec![next - 1], manager.take_all());
+        assert_eq!(Vec::<u32>::new(), manager.take_all());
+    }
+
+    #[test]
+    fn restored_duplicates_are_taken_too() {
+        let mut manager = manager();
+        manager.restored("dog", 3, Action::Cull);
+        manager.restored("dog", 1, Action::Conserve);
+        manager.restored("cat", 2, Action::Cull);
+        assert_eq!(vec![delete("dog", 3)], manager.observe("dog", 2));
+        assert_eq!(vec![1, 2, 3], manager.take_all());
+        assert_eq!(Vec::<u32>::new(), manager.take_all());
+    }
+
+    #[test]
+    fn debounced_policies_change_after_enough_observations_in_a_row() {
+        let mut manager = manager();
+        manager.set_debounce(3);
+        assert_eq!(Vec::<Op>::new(), manager.observe("dog", 0));
+        assert_eq!(Vec::<Op>::new(), manager.observe("dog", 0));
+        assert_eq!(
+            vec![create("dog", Action::Conserve)],
+            manager.observe("dog", 0)
+        );
+        manager.created("dog", 1, Action::Conserve);
+
+        // Going back and forth changes nothing, and neither does calling
+        // for two other things in turn.
+        for count in [9, 9, 0, 9, 2, 9, 9, 0] {
+            assert_eq!(Vec::<Op>::new(), manager.observe("dog", count));
+        }
+        for count in [2, 2] {
+            assert_eq!(Vec::<Op>::new(), manager.observe("dog", count));
+        }
+        assert_eq!(vec![delete("dog", 1)], manager.observe("dog", 2));
+        manager.deleted("dog");
+        assert_eq!(Vec::<Op>::new(), manager.observe("dog", 2));
+    }
+
+    #[test]
+    fn debounced_policies_taken_come_back_at_once() {
+        let mut manager = manager();
+        manager.set_debounce(3);
+        manager.created("dog", 1, Action::Cull);
+        assert_eq!(vec![1], manager.take_all());
+        assert_eq!(vec![create("dog", Action::Cull)], manager.observe("dog", 9));
+
+        // A refused create is asked for again on the next observation.
+        assert_eq!(vec![create("dog", Action::Cull)], manager.observe("dog", 9));
+        manager.created("dog", 2, Action::Cull);
+        assert_eq!(vec![2], manager.take_all());
+        assert_eq!(Vec::<Op>::new(), manager.observe("dog", 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "second policy for 'dog'")]
+    fn creating_a_second_policy_panics() {
+        let mut manager = manager();
+        manager.created("dog", 1, Action::Conserve);
+        manager.created("dog", 2, Action::Cull);
+    }
+}
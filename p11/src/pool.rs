@@ -0,0 +1,79 @@
+//! Slots for connections to the authority, so no more of them are open at
+//! once than the authority allows.
+
+use std::sync::Arc;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// Held for as long as a connection is open, and given back when dropped.
+pub type Slot = OwnedSemaphorePermit;
+
+/// Shared by every site handler. A handler that can't get a slot asks for
+/// one, and the first handler that isn't using its connection is expected
+/// to close it.
+pub struct Pool {
+    slots: Arc<Semaphore>,
+    wanted: Notify,
+}
+
+impl Pool {
+    /// A pool of `limit` slots, or of as many as are asked for without one.
+    pub fn new(limit: Option<usize>) -> Pool {
+        Pool {
+            slots: Arc::new(Semaphore::new(limit.unwrap_or(Semaphore::MAX_PERMITS))),
+            wanted: Notify::new(),
+        }
+    }
+
+    /// Takes a slot, asking for one to be given back if there's none free
+    /// and waiting for it.
+    pub async fn take(&self) -> Slot {
+        if let Ok(slot) = self.slots.clone().try_acquire_owned() {
+            return slot;
+        }
+        self.wanted.notify_one();
+        let slot = self.slots.clone().acquire_owned().await;
+        slot.expect("the pool is never closed")
+    }
+
+    /// Resolves when a slot is asked for, for one holder at a time. A
+    /// request made while no one is waiting here goes to the next one that
+    /// does.
+    pub async fn wanted(&self) {
+        self.wanted.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn a_full_pool_asks_for_a_slot_back() {
+        let pool = Arc::new(Pool::new(Some(1)));
+        let held = pool.take().await;
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.take().await }
+        });
+        timeout(Duration::from_secs(5), pool.wanted())
+            .await
+            .unwrap();
+        assert!(!waiting.is_finished());
+        drop(held);
+        let slot = timeout(Duration::from_secs(5), waiting).await.unwrap();
+        drop(slot.unwrap());
+    }
+
+    #[tokio::test]
+    async fn pools_without_a_limit_never_ask() {
+        let pool = Pool::new(None);
+        let mut slots = vec![];
+        for _ in 0..100 {
+            slots.push(pool.take().await);
+        }
+        let wanted = timeout(Duration::from_millis(10), pool.wanted()).await;
+        assert!(wanted.is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Policies made at the authority, kept in a file so that after a restart
+//! they can be deleted rather than left behind.
+
+use anyhow::{bail, Context, Result};
+use pestcontrol::Action;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::task;
+use tracing::warn;
+
+/// Species and action of each policy, by site and id.
+type Policies = BTreeMap<(u32, u32), (String, Action)>;
+
+/// Every policy the authority said it made and hasn't deleted yet, shared
+/// by every site handler. With a path, the whole of it is written there on
+/// every change, one policy per line as `site id action species`.
+#[derive(Default)]
+pub struct Store {
+    path: Option<PathBuf>,
+    policies: Mutex<Policies>,
+    /// Held while the file is written, so writes happen one at a time and
+    /// the last one has the latest policies.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Store {
+    /// Reads what was stored at `path` last time, if anything.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Store> {
+        let path = path.into();
+        let policies = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text).with_context(|| format!("reading {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Policies::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        Ok(Store {
+            path: Some(path),
+            policies: Mutex::new(policies),
+            saving: tokio::sync::Mutex::default(),
+        })
+    }
+
+    /// Species, id and action of each stored policy, by site.
+    pub fn sites(&self) -> BTreeMap<u32, Vec<(String, u32, Action)>> {
+        let mut sites: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for ((site, id), (species, action)) in self.policies.lock().unwrap().iter() {
+            let policy = (species.clone(), *id, *action);
+            sites.entry(*site).or_default().push(policy);
+        }
+        sites
+    }
+
+    pub async fn created(&self, site: u32, policy: u32, species: &str, action: Action) {
+        let made = (species.to_owned(), action);
+        self.policies.lock().unwrap().insert((site, policy), made);
+        self.save().await;
+    }
+
+    pub async fn deleted(&self, site: u32, policy: u32) {
+        let removed = self.policies.lock().unwrap().remove(&(site, policy));
+        if removed.is_some() {
+            self.save().await;
+        }
+    }
+
+    /// Writes the policies to the path, off the runtime's threads as it
+    /// waits on the disk. Failing only gets logged, since the policies are
+    /// in place either way.
+    async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _saving = self.saving.lock().await;
+        let mut text = String::new();
+        for ((site, id), (species, action)) in self.policies.lock().unwrap().iter() {
+            let action = match action {
+                Action::Cull => "cull",
+                Action::Conserve => "conserve",
+            };
+            writeln!(text, "{site} {id} {action} {species}").unwrap();
+        }
+        let to = path.clone();
+        let saved = task::spawn_blocking(move || write(&to, &text)).await;
+        if let Err(e) = saved.map_err(io::Error::from).and_then(|saved| saved) {
+            warn!("saving policies to {} failed: {e}", path.display());
+        }
+    }
+}
+
+/// Writes `text` to a file next to `path`, syncs it and moves it over
+/// `path`, so a crash leaves either the old contents or the new ones and
+/// never an empty or half-written file.
+fn write(path: &Path, text: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn parse(text: &str) -> Result<Policies> {
+    let mut policies = Policies::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut fields = line.splitn(4, ' ');
+        let (Some(site), Some(id), Some(action), Some(species)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            bail!("line {}: expected 'site id action species'", n + 1);
+        };
+        let action = match action {
+            "cull" => Action::Cull,
+            "conserve" => Action::Conserve,
+            other => bail!("line {}: unknown action '{other}'", n + 1),
+        };
+        let key = (site.parse()?, id.parse()?);
+        policies.insert(key, (species.to_owned(), action));
+    }
+    Ok(policies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn policies_are_read_back_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policies");
+
+        let store = Store::open(&path).unwrap();
+        assert!(store.sites().is_empty());
+        store.created(2, 7, "dog", Action::Cull).await;
+        store.created(1, 3, "big cat", Action::Conserve).await;
+        store.created(1, 4, "big cat", Action::Cull).await;
+        store.created(2, 8, "fox", Action::Cull).await;
+        store.deleted(2, 8).await;
+        store.deleted(2, 9).await;
+        drop(store);
+
+        let store = Store::open(&path).unwrap();
+        let expected = BTreeMap::from([
+            (
+                1,
+                vec![
+                    ("big cat".to_owned(), 3, Action::Conserve),
+                    ("big cat".to_owned(), 4, Action::Cull),
+                ],
+            ),
+            (2, vec![("dog".to_owned(), 7, Action::Cull)]),
+        ]);
+        assert_eq!(expected, store.sites());
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn broken_files_are_refused() {
+        assert!(parse("1 2 cull dog\n").is_ok());
+        assert!(parse("1 2 cull\n").is_err());
+        assert!(parse("1 2 feed dog\n").is_err());
+        assert!(parse("one 2 cull dog\n").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Target populations of each site, as the authority last gave them.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// (min, max) by species.
+pub type Targets = HashMap<String, (u32, u32)>;
+
+/// What dialing each site's authority said, shared by every site handler.
+/// With a ttl, targets older than it are stale and the handler dials again
+/// to refresh them.
+pub struct TargetCache {
+    ttl: Option<Duration>,
+    sites: Mutex<HashMap<u32, Cached>>,
+}
+
+struct Cached {
+    targets: Arc<Targets>,
+    fetched: Instant,
+}
+
+impl TargetCache {
+    pub fn new(ttl: Option<Duration>) -> TargetCache {
+        TargetCache {
+            ttl,
+            sites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records what dialing `site` said at `now`.
+    pub fn insert(&self, site: u32, targets: Targets, now: Instant) -> Arc<Targets> {
+        let targets = Arc::new(targets);
+        let cached = Cached {
+            targets: targets.clone(),
+            fetched: now,
+        };
+        self.sites.lock().unwrap().insert(site, cached);
+        targets
+    }
+
+    /// Targets of `site`, unless there are none or they're stale at `now`.
+    pub fn get(&self, site: u32, now: Instant) -> Option<Arc<Targets>> {
+        let sites = self.sites.lock().unwrap();
+        let cached = sites.get(&site)?;
+        let stale = self
+            .ttl
+            .is_some_and(|ttl| now.duration_since(cached.fetched) >= ttl);
+        (!stale).then(|| cached.targets.clone())
+    }
+
+    /// One line per site, in order, with its targets by species and how
+    /// long ago they were fetched.
+    pub fn report(&self, now: Instant) -> String {
+        let sites = self.sites.lock().unwrap();
+        let mut ids: Vec<_> = sites.keys().copied().collect();
+        ids.sort_unstable();
+        let mut report = String::new();
+        for id in ids {
+            let cached = &sites[&id];
+            let mut species: Vec<_> = cached.targets.iter().collect();
+            species.sort_unstable();
+            write!(report, "site {id}:").unwrap();
+            for (name, (min, max)) in species {
+                write!(report, " {name}={min}..{max}").unwrap();
+            }
+            let age = now.duration_since(cached.fetched).as_secs();
+            writeln!(report, " ({age}s old)").unwrap();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(species: &[(&str, u32, u32)]) -> Targets {
+        species
+            .iter()
+            .map(|&(name, min, max)| (name.to_owned(), (min, max)))
+            .collect()
+    }
+
+    #[test]
+    fn targets_go_stale_after_the_ttl() {
+        let start = Instant::now();
+        let cache = TargetCache::new(Some(Duration::from_secs(60)));
+        assert_eq!(None, cache.get(1, start));
+        cache.insert(1, targets(&[("dog", 1, 3)]), start);
+        let later = start + Duration::from_secs(59);
+        assert_eq!(targets(&[("dog", 1, 3)]), *cache.get(1, later).unwrap());
+        assert_eq!(None, cache.get(1, start + Duration::from_secs(60)));
+
+        let cache = TargetCache::new(None);
+        cache.insert(1, targets(&[]), start);
+        assert!(cache.get(1, start + Duration::from_secs(1 << 20)).is_some());
+    }
+
+    #[test]
+    fn report_lists_sites_and_species_in_order() {
+        let start = Instant::now();
+        let cache = TargetCache::new(None);
+        cache.insert(9, targets(&[]), start);
+        cache.insert(2, targets(&[("dog", 1, 3), ("cat", 0, 2)]), start);
+        let report = cache.report(start + Duration::from_secs(5));
+        assert_eq!(
+            "site 2: cat=0..2 dog=1..3 (5s old)\nsite 9: (5s old)\n",
+            report
+        );
+    }
+}
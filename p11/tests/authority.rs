@@ -0,0 +1,147 @@
+use pestcontrol::mock::{Fault, MockAuthority};
+use pestcontrol::{Action, ObservedPopulation, PestControlClient, TargetPopulation};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// A p11 server talking to a mock authority, killed when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    async fn start(authority: &MockAuthority, extra: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{port}");
+        let child = Command::new(env!("CARGO_BIN_EXE_p11"))
+            .args(["--listen", &addr, "--authority"])
+            .arg(authority.addr().to_string())
+            .args(extra)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, addr };
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(&server.addr).await.is_ok() {
+                return server;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server didn't start listening on {}", server.addr);
+    }
+
+    async fn connect(&self) -> PestControlClient {
+        PestControlClient::connect(&self.addr).await.unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// An authority for sites 1 and 2, both wanting 1 to 3 dogs.
+async fn authority() -> MockAuthority {
+    let dogs = vec![TargetPopulation {
+        species: "dog".to_owned(),
+        min: 1,
+        max: 3,
+    }];
+    let targets = HashMap::from([(1, dogs.clone()), (2, dogs)]);
+    MockAuthority::start("127.0.0.1:0", targets).await.unwrap()
+}
+
+fn dogs(count: u32) -> Vec<ObservedPopulation> {
+    vec![ObservedPopulation {
+        species: "dog".to_owned(),
+        count,
+    }]
+}
+
+/// Waits for `site` to have just `policies`.
+async fn wait_for(authority: &MockAuthority, site: u32, policies: &[(&str, Action)]) {
+    let policies: Vec<_> = policies.iter().map(|&(s, a)| (s.to_owned(), a)).collect();
+    for _ in 0..100 {
+        if authority.policies(site) == policies {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(policies, authority.policies(site));
+}
+
+#[tokio::test]
+async fn visits_set_the_policies_of_their_site() {
+    let authority = authority().await;
+    let server = Server::start(&authority, &[]).await;
+    let mut client = server.connect().await;
+    client.site_visit(1, dogs(0)).await.unwrap();
+    wait_for(&authority, 1, &[("dog", Action::Conserve)]).await;
+    client.site_visit(1, dogs(7)).await.unwrap();
+    wait_for(&authority, 1, &[("dog", Action::Cull)]).await;
+
+    // Other clients and sites don't get in the way.
+    let mut other = server.connect().await;
+    other.site_visit(2, vec![]).await.unwrap();
+    wait_for(&authority, 2, &[("dog", Action::Conserve)]).await;
+    other.site_visit(1, dogs(2)).await.unwrap();
+    wait_for(&authority, 1, &[]).await;
+    assert_eq!(2, authority.connections());
+}
+
+#[tokio::test]
+async fn authority_faults_are_recovered_from() {
+    let authority = authority().await;
+    let server = Server::start(&authority, &["--authority-timeout-secs", "1"]).await;
+    let mut client = server.connect().await;
+
+    authority.inject(Fault::Error);
+    client.site_visit(1, dogs(0)).await.unwrap();
+    // The refused policy is asked for again on the next visit.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(authority.policies(1).is_empty());
+    client.site_visit(1, dogs(0)).await.unwrap();
+    wait_for(&authority, 1, &[("dog", Action::Conserve)]).await;
+
+    // Losing the connection, or the authority going quiet, is recovered
+    // from by dialing again.
+    authority.inject(Fault::Hangup);
+    client.site_visit(1, dogs(9)).await.unwrap();
+    wait_for(&authority, 1, &[("dog", Action::Cull)]).await;
+    authority.inject(Fault::Silence);
+    client.site_visit(1, dogs(0)).await.unwrap();
+    wait_for(&authority, 1, &[("dog", Action::Conserve)]).await;
+    assert_eq!(3, authority.connections());
+}
+
+#[tokio::test]
+async fn policies_left_by_a_restart_are_deleted() {
+    let authority = authority().await;
+    let dir = std::env::temp_dir().join(format!("p11-restart-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let state = dir.join("policies");
+    let state = state.to_str().unwrap();
+
+    let server = Server::start(&authority, &["--state", state]).await;
+    let mut client = server.connect().await;
+    client.site_visit(1, dogs(0)).await.unwrap();
+    client.site_visit(2, dogs(9)).await.unwrap();
+    wait_for(&authority, 1, &[("dog", Action::Conserve)]).await;
+    wait_for(&authority, 2, &[("dog", Action::Cull)]).await;
+    drop(server);
+
+    // Nothing visits after the restart, but the policies go anyway.
+    let _server = Server::start(&authority, &["--state", state]).await;
+    wait_for(&authority, 1, &[]).await;
+    wait_for(&authority, 2, &[]).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
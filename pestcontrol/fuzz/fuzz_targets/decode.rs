@@ -0,0 +1,28 @@
+//! Decodes frames one after another from arbitrary bytes, checking that
+//! nothing panics and that every frame accepted encodes back to the bytes
+//! it was read from. Run with `cargo +nightly fuzz run decode` from the
+//! pestcontrol directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pestcontrol::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let mut rest = data;
+        loop {
+            let start = rest;
+            let Ok(msg) = Message::decode(&mut rest).await else {
+                break;
+            };
+            let frame = &start[..start.len() - rest.len()];
+            let mut encoded = vec![];
+            msg.encode(&mut encoded).await.unwrap();
+            assert_eq!(frame, encoded, "{msg:?}");
+        }
+    });
+});
@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use pestcontrol::mock::MockAuthority;
+use pestcontrol::TargetPopulation;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Serves as the authority server for trying a pest control server out
+/// locally, printing the policies of each site as they change.
+#[derive(Parser)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:20547")]
+    listen: String,
+    /// A site and its targets, as `site:species=min..max,...`. May be given
+    /// more than once.
+    #[arg(long = "site", value_parser = parse_site)]
+    sites: Vec<(u32, Vec<TargetPopulation>)>,
+}
+
+fn parse_site(s: &str) -> Result<(u32, Vec<TargetPopulation>)> {
+    let (site, targets) = s.split_once(':').unwrap_or((s, ""));
+    let site = site.parse().context("bad site")?;
+    let mut populations = vec![];
+    for target in targets.split(',').filter(|t| !t.is_empty()) {
+        let parse = || {
+            let (species, range) = target.split_once('=')?;
+            let (min, max) = range.split_once("..")?;
+            Some(TargetPopulation {
+                species: species.to_owned(),
+                min: min.parse().ok()?,
+                max: max.parse().ok()?,
+            })
+        };
+        populations.push(parse().with_context(|| format!("bad target {target:?}"))?);
+    }
+    Ok((site, populations))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let sites: HashMap<_, _> = args.sites.into_iter().collect();
+    let mut ids: Vec<u32> = sites.keys().copied().collect();
+    ids.sort_unstable();
+    let mock = MockAuthority::start(&args.listen, sites).await?;
+    println!("listening on {}", mock.addr());
+    let mut last = HashMap::new();
+    loop {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        for &site in &ids {
+            let policies = mock.policies(site);
+            if last.get(&site) != Some(&policies) {
+                println!("site {site}: {policies:?}");
+                last.insert(site, policies);
+            }
+        }
+    }
+}
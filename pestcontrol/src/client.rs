@@ -0,0 +1,241 @@
+use crate::{hello, Action, Message, ObservedPopulation, TargetPopulation};
+use anyhow::{anyhow, bail, Result};
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time;
+
+/// How long an authority connection may go quiet before the OS starts
+/// probing whether the authority is still there.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+
+/// How often it probes after that, until it gives up on the connection.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A connection to the authority server, dialed to one site.
+///
+/// Each request waits at most the timeout it was dialed with for its
+/// reply. One that isn't answered in time fails like a broken connection,
+/// since a late reply would be taken for the answer to the next request,
+/// so the connection shouldn't be used after any error. Between requests,
+/// TCP keepalive finds out if the authority went away without hanging up,
+/// which [`AuthorityClient::closed`] reports.
+pub struct AuthorityClient {
+    read: BufReader<OwnedReadHalf>,
+    write: OwnedWriteHalf,
+    timeout: Duration,
+}
+
+impl AuthorityClient {
+    /// Connects to the authority at `addr` and dials `site`, returning the
+    /// connection and the site's target populations.
+    pub async fn dial(
+        addr: impl ToSocketAddrs,
+        site: u32,
+        timeout: Duration,
+    ) -> Result<(AuthorityClient, Vec<TargetPopulation>)> {
+        let Ok(stream) = time::timeout(timeout, TcpStream::connect(addr)).await else {
+            bail!("connecting took over {timeout:?}");
+        };
+        let stream = stream?.into_std()?;
+        let keepalive = TcpKeepalive::new()
+            .with_time(KEEPALIVE_TIME)
+            .with_interval(KEEPALIVE_INTERVAL);
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        let (read, write) = TcpStream::from_std(stream)?.into_split();
+        let mut client = AuthorityClient {
+            read: BufReader::new(read),
+            write,
+            timeout,
+        };
+        hello().encode(&mut client.write).await?;
+        let reply = client.reply().await?;
+        if reply != hello() {
+            bail!("bad hello from the authority: {reply:?}");
+        }
+        match client.request(Message::DialAuthority { site }).await? {
+            Message::TargetPopulations {
+                site: dialed,
+                populations,
+            } if dialed == site => Ok((client, populations)),
+            other => bail!("unexpected reply to dialing site {site}: {other:?}"),
+        }
+    }
+
+    /// Creates a policy and returns its id, or what the authority replied
+    /// instead, such as an error.
+    pub async fn create_policy(
+        &mut self,
+        species: &str,
+        action: Action,
+    ) -> Result<Result<u32, Message>> {
+        let request = Message::CreatePolicy {
+            species: species.to_owned(),
+            action,
+        };
+        match self.request(request).await? {
+            Message::PolicyResult { policy } => Ok(Ok(policy)),
+            other => Ok(Err(other)),
+        }
+    }
+
+    /// Deletes a policy, or returns what the authority replied instead of
+    /// saying it did.
+    pub async fn delete_policy(&mut self, policy: u32) -> Result<Result<(), Message>> {
+        match self.request(Message::DeletePolicy { policy }).await? {
+            Message::Ok => Ok(Ok(())),
+            other => Ok(Err(other)),
+        }
+    }
+
+    /// Sends any message and returns the reply.
+    pub async fn request(&mut self, request: Message) -> Result<Message> {
+        request.encode(&mut self.write).await?;
+        self.reply().await
+    }
+
+    /// Waits for the connection to turn out dead while no request is
+    /// waiting for a reply: the authority hanging up, keepalive giving up
+    /// on it, or the authority sending anything unasked. Dropping it before
+    /// then leaves the connection as it was.
+    pub async fn closed(&mut self) -> anyhow::Error {
+        match self.read.fill_buf().await {
+            Ok([]) => anyhow!("the authority hung up"),
+            Ok(_) => anyhow!("the authority sent a message unasked"),
+            Err(e) => e.into(),
+        }
+    }
+
+    async fn reply(&mut self) -> Result<Message> {
+        match time::timeout(self.timeout, Message::decode(&mut self.read)).await {
+            Ok(reply) => reply,
+            Err(_) => bail!("no reply in {:?}", self.timeout),
+        }
+    }
+}
+
+/// A connection to a pest control server, as a site's observers would
+/// have.
+pub struct PestControlClient {
+    read: BufReader<OwnedReadHalf>,
+    write: OwnedWriteHalf,
+}
+
+impl PestControlClient {
+    /// Connects to the server at `addr` and exchanges hellos.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<PestControlClient> {
+        let (read, write) = TcpStream::connect(addr).await?.into_split();
+        let mut client = PestControlClient {
+            read: BufReader::new(read),
+            write,
+        };
+        hello().encode(&mut client.write).await?;
+        let reply = client.recv().await?;
+        if reply != hello() {
+            bail!("bad hello from the server: {reply:?}");
+        }
+        Ok(client)
+    }
+
+    /// Reports what was counted at `site`. The server doesn't answer
+    /// visits, except with an error for a bad one.
+    pub async fn site_visit(
+        &mut self,
+        site: u32,
+        populations: Vec<ObservedPopulation>,
+    ) -> Result<()> {
+        Message::SiteVisit { site, populations }
+            .encode(&mut self.write)
+            .await
+    }
+
+    /// Sends any message, well formed or not.
+    pub async fn send(&mut self, message: &Message) -> Result<()> {
+        message.encode(&mut self.write).await
+    }
+
+    /// Waits for the next message from the server.
+    pub async fn recv(&mut self) -> Result<Message> {
+        Message::decode(&mut self.read).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn authority_refusals_come_back_as_replies() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = list.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut read = BufReader::new(read);
+            let targets = Message::TargetPopulations {
+                site: 4,
+                populations: vec![],
+            };
+            let refusal = Message::Error {
+                message: "no".to_owned(),
+            };
+            for reply in [
+                hello(),
+                targets,
+                Message::PolicyResult { policy: 2 },
+                refusal,
+            ] {
+                Message::decode(&mut read).await.unwrap();
+                reply.encode(&mut write).await.unwrap();
+            }
+        });
+
+        let timeout = Duration::from_secs(5);
+        let (mut client, targets) = AuthorityClient::dial(addr, 4, timeout).await.unwrap();
+        assert!(targets.is_empty());
+        let created = client.create_policy("dog", Action::Cull).await.unwrap();
+        assert_eq!(Ok(2), created);
+        let refusal = Message::Error {
+            message: "no".to_owned(),
+        };
+        assert_eq!(Err(refusal), client.delete_policy(2).await.unwrap());
+        // The authority hung up after that.
+        assert!(client.delete_policy(2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_noticed_between_requests() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        tokio::spawn(async move {
+            for unasked in [Some(Message::Ok), None] {
+                let (stream, _) = list.accept().await.unwrap();
+                let (read, mut write) = stream.into_split();
+                let mut read = BufReader::new(read);
+                let targets = Message::TargetPopulations {
+                    site: 4,
+                    populations: vec![],
+                };
+                for reply in [hello(), targets] {
+                    Message::decode(&mut read).await.unwrap();
+                    reply.encode(&mut write).await.unwrap();
+                }
+                if let Some(unasked) = unasked {
+                    unasked.encode(&mut write).await.unwrap();
+                    // Stays up until the client hangs up.
+                    while Message::decode(&mut read).await.is_ok() {}
+                }
+            }
+        });
+
+        let timeout = Duration::from_secs(5);
+        for expected in ["unasked", "hung up"] {
+            let (mut client, _) = AuthorityClient::dial(addr, 4, timeout).await.unwrap();
+            let closed = time::timeout(timeout, client.closed()).await.unwrap();
+            assert!(closed.to_string().contains(expected), "{closed}");
+        }
+    }
+}
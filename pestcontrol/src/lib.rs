@@ -0,0 +1,18 @@
+//! The pest control protocol (p11): its messages, and clients for both the
+//! authority server and a pest control server, shared by the p11 server,
+//! its tests and tooling.
+
+mod client;
+mod messages;
+pub mod mock;
+
+pub use client::{AuthorityClient, PestControlClient};
+pub use messages::*;
+
+/// The `Hello` every connection starts with, from both ends.
+pub fn hello() -> Message {
+    Message::Hello {
+        protocol: "pestcontrol".to_owned(),
+        version: 1,
+    }
+}
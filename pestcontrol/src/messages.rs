@@ -1,7 +1,27 @@
 use anyhow::{bail, ensure, Result};
+use std::fmt;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 
-#[derive(Debug, PartialEq)]
+/// Longest frame accepted, in bytes.
+pub const MAX_LEN: u32 = 1024 * 1024;
+
+/// Shortest frame there is: a type, a length and a checksum.
+const MIN_LEN: u32 = 1 + 4 + 1;
+
+/// A frame that breaks the protocol, as opposed to the connection failing
+/// while reading one. The peer should be sent an `Error` and cut off.
+#[derive(Debug)]
+pub struct ProtocolError(pub String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TargetPopulation {
     pub species: String,
     pub min: u32,
@@ -24,7 +44,7 @@ impl TargetPopulation {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ObservedPopulation {
     pub species: String,
     pub count: u32,
@@ -51,7 +71,7 @@ pub enum Action {
 }
 
 impl Action {
-    fn to_u8(&self) -> u8 {
+    fn to_u8(self) -> u8 {
         match self {
             Self::Cull => 0x90,
             Self::Conserve => 0xa0,
@@ -107,33 +127,51 @@ impl Message {
         }
     }
 
+    /// Reads one frame. Frames that break the protocol are
+    /// [`ProtocolError`]s; the connection failing, even mid-frame, is any
+    /// other error.
     pub async fn decode(r: &mut (impl AsyncBufReadExt + Unpin)) -> Result<Self> {
         let id = r.read_u8().await?;
         let msg_len = r.read_u32().await?;
-        ensure!(msg_len < 1024 * 1024);
+        if !(MIN_LEN..=MAX_LEN).contains(&msg_len) {
+            let e = format!("message length {msg_len} is out of {MIN_LEN}..={MAX_LEN}");
+            return Err(ProtocolError(e).into());
+        }
         // Space for the rest of the message and checksum ignoring header
         let mut inner_buf = vec![0; (msg_len - 1 - 4) as usize];
         r.read_exact(&mut inner_buf).await?;
+        Self::parse(id, msg_len, &inner_buf)
+            .await
+            .map_err(|e| ProtocolError(format!("{e:#}")).into())
+    }
+
+    /// Parses a frame of type `id` and length `msg_len`, given everything
+    /// after the length.
+    async fn parse(id: u8, msg_len: u32, inner_buf: &[u8]) -> Result<Self> {
+        // The checksum makes every byte of the frame add up to 0.
+        let sum = [id]
+            .iter()
+            .chain(&msg_len.to_be_bytes())
+            .chain(inner_buf)
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        ensure!(sum == 0, "invalid cksum, frame sums to {sum:#x}");
+        let mut body = &inner_buf[..inner_buf.len() - 1];
         let msg = match id {
-            0x50 => Self::decode_hello(&mut inner_buf.as_slice()).await?,
-            0x51 => Self::decode_error(&mut inner_buf.as_slice()).await?,
-            0x52 => Self::decode_ok(&mut inner_buf.as_slice()).await?,
-            0x53 => Self::decode_dialauthority(&mut inner_buf.as_slice()).await?,
-            0x54 => Self::decode_targetpopulations(&mut inner_buf.as_slice()).await?,
-            0x55 => Self::decode_createpolicy(&mut inner_buf.as_slice()).await?,
-            0x56 => Self::decode_deletepolicy(&mut inner_buf.as_slice()).await?,
-            0x57 => Self::decode_policyresult(&mut inner_buf.as_slice()).await?,
-            0x58 => Self::decode_sitevisit(&mut inner_buf.as_slice()).await?,
-            id => unimplemented!("msg with {id:0x} is not implemented"),
+            0x50 => Self::decode_hello(&mut body).await?,
+            0x51 => Self::decode_error(&mut body).await?,
+            0x52 => Self::decode_ok(&mut body).await?,
+            0x53 => Self::decode_dialauthority(&mut body).await?,
+            0x54 => Self::decode_targetpopulations(&mut body).await?,
+            0x55 => Self::decode_createpolicy(&mut body).await?,
+            0x56 => Self::decode_deletepolicy(&mut body).await?,
+            0x57 => Self::decode_policyresult(&mut body).await?,
+            0x58 => Self::decode_sitevisit(&mut body).await?,
+            id => bail!("unknown message type {id:#x}"),
         };
-
-        let mut buf = vec![];
-        msg.encode(&mut buf).await?;
         ensure!(
-            inner_buf.last().copied() == buf.last().copied(),
-            "invalid cksum, expected {:?}, got {:?}",
-            buf.last(),
-            inner_buf.last(),
+            body.is_empty(),
+            "{} unused bytes in the message",
+            body.len()
         );
 
         Ok(msg)
@@ -147,10 +185,10 @@ impl Message {
         let mut buf = vec![];
         buf.write_u8(self.id()).await?;
         buf.write_u32(len).await?;
-        buf.write(&inner_buf).await?;
+        buf.write_all(&inner_buf).await?;
         let cksum = (256 - buf.iter().fold(0u8, |a, b| a.overflowing_add(*b).0) as u16) as u8;
         buf.write_u8(cksum).await?;
-        w.write(&buf).await?;
+        w.write_all(&buf).await?;
         Ok(())
     }
 
@@ -260,20 +298,26 @@ impl Message {
 
 async fn read_string(r: &mut (impl AsyncBufReadExt + Unpin)) -> Result<String> {
     let len = r.read_u32().await?;
+    ensure!(
+        len < MAX_LEN,
+        "string of {len} bytes can't fit in a message"
+    );
     let mut buf = vec![0u8; len as usize];
     r.read_exact(&mut buf).await?;
     Ok(std::str::from_utf8(&buf)?.to_owned())
 }
 
 async fn write_string(w: &mut (impl AsyncWriteExt + Unpin), s: &str) -> Result<()> {
-    w.write_u32(s.as_bytes().len() as u32).await?;
-    w.write(s.as_bytes()).await?;
+    w.write_u32(s.len() as u32).await?;
+    w.write_all(s.as_bytes()).await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
     use tokio::io::BufReader;
 
     #[tokio::test]
@@ -282,7 +326,7 @@ mod tests {
             0x50, 0, 0, 0, 0x19, 0, 0, 0, 0xb, 0x70, 0x65, 0x73, 0x74, 0x63, 0x6f, 0x6e, 0x74,
             0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0xce,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::Hello {
             protocol: "pestcontrol".to_owned(),
@@ -301,12 +345,34 @@ mod tests {
             0x50, 0, 0, 0, 0x19, 0, 0, 0, 0xb, 0x70, 0x65, 0x73, 0x74, 0x63, 0x6f, 0x6e, 0x74,
             0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, /* bad cksum */ 0x00,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         assert!(Message::decode(&mut input).await.is_err());
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_corrupted_bytes() -> Result<()> {
+        let msg = Message::SiteVisit {
+            site: 12345,
+            populations: vec![ObservedPopulation {
+                species: "dog".to_owned(),
+                count: 1,
+            }],
+        };
+        let mut frame = vec![];
+        msg.encode(&mut frame).await?;
+        // Past the length, so each corrupted frame is read whole.
+        for i in 5..frame.len() - 1 {
+            let mut corrupted = frame.clone();
+            corrupted[i] ^= 0x10;
+            let mut input = BufReader::new(corrupted.as_slice());
+            assert!(Message::decode(&mut input).await.is_err(), "byte {i}");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_hello_4_unused() -> Result<()> {
         let input_bytes: &[u8] = &[
@@ -314,7 +380,7 @@ mod tests {
             0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
             0xca,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         assert!(Message::decode(&mut input).await.is_err());
 
         Ok(())
@@ -326,7 +392,57 @@ mod tests {
             0x50, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x0b, 0x70, 0x65, 0x73, 0x74, 0x63,
             0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0xca,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
+        assert!(Message::decode(&mut input).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lengths_out_of_bounds() -> Result<()> {
+        for len in [0, 4, 5, MAX_LEN + 1, u32::MAX] {
+            let mut input_bytes = vec![0x52];
+            input_bytes.extend(len.to_be_bytes());
+            let mut input = BufReader::new(input_bytes.as_slice());
+            let e = Message::decode(&mut input).await.unwrap_err();
+            assert!(e.is::<ProtocolError>(), "{len}: {e}");
+        }
+
+        // A frame cut short is the connection failing, not the protocol.
+        let input_bytes: &[u8] = &[0x52, 0x00, 0x00, 0x00, 0x06];
+        let mut input = BufReader::new(input_bytes);
+        let e = Message::decode(&mut input).await.unwrap_err();
+        assert!(!e.is::<ProtocolError>(), "{e}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bodies_must_be_used_up() -> Result<()> {
+        let frames: [&[u8]; 3] = [
+            // Ok with a byte left over.
+            &[0x52, 0x00, 0x00, 0x00, 0x07, 0x00, 0xa7],
+            // A string longer than its message.
+            &[0x51, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x03, 0xa2],
+            // A SiteVisit counting more populations than it has.
+            &[
+                0x58, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x98,
+            ],
+        ];
+        for frame in frames {
+            let mut input = BufReader::new(frame);
+            let e = Message::decode(&mut input).await.unwrap_err();
+            assert!(e.is::<ProtocolError>(), "{frame:x?}: {e}");
+            assert!(!e.to_string().contains("cksum"), "{frame:x?}: {e}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_type() -> Result<()> {
+        let input_bytes: &[u8] = &[0x60, 0x00, 0x00, 0x00, 0x06, 0x9a];
+        let mut input = BufReader::new(input_bytes);
         assert!(Message::decode(&mut input).await.is_err());
 
         Ok(())
@@ -337,7 +453,7 @@ mod tests {
         let input_bytes: &[u8] = &[
             0x51, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x03, 0x62, 0x61, 0x64, 0x78,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::Error {
             message: "bad".to_owned(),
@@ -352,7 +468,7 @@ mod tests {
     #[tokio::test]
     async fn test_ok() -> Result<()> {
         let input_bytes: &[u8] = &[0x52, 0x00, 0x00, 0x00, 0x06, 0xa8];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::Ok {};
         assert_eq!(expected, msg);
@@ -365,7 +481,7 @@ mod tests {
     #[tokio::test]
     async fn test_dialauthority() -> Result<()> {
         let input_bytes: &[u8] = &[0x53, 0x0, 0x0, 0x0, 0xa, 0x00, 0x00, 0x30, 0x39, 0x3a];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::DialAuthority { site: 12345 };
         assert_eq!(expected, msg);
@@ -383,7 +499,7 @@ mod tests {
             0x00, 0x00, 0x00, 0x03, 0x72, 0x61, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             0x0a, 0x80,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::TargetPopulations {
             site: 12345,
@@ -412,7 +528,7 @@ mod tests {
         let input_bytes: &[u8] = &[
             0x55, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x03, 0x64, 0x6f, 0x67, 0xa0, 0xc0,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::CreatePolicy {
             species: "dog".to_owned(),
@@ -428,7 +544,7 @@ mod tests {
     #[tokio::test]
     async fn test_deletepolicy() -> Result<()> {
         let input_bytes: &[u8] = &[0x56, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x7b, 0x25];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::DeletePolicy { policy: 123 };
         assert_eq!(expected, msg);
@@ -441,7 +557,7 @@ mod tests {
     #[tokio::test]
     async fn test_policyresult() -> Result<()> {
         let input_bytes: &[u8] = &[0x57, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x7b, 0x24];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::PolicyResult { policy: 123 };
         assert_eq!(expected, msg);
@@ -458,7 +574,7 @@ mod tests {
             0x00, 0x00, 0x03, 0x64, 0x6f, 0x67, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
             0x72, 0x61, 0x74, 0x00, 0x00, 0x00, 0x05, 0x8c,
         ];
-        let mut input = BufReader::new(input_bytes.clone());
+        let mut input = BufReader::new(input_bytes);
         let msg = Message::decode(&mut input).await?;
         let expected = Message::SiteVisit {
             site: 12345,
@@ -479,4 +595,86 @@ mod tests {
         assert_eq!(input_bytes, output);
         Ok(())
     }
+
+    /// Any message, with strings and lists short enough to shrink well.
+    fn message() -> impl Strategy<Value = Message> {
+        let species = || "[a-z ]{0,12}";
+        let action = prop_oneof![Just(Action::Cull), Just(Action::Conserve)];
+        let target = (species(), any::<u32>(), any::<u32>())
+            .prop_map(|(species, min, max)| TargetPopulation { species, min, max });
+        let observed = (species(), any::<u32>())
+            .prop_map(|(species, count)| ObservedPopulation { species, count });
+        prop_oneof![
+            (".{0,16}", any::<u32>())
+                .prop_map(|(protocol, version)| Message::Hello { protocol, version }),
+            ".{0,32}".prop_map(|message| Message::Error { message }),
+            Just(()).prop_map(|()| Message::Ok),
+            any::<u32>().prop_map(|site| Message::DialAuthority { site }),
+            (any::<u32>(), vec(target, 0..4))
+                .prop_map(|(site, populations)| Message::TargetPopulations { site, populations }),
+            (species(), action)
+                .prop_map(|(species, action)| Message::CreatePolicy { species, action }),
+            any::<u32>().prop_map(|policy| Message::DeletePolicy { policy }),
+            any::<u32>().prop_map(|policy| Message::PolicyResult { policy }),
+            (any::<u32>(), vec(observed, 0..4))
+                .prop_map(|(site, populations)| Message::SiteVisit { site, populations }),
+        ]
+    }
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        let rt = tokio::runtime::Builder::new_current_thread().build();
+        rt.unwrap().block_on(f)
+    }
+
+    fn encoded(msg: &Message) -> Vec<u8> {
+        let mut bytes = vec![];
+        block_on(msg.encode(&mut bytes)).unwrap();
+        bytes
+    }
+
+    fn decoded(bytes: &[u8]) -> Result<Message> {
+        block_on(Message::decode(&mut BufReader::new(bytes)))
+    }
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(msg in message()) {
+            let bytes = encoded(&msg);
+            let len = u32::from_be_bytes(bytes[1..5].try_into().unwrap());
+            prop_assert_eq!(bytes.len(), len as usize);
+            prop_assert_eq!(0, sum(&bytes));
+            prop_assert_eq!(msg, decoded(&bytes).unwrap());
+        }
+
+        #[test]
+        fn corrupted_frames_are_protocol_errors_or_read_back_the_same(
+            msg in message(),
+            at in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            // Changes a byte of the body, or the type, and fixes the
+            // checksum, so the frame gets as far as being parsed.
+            let mut bytes = encoded(&msg);
+            let last = bytes.len() - 1;
+            let at = match at.index(last - 4) {
+                0 => 0,
+                i => i + 4,
+            };
+            bytes[at] = byte;
+            bytes[last] = bytes[last].wrapping_sub(sum(&bytes));
+            match decoded(&bytes) {
+                Ok(msg) => prop_assert_eq!(bytes, encoded(&msg)),
+                Err(e) => prop_assert!(e.is::<ProtocolError>(), "{:#}", e),
+            }
+        }
+
+        #[test]
+        fn any_bytes_decode_without_panicking(bytes in vec(any::<u8>(), 0..64)) {
+            let _ = decoded(&bytes);
+        }
+    }
 }
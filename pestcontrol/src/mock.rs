@@ -0,0 +1,227 @@
+//! An authority server to test against, with faults that can be injected
+//! into its replies.
+
+use crate::{hello, Action, Message, TargetPopulation};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// What the mock does with a policy request instead of answering it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Replies with an `Error` and leaves the policies as they were.
+    Error,
+    /// Closes the connection without doing anything.
+    Hangup,
+    /// Never replies, until the client gives up on the connection.
+    Silence,
+}
+
+/// An authority server running on its own task until dropped. Policies
+/// belong to sites, not connections, so they outlive the connection that
+/// made them.
+pub struct MockAuthority {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct State {
+    targets: HashMap<u32, Vec<TargetPopulation>>,
+    /// Site, species and action of every policy in place, by id.
+    policies: HashMap<u32, (u32, String, Action)>,
+    last_id: u32,
+    /// For the next policy requests, in order, from any connection.
+    faults: VecDeque<Fault>,
+    connections: usize,
+}
+
+impl MockAuthority {
+    /// Starts serving on `addr` with the target populations of each site.
+    /// Dialing any other site gets an error.
+    pub async fn start(
+        addr: impl ToSocketAddrs,
+        targets: HashMap<u32, Vec<TargetPopulation>>,
+    ) -> Result<MockAuthority> {
+        let list = TcpListener::bind(addr).await?;
+        let addr = list.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            targets,
+            ..State::default()
+        }));
+        let task = tokio::spawn(serve(list, state.clone()));
+        Ok(MockAuthority { addr, state, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Species and action of the policies in place at `site`, in order.
+    pub fn policies(&self, site: u32) -> Vec<(String, Action)> {
+        let state = self.state.lock().unwrap();
+        let mut policies: Vec<_> = state
+            .policies
+            .values()
+            .filter(|(s, _, _)| *s == site)
+            .map(|(_, species, action)| (species.clone(), *action))
+            .collect();
+        policies.sort_by(|a, b| a.0.cmp(&b.0));
+        policies
+    }
+
+    /// Makes the next policy request that isn't taken by an earlier fault
+    /// get `fault`.
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push_back(fault);
+    }
+
+    /// Connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+}
+
+impl Drop for MockAuthority {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(list: TcpListener, state: Arc<Mutex<State>>) {
+    let mut conns = tokio::task::JoinSet::new();
+    while let Ok((stream, _)) = list.accept().await {
+        state.lock().unwrap().connections += 1;
+        conns.spawn(handle(stream, state.clone()));
+    }
+}
+
+async fn handle(stream: TcpStream, state: Arc<Mutex<State>>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    if Message::decode(&mut read).await? != hello() {
+        bail!("bad hello");
+    }
+    hello().encode(&mut write).await?;
+    let Message::DialAuthority { site } = Message::decode(&mut read).await? else {
+        bail!("expected DialAuthority");
+    };
+    let targets = state.lock().unwrap().targets.get(&site).cloned();
+    let Some(populations) = targets else {
+        let message = format!("no site {site}");
+        Message::Error { message }.encode(&mut write).await?;
+        bail!("dialed unknown site {site}");
+    };
+    Message::TargetPopulations { site, populations }
+        .encode(&mut write)
+        .await?;
+    loop {
+        let request = Message::decode(&mut read).await?;
+        let fault = match request {
+            Message::CreatePolicy { .. } | Message::DeletePolicy { .. } => {
+                state.lock().unwrap().faults.pop_front()
+            }
+            _ => None,
+        };
+        let reply = match fault {
+            Some(Fault::Error) => Message::Error {
+                message: "injected".to_owned(),
+            },
+            Some(Fault::Hangup) => return Ok(()),
+            Some(Fault::Silence) => {
+                // Whatever comes next is ignored until the client hangs up.
+                while Message::decode(&mut read).await.is_ok() {}
+                return Ok(());
+            }
+            None => answer(&mut state.lock().unwrap(), site, request),
+        };
+        reply.encode(&mut write).await?;
+    }
+}
+
+fn answer(state: &mut State, site: u32, request: Message) -> Message {
+    match request {
+        Message::CreatePolicy { species, action } => {
+            state.last_id += 1;
+            let policy = state.last_id;
+            state.policies.insert(policy, (site, species, action));
+            Message::PolicyResult { policy }
+        }
+        Message::DeletePolicy { policy } => match state.policies.get(&policy) {
+            Some(&(s, _, _)) if s == site => {
+                state.policies.remove(&policy);
+                Message::Ok
+            }
+            _ => Message::Error {
+                message: format!("no policy {policy}"),
+            },
+        },
+        other => Message::Error {
+            message: format!("unexpected {other:?}"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AuthorityClient;
+    use std::time::Duration;
+
+    async fn mock() -> MockAuthority {
+        let dog = TargetPopulation {
+            species: "dog".to_owned(),
+            min: 1,
+            max: 3,
+        };
+        MockAuthority::start("127.0.0.1:0", HashMap::from([(1, vec![dog])]))
+            .await
+            .unwrap()
+    }
+
+    async fn dial(mock: &MockAuthority, site: u32) -> Result<AuthorityClient> {
+        let (client, _) = AuthorityClient::dial(mock.addr(), site, Duration::from_secs(5)).await?;
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn policies_outlive_their_connection() {
+        let mock = mock().await;
+        let mut client = dial(&mock, 1).await.unwrap();
+        assert_eq!(
+            Ok(1),
+            client.create_policy("dog", Action::Cull).await.unwrap()
+        );
+        assert_eq!(vec![("dog".to_owned(), Action::Cull)], mock.policies(1));
+        drop(client);
+
+        let mut client = dial(&mock, 1).await.unwrap();
+        assert!(client.delete_policy(2).await.unwrap().is_err());
+        assert_eq!(Ok(()), client.delete_policy(1).await.unwrap());
+        assert_eq!(Vec::<(String, Action)>::new(), mock.policies(1));
+        assert!(dial(&mock, 2).await.is_err());
+        assert_eq!(3, mock.connections());
+    }
+
+    #[tokio::test]
+    async fn faults_are_injected_in_order() {
+        let mock = mock().await;
+        mock.inject(Fault::Error);
+        mock.inject(Fault::Hangup);
+        let mut client = dial(&mock, 1).await.unwrap();
+        let refused = client.create_policy("dog", Action::Cull).await.unwrap();
+        assert!(matches!(refused, Err(Message::Error { .. })));
+        assert!(client.create_policy("dog", Action::Cull).await.is_err());
+        assert!(mock.policies(1).is_empty());
+
+        mock.inject(Fault::Silence);
+        let (mut client, _) = AuthorityClient::dial(mock.addr(), 1, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(client.create_policy("dog", Action::Cull).await.is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Latency histograms the servers keep in their metrics.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+
+/// Buckets of a `Latency`; bucket `i` holds samples under 2^i µs, which
+/// tops out at over an hour.
+const BUCKETS: usize = 32;
+
+/// Distribution of durations, kept in power-of-two buckets so quantiles can
+/// be read off to within a factor of two.
+pub struct Latency {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            total_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Latency {
+    pub fn record(&self, d: Duration) {
+        let us = d.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Relaxed);
+        self.count.fetch_add(1, Relaxed);
+        self.total_us.fetch_add(us, Relaxed);
+        self.max_us.fetch_max(us, Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Relaxed)
+    }
+
+    pub fn mean(&self) -> Duration {
+        let count = self.count();
+        Duration::from_micros(self.total_us.load(Relaxed).checked_div(count).unwrap_or(0))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us.load(Relaxed))
+    }
+
+    /// Upper bound of the bucket holding quantile `q` of the samples.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (self.count() as f64 * q).ceil() as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Relaxed);
+            if seen >= target.max(1) {
+                return Duration::from_micros(1 << i).min(self.max());
+            }
+        }
+        self.max()
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} mean={:?} p50<={:?} p99<={:?} max={:?}",
+            self.count(),
+            self.mean(),
+            self.quantile(0.5),
+            self.quantile(0.99),
+            self.max(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds() {
+        let latency = Latency::default();
+        for ms in [1, 1, 1, 2, 50] {
+            latency.record(Duration::from_millis(ms));
+        }
+        assert_eq!(5, latency.count());
+        assert_eq!(Duration::from_micros(11_000), latency.mean());
+        assert_eq!(Duration::from_micros(1024), latency.quantile(0.5));
+        assert_eq!(Duration::from_millis(50), latency.quantile(0.99));
+        assert_eq!(Duration::from_millis(50), latency.max());
+        assert_eq!(Duration::ZERO, Latency::default().quantile(0.5));
+    }
+}
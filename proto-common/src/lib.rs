@@ -0,0 +1,17 @@
+//! Helpers shared by the servers.
+
+mod latency;
+mod limit;
+mod lines;
+mod listen;
+mod logging;
+mod report;
+mod serve;
+
+pub use latency::Latency;
+pub use limit::{ConnLimit, ConnMetrics, Limiter, Overflow, Permit};
+pub use lines::{LineCodec, LineError, DEFAULT_MAX_LEN};
+pub use listen::Listen;
+pub use logging::init_tracing;
+pub use report::{report, Report, Reporting, REPORT_INTERVAL};
+pub use serve::{signal, Server, Shutdown, SHUTDOWN_GRACE};
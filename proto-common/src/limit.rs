@@ -0,0 +1,204 @@
+//! Capping how many connections a server serves at once, so that a flood
+//! of them can't take the box down.
+
+use crate::report::{report, Reporting};
+use clap::{Args, ValueEnum};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info};
+
+/// Options every TCP server flattens into its own arguments.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ConnLimit {
+    /// Most connections served at once; no limit if not given.
+    #[arg(long, env = "PROTOHACKERS_MAX_CONNECTIONS")]
+    pub max_connections: Option<NonZeroUsize>,
+    /// What happens to connections beyond --max-connections.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_ON_OVERFLOW",
+        value_enum,
+        default_value_t = Overflow::Refuse
+    )]
+    pub on_overflow: Overflow,
+}
+
+/// What happens to connections beyond the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// Accepted and closed straight away.
+    #[default]
+    Refuse,
+    /// Left in the listen backlog until a connection being served closes.
+    Queue,
+}
+
+/// Connections a server has taken and turned away.
+#[derive(Default)]
+pub struct ConnMetrics {
+    pub accepted: AtomicU64,
+    /// Closed straight away for being over the limit.
+    pub rejected: AtomicU64,
+}
+
+impl fmt::Display for ConnMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "accepted: {}, rejected: {}",
+            self.accepted.load(Relaxed),
+            self.rejected.load(Relaxed),
+        )
+    }
+}
+
+/// Held for as long as a connection is served; dropping it makes room for
+/// the next one.
+pub struct Permit {
+    _held: Option<OwnedSemaphorePermit>,
+}
+
+/// Accepts connections while keeping to a `ConnLimit`.
+#[derive(Default)]
+pub struct Limiter {
+    /// One permit per connection allowed, or none for no limit.
+    permits: Option<Arc<Semaphore>>,
+    overflow: Overflow,
+    metrics: Arc<ConnMetrics>,
+}
+
+impl Limiter {
+    pub fn new(limit: &ConnLimit) -> Limiter {
+        Limiter {
+            permits: limit
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max.get()))),
+            overflow: limit.on_overflow,
+            metrics: Default::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<ConnMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Reports the connection counts with the other metrics whenever they
+    /// changed, for as long as the returned `Reporting` is kept.
+    pub fn report(&self) -> Reporting {
+        let conns = self.metrics();
+        let mut last = None;
+        report(move |_| {
+            let now = (conns.accepted.load(Relaxed), conns.rejected.load(Relaxed));
+            if last.replace(now) != Some(now) {
+                info!("connections: {conns}");
+            }
+        })
+    }
+
+    /// Next connection to serve, with the permit to hold while serving it.
+    /// Connections over the limit are closed as soon as they are accepted,
+    /// or, when queueing, not accepted until there's room for them. Cancel
+    /// safe.
+    pub async fn accept(&self, list: &TcpListener) -> io::Result<(TcpStream, SocketAddr, Permit)> {
+        loop {
+            let queued = match (&self.permits, self.overflow) {
+                (Some(permits), Overflow::Queue) => Some(
+                    permits
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("permits are never closed"),
+                ),
+                _ => None,
+            };
+            let (stream, addr) = list.accept().await?;
+            let permit = match (&self.permits, queued) {
+                (_, Some(permit)) => Some(permit),
+                (Some(permits), None) => match permits.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        self.metrics.rejected.fetch_add(1, Relaxed);
+                        debug!(peer = %addr, "rejecting connection over the limit");
+                        continue;
+                    }
+                },
+                (None, None) => None,
+            };
+            self.metrics.accepted.fetch_add(1, Relaxed);
+            return Ok((stream, addr, Permit { _held: permit }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+
+    fn limiter(max: usize, on_overflow: Overflow) -> Limiter {
+        Limiter::new(&ConnLimit {
+            max_connections: NonZeroUsize::new(max),
+            on_overflow,
+        })
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_rejected() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let limiter = limiter(1, Overflow::Refuse);
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let (_served, _, permit) = limiter.accept(&list).await.unwrap();
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let waiting = timeout(Duration::from_millis(50), limiter.accept(&list)).await;
+        assert!(waiting.is_err());
+        assert_eq!(0, second.read(&mut [0; 1]).await.unwrap());
+        assert_eq!(1, limiter.metrics.rejected.load(Relaxed));
+
+        drop(permit);
+        let _third = TcpStream::connect(addr).await.unwrap();
+        limiter.accept(&list).await.unwrap();
+        assert_eq!("accepted: 2, rejected: 1", limiter.metrics.to_string());
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_when_queueing() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let limiter = limiter(1, Overflow::Queue);
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let (_served, _, permit) = limiter.accept(&list).await.unwrap();
+
+        let _second = TcpStream::connect(addr).await.unwrap();
+        let waiting = timeout(Duration::from_millis(50), limiter.accept(&list)).await;
+        assert!(waiting.is_err());
+        drop(permit);
+        timeout(Duration::from_secs(1), limiter.accept(&list))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!("accepted: 2, rejected: 0", limiter.metrics.to_string());
+    }
+
+    #[tokio::test]
+    async fn no_limit_takes_everything() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let limiter = Limiter::default();
+        let mut held = vec![];
+        for _ in 0..3 {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            held.push(limiter.accept(&list).await.unwrap());
+        }
+        assert_eq!("accepted: 3, rejected: 0", limiter.metrics.to_string());
+    }
+}
@@ -0,0 +1,204 @@
+//! Lines of text, for the servers whose protocols are made of them.
+
+use anyhow::Result;
+use std::fmt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest line a [`LineCodec`] accepts unless told otherwise.
+pub const DEFAULT_MAX_LEN: usize = 1024 * 1024;
+
+/// A line that couldn't be read, as opposed to the connection failing
+/// while reading it.
+#[derive(Debug, PartialEq)]
+pub enum LineError {
+    /// Longer than the codec's limit, not counting the terminator.
+    TooLong(usize),
+    /// Cut off by the end of the stream before its `\n`.
+    Unterminated,
+    NotUtf8,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineError::TooLong(max) => write!(f, "line longer than {max} bytes"),
+            LineError::Unterminated => f.write_str("stream ended in the middle of a line"),
+            LineError::NotUtf8 => f.write_str("line isn't valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for LineError {}
+
+/// Reads and writes `\n` terminated lines, without their terminators.
+///
+/// Lines longer than the limit are refused rather than buffered. With CRLF
+/// handling, a `\r` right before the `\n` is taken as part of the
+/// terminator; without it, it's part of the line. Lines are always written
+/// with a bare `\n`.
+///
+/// Reading isn't cancel safe: whatever was read of a line is lost if
+/// reading it is given up on.
+#[derive(Debug, Clone, Copy)]
+pub struct LineCodec {
+    max_len: usize,
+    crlf: bool,
+}
+
+impl Default for LineCodec {
+    fn default() -> LineCodec {
+        LineCodec::new(DEFAULT_MAX_LEN)
+    }
+}
+
+impl LineCodec {
+    pub const fn new(max_len: usize) -> LineCodec {
+        LineCodec {
+            max_len,
+            crlf: false,
+        }
+    }
+
+    pub const fn with_crlf(self, crlf: bool) -> LineCodec {
+        LineCodec { crlf, ..self }
+    }
+
+    /// Reads the next line, or `None` if the stream ended cleanly before
+    /// it. Lines that can't be read are [`LineError`]s.
+    pub async fn read_line(&self, r: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<String>> {
+        // Room for the `\r` of a CRLF until the end of the line is found.
+        let limit = self.max_len + usize::from(self.crlf);
+        let mut line = vec![];
+        loop {
+            let available = r.fill_buf().await?;
+            if available.is_empty() {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                return Err(LineError::Unterminated.into());
+            }
+            let (chunk, done) = match available.iter().position(|b| *b == b'\n') {
+                Some(end) => (&available[..end], true),
+                None => (available, false),
+            };
+            if line.len() + chunk.len() > limit {
+                return Err(LineError::TooLong(self.max_len).into());
+            }
+            line.extend_from_slice(chunk);
+            let used = chunk.len() + usize::from(done);
+            r.consume(used);
+            if done {
+                break;
+            }
+        }
+        if self.crlf && line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.len() > self.max_len {
+            return Err(LineError::TooLong(self.max_len).into());
+        }
+        Ok(Some(
+            String::from_utf8(line).map_err(|_| LineError::NotUtf8)?,
+        ))
+    }
+
+    /// Writes `line` and a `\n`, and flushes.
+    pub async fn write_line(&self, w: &mut (impl AsyncWrite + Unpin), line: &str) -> Result<()> {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        w.write_all(&bytes).await?;
+        Ok(w.flush().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn read_all(codec: LineCodec, input: &[u8]) -> Vec<Result<String, String>> {
+        // A tiny buffer, so lines span several reads.
+        let mut r = BufReader::with_capacity(3, input);
+        let mut lines = vec![];
+        loop {
+            match codec.read_line(&mut r).await {
+                Ok(Some(line)) => lines.push(Ok(line)),
+                Ok(None) => return lines,
+                Err(e) => {
+                    lines.push(Err(e.downcast::<LineError>().unwrap().to_string()));
+                    return lines;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lines_come_without_their_terminators() {
+        let lines = read_all(LineCodec::default(), b"hello\n\nworld  \n").await;
+        let expected = vec![
+            Ok("hello".to_owned()),
+            Ok(String::new()),
+            Ok("world  ".to_owned()),
+        ];
+        assert_eq!(expected, lines);
+    }
+
+    #[tokio::test]
+    async fn carriage_returns_are_kept_unless_asked() {
+        let input = b"a\r\nb\r\r\n\r\n";
+        let kept = read_all(LineCodec::default(), input).await;
+        let expected = vec![
+            Ok("a\r".to_owned()),
+            Ok("b\r\r".to_owned()),
+            Ok("\r".to_owned()),
+        ];
+        assert_eq!(expected, kept);
+
+        let stripped = read_all(LineCodec::default().with_crlf(true), input).await;
+        let expected = vec![Ok("a".to_owned()), Ok("b\r".to_owned()), Ok(String::new())];
+        assert_eq!(expected, stripped);
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_refused() {
+        let codec = LineCodec::new(4);
+        let lines = read_all(codec, b"four\nfive!\n").await;
+        let too_long = LineError::TooLong(4).to_string();
+        assert_eq!(vec![Ok("four".to_owned()), Err(too_long.clone())], lines);
+
+        // The `\r` of a CRLF doesn't count, but any other does.
+        let codec = codec.with_crlf(true);
+        assert_eq!(
+            vec![Ok("four".to_owned())],
+            read_all(codec, b"four\r\n").await
+        );
+        let lines = read_all(codec, b"four\r\r\n").await;
+        assert_eq!(vec![Err(too_long)], lines);
+    }
+
+    #[tokio::test]
+    async fn broken_lines_are_errors() {
+        let lines = read_all(LineCodec::default(), b"ok\ncut").await;
+        let cut = LineError::Unterminated.to_string();
+        assert_eq!(vec![Ok("ok".to_owned()), Err(cut)], lines);
+        let lines = read_all(LineCodec::default(), b"\xff\n").await;
+        assert_eq!(vec![Err(LineError::NotUtf8.to_string())], lines);
+    }
+
+    #[tokio::test]
+    async fn written_lines_read_back() {
+        let codec = LineCodec::default();
+        let mut out = vec![];
+        for line in ["one", "", "three"] {
+            codec.write_line(&mut out, line).await.unwrap();
+        }
+        assert_eq!(b"one\n\nthree\n", &out[..]);
+        let expected = vec![
+            Ok("one".to_owned()),
+            Ok(String::new()),
+            Ok("three".to_owned()),
+        ];
+        assert_eq!(expected, read_all(codec, &out).await);
+    }
+}
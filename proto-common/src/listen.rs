@@ -0,0 +1,52 @@
+//! Where a server listens, taken from its command line or environment.
+
+use clap::Args;
+use std::net::SocketAddr;
+
+/// Options every server flattens into its own arguments. The environment
+/// variables are there for deployments where changing the command line is
+/// harder than changing the environment.
+#[derive(Args, Debug, Clone)]
+pub struct Listen {
+    /// Address to listen on.
+    #[arg(long, env = "PROTOHACKERS_LISTEN", default_value = "0.0.0.0:4567")]
+    listen: SocketAddr,
+    /// Port to listen on, in place of the one in --listen.
+    #[arg(long, env = "PROTOHACKERS_PORT")]
+    port: Option<u16>,
+}
+
+impl Listen {
+    pub fn addr(&self) -> SocketAddr {
+        let mut addr = self.listen;
+        if let Some(port) = self.port {
+            addr.set_port(port);
+        }
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        listen: Listen,
+    }
+
+    fn addr(args: &[&str]) -> String {
+        let cli = Cli::try_parse_from(["server"].iter().chain(args)).unwrap();
+        cli.listen.addr().to_string()
+    }
+
+    #[test]
+    fn port_replaces_the_one_in_the_address() {
+        assert_eq!("127.0.0.1:9", addr(&["--listen", "127.0.0.1:9"]));
+        assert_eq!("0.0.0.0:10001", addr(&["--port", "10001"]));
+        let args = ["--listen", "[::1]:9", "--port", "10001"];
+        assert_eq!("[::1]:10001", addr(&args));
+    }
+}
@@ -0,0 +1,10 @@
+//! Where the servers' logs go.
+
+use tracing_subscriber::EnvFilter;
+
+/// Logs to stdout at the levels `RUST_LOG` asks for, or at info and above
+/// if it doesn't.
+pub fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
@@ -0,0 +1,94 @@
+//! Reporting the servers' metrics from one place. Servers register what
+//! they want logged, and a single task logs all of it every
+//! `REPORT_INTERVAL`, each in the span of the server that registered it,
+//! until the server drops its registration.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::Span;
+
+/// How often metrics are reported.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Metrics a server logs every `REPORT_INTERVAL`.
+pub trait Report: Send + 'static {
+    /// Logs the metrics; `elapsed` is the time since the last report, for
+    /// working out rates.
+    fn report(&mut self, elapsed: Duration);
+}
+
+impl<F: FnMut(Duration) + Send + 'static> Report for F {
+    fn report(&mut self, elapsed: Duration) {
+        self(elapsed)
+    }
+}
+
+struct Entry {
+    id: u64,
+    span: Span,
+    report: Box<dyn Report>,
+}
+
+/// Everything registered, and the task reporting it, which is started on
+/// the first registration and stops once there's nothing left to report.
+struct Registry {
+    next_id: u64,
+    entries: Vec<Entry>,
+    task: Option<JoinHandle<()>>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 0,
+    entries: vec![],
+    task: None,
+});
+
+/// Keeps a report registered; dropping it stops the report.
+#[must_use = "the report stops when this is dropped"]
+pub struct Reporting(u64);
+
+impl Drop for Reporting {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.entries.retain(|entry| entry.id != self.0);
+    }
+}
+
+/// Reports `report` with the other metrics, in the current span, until the
+/// returned `Reporting` is dropped. Must be called within a runtime.
+pub fn report(report: impl Report) -> Reporting {
+    let mut registry = REGISTRY.lock().unwrap();
+    let id = registry.next_id;
+    registry.next_id += 1;
+    registry.entries.push(Entry {
+        id,
+        span: Span::current(),
+        report: Box::new(report),
+    });
+    // The task dies with the runtime it was spawned on, so it's started
+    // again if an earlier runtime went away.
+    if registry.task.as_ref().is_none_or(JoinHandle::is_finished) {
+        registry.task = Some(tokio::spawn(report_all()));
+    }
+    Reporting(id)
+}
+
+async fn report_all() {
+    let mut interval = time::interval_at(Instant::now() + REPORT_INTERVAL, REPORT_INTERVAL);
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        let elapsed = last.elapsed();
+        last = Instant::now();
+        let mut registry = REGISTRY.lock().unwrap();
+        if registry.entries.is_empty() {
+            registry.task = None;
+            return;
+        }
+        for entry in &mut registry.entries {
+            entry.span.in_scope(|| entry.report.report(elapsed));
+        }
+    }
+}
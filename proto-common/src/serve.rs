@@ -0,0 +1,248 @@
+//! Accepting connections until told to stop, and then giving the ones
+//! still open a little while to finish.
+
+use crate::limit::{ConnLimit, ConnMetrics, Limiter};
+use anyhow::Result;
+use std::future::{self, Future};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{info, info_span, Instrument};
+
+/// How long connections get on shutdown unless told otherwise.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Tells a connection that the server is shutting down. Connections are
+/// expected to stop taking requests, finish the ones in hand and return.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// One that never starts, for connections served on their own.
+    pub fn never() -> Shutdown {
+        Shutdown(watch::channel(false).1)
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutting down has started. Cancel safe, so it can be
+    /// raced against reading the next request.
+    pub async fn started(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM where there is such a thing.
+pub async fn signal() {
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(_) => future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let term = future::pending::<()>();
+    let int = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            future::pending::<()>().await;
+        }
+    };
+    select! {
+        () = term => {}
+        () = int => {}
+    }
+}
+
+/// Spawns a task for every accepted connection and keeps track of them, so
+/// that on shutdown they can be waited for. Each runs in a `conn` span with
+/// its id and peer address. Connections over the limit, if there is one,
+/// are rejected or queued as the `Limiter` sees fit.
+pub struct Server {
+    list: TcpListener,
+    grace: Duration,
+    limiter: Limiter,
+}
+
+impl Server {
+    pub fn new(list: TcpListener) -> Server {
+        Server {
+            list,
+            grace: SHUTDOWN_GRACE,
+            limiter: Limiter::default(),
+        }
+    }
+
+    pub fn with_limit(mut self, limit: &ConnLimit) -> Server {
+        self.limiter = Limiter::new(limit);
+        self
+    }
+
+    /// Counts of connections taken and rejected. They are reported while the
+    /// server runs.
+    pub fn metrics(&self) -> Arc<ConnMetrics> {
+        self.limiter.metrics()
+    }
+
+    /// How long connections get to return once shutdown starts, before
+    /// they are dropped.
+    pub fn with_grace(mut self, grace: Duration) -> Server {
+        self.grace = grace;
+        self
+    }
+
+    /// Serves connections with `handle` until `stop` resolves. Nothing is
+    /// accepted after that, and the connections still open are told to
+    /// shut down and waited for until the grace period runs out.
+    pub async fn run<F, Fut>(self, stop: impl Future<Output = ()>, mut handle: F) -> Result<()>
+    where
+        F: FnMut(TcpStream, SocketAddr, Shutdown) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        info!("listening on {}", self.list.local_addr()?);
+        let _reporting = self.limiter.report();
+        let (shutdown, stopping) = watch::channel(false);
+        let mut conns = JoinSet::new();
+        let mut ids = 0u64..;
+        tokio::pin!(stop);
+        loop {
+            select! {
+                accepted = self.limiter.accept(&self.list) => {
+                    let (stream, addr, permit) = accepted?;
+                    let span = info_span!("conn", id = ids.next(), peer = %addr);
+                    let conn = handle(stream, addr, Shutdown(stopping.clone()));
+                    conns.spawn(async move {
+                        conn.await;
+                        drop(permit);
+                    }.instrument(span));
+                }
+                Some(_) = conns.join_next() => {}
+                () = &mut stop => break,
+            }
+        }
+        drop(self.list);
+        info!(
+            "shutting down, {} connections open, {}",
+            conns.len(),
+            self.limiter.metrics()
+        );
+        let _ = shutdown.send(true);
+        let _ = timeout(self.grace, async {
+            while conns.join_next().await.is_some() {}
+        })
+        .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Overflow;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn connections_finish_what_they_have_in_hand() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let server = tokio::spawn(Server::new(list).run(
+            async {
+                stopped.await.unwrap();
+            },
+            |mut stream, _, mut shutdown| async move {
+                shutdown.started().await;
+                let _ = stream.write_all(b"bye").await;
+            },
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // Once the connection is accepted, stop the server.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        let mut bye = vec![];
+        client.read_to_end(&mut bye).await.unwrap();
+        assert_eq!(b"bye", &bye[..]);
+        server.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_that_dont_finish_are_dropped() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let grace = Duration::from_millis(100);
+        let server = tokio::spawn(Server::new(list).with_grace(grace).run(
+            async {
+                stopped.await.unwrap();
+            },
+            |stream, _, _| async move {
+                future::pending::<()>().await;
+                drop(stream);
+            },
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(start.elapsed() >= grace);
+        assert_eq!(0, client.read(&mut [0; 1]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn finished_connections_make_room_for_queued_ones() {
+        let list = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = list.local_addr().unwrap();
+        let limit = ConnLimit {
+            max_connections: Some(1.try_into().unwrap()),
+            on_overflow: Overflow::Queue,
+        };
+        let server = Server::new(list).with_limit(&limit);
+        let metrics = server.metrics();
+        tokio::spawn(
+            server.run(future::pending(), |mut stream, _, _| async move {
+                let _ = stream.write_all(b"hi").await;
+            }),
+        );
+
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let mut hi = vec![];
+            client.read_to_end(&mut hi).await.unwrap();
+            assert_eq!(b"hi", &hi[..]);
+        }
+        assert_eq!("accepted: 3, rejected: 0", metrics.to_string());
+    }
+
+    #[tokio::test]
+    async fn shutdown_that_already_started_is_seen() {
+        let (tx, rx) = watch::channel(false);
+        tx.send(true).unwrap();
+        let mut shutdown = Shutdown(rx);
+        assert!(shutdown.is_started());
+        timeout(Duration::from_secs(1), shutdown.started())
+            .await
+            .unwrap();
+        let never = timeout(Duration::from_millis(10), Shutdown::never().started()).await;
+        assert!(never.is_err());
+    }
+}
@@ -0,0 +1,25 @@
+//! The report registry is process-wide, so its test runs on its own rather
+//! than next to the other tests' runtimes.
+
+use proto_common::{report, REPORT_INTERVAL};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use tokio::time;
+
+#[tokio::test(start_paused = true)]
+async fn reports_until_dropped() {
+    let reports = Arc::new(AtomicU64::new(0));
+    let reporting = report({
+        let reports = reports.clone();
+        move |elapsed| {
+            assert!(elapsed >= REPORT_INTERVAL);
+            reports.fetch_add(1, Relaxed);
+        }
+    });
+    time::sleep(REPORT_INTERVAL * 3 + REPORT_INTERVAL / 2).await;
+    assert_eq!(3, reports.load(Relaxed));
+
+    drop(reporting);
+    time::sleep(REPORT_INTERVAL * 2).await;
+    assert_eq!(3, reports.load(Relaxed));
+}
@@ -0,0 +1,205 @@
+//! Runs any of the problem servers side by side in one process, on one
+//! runtime, with one shutdown, one log and one metrics report for all of
+//! them.
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{error, info_span, Instrument};
+
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the given servers until SIGINT or SIGTERM, as in
+    /// `protohackers run p01:10001 p11:10011 --authority host:port`.
+    Run {
+        /// Address every server listens on.
+        #[arg(long, default_value = "0.0.0.0")]
+        host: IpAddr,
+        /// Problem and port of each server, as `p01:10001`, each followed
+        /// by options of its own, as its binary takes them.
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        servers: Vec<String>,
+    },
+}
+
+/// A server to run.
+#[derive(Debug, Clone, PartialEq)]
+struct Spec {
+    problem: String,
+    port: u16,
+}
+
+impl FromStr for Spec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Spec> {
+        let Some((problem, port)) = s.split_once(':') else {
+            bail!("expected problem:port, as in p01:10001");
+        };
+        if !PROBLEMS.contains(&problem) {
+            bail!("unknown problem '{problem}', expected one of {PROBLEMS:?}");
+        }
+        let port = port.parse().with_context(|| format!("bad port '{port}'"))?;
+        Ok(Spec {
+            problem: problem.to_owned(),
+            port,
+        })
+    }
+}
+
+/// Splits `args` into servers to run, each with the options that follow it
+/// up to the next server.
+fn split_servers(args: &[String]) -> Result<Vec<(Spec, Vec<String>)>> {
+    let mut servers: Vec<(Spec, Vec<String>)> = vec![];
+    for arg in args {
+        match (arg.parse(), servers.last_mut()) {
+            (Ok(spec), _) => servers.push((spec, vec![])),
+            (Err(_), Some((_, options))) => options.push(arg.clone()),
+            (Err(e), None) => return Err(e),
+        }
+    }
+    Ok(servers)
+}
+
+type Serving = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Every problem is a crate with `Args` and `run`, as its own binary uses
+/// them.
+macro_rules! problems {
+    ($($problem:ident),*) => {
+        const PROBLEMS: &[&str] = &[$(stringify!($problem)),*];
+
+        /// The server for `problem`, with options taken from `argv` the way
+        /// its binary would take them. It runs until `stop` flips.
+        fn server(problem: &str, argv: &[String], stop: watch::Receiver<bool>) -> Result<Serving> {
+            match problem {
+                $(stringify!($problem) => {
+                    let args = <$problem::Args as Parser>::try_parse_from(argv)?;
+                    Ok(Box::pin($problem::run(args, stopped(stop))))
+                })*
+                _ => bail!("unknown problem '{problem}'"),
+            }
+        }
+    };
+}
+
+problems!(p00, p01, p02, p03, p04, p05, p06, p07, p08, p09, p10, p11);
+
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    let _ = stop.changed().await;
+}
+
+fn outcome(done: Result<Result<()>, JoinError>) -> Result<()> {
+    done?
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let Command::Run { servers, host } = Cli::parse().command;
+    proto_common::init_tracing();
+    let (stop, stopping) = watch::channel(false);
+    let mut running = JoinSet::new();
+    for (Spec { problem, port }, options) in split_servers(&servers)? {
+        // The port goes in on its own too, or PROTOHACKERS_PORT would put
+        // every server on the same one.
+        let listen = SocketAddr::new(host, port).to_string();
+        let port = port.to_string();
+        let mut argv = vec![
+            problem.clone(),
+            "--listen".into(),
+            listen,
+            "--port".into(),
+            port,
+        ];
+        argv.extend(options);
+        let serving = server(&problem, &argv, stopping.clone())?;
+        let span = info_span!("server", %problem);
+        running.spawn(
+            async move { serving.await.with_context(|| format!("running {problem}")) }
+                .instrument(span),
+        );
+    }
+
+    // A server that stops on its own, say because its port is taken, stops
+    // the others too.
+    let mut result = tokio::select! {
+        () = proto_common::signal() => Ok(()),
+        Some(done) = running.join_next() => outcome(done),
+    };
+    let _ = stop.send(true);
+    while let Some(done) = running.join_next().await {
+        if let Err(e) = outcome(done) {
+            error!("{e:#}");
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_name_a_known_problem_and_a_port() {
+        let spec: Spec = "p06:10006".parse().unwrap();
+        let expected = Spec {
+            problem: "p06".to_owned(),
+            port: 10006,
+        };
+        assert_eq!(expected, spec);
+        assert!("p06".parse::<Spec>().is_err());
+        assert!("p12:10012".parse::<Spec>().is_err());
+        assert!("p06:http".parse::<Spec>().is_err());
+    }
+
+    #[test]
+    fn options_go_to_the_server_before_them() {
+        let args: Vec<String> = [
+            "p05:10005",
+            "--upstream",
+            "chat.example:16963",
+            "p06:10006",
+            "p11:10011",
+            "--authority",
+            "pestcontrol.example:20547",
+        ]
+        .map(String::from)
+        .into();
+        let servers = split_servers(&args).unwrap();
+        let options: Vec<_> = servers
+            .iter()
+            .map(|(spec, options)| (spec.port, options.join(" ")))
+            .collect();
+        assert_eq!(
+            vec![
+                (10005, "--upstream chat.example:16963".to_owned()),
+                (10006, String::new()),
+                (10011, "--authority pestcontrol.example:20547".to_owned()),
+            ],
+            options
+        );
+        assert!(split_servers(&["--upstream".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn server_options_are_parsed_by_the_server() {
+        let (stop, stopping) = watch::channel(false);
+        let argv = ["p09", "--log", "jobs.log"].map(String::from);
+        assert!(server("p09", &argv, stopping.clone()).is_ok());
+        let argv = ["p09", "--no-such-option"].map(String::from);
+        assert!(server("p09", &argv, stopping).is_err());
+        drop(stop);
+    }
+}